@@ -1,8 +1,11 @@
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
 
+/// Board built when `BOARD` is not set.
+const DEFAULT_BOARD: &str = "flat7";
+
 fn main() {
     // Put the linker script somewhere the linker can find it
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
@@ -15,4 +18,56 @@ fn main() {
     // Only re-run the build script when memory.x is changed,
     // instead of when any part of the source code changes.
     println!("cargo:rerun-if-changed=memory.x");
+
+    let board = env::var("BOARD").unwrap_or_else(|_| DEFAULT_BOARD.to_string());
+    let path = format!("boards/{}.txt", board);
+    println!("cargo:rerun-if-env-changed=BOARD");
+    println!("cargo:rerun-if-changed={}", path);
+    let text = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+    fs::write(out.join("board.rs"), generate_board(&path, &text)).unwrap();
+}
+
+/// Constants of the board definition, lines of `key = value` and comments of `#`.
+fn generate_board(path: &str, text: &str) -> String {
+    let mut manufacturer = None;
+    let mut product = None;
+    let mut vid = None;
+    let mut pid = None;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fail = |msg: &str| -> ! { panic!("{}:{}: {}", path, i + 1, msg) };
+        let (key, value) = match line.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => fail("expected `key = value`"),
+        };
+        match key {
+            "manufacturer" => manufacturer = Some(value.to_string()),
+            "product" => product = Some(value.to_string()),
+            "vid" => vid = Some(parse_id(value).unwrap_or_else(|| fail("invalid vid"))),
+            "pid" => pid = Some(parse_id(value).unwrap_or_else(|| fail("invalid pid"))),
+            _ => fail(&format!("unknown key `{}`", key)),
+        }
+    }
+    let missing = |key: &str| -> ! { panic!("{}: `{}` is missing", path, key) };
+    format!(
+        "pub const MANUFACTURER: &str = {:?};\n\
+         pub const PRODUCT: &str = {:?};\n\
+         pub const VID: u16 = {:#06x};\n\
+         pub const PID: u16 = {:#06x};\n",
+        manufacturer.unwrap_or_else(|| missing("manufacturer")),
+        product.unwrap_or_else(|| missing("product")),
+        vid.unwrap_or_else(|| missing("vid")),
+        pid.unwrap_or_else(|| missing("pid")),
+    )
+}
+
+/// USB vendor or product ID, in hex with `0x` or in decimal.
+fn parse_id(s: &str) -> Option<u16> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
 }
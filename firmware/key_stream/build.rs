@@ -0,0 +1,346 @@
+//! Compile `keymap.txt` into the static keymaps included by `src/keymap.rs`.
+
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+#[path = "src/chord.rs"]
+#[allow(dead_code)]
+mod chord;
+#[path = "src/grid.rs"]
+#[allow(dead_code)]
+mod grid;
+#[path = "src/hid_keycodes.rs"]
+#[allow(dead_code)]
+mod hid_keycodes;
+#[path = "src/host_layout.rs"]
+#[allow(dead_code)]
+mod host_layout;
+#[path = "src/keymap_format.rs"]
+#[allow(dead_code)]
+mod keymap_format;
+#[path = "src/validate.rs"]
+#[allow(dead_code)]
+mod validate;
+
+use hid_keycodes::Kc;
+use keymap_format::{Action, KeymapFile, MODIFIERS};
+
+const KEYMAP: &str = "keymap.txt";
+/// Static of each layer, layer n is `LAYER_STATICS[n]`.
+const LAYER_STATICS: [&str; 4] = [
+    "SIMPLE_KEY_MAP",
+    "MOD1_KEY_MAP",
+    "MOD2_KEY_MAP",
+    "MOD3_KEY_MAP",
+];
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", KEYMAP);
+    for src in [
+        "chord.rs",
+        "grid.rs",
+        "hid_keycodes.rs",
+        "host_layout.rs",
+        "keymap_format.rs",
+        "validate.rs",
+    ]
+    .iter()
+    {
+        println!("cargo:rerun-if-changed=src/{}", src);
+    }
+
+    let text = fs::read_to_string(KEYMAP).unwrap_or_else(|e| fail(&format!("{}: {}", KEYMAP, e)));
+    let file = keymap_format::parse(&text).unwrap_or_else(|e| fail(&format!("{}:{}", KEYMAP, e)));
+    let diags = validate::check(&file);
+    for d in diags.iter() {
+        println!("cargo:warning={}:{}", KEYMAP, d);
+    }
+    if validate::has_error(&diags) {
+        fail(&format!("{}: the keymap has errors", KEYMAP));
+    }
+    let code = generate(&file).unwrap_or_else(|e| fail(&format!("{}: {}", KEYMAP, e)));
+
+    let out = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    fs::write(out.join("keymap.rs"), code).unwrap();
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
+
+fn generate(file: &KeymapFile) -> Result<String, String> {
+    if !file.settings.is_empty() {
+        return Err("settings are not supported in the built-in keymap".to_string());
+    }
+    if let Some(l) = file.layers.iter().find(|l| l.number >= LAYER_STATICS.len()) {
+        return Err(format!(
+            "line {}: layer {} is out of range, expected 0 to {}",
+            l.line,
+            l.number,
+            LAYER_STATICS.len() - 1
+        ));
+    }
+    let mut out = format!("// Generated by build.rs from {}.\n\n", KEYMAP);
+    for m in file.macros.iter() {
+        writeln!(
+            out,
+            "pub static {}: Command = {};",
+            m.name,
+            command(&m.action)
+        )
+        .unwrap();
+    }
+    for (n, name) in LAYER_STATICS.iter().enumerate() {
+        let layer = file
+            .layers
+            .iter()
+            .find(|l| l.number == n)
+            .ok_or_else(|| format!("layer {} is not defined", n))?;
+        writeln!(
+            out,
+            "\npub static {}: [Command; {}] = [",
+            name,
+            layer.keys.len()
+        )
+        .unwrap();
+        for a in layer.keys.iter() {
+            writeln!(out, "    {},", command(a)).unwrap();
+        }
+        out += "];\n";
+    }
+    writeln!(
+        out,
+        "\npub static COMBO_KEYS: [(Pos, Pos, Command); {}] = [",
+        file.combos.len()
+    )
+    .unwrap();
+    for c in file.combos.iter() {
+        writeln!(
+            out,
+            "    ({:#04x}, {:#04x}, {}),",
+            c.k1,
+            c.k2,
+            command(&c.action)
+        )
+        .unwrap();
+    }
+    out += "];\n";
+    writeln!(
+        out,
+        "const _: () = assert!({} <= N_COMBOS, \"too many combos in {}\");",
+        file.combos.len(),
+        KEYMAP
+    )
+    .unwrap();
+    generate_profiles(file, &mut out)?;
+    writeln!(
+        out,
+        "\npub static CHORDS: [Chord; {}] = [",
+        file.chords.len()
+    )
+    .unwrap();
+    for c in file.chords.iter() {
+        let chord = chord::Chord::new(&c.keys, c.text.as_bytes())
+            .ok_or_else(|| format!("line {}: invalid chord", c.line))?;
+        writeln!(
+            out,
+            "    Chord {{ keys: [{:#04x}, {:#04x}, {:#04x}], text: *b{:?} }},",
+            chord.keys[0],
+            chord.keys[1],
+            chord.keys[2],
+            String::from_utf8_lossy(&chord.text)
+        )
+        .unwrap();
+    }
+    out += "];\n";
+    if !file.chords.is_empty() {
+        writeln!(
+            out,
+            "const _: () = assert!({} <= N_CHORDS, \"too many chords in {}\");",
+            file.chords.len(),
+            KEYMAP
+        )
+        .unwrap();
+    }
+    if file.strings.len() > u8::MAX as usize + 1 {
+        return Err(format!("more than {} strings", u8::MAX as usize + 1));
+    }
+    // Each string is terminated by 0, the layout of `Keymap::strings`.
+    let strings: Vec<String> = file
+        .strings
+        .iter()
+        .flat_map(|s| s.bytes().chain(Some(0)))
+        .map(|b| format!("{:#04x}", b))
+        .collect();
+    writeln!(
+        out,
+        "\npub static STRINGS: [u8; {}] = [{}];",
+        strings.len(),
+        strings.join(", ")
+    )
+    .unwrap();
+    if !strings.is_empty() {
+        writeln!(
+            out,
+            "const _: () = assert!({} <= STRINGS_SIZE, \"too long strings in {}\");",
+            strings.len(),
+            KEYMAP
+        )
+        .unwrap();
+    }
+    Ok(out)
+}
+
+/// `PROFILE_LAYERS` and `PROFILE_COMBO_KEYS` of profiles 1 and on, which must be numbered in
+/// order and have all layers.
+fn generate_profiles(file: &KeymapFile, out: &mut String) -> Result<(), String> {
+    for (i, p) in file.profiles.iter().enumerate() {
+        if p.number != i + 1 {
+            return Err(format!(
+                "line {}: profile {} is defined before profile {}",
+                p.line,
+                p.number,
+                i + 1
+            ));
+        }
+    }
+    writeln!(
+        out,
+        "\npub static PROFILE_LAYERS: [[[Command; N_KEYS]; N_LAYERS]; {}] = [",
+        file.profiles.len()
+    )
+    .unwrap();
+    for p in file.profiles.iter() {
+        if let Some(l) = p.layers.iter().find(|l| l.number >= LAYER_STATICS.len()) {
+            return Err(format!(
+                "line {}: layer {} is out of range, expected 0 to {}",
+                l.line,
+                l.number,
+                LAYER_STATICS.len() - 1
+            ));
+        }
+        out.push_str("    [\n");
+        for n in 0..LAYER_STATICS.len() {
+            let layer = p
+                .layers
+                .iter()
+                .find(|l| l.number == n)
+                .ok_or_else(|| format!("layer {} of profile {} is not defined", n, p.number))?;
+            out.push_str("        [\n");
+            for a in layer.keys.iter() {
+                writeln!(out, "            {},", command(a)).unwrap();
+            }
+            out.push_str("        ],\n");
+        }
+        out.push_str("    ],\n");
+    }
+    out.push_str("];\n");
+    writeln!(
+        out,
+        "\npub static PROFILE_COMBO_KEYS: [&[(Pos, Pos, Command)]; {}] = [",
+        file.profiles.len()
+    )
+    .unwrap();
+    for p in file.profiles.iter() {
+        out.push_str("    &[\n");
+        for c in p.combos.iter() {
+            writeln!(
+                out,
+                "        ({:#04x}, {:#04x}, {}),",
+                c.k1,
+                c.k2,
+                command(&c.action)
+            )
+            .unwrap();
+        }
+        out.push_str("    ],\n");
+    }
+    out.push_str("];\n");
+    for p in file.profiles.iter() {
+        writeln!(
+            out,
+            "const _: () = assert!({} <= N_COMBOS, \"too many combos in {}\");",
+            p.combos.len(),
+            KEYMAP
+        )
+        .unwrap();
+    }
+    // Profiles of `[profile n]` and of keys switching profiles.
+    let profiles = file.profiles.iter().map(|p| (&p.layers, &p.combos));
+    let max_profile = Some((&file.layers, &file.combos))
+        .into_iter()
+        .chain(profiles)
+        .flat_map(|(layers, combos)| {
+            layers
+                .iter()
+                .flat_map(|l| l.keys.iter())
+                .chain(combos.iter().map(|c| &c.action))
+        })
+        .filter_map(|a| match *a {
+            Action::SwitchProfile(n) => Some(n),
+            _ => None,
+        })
+        .chain(Some(file.profiles.len()))
+        .max()
+        .unwrap_or(0);
+    if max_profile > 0 {
+        writeln!(
+            out,
+            "const _: () = assert!({} < N_PROFILES, \"too many profiles in {}\");",
+            max_profile, KEYMAP
+        )
+        .unwrap();
+    }
+    Ok(())
+}
+
+/// Rust expression of the action, using the helpers in `keymap.rs`.
+fn command(a: &Action) -> String {
+    match *a {
+        Action::Nop => "nop()".to_string(),
+        Action::Key(kc) => format!("k({})", key_code(kc)),
+        Action::Modifier(i) => format!("m(ModifierKey::{})", MODIFIERS[i]),
+        Action::ModifiedKey(mods, kc) => format!(
+            "Command::ModifiedKey {{ mk: {}, kc: {} }}",
+            modifiers(mods),
+            key_code(kc)
+        ),
+        Action::Reset => "Command::RequestReset".to_string(),
+        Action::Char(mods, c) => format!(
+            "Command::Char {{ mk: {}, c: b{:?} }}",
+            modifiers(mods),
+            c as char
+        ),
+        Action::SendString(i) => format!("Command::SendString {{ index: {} }}", i),
+        Action::Unicode(c) => format!("Command::Unicode {{ c: '{}' }}", c.escape_unicode()),
+        Action::ToggleNicola => "Command::ToggleNicola".to_string(),
+        Action::ToggleChords => "Command::ToggleChords".to_string(),
+        Action::ModTap(i, kc) => format!(
+            "Command::ModTap {{ mk: ModifierKey::{}, kc: {} }}",
+            MODIFIERS[i],
+            key_code(kc)
+        ),
+        Action::EnterSteno => "Command::EnterSteno".to_string(),
+        Action::SwitchProfile(n) => format!("Command::SwitchProfile {{ n: {} }}", n),
+        Action::SetHostOs(i) => format!("Command::SetHostOs {{ os: HostOs::ALL[{}] }}", i),
+    }
+}
+
+fn modifiers(mods: u16) -> String {
+    let mks: Vec<String> = (0..MODIFIERS.len())
+        .filter(|i| mods & (1 << i) != 0)
+        .map(|i| format!("ModifierKey::{}", MODIFIERS[i]))
+        .collect();
+    format!("Modifiers::new(&[{}])", mks.join(", "))
+}
+
+fn key_code(kc: Kc) -> String {
+    match kc.name() {
+        Some(name) => format!("KC::{}", name),
+        None => format!("Kc::from_code({:#04x})", kc.code()),
+    }
+}
@@ -0,0 +1,129 @@
+//! Chords: keys pressed together type a short text, for kana layouts such as 薙刀式 which
+//! type kana as romaji on the host IME.
+//!
+//! Chords are the `[chords]` section of the keymap file, keys of the grid and the text typed
+//! for them, such as `R2C4 R2C8 "ga"`.  While the chord input is on, keys of chords on the base
+//! layer type texts instead of their keys:
+//! - Keys pressed within the threshold of the first one are pressed together.  The chord of
+//!   the most of them is typed, and the rest are processed again.
+//! - Keys of a chord of two or more keys shift the keys pressed while they are held: the
+//!   chord of the most of the held keys with the new keys is typed if there is one
+//!   (continuous shift), otherwise the chord of the new keys.
+//! - A key which is not a chord alone shifts the same way while held, such as a space key of
+//!   a layout, and types its key of the layer when released without shifting any key.
+
+/// Keys of a chord at most.
+pub const CHORD_KEYS: usize = 3;
+/// Bytes of the text of a chord at most.
+pub const TEXT_SIZE: usize = 5;
+
+/// Keys and the text of a chord.  Unused slots have no keys.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Chord {
+    /// Positions of different keys, in any order, then 0.
+    pub keys: [u8; CHORD_KEYS],
+    /// Printable ASCII characters, then 0.
+    pub text: [u8; TEXT_SIZE],
+}
+
+impl Chord {
+    pub const EMPTY: Chord = Chord {
+        keys: [0; CHORD_KEYS],
+        text: [0; TEXT_SIZE],
+    };
+    /// Size of the byte representation used by the storage and the raw HID protocol.
+    pub const ENCODED_SIZE: usize = CHORD_KEYS + TEXT_SIZE;
+
+    /// Chord of 1 to `CHORD_KEYS` different keys typing 1 to `TEXT_SIZE` printable ASCII
+    /// characters, or `None`.
+    pub fn new(keys: &[u8], text: &[u8]) -> Option<Chord> {
+        if keys.is_empty() || keys.len() > CHORD_KEYS || text.is_empty() || text.len() > TEXT_SIZE {
+            return None;
+        }
+        if !text.iter().all(|c| c.is_ascii_graphic() || *c == b' ') {
+            return None;
+        }
+        for (i, k) in keys.iter().enumerate() {
+            if *k == 0 || keys[..i].contains(k) {
+                return None;
+            }
+        }
+        let mut chord = Chord::EMPTY;
+        chord.keys[..keys.len()].copy_from_slice(keys);
+        chord.text[..text.len()].copy_from_slice(text);
+        Some(chord)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys[0] == 0
+    }
+
+    pub fn keys(&self) -> &[u8] {
+        let n = self.keys.iter().position(|k| *k == 0).unwrap_or(CHORD_KEYS);
+        &self.keys[..n]
+    }
+
+    pub fn text(&self) -> &[u8] {
+        let n = self.text.iter().position(|c| *c == 0).unwrap_or(TEXT_SIZE);
+        &self.text[..n]
+    }
+
+    /// True if the keys are the keys of the chord in any order.
+    pub fn is_pressed_by(&self, keys: &[u8]) -> bool {
+        let own = self.keys();
+        !own.is_empty() && own.len() == keys.len() && keys.iter().all(|k| own.contains(k))
+    }
+
+    /// `[keys..., text...]`.
+    pub fn encode(&self) -> [u8; Chord::ENCODED_SIZE] {
+        let mut b = [0; Chord::ENCODED_SIZE];
+        b[..CHORD_KEYS].copy_from_slice(&self.keys);
+        b[CHORD_KEYS..].copy_from_slice(&self.text);
+        b
+    }
+
+    pub fn decode(b: &[u8]) -> Option<Chord> {
+        if b.len() < Chord::ENCODED_SIZE {
+            return None;
+        }
+        if b[..Chord::ENCODED_SIZE].iter().all(|x| *x == 0) {
+            return Some(Chord::EMPTY);
+        }
+        let keys = &b[..CHORD_KEYS];
+        let text = &b[CHORD_KEYS..Chord::ENCODED_SIZE];
+        let n_keys = keys.iter().position(|k| *k == 0).unwrap_or(CHORD_KEYS);
+        let n_text = text.iter().position(|c| *c == 0).unwrap_or(TEXT_SIZE);
+        let chord = Chord::new(&keys[..n_keys], &text[..n_text])?;
+        // Nothing but 0 after the end.
+        if chord.encode()[..] == b[..Chord::ENCODED_SIZE] {
+            Some(chord)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chord() {
+        let chord = Chord::new(&[0x24, 0xa2], b"ga").unwrap();
+        assert_eq!(chord.keys(), &[0x24, 0xa2]);
+        assert_eq!(chord.text(), b"ga");
+        assert!(chord.is_pressed_by(&[0xa2, 0x24]));
+        assert!(!chord.is_pressed_by(&[0xa2]));
+        assert!(!Chord::EMPTY.is_pressed_by(&[]));
+        assert_eq!(Chord::decode(&chord.encode()), Some(chord));
+        assert_eq!(Chord::decode(&[0; 8]), Some(Chord::EMPTY));
+        assert_eq!(Chord::decode(&[0x24, 0, 0xa2, b'g', b'a', 0, 0, 0]), None);
+
+        assert!(Chord::new(&[0x24, 0x24], b"ga").is_none());
+        assert!(Chord::new(&[0x11, 0x12, 0x13, 0x14], b"a").is_none());
+        assert!(Chord::new(&[0x11], b"xtsuxa").is_none());
+        assert!(Chord::new(&[0x11], "が".as_bytes()).is_none());
+        assert!(Chord::new(&[0x11], b"").is_none());
+    }
+}
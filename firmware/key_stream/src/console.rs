@@ -0,0 +1,218 @@
+//! Text console on the USB serial port, to watch and configure the keyboard from a terminal
+//! such as `screen /dev/ttyACM0`.
+//!
+//! Lines typed by the host are echoed and run by the `shell` when Enter is pressed, and key
+//! events are printed after `log on`.
+//!
+//! The serial port sends steno strokes in the steno mode, so the console ignores input and
+//! prints nothing while the steno mode is on.
+
+use core::fmt::{self, Write};
+
+use crate::cnt_to_ms;
+use crate::grid::pos_grid;
+use crate::keymap::Pos;
+use crate::shell::Output;
+
+/// Characters of the longest line.  Extra characters are ignored.
+pub const LINE_SIZE: usize = 64;
+const PROMPT: &str = "> ";
+
+/// Line typed by the host.
+#[derive(Copy, Clone)]
+pub struct Line {
+    buf: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Line {
+    const EMPTY: Line = Line {
+        buf: [0; LINE_SIZE],
+        len: 0,
+    };
+
+    pub fn as_str(&self) -> &str {
+        // Only printable ASCII is taken.
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+pub struct Console {
+    /// Line being typed.
+    line: Line,
+    /// Output waiting for the host.
+    out: Output,
+    /// True to print key events.
+    pub log: bool,
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console {
+            line: Line::EMPTY,
+            out: Output::new(),
+            log: false,
+        }
+    }
+
+    /// Take a byte typed by the host and echo it.  Return the line when it is completed by
+    /// CR or LF.
+    pub fn input(&mut self, b: u8) -> Option<Line> {
+        match b {
+            b'\r' | b'\n' => {
+                if self.line.len == 0 && b == b'\n' {
+                    // LF of CR LF.
+                    return None;
+                }
+                self.out.push(b"\r\n");
+                let line = self.line;
+                self.line = Line::EMPTY;
+                Some(line)
+            }
+            // Backspace and DEL.
+            0x08 | 0x7f => {
+                if self.line.len > 0 {
+                    self.line.len -= 1;
+                    self.out.push(b"\x08 \x08");
+                }
+                None
+            }
+            0x20..=0x7e if self.line.len < LINE_SIZE => {
+                self.line.buf[self.line.len] = b;
+                self.line.len += 1;
+                self.out.push(&[b]);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    pub fn prompt(&mut self) {
+        self.out.push(PROMPT.as_bytes());
+    }
+
+    /// Print the output of a command, with LF sent as CR LF for terminals.
+    pub fn print(&mut self, b: &[u8]) {
+        for (i, part) in b.split(|c| *c == b'\n').enumerate() {
+            if i > 0 {
+                self.out.push(b"\r\n");
+            }
+            self.out.push(part);
+        }
+    }
+
+    /// Output waiting for the host.
+    pub fn output(&self) -> &[u8] {
+        self.out.as_slice()
+    }
+
+    /// Remove the first n bytes of the output, written to the host.
+    pub fn consume_output(&mut self, n: usize) {
+        self.out.consume(n);
+    }
+}
+
+impl Default for Console {
+    fn default() -> Console {
+        Console::new()
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.print(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Print a key event of the position for `log on`.
+pub fn log_event(console: &mut Console, pos: Pos, down: bool, cnt: u16) {
+    let action = if down { "down" } else { "up" };
+    // The time wraps in about a minute.
+    let ms = cnt_to_ms(cnt);
+    match pos_grid(pos) {
+        Some((row, col)) => writeln!(console, "R{}C{} {} {} ms", row + 1, col + 1, action, ms),
+        None => writeln!(console, "0x{:02x} {} {} ms", pos, action, ms),
+    }
+    .ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::grid_pos;
+    use crate::shell::OUTPUT_SIZE;
+    use crate::{settings, KeyStream};
+
+    /// Type the text into the console of the stream, and take the output.
+    fn type_text(stream: &mut KeyStream, text: &str) -> std::string::String {
+        stream.console_input(text.as_bytes());
+        let out = std::string::String::from_utf8(stream.console.output().to_vec()).unwrap();
+        stream.console.consume_output(OUTPUT_SIZE);
+        out
+    }
+
+    #[test]
+    fn test_line_editing() {
+        let mut console = Console::new();
+        let mut lines = std::vec::Vec::new();
+        for b in b"gx\x7fet\r\n\r\n".iter() {
+            if let Some(line) = console.input(*b) {
+                lines.push(std::string::String::from(line.as_str()));
+            }
+        }
+        assert_eq!(lines, vec!["get", ""]);
+        assert_eq!(console.output(), b"gx\x08 \x08et\r\n\r\n");
+        console.consume_output(3);
+        assert_eq!(console.output(), b" \x08et\r\n\r\n");
+        for _ in 0..OUTPUT_SIZE {
+            console.prompt();
+        }
+        assert_eq!(console.output().len(), OUTPUT_SIZE);
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut stream = KeyStream::new();
+        assert_eq!(
+            type_text(&mut stream, "get chords\r"),
+            "get chords\r\nchords = 0\r\n> "
+        );
+        let mut mat = [0u8; 8];
+        mat[0] = grid_pos(0, 0);
+        stream.push(&mat, &[0; 8], 0);
+        assert_eq!(
+            type_text(&mut stream, "matrix\r"),
+            "matrix\r\n\
+             #..... ......\r\n\
+             ...... ......\r\n\
+             ...... ......\r\n\
+             ...... ......\r\n> "
+        );
+    }
+
+    #[test]
+    fn test_log() {
+        let mut stream = KeyStream::new();
+        type_text(&mut stream, "log on\r");
+        let mut mat = [0u8; 8];
+        mat[0] = grid_pos(1, 2);
+        // 75 and 150 cnt.
+        stream.push(&mat, &[0; 8], 75 << 16);
+        stream.push(&[0; 8], &[0; 8], 150 << 16);
+        assert_eq!(
+            type_text(&mut stream, ""),
+            "R2C3 down 102 ms\r\nR2C3 up 205 ms\r\n"
+        );
+        type_text(&mut stream, "log off\r");
+        stream.push(&mat, &[0; 8], 330 << 16);
+        assert_eq!(type_text(&mut stream, ""), "");
+    }
+
+    #[test]
+    fn test_steno() {
+        let mut stream = KeyStream::new();
+        assert!(stream.set_setting(settings::STENO, 1));
+        assert_eq!(type_text(&mut stream, "get\r"), "");
+    }
+}
@@ -0,0 +1,53 @@
+//! Both halves of the keyboard seen as one grid, left half first.
+//! Tools and the keymap file name keys by the row and the column of this grid.
+//! This file is also included by `build.rs`.
+
+pub const GRID_ROWS: usize = 4;
+pub const GRID_COLS: usize = 12;
+const HALF_COLS: usize = GRID_COLS / 2;
+
+/// Position (see `keymap::Pos`) of the key at (row, col) of the grid, counting from 0.
+pub fn grid_pos(row: usize, col: usize) -> u8 {
+    let (row_base, col) = if col < HALF_COLS {
+        (1, col)
+    } else {
+        (9, col - HALF_COLS)
+    };
+    (((row + row_base) << 4) | (col + 1)) as u8
+}
+
+/// Grid (row, col) of the position, if it is a key of the keyboard.
+pub fn pos_grid(pos: u8) -> Option<(usize, usize)> {
+    let row = (pos >> 4) as usize;
+    let col = (pos & 0x0f) as usize;
+    if !(1..=HALF_COLS).contains(&col) {
+        return None;
+    }
+    match row {
+        1..=4 => Some((row - 1, col - 1)),
+        9..=12 => Some((row - 9, col - 1 + HALF_COLS)),
+        _ => None,
+    }
+}
+
+/// Index into a keymap layer of the key at (row, col) of the grid.
+pub fn grid_index(row: usize, col: usize) -> usize {
+    if col < HALF_COLS {
+        row * HALF_COLS + col
+    } else {
+        GRID_ROWS * HALF_COLS + row * HALF_COLS + col - HALF_COLS
+    }
+}
+
+/// Grid (row, col) of the index into a keymap layer.
+pub fn index_grid(index: usize) -> (usize, usize) {
+    let half = GRID_ROWS * HALF_COLS;
+    if index < half {
+        (index / HALF_COLS, index % HALF_COLS)
+    } else {
+        (
+            (index - half) / HALF_COLS,
+            (index - half) % HALF_COLS + HALF_COLS,
+        )
+    }
+}
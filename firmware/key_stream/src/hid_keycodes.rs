@@ -1,241 +1,372 @@
-/// See `hid_keycodes` for mapping.
-pub type Kc = u8;
-
-#[allow(dead_code)]
-pub static KBD_NONE: Kc = 0;
-#[allow(dead_code)]
-pub static KBD_A: Kc = 4;
-#[allow(dead_code)]
-pub static KBD_B: Kc = 5;
-#[allow(dead_code)]
-pub static KBD_C: Kc = 6;
-#[allow(dead_code)]
-pub static KBD_D: Kc = 7;
-#[allow(dead_code)]
-pub static KBD_E: Kc = 8;
-#[allow(dead_code)]
-pub static KBD_F: Kc = 9;
-#[allow(dead_code)]
-pub static KBD_G: Kc = 10;
-#[allow(dead_code)]
-pub static KBD_H: Kc = 11;
-#[allow(dead_code)]
-pub static KBD_I: Kc = 12;
-#[allow(dead_code)]
-pub static KBD_J: Kc = 13;
-#[allow(dead_code)]
-pub static KBD_K: Kc = 14;
-#[allow(dead_code)]
-pub static KBD_L: Kc = 15;
-#[allow(dead_code)]
-pub static KBD_M: Kc = 16;
-#[allow(dead_code)]
-pub static KBD_N: Kc = 17;
-#[allow(dead_code)]
-pub static KBD_O: Kc = 18;
-#[allow(dead_code)]
-pub static KBD_P: Kc = 19;
-#[allow(dead_code)]
-pub static KBD_Q: Kc = 20;
-#[allow(dead_code)]
-pub static KBD_R: Kc = 21;
-#[allow(dead_code)]
-pub static KBD_S: Kc = 22;
-#[allow(dead_code)]
-pub static KBD_T: Kc = 23;
-#[allow(dead_code)]
-pub static KBD_U: Kc = 24;
-#[allow(dead_code)]
-pub static KBD_V: Kc = 25;
-#[allow(dead_code)]
-pub static KBD_W: Kc = 26;
-#[allow(dead_code)]
-pub static KBD_X: Kc = 27;
-#[allow(dead_code)]
-pub static KBD_Y: Kc = 28;
-#[allow(dead_code)]
-pub static KBD_Z: Kc = 29;
-#[allow(dead_code)]
-pub static KBD_1: Kc = 30;
-#[allow(dead_code)]
-pub static KBD_2: Kc = 31;
-#[allow(dead_code)]
-pub static KBD_3: Kc = 32;
-#[allow(dead_code)]
-pub static KBD_4: Kc = 33;
-#[allow(dead_code)]
-pub static KBD_5: Kc = 34;
-#[allow(dead_code)]
-pub static KBD_6: Kc = 35;
-#[allow(dead_code)]
-pub static KBD_7: Kc = 36;
-#[allow(dead_code)]
-pub static KBD_8: Kc = 37;
-#[allow(dead_code)]
-pub static KBD_9: Kc = 38;
-#[allow(dead_code)]
-pub static KBD_0: Kc = 39;
-#[allow(dead_code)]
-pub static KBD_ENTER: Kc = 40;
-#[allow(dead_code)]
-pub static KBD_ESCAPE: Kc = 41;
-#[allow(dead_code)]
-pub static KBD_BACKSPACE: Kc = 42;
-#[allow(dead_code)]
-pub static KBD_TAB: Kc = 43;
-#[allow(dead_code)]
-pub static KBD_SPACEBAR: Kc = 44;
-#[allow(dead_code)]
-pub static KBD_UNDERSCORE: Kc = 45;
-#[allow(dead_code)]
-pub static KBD_JP_HYPHEN: Kc = 45; // - / =
-#[allow(dead_code)]
-pub static KBD_PLUS: Kc = 46;
-#[allow(dead_code)]
-pub static KBD_JP_CARET: Kc = 46; // ^ / ~
-#[allow(dead_code)]
-pub static KBD_OPEN_BRACKET: Kc = 47;
-#[allow(dead_code)]
-pub static KBD_JP_AT: Kc = 47; // @ / `
-#[allow(dead_code)]
-pub static KBD_CLOSE_BRACKET: Kc = 48;
-#[allow(dead_code)]
-pub static KBD_JP_OPEN_BRACKET: Kc = 48; // [ / {
-#[allow(dead_code)]
-pub static KBD_BACKSLASH: Kc = 49;
-#[allow(dead_code)]
-pub static KBD_JP_CLOSE_BRACKET: Kc = 49; // ] / }
-#[allow(dead_code)]
-pub static KBD_ASH: Kc = 50;
-#[allow(dead_code)]
-pub static KBD_COLON: Kc = 51;
-#[allow(dead_code)]
-pub static KBD_JP_SEMICOLON: Kc = 51; // ; / +
-#[allow(dead_code)]
-pub static KBD_QUOTE: Kc = 52;
-#[allow(dead_code)]
-pub static KBD_JP_COLON: Kc = 52; // : / *
-#[allow(dead_code)]
-pub static KBD_TILDE: Kc = 53;
-#[allow(dead_code)]
-pub static KBD_COMMA: Kc = 54;
-#[allow(dead_code)]
-pub static KBD_DOT: Kc = 55;
-#[allow(dead_code)]
-pub static KBD_SLASH: Kc = 56;
-#[allow(dead_code)]
-pub static KBD_CAPS_LOCK: Kc = 57;
-#[allow(dead_code)]
-pub static KBD_F1: Kc = 58;
-#[allow(dead_code)]
-pub static KBD_F2: Kc = 59;
-#[allow(dead_code)]
-pub static KBD_F3: Kc = 60;
-#[allow(dead_code)]
-pub static KBD_F4: Kc = 61;
-#[allow(dead_code)]
-pub static KBD_F5: Kc = 62;
-#[allow(dead_code)]
-pub static KBD_F6: Kc = 63;
-#[allow(dead_code)]
-pub static KBD_F7: Kc = 64;
-#[allow(dead_code)]
-pub static KBD_F8: Kc = 65;
-#[allow(dead_code)]
-pub static KBD_F9: Kc = 66;
-#[allow(dead_code)]
-pub static KBD_F10: Kc = 67;
-#[allow(dead_code)]
-pub static KBD_F11: Kc = 68;
-#[allow(dead_code)]
-pub static KBD_F12: Kc = 69;
-#[allow(dead_code)]
-pub static KBD_PRINTSCREEN: Kc = 70;
-#[allow(dead_code)]
-pub static KBD_SCROLL_LOCK: Kc = 71;
-#[allow(dead_code)]
-pub static KBD_PAUSE: Kc = 72;
-#[allow(dead_code)]
-pub static KBD_INSERT: Kc = 73;
-#[allow(dead_code)]
-pub static KBD_HOME: Kc = 74;
-#[allow(dead_code)]
-pub static KBD_PAGEUP: Kc = 75;
-#[allow(dead_code)]
-pub static KBD_DELETE: Kc = 76;
-#[allow(dead_code)]
-pub static KBD_END: Kc = 77;
-#[allow(dead_code)]
-pub static KBD_PAGEDOWN: Kc = 78;
-#[allow(dead_code)]
-pub static KBD_RIGHT: Kc = 79;
-#[allow(dead_code)]
-pub static KBD_LEFT: Kc = 80;
-#[allow(dead_code)]
-pub static KBD_DOWN: Kc = 81;
-#[allow(dead_code)]
-pub static KBD_UP: Kc = 82;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_NUM_LOCK: Kc = 83;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_DIVIDE: Kc = 84;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_AT: Kc = 85;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_MULTIPLY: Kc = 85;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_MINUS: Kc = 86;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_PLUS: Kc = 87;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_ENTER: Kc = 88;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_1: Kc = 89;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_2: Kc = 90;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_3: Kc = 91;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_4: Kc = 92;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_5: Kc = 93;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_6: Kc = 94;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_7: Kc = 95;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_8: Kc = 96;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_9: Kc = 97;
-#[allow(dead_code)]
-pub static KBD_KEYPAD_0: Kc = 98;
-
-#[allow(dead_code)]
-pub static KBD_JP_BACKSLASH: Kc = 137; // \(¥) / |
-#[allow(dead_code)]
-pub static KBD_JP_UNDERSCORE: Kc = 135;
-#[allow(dead_code)]
-pub static KBD_JP_MUHENKAN: Kc = 139;
-#[allow(dead_code)]
-pub static KBD_JP_HANKAKU_ZENAKKU: Kc = 138;
-#[allow(dead_code)]
-pub static KBD_JP_HENKAN: Kc = 136;
-
-#[allow(dead_code)]
-pub static KBD_MODIFIER_NONE: Kc = 0x00;
-#[allow(dead_code)]
-pub static KBD_MODIFIER_LEFT_CTRL: Kc = 0x01;
-#[allow(dead_code)]
-pub static KBD_MODIFIER_LEFT_SHIFT: Kc = 0x02;
-#[allow(dead_code)]
-pub static KBD_MODIFIER_LEFT_ALT: Kc = 0x04;
-#[allow(dead_code)]
-pub static KBD_MODIFIER_LEFT_UI: Kc = 0x08;
-#[allow(dead_code)]
-pub static KBD_MODIFIER_RIGHT_CTRL: Kc = 0x10;
-#[allow(dead_code)]
-pub static KBD_MODIFIER_RIGHT_SHIFT: Kc = 0x20;
-#[allow(dead_code)]
-pub static KBD_MODIFIER_RIGHT_ALT: Kc = 0x40;
-#[allow(dead_code)]
-pub static KBD_MODIFIER_RIGHT_UI: Kc = 0x80;
+//! Key codes of the Keyboard/Keypad usage page of USB HID, from 0x00 to 0xe7.
+//! This file is also included by `build.rs` to resolve names in the keymap file.
+
+use core::fmt;
+use core::str::FromStr;
+
+/// Usage page of all key codes, Keyboard/Keypad.
+pub const USAGE_PAGE: u16 = 0x07;
+
+/// Usage ID of a key in `USAGE_PAGE`, which is the value in keyboard reports.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Kc(u8);
+
+impl Kc {
+    pub const fn from_code(code: u8) -> Kc {
+        Kc(code)
+    }
+
+    pub const fn code(self) -> u8 {
+        self.0
+    }
+
+    /// Extended usage with the usage page in the upper 16 bits, as in report descriptors.
+    pub const fn usage(self) -> u32 {
+        (USAGE_PAGE as u32) << 16 | self.0 as u32
+    }
+
+    /// Name in `NAMES`.  The last alias is used, which is the JIS name for JIS keys.
+    pub fn name(self) -> Option<&'static str> {
+        NAMES
+            .iter()
+            .rev()
+            .find(|(_, kc)| *kc == self)
+            .map(|(name, _)| *name)
+    }
+
+    pub fn from_name(name: &str) -> Option<Kc> {
+        NAMES.iter().find(|(n, _)| *n == name).map(|(_, kc)| *kc)
+    }
+}
+
+/// The name such as `KBD_JP_SEMICOLON`, or the code such as `0x87` for keys without names.
+impl fmt::Display for Kc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => f.write_str(name),
+            None => write!(f, "{:#04x}", self.0),
+        }
+    }
+}
+
+impl fmt::Debug for Kc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ParseKcError;
+
+impl fmt::Display for ParseKcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("unknown key code")
+    }
+}
+
+/// Parse the form of `Display`.
+impl FromStr for Kc {
+    type Err = ParseKcError;
+
+    fn from_str(s: &str) -> Result<Kc, ParseKcError> {
+        if let Some(kc) = Kc::from_name(s) {
+            return Ok(kc);
+        }
+        match s.strip_prefix("0x").map(|hex| u8::from_str_radix(hex, 16)) {
+            Some(Ok(code)) => Ok(Kc(code)),
+            _ => Err(ParseKcError),
+        }
+    }
+}
+
+/// Define key codes and their name table.
+macro_rules! keycodes {
+    ($($name:ident = $code:expr,)*) => {
+        $(
+            pub const $name: Kc = Kc($code);
+        )*
+
+        /// Names of key codes, including aliases of the same code.
+        pub static NAMES: &[(&str, Kc)] = &[$((stringify!($name), Kc($code)),)*];
+    };
+}
+
+keycodes! {
+    KBD_NONE = 0,
+    KBD_ERROR_ROLL_OVER = 1,
+    KBD_POST_FAIL = 2,
+    KBD_ERROR_UNDEFINED = 3,
+    KBD_A = 4,
+    KBD_B = 5,
+    KBD_C = 6,
+    KBD_D = 7,
+    KBD_E = 8,
+    KBD_F = 9,
+    KBD_G = 10,
+    KBD_H = 11,
+    KBD_I = 12,
+    KBD_J = 13,
+    KBD_K = 14,
+    KBD_L = 15,
+    KBD_M = 16,
+    KBD_N = 17,
+    KBD_O = 18,
+    KBD_P = 19,
+    KBD_Q = 20,
+    KBD_R = 21,
+    KBD_S = 22,
+    KBD_T = 23,
+    KBD_U = 24,
+    KBD_V = 25,
+    KBD_W = 26,
+    KBD_X = 27,
+    KBD_Y = 28,
+    KBD_Z = 29,
+    KBD_1 = 30,
+    KBD_2 = 31,
+    KBD_3 = 32,
+    KBD_4 = 33,
+    KBD_5 = 34,
+    KBD_6 = 35,
+    KBD_7 = 36,
+    KBD_8 = 37,
+    KBD_9 = 38,
+    KBD_0 = 39,
+    KBD_ENTER = 40,
+    KBD_ESCAPE = 41,
+    KBD_BACKSPACE = 42,
+    KBD_TAB = 43,
+    KBD_SPACEBAR = 44,
+    KBD_UNDERSCORE = 45,
+    KBD_JP_HYPHEN = 45, // - / =
+    KBD_PLUS = 46,
+    KBD_JP_CARET = 46, // ^ / ~
+    KBD_OPEN_BRACKET = 47,
+    KBD_JP_AT = 47, // @ / `
+    KBD_CLOSE_BRACKET = 48,
+    KBD_JP_OPEN_BRACKET = 48, // [ / {
+    KBD_BACKSLASH = 49,
+    KBD_JP_CLOSE_BRACKET = 49, // ] / }
+    KBD_ASH = 50,
+    KBD_COLON = 51,
+    KBD_JP_SEMICOLON = 51, // ; / +
+    KBD_QUOTE = 52,
+    KBD_JP_COLON = 52, // : / *
+    KBD_TILDE = 53,
+    KBD_COMMA = 54,
+    KBD_DOT = 55,
+    KBD_SLASH = 56,
+    KBD_CAPS_LOCK = 57,
+    KBD_F1 = 58,
+    KBD_F2 = 59,
+    KBD_F3 = 60,
+    KBD_F4 = 61,
+    KBD_F5 = 62,
+    KBD_F6 = 63,
+    KBD_F7 = 64,
+    KBD_F8 = 65,
+    KBD_F9 = 66,
+    KBD_F10 = 67,
+    KBD_F11 = 68,
+    KBD_F12 = 69,
+    KBD_PRINTSCREEN = 70,
+    KBD_SCROLL_LOCK = 71,
+    KBD_PAUSE = 72,
+    KBD_INSERT = 73,
+    KBD_HOME = 74,
+    KBD_PAGEUP = 75,
+    KBD_DELETE = 76,
+    KBD_END = 77,
+    KBD_PAGEDOWN = 78,
+    KBD_RIGHT = 79,
+    KBD_LEFT = 80,
+    KBD_DOWN = 81,
+    KBD_UP = 82,
+    KBD_KEYPAD_NUM_LOCK = 83,
+    KBD_KEYPAD_DIVIDE = 84,
+    KBD_KEYPAD_MULTIPLY = 85,
+    KBD_KEYPAD_MINUS = 86,
+    KBD_KEYPAD_PLUS = 87,
+    KBD_KEYPAD_ENTER = 88,
+    KBD_KEYPAD_1 = 89,
+    KBD_KEYPAD_2 = 90,
+    KBD_KEYPAD_3 = 91,
+    KBD_KEYPAD_4 = 92,
+    KBD_KEYPAD_5 = 93,
+    KBD_KEYPAD_6 = 94,
+    KBD_KEYPAD_7 = 95,
+    KBD_KEYPAD_8 = 96,
+    KBD_KEYPAD_9 = 97,
+    KBD_KEYPAD_0 = 98,
+    KBD_KEYPAD_DOT = 99,
+    KBD_NON_US_BACKSLASH = 100,
+    KBD_APPLICATION = 101,
+    KBD_POWER = 102,
+    KBD_KEYPAD_EQUAL = 103,
+    KBD_F13 = 104,
+    KBD_F14 = 105,
+    KBD_F15 = 106,
+    KBD_F16 = 107,
+    KBD_F17 = 108,
+    KBD_F18 = 109,
+    KBD_F19 = 110,
+    KBD_F20 = 111,
+    KBD_F21 = 112,
+    KBD_F22 = 113,
+    KBD_F23 = 114,
+    KBD_F24 = 115,
+    KBD_EXECUTE = 116,
+    KBD_HELP = 117,
+    KBD_MENU = 118,
+    KBD_SELECT = 119,
+    KBD_STOP = 120,
+    KBD_AGAIN = 121,
+    KBD_UNDO = 122,
+    KBD_CUT = 123,
+    KBD_COPY = 124,
+    KBD_PASTE = 125,
+    KBD_FIND = 126,
+    KBD_MUTE = 127,
+    KBD_VOLUME_UP = 128,
+    KBD_VOLUME_DOWN = 129,
+    KBD_LOCKING_CAPS_LOCK = 130,
+    KBD_LOCKING_NUM_LOCK = 131,
+    KBD_LOCKING_SCROLL_LOCK = 132,
+    KBD_KEYPAD_COMMA = 133,
+    KBD_KEYPAD_EQUAL_SIGN = 134,
+    KBD_INTERNATIONAL1 = 135,
+    KBD_INTERNATIONAL2 = 136,
+    KBD_INTERNATIONAL3 = 137,
+    KBD_INTERNATIONAL4 = 138,
+    KBD_INTERNATIONAL5 = 139,
+    KBD_INTERNATIONAL6 = 140,
+    KBD_INTERNATIONAL7 = 141,
+    KBD_INTERNATIONAL8 = 142,
+    KBD_INTERNATIONAL9 = 143,
+    KBD_LANG1 = 144,
+    KBD_LANG2 = 145,
+    KBD_LANG3 = 146,
+    KBD_LANG4 = 147,
+    KBD_LANG5 = 148,
+    KBD_LANG6 = 149,
+    KBD_LANG7 = 150,
+    KBD_LANG8 = 151,
+    KBD_LANG9 = 152,
+    KBD_ALTERNATE_ERASE = 153,
+    KBD_SYSREQ = 154,
+    KBD_CANCEL = 155,
+    KBD_CLEAR = 156,
+    KBD_PRIOR = 157,
+    KBD_RETURN = 158,
+    KBD_SEPARATOR = 159,
+    KBD_OUT = 160,
+    KBD_OPER = 161,
+    KBD_CLEAR_AGAIN = 162,
+    KBD_CRSEL = 163,
+    KBD_EXSEL = 164,
+    KBD_KEYPAD_00 = 176,
+    KBD_KEYPAD_000 = 177,
+    KBD_THOUSANDS_SEPARATOR = 178,
+    KBD_DECIMAL_SEPARATOR = 179,
+    KBD_CURRENCY_UNIT = 180,
+    KBD_CURRENCY_SUBUNIT = 181,
+    KBD_KEYPAD_OPEN_PAREN = 182,
+    KBD_KEYPAD_CLOSE_PAREN = 183,
+    KBD_KEYPAD_OPEN_BRACE = 184,
+    KBD_KEYPAD_CLOSE_BRACE = 185,
+    KBD_KEYPAD_TAB = 186,
+    KBD_KEYPAD_BACKSPACE = 187,
+    KBD_KEYPAD_A = 188,
+    KBD_KEYPAD_B = 189,
+    KBD_KEYPAD_C = 190,
+    KBD_KEYPAD_D = 191,
+    KBD_KEYPAD_E = 192,
+    KBD_KEYPAD_F = 193,
+    KBD_KEYPAD_XOR = 194,
+    KBD_KEYPAD_CARET = 195,
+    KBD_KEYPAD_PERCENT = 196,
+    KBD_KEYPAD_LESS = 197,
+    KBD_KEYPAD_GREATER = 198,
+    KBD_KEYPAD_AMPERSAND = 199,
+    KBD_KEYPAD_DOUBLE_AMPERSAND = 200,
+    KBD_KEYPAD_BAR = 201,
+    KBD_KEYPAD_DOUBLE_BAR = 202,
+    KBD_KEYPAD_COLON = 203,
+    KBD_KEYPAD_HASH = 204,
+    KBD_KEYPAD_SPACE = 205,
+    KBD_KEYPAD_AT = 206,
+    KBD_KEYPAD_EXCLAIM = 207,
+    KBD_KEYPAD_MEMORY_STORE = 208,
+    KBD_KEYPAD_MEMORY_RECALL = 209,
+    KBD_KEYPAD_MEMORY_CLEAR = 210,
+    KBD_KEYPAD_MEMORY_ADD = 211,
+    KBD_KEYPAD_MEMORY_SUBTRACT = 212,
+    KBD_KEYPAD_MEMORY_MULTIPLY = 213,
+    KBD_KEYPAD_MEMORY_DIVIDE = 214,
+    KBD_KEYPAD_PLUS_MINUS = 215,
+    KBD_KEYPAD_CLEAR = 216,
+    KBD_KEYPAD_CLEAR_ENTRY = 217,
+    KBD_KEYPAD_BINARY = 218,
+    KBD_KEYPAD_OCTAL = 219,
+    KBD_KEYPAD_DECIMAL = 220,
+    KBD_KEYPAD_HEXADECIMAL = 221,
+    KBD_LEFT_CTRL = 224,
+    KBD_LEFT_SHIFT = 225,
+    KBD_LEFT_ALT = 226,
+    KBD_LEFT_UI = 227,
+    KBD_RIGHT_CTRL = 228,
+    KBD_RIGHT_SHIFT = 229,
+    KBD_RIGHT_ALT = 230,
+    KBD_RIGHT_UI = 231,
+
+    // Names of the keys of JIS keyboards, after the names above to be shown by `Kc::name`.
+    KBD_JP_HANKAKU_ZENKAKU = 53,
+    KBD_JP_UNDERSCORE = 135, // International1, Ro, \ / _
+    KBD_JP_KATAKANA_HIRAGANA = 136, // International2
+    KBD_JP_BACKSLASH = 137, // International3, \(¥) / |
+    KBD_JP_HENKAN = 138, // International4
+    KBD_JP_MUHENKAN = 139, // International5
+    KBD_JP_KANA = 144, // LANG1, かな of Mac
+    KBD_JP_EISU = 145, // LANG2, 英数 of Mac
+}
+
+// Bits of the modifier byte of keyboard reports.
+pub const KBD_MODIFIER_NONE: u8 = 0x00;
+pub const KBD_MODIFIER_LEFT_CTRL: u8 = 0x01;
+pub const KBD_MODIFIER_LEFT_SHIFT: u8 = 0x02;
+pub const KBD_MODIFIER_LEFT_ALT: u8 = 0x04;
+pub const KBD_MODIFIER_LEFT_UI: u8 = 0x08;
+pub const KBD_MODIFIER_RIGHT_CTRL: u8 = 0x10;
+pub const KBD_MODIFIER_RIGHT_SHIFT: u8 = 0x20;
+pub const KBD_MODIFIER_RIGHT_ALT: u8 = 0x40;
+pub const KBD_MODIFIER_RIGHT_UI: u8 = 0x80;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(KBD_JP_SEMICOLON.to_string(), "KBD_JP_SEMICOLON");
+        assert_eq!(KBD_COLON.to_string(), "KBD_JP_SEMICOLON");
+        assert_eq!(Kc::from_code(0x87).to_string(), "KBD_JP_UNDERSCORE");
+        assert_eq!(Kc::from_code(0xa5).to_string(), "0xa5");
+        assert_eq!(Kc::from_code(0x35).to_string(), "KBD_JP_HANKAKU_ZENKAKU");
+        assert_eq!(format!("{:?}", Some(KBD_A)), "Some(KBD_A)");
+        assert_eq!("KBD_COLON".parse(), Ok(KBD_JP_SEMICOLON));
+        assert_eq!("0xa5".parse(), Ok(Kc::from_code(0xa5)));
+        assert_eq!("COLON".parse::<Kc>(), Err(ParseKcError));
+        assert_eq!(KBD_A.usage(), 0x0007_0004);
+        assert_eq!(KBD_INTERNATIONAL4.to_string(), "KBD_JP_HENKAN");
+        assert_eq!(KBD_LANG2.to_string(), "KBD_JP_EISU");
+        assert_eq!(KBD_RIGHT_UI.to_string(), "KBD_RIGHT_UI");
+    }
+
+    #[test]
+    fn test_usage_page_coverage() {
+        // 0xa5 to 0xaf and 0xde to 0xdf are reserved.
+        for code in (0..=0xa4).chain(0xb0..=0xdd).chain(0xe0..=0xe7) {
+            assert!(Kc::from_code(code).name().is_some(), "{:#04x}", code);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+//! Keyboard layouts of the host OS.
+//!
+//! The host turns key codes into characters by its own layout setting, so the key which types
+//! a symbol depends on the host.  `Command::Char` names the character, and the key is looked
+//! up here when the command is reported.
+
+use crate::hid_keycodes::{self as KC, Kc};
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum HostLayout {
+    /// Japanese, with the key names of `KBD_JP_*`.
+    Jis,
+    /// US ANSI.
+    Us,
+}
+
+/// (character, shift, key code) of symbols of a layout.
+type Symbols = [(u8, bool, Kc); 32];
+
+static JIS_SYMBOLS: Symbols = [
+    (b'!', true, KC::KBD_1),
+    (b'"', true, KC::KBD_2),
+    (b'#', true, KC::KBD_3),
+    (b'$', true, KC::KBD_4),
+    (b'%', true, KC::KBD_5),
+    (b'&', true, KC::KBD_6),
+    (b'\'', true, KC::KBD_7),
+    (b'(', true, KC::KBD_8),
+    (b')', true, KC::KBD_9),
+    (b'-', false, KC::KBD_JP_HYPHEN),
+    (b'=', true, KC::KBD_JP_HYPHEN),
+    (b'^', false, KC::KBD_JP_CARET),
+    (b'~', true, KC::KBD_JP_CARET),
+    (b'@', false, KC::KBD_JP_AT),
+    (b'`', true, KC::KBD_JP_AT),
+    (b'[', false, KC::KBD_JP_OPEN_BRACKET),
+    (b'{', true, KC::KBD_JP_OPEN_BRACKET),
+    (b']', false, KC::KBD_JP_CLOSE_BRACKET),
+    (b'}', true, KC::KBD_JP_CLOSE_BRACKET),
+    (b';', false, KC::KBD_JP_SEMICOLON),
+    (b'+', true, KC::KBD_JP_SEMICOLON),
+    (b':', false, KC::KBD_JP_COLON),
+    (b'*', true, KC::KBD_JP_COLON),
+    (b',', false, KC::KBD_COMMA),
+    (b'<', true, KC::KBD_COMMA),
+    (b'.', false, KC::KBD_DOT),
+    (b'>', true, KC::KBD_DOT),
+    (b'/', false, KC::KBD_SLASH),
+    (b'?', true, KC::KBD_SLASH),
+    (b'_', true, KC::KBD_JP_UNDERSCORE),
+    (b'\\', false, KC::KBD_JP_BACKSLASH),
+    (b'|', true, KC::KBD_JP_BACKSLASH),
+];
+
+static US_SYMBOLS: Symbols = [
+    (b'!', true, KC::KBD_1),
+    (b'@', true, KC::KBD_2),
+    (b'#', true, KC::KBD_3),
+    (b'$', true, KC::KBD_4),
+    (b'%', true, KC::KBD_5),
+    (b'^', true, KC::KBD_6),
+    (b'&', true, KC::KBD_7),
+    (b'*', true, KC::KBD_8),
+    (b'(', true, KC::KBD_9),
+    (b')', true, KC::KBD_0),
+    (b'-', false, KC::KBD_UNDERSCORE),
+    (b'_', true, KC::KBD_UNDERSCORE),
+    (b'=', false, KC::KBD_PLUS),
+    (b'+', true, KC::KBD_PLUS),
+    (b'[', false, KC::KBD_OPEN_BRACKET),
+    (b'{', true, KC::KBD_OPEN_BRACKET),
+    (b']', false, KC::KBD_CLOSE_BRACKET),
+    (b'}', true, KC::KBD_CLOSE_BRACKET),
+    (b'\\', false, KC::KBD_BACKSLASH),
+    (b'|', true, KC::KBD_BACKSLASH),
+    (b';', false, KC::KBD_COLON),
+    (b':', true, KC::KBD_COLON),
+    (b'\'', false, KC::KBD_QUOTE),
+    (b'"', true, KC::KBD_QUOTE),
+    (b'`', false, KC::KBD_TILDE),
+    (b'~', true, KC::KBD_TILDE),
+    (b',', false, KC::KBD_COMMA),
+    (b'<', true, KC::KBD_COMMA),
+    (b'.', false, KC::KBD_DOT),
+    (b'>', true, KC::KBD_DOT),
+    (b'/', false, KC::KBD_SLASH),
+    (b'?', true, KC::KBD_SLASH),
+];
+
+/// Key code of the key `n` keys after `kc`, for letters and digits.
+fn offset(kc: Kc, n: u8) -> Kc {
+    Kc::from_code(kc.code() + n)
+}
+
+impl HostLayout {
+    /// All layouts, in the order of their values of `settings::HOST_LAYOUT`.
+    pub const ALL: [HostLayout; 2] = [HostLayout::Jis, HostLayout::Us];
+
+    pub fn from_u16(v: u16) -> Option<HostLayout> {
+        HostLayout::ALL.get(v as usize).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HostLayout::Jis => "jis",
+            HostLayout::Us => "us",
+        }
+    }
+
+    /// Key code which types the character, and whether shift is needed.
+    pub fn char_key(&self, c: u8) -> Option<(bool, Kc)> {
+        match c {
+            b'a'..=b'z' => return Some((false, offset(KC::KBD_A, c - b'a'))),
+            b'A'..=b'Z' => return Some((true, offset(KC::KBD_A, c - b'A'))),
+            b'1'..=b'9' => return Some((false, offset(KC::KBD_1, c - b'1'))),
+            b'0' => return Some((false, KC::KBD_0)),
+            b' ' => return Some((false, KC::KBD_SPACEBAR)),
+            _ => {}
+        }
+        let symbols = match *self {
+            HostLayout::Jis => &JIS_SYMBOLS,
+            HostLayout::Us => &US_SYMBOLS,
+        };
+        symbols
+            .iter()
+            .find(|(sc, _, _)| *sc == c)
+            .map(|(_, shift, kc)| (*shift, *kc))
+    }
+
+    /// Key of a character of `Command::SendString`, which also has line breaks and tabs.
+    pub fn string_key(&self, c: u8) -> Option<(bool, Kc)> {
+        match c {
+            b'\n' => Some((false, KC::KBD_ENTER)),
+            b'\t' => Some((false, KC::KBD_TAB)),
+            _ => self.char_key(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_char_key() {
+        let jis = HostLayout::Jis;
+        let us = HostLayout::Us;
+        assert_eq!(jis.char_key(b'a'), Some((false, KC::KBD_A)));
+        assert_eq!(us.char_key(b'Z'), Some((true, KC::KBD_Z)));
+        assert_eq!(us.char_key(b'0'), Some((false, KC::KBD_0)));
+        assert_eq!(jis.char_key(b'*'), Some((true, KC::KBD_JP_COLON)));
+        assert_eq!(us.char_key(b'*'), Some((true, KC::KBD_8)));
+        assert_eq!(jis.char_key(b'['), Some((false, KC::KBD_JP_OPEN_BRACKET)));
+        assert_eq!(us.char_key(b'['), Some((false, KC::KBD_OPEN_BRACKET)));
+        assert_eq!(jis.char_key(b'\n'), None);
+        // Every printable character is typed by both layouts.
+        for c in 0x20..0x7f {
+            assert!(jis.char_key(c).is_some(), "{}", c as char);
+            assert!(us.char_key(c).is_some(), "{}", c as char);
+        }
+        assert_eq!(us.string_key(b'\n'), Some((false, KC::KBD_ENTER)));
+        assert_eq!(jis.string_key(b'\t'), Some((false, KC::KBD_TAB)));
+        assert_eq!(jis.string_key(b'|'), jis.char_key(b'|'));
+        assert_eq!(us.string_key(0xc3), None);
+        assert_eq!(HostLayout::from_u16(1), Some(HostLayout::Us));
+        assert_eq!(HostLayout::from_u16(2), None);
+    }
+}
@@ -0,0 +1,85 @@
+//! Operating system of the host, which changes the modifiers sent for modifier keys.
+//!
+//! Keymaps are written for macOS: UI1 is ⌘ next to the space bar, and shortcuts such as
+//! `UI1+'{'` are typed with ⌘.  On Windows and Linux, ALT1 and UI1 pressed alone are swapped,
+//! so that Alt is next to the space bar, and UI1 of shortcuts is CTRL1, the modifier of the
+//! same shortcuts there.  Strings and the input modes are not changed, since they are typed by
+//! `host_layout` and `unicode_mode`.
+
+use crate::keymap::ModifierKey;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum HostOs {
+    Mac,
+    Windows,
+    Linux,
+}
+
+impl HostOs {
+    /// All OSes, in the order of their values of `settings::HOST_OS`.
+    pub const ALL: [HostOs; 3] = [HostOs::Mac, HostOs::Windows, HostOs::Linux];
+
+    pub fn from_u16(v: u16) -> Option<HostOs> {
+        HostOs::ALL.get(v as usize).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HostOs::Mac => "mac",
+            HostOs::Windows => "windows",
+            HostOs::Linux => "linux",
+        }
+    }
+
+    /// Modifier sent for a modifier key held by itself.
+    pub fn modifier(&self, mk: ModifierKey) -> ModifierKey {
+        match (*self, mk) {
+            (HostOs::Mac, _) => mk,
+            (_, ModifierKey::ALT1) => ModifierKey::UI1,
+            (_, ModifierKey::UI1) => ModifierKey::ALT1,
+            (_, ModifierKey::RALT) => ModifierKey::RUI,
+            (_, ModifierKey::RUI) => ModifierKey::RALT,
+            _ => mk,
+        }
+    }
+
+    /// Modifier sent for a modifier of a shortcut, a key typed with modifiers.
+    pub fn shortcut_modifier(&self, mk: ModifierKey) -> ModifierKey {
+        match (*self, mk) {
+            (HostOs::Mac, _) => mk,
+            (_, ModifierKey::UI1) => ModifierKey::CTRL1,
+            (_, ModifierKey::RUI) => ModifierKey::RCTRL,
+            _ => mk,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modifiers() {
+        assert_eq!(HostOs::Mac.modifier(ModifierKey::UI1), ModifierKey::UI1);
+        assert_eq!(HostOs::Linux.modifier(ModifierKey::UI1), ModifierKey::ALT1);
+        assert_eq!(
+            HostOs::Windows.modifier(ModifierKey::RALT),
+            ModifierKey::RUI
+        );
+        assert_eq!(
+            HostOs::Linux.modifier(ModifierKey::CTRL1),
+            ModifierKey::CTRL1
+        );
+        assert_eq!(
+            HostOs::Linux.shortcut_modifier(ModifierKey::UI1),
+            ModifierKey::CTRL1
+        );
+        assert_eq!(
+            HostOs::Linux.shortcut_modifier(ModifierKey::ALT1),
+            ModifierKey::ALT1
+        );
+        assert_eq!(HostOs::from_u16(2), Some(HostOs::Linux));
+        assert_eq!(HostOs::from_u16(3), None);
+    }
+}
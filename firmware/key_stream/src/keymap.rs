@@ -1,5 +1,9 @@
+use crate::chord::Chord;
 use crate::hid_keycodes as KC;
+use crate::host_os::HostOs;
 use crate::keymap::Command::{KeyPress, Nop, PressModifier};
+#[cfg(not(target_arch = "arm"))]
+use crate::keymap_format::{Action, Combo, KeymapFile, Layer};
 use KC::Kc;
 
 /// See `matrix` for encoding rule.
@@ -9,10 +13,50 @@ pub type Pos = u8;
 #[cfg_attr(test, derive(Debug))]
 pub enum Command {
     Nop,
-    KeyPress { kc: Kc },
-    PressModifier { mk: ModifierKey },
-    ModifiedKey { mk: &'static [ModifierKey], kc: Kc },
+    KeyPress {
+        kc: Kc,
+    },
+    PressModifier {
+        mk: ModifierKey,
+    },
+    ModifiedKey {
+        mk: Modifiers,
+        kc: Kc,
+    },
     RequestReset,
+    /// Printable ASCII character, typed by the key of the host layout.  See `host_layout`.
+    Char {
+        mk: Modifiers,
+        c: u8,
+    },
+    /// Type the string of `Keymap::strings` by the host layout.
+    SendString {
+        index: u8,
+    },
+    /// Character entered by the Unicode input method of the host.  See `unicode`.
+    Unicode {
+        c: char,
+    },
+    /// Turn the NICOLA thumb-shift input on or off.  See `nicola`.
+    ToggleNicola,
+    /// Turn the chord input on or off.  See `chord`.
+    ToggleChords,
+    /// Modifier key while held, which taps the key when released without pressing another
+    /// key, such as UI1 tapping JP_EISU (英数) on macOS.
+    ModTap {
+        mk: ModifierKey,
+        kc: Kc,
+    },
+    /// Turn the steno mode on.  See `steno`.
+    EnterSteno,
+    /// Switch to keymap profile n.  See `Keymap::switch_profile`.
+    SwitchProfile {
+        n: u8,
+    },
+    /// Change the OS of the host, which swaps modifiers.  See `host_os`.
+    SetHostOs {
+        os: HostOs,
+    },
 }
 
 const fn k(kc: Kc) -> Command {
@@ -37,322 +81,502 @@ pub enum ModifierKey {
     UI1,
     MOD2,
     MOD3,
+    /// Right-hand modifiers, which hosts can tell from CTRL1, SHIFT1, ALT1 and UI1.
+    RCTRL,
+    RSHIFT,
+    RALT,
+    RUI,
+    /// CTRL1, SHIFT1, ALT1 and UI1 together.
+    HYPER,
+    /// CTRL1, SHIFT1 and ALT1 together.
+    MEH,
 }
 
-pub static SIMPLE_KEY_MAP: [Command; 48] = [
-    // Left
-    // R1
-    k(KC::KBD_TAB),
-    k(KC::KBD_Q),
-    k(KC::KBD_W),
-    k(KC::KBD_E),
-    k(KC::KBD_R),
-    k(KC::KBD_T),
-    // R2
-    m(ModifierKey::CTRL1),
-    k(KC::KBD_A),
-    k(KC::KBD_S),
-    k(KC::KBD_D),
-    k(KC::KBD_F),
-    k(KC::KBD_G),
-    // R3
-    m(ModifierKey::MOD3),
-    k(KC::KBD_Z),
-    k(KC::KBD_X),
-    k(KC::KBD_C),
-    k(KC::KBD_V),
-    k(KC::KBD_B),
-    // R4
-    k(KC::KBD_TILDE),
-    k(KC::KBD_JP_CARET),
-    m(ModifierKey::ALT1),
-    m(ModifierKey::UI1),
-    k(KC::KBD_SPACEBAR),
-    m(ModifierKey::MOD2),
-    // Right
-    // R1
-    k(KC::KBD_Y),
-    k(KC::KBD_U),
-    k(KC::KBD_I),
-    k(KC::KBD_O),
-    k(KC::KBD_P),
-    k(KC::KBD_JP_HYPHEN),
-    // R2
-    k(KC::KBD_H),
-    k(KC::KBD_J),
-    k(KC::KBD_K),
-    k(KC::KBD_L),
-    k(KC::KBD_JP_SEMICOLON),
-    k(KC::KBD_BACKSPACE),
-    // R3
-    k(KC::KBD_N),
-    k(KC::KBD_M),
-    k(KC::KBD_COMMA),
-    k(KC::KBD_DOT),
-    k(KC::KBD_SLASH),
-    k(KC::KBD_JP_BACKSLASH),
-    // R4
-    m(ModifierKey::SHIFT1),
-    k(KC::KBD_JP_UNDERSCORE),
-    nop(),
-    nop(),
-    k(KC::KBD_JP_AT),
-    k(KC::KBD_JP_COLON),
-];
-
-pub static MOD1_KEY_MAP: [Command; 48] = [
-    // Left
-    // R1
-    k(KC::KBD_Y),
-    k(KC::KBD_U),
-    k(KC::KBD_I),
-    k(KC::KBD_O),
-    k(KC::KBD_P),
-    k(KC::KBD_JP_HYPHEN),
-    // R2
-    m(ModifierKey::CTRL1),
-    k(KC::KBD_J),
-    k(KC::KBD_K),
-    k(KC::KBD_L),
-    k(KC::KBD_JP_SEMICOLON),
-    k(KC::KBD_BACKSPACE),
-    // R3
-    m(ModifierKey::MOD3),
-    k(KC::KBD_M),
-    k(KC::KBD_COMMA),
-    k(KC::KBD_DOT),
-    k(KC::KBD_SLASH),
-    k(KC::KBD_JP_BACKSLASH),
-    // R4
-    nop(),
-    m(ModifierKey::MOD1),
-    m(ModifierKey::ALT1),
-    m(ModifierKey::UI1),
-    k(KC::KBD_SPACEBAR),
-    m(ModifierKey::MOD2),
-    // Right
-    // R1
-    k(KC::KBD_TAB),
-    k(KC::KBD_Q),
-    k(KC::KBD_W),
-    k(KC::KBD_E),
-    k(KC::KBD_R),
-    k(KC::KBD_T),
-    // R2
-    nop(),
-    k(KC::KBD_A),
-    k(KC::KBD_S),
-    k(KC::KBD_D),
-    k(KC::KBD_F),
-    k(KC::KBD_G),
-    // R3
-    nop(),
-    k(KC::KBD_Z),
-    k(KC::KBD_X),
-    k(KC::KBD_C),
-    k(KC::KBD_V),
-    k(KC::KBD_B),
-    // R4
-    m(ModifierKey::SHIFT1),
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-];
-
-// JP keyboard.
-static EXCLAIM: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_1,
-};
-static DOUBLE_QUOTE: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_2,
-};
-static NUMBER: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_3,
-};
-static DOLLAR: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_4,
-};
-static PERCENT: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_5,
-};
-static AMPERSAND: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_6,
-};
-static SINGLE_QUOTE: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_7,
-};
-static OPEN_PAREN: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_8,
-};
-static CLOSE_PAREN: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_9,
-};
-static EQUAL: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_JP_HYPHEN,
-};
-pub static ASTERISK: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_JP_COLON,
-};
-static CMD_LBRACE: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::UI1, ModifierKey::SHIFT1],
-    kc: KC::KBD_JP_OPEN_BRACKET,
-};
-static CMD_RBRACE: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::UI1, ModifierKey::SHIFT1],
-    kc: KC::KBD_JP_CLOSE_BRACKET,
-};
-static LBRACE: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_JP_OPEN_BRACKET,
-};
-static RBRACE: Command = Command::ModifiedKey {
-    mk: &[ModifierKey::SHIFT1],
-    kc: KC::KBD_JP_CLOSE_BRACKET,
-};
-
-pub static MOD2_KEY_MAP: [Command; 48] = [
-    // Left
-    // R1
-    nop(),
-    EXCLAIM,
-    DOUBLE_QUOTE,
-    NUMBER,
-    DOLLAR,
-    PERCENT,
-    // R2
-    m(ModifierKey::CTRL1),
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-    DOLLAR,
-    // R3
-    m(ModifierKey::MOD3),
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-    // R4
-    nop(),
-    m(ModifierKey::MOD1),
-    m(ModifierKey::ALT1),
-    m(ModifierKey::UI1),
-    nop(),
-    m(ModifierKey::MOD2),
-    // Right
-    // R1
-    AMPERSAND,
-    SINGLE_QUOTE,
-    OPEN_PAREN,
-    CLOSE_PAREN,
-    nop(),
-    EQUAL,
-    // R2
-    k(KC::KBD_LEFT),
-    k(KC::KBD_DOWN),
-    k(KC::KBD_UP),
-    k(KC::KBD_RIGHT),
-    nop(),
-    nop(),
-    // R3
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-    // R4
-    m(ModifierKey::SHIFT1),
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-];
-
-pub static MOD3_KEY_MAP: [Command; 48] = [
-    // Left
-    // R1
-    nop(),
-    k(KC::KBD_F9),
-    k(KC::KBD_F10),
-    k(KC::KBD_F11),
-    k(KC::KBD_F12),
-    nop(),
-    // R2
-    m(ModifierKey::CTRL1),
-    k(KC::KBD_F5),
-    k(KC::KBD_F6),
-    k(KC::KBD_F7),
-    k(KC::KBD_F8),
-    nop(),
-    // R3
-    m(ModifierKey::SHIFT1),
-    k(KC::KBD_F1),
-    k(KC::KBD_F2),
-    k(KC::KBD_F3),
-    k(KC::KBD_F4),
-    nop(),
-    // R4
-    nop(),
-    m(ModifierKey::MOD1),
-    m(ModifierKey::ALT1),
-    m(ModifierKey::UI1),
-    nop(),
-    m(ModifierKey::MOD2),
-    // Right
-    // R1
-    nop(),
-    k(KC::KBD_7),
-    k(KC::KBD_8),
-    k(KC::KBD_9),
-    ASTERISK,
-    nop(),
-    // R2
-    LBRACE,
-    k(KC::KBD_4),
-    k(KC::KBD_5),
-    k(KC::KBD_6),
-    k(KC::KBD_KEYPAD_PLUS),
-    k(KC::KBD_JP_OPEN_BRACKET),
-    // R3
-    RBRACE,
-    k(KC::KBD_1),
-    k(KC::KBD_2),
-    k(KC::KBD_3),
-    k(KC::KBD_0),
-    k(KC::KBD_JP_CLOSE_BRACKET),
-    // R4
-    m(ModifierKey::SHIFT1),
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-    nop(),
-];
-
-pub static COMBO_KEYS: [(Pos, Pos, Command); 9] = [
-    (0xa2, 0xa3, k(KC::KBD_ENTER)),
-    (0x24, 0x25, k(KC::KBD_ESCAPE)),
-    (0xa3, 0xa4, k(KC::KBD_JP_OPEN_BRACKET)),
-    (0xa4, 0xa5, k(KC::KBD_JP_CLOSE_BRACKET)),
-    (0xb3, 0xb4, LBRACE),
-    (0xb4, 0xb5, RBRACE),
-    (0x94, 0x96, CMD_LBRACE),
-    (0xa4, 0xa6, CMD_RBRACE),
-    (0x22, 0x23, Command::RequestReset),
-];
+impl ModifierKey {
+    /// All modifier keys, in the order of their bit in `Modifiers`.
+    pub const ALL: [ModifierKey; 13] = [
+        ModifierKey::CTRL1,
+        ModifierKey::SHIFT1,
+        ModifierKey::MOD1,
+        ModifierKey::ALT1,
+        ModifierKey::UI1,
+        ModifierKey::MOD2,
+        ModifierKey::MOD3,
+        ModifierKey::RCTRL,
+        ModifierKey::RSHIFT,
+        ModifierKey::RALT,
+        ModifierKey::RUI,
+        ModifierKey::HYPER,
+        ModifierKey::MEH,
+    ];
+
+    pub fn from_u8(v: u8) -> Option<ModifierKey> {
+        ModifierKey::ALL.get(v as usize).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ModifierKey::CTRL1 => "CTRL1",
+            ModifierKey::SHIFT1 => "SHIFT1",
+            ModifierKey::MOD1 => "MOD1",
+            ModifierKey::ALT1 => "ALT1",
+            ModifierKey::UI1 => "UI1",
+            ModifierKey::MOD2 => "MOD2",
+            ModifierKey::MOD3 => "MOD3",
+            ModifierKey::RCTRL => "RCTRL",
+            ModifierKey::RSHIFT => "RSHIFT",
+            ModifierKey::RALT => "RALT",
+            ModifierKey::RUI => "RUI",
+            ModifierKey::HYPER => "HYPER",
+            ModifierKey::MEH => "MEH",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ModifierKey> {
+        ModifierKey::ALL.iter().copied().find(|m| m.name() == name)
+    }
+}
+
+/// Set of modifier keys pressed together with a key.
+/// Bit n is set when `ModifierKey::ALL[n]` is in the set.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Modifiers(u16);
+
+impl Modifiers {
+    pub const fn new(mks: &[ModifierKey]) -> Modifiers {
+        let mut bits = 0u16;
+        let mut i = 0;
+        while i < mks.len() {
+            bits |= 1 << (mks[i] as u16);
+            i += 1;
+        }
+        Modifiers(bits)
+    }
+
+    pub const fn from_bits(bits: u16) -> Modifiers {
+        Modifiers(bits)
+    }
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(&self, mk: ModifierKey) -> bool {
+        self.0 & (1 << (mk as u16)) != 0
+    }
+
+    /// Bits in bytes 1 and 3 of the encoded command.
+    fn decode(b: &[u8]) -> Option<Modifiers> {
+        let bits = b[1] as u16 | (b[3] as u16) << 8;
+        if bits < (1 << ModifierKey::ALL.len()) {
+            Some(Modifiers(bits))
+        } else {
+            None
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ModifierKey> + '_ {
+        ModifierKey::ALL
+            .iter()
+            .copied()
+            .filter(move |m| self.contains(*m))
+    }
+}
+
+// `SIMPLE_KEY_MAP`, `MOD1_KEY_MAP` to `MOD3_KEY_MAP`, `COMBO_KEYS`, `PROFILE_LAYERS` and
+// `PROFILE_COMBO_KEYS` of profiles 1 and on, `CHORDS`, `STRINGS` and macros like `ASTERISK`,
+// generated from `keymap.txt`.
+include!(concat!(env!("OUT_DIR"), "/keymap.rs"));
+
+pub const N_KEYS: usize = 48;
+pub const N_LAYERS: usize = 4;
+pub const N_COMBOS: usize = 16;
+pub const N_PROFILES: usize = 2;
+pub const N_CHORDS: usize = 192;
+/// Bytes of `Keymap::strings`.
+pub const STRINGS_SIZE: usize = 512;
+
+/// Layers and combos of a keymap profile, such as a keymap for each host.
+#[derive(Copy, Clone)]
+pub struct Profile {
+    pub layers: [[Command; N_KEYS]; N_LAYERS],
+    pub combos: [(Pos, Pos, Command); N_COMBOS],
+}
+
+/// Keymap used by `KeyStream`, initialized from the static maps above.
+/// Layer 0 is `SIMPLE_KEY_MAP`, and layer n is `MODn_KEY_MAP`.
+/// Unused combo slots hold `(0, 0, Nop)`, and unused chord slots `Chord::EMPTY`.
+/// Profiles which are not in `keymap.txt` start as copies of profile 0.
+pub struct Keymap {
+    /// Layers of the active profile.
+    pub layers: [[Command; N_KEYS]; N_LAYERS],
+    /// Combos of the active profile.
+    pub combos: [(Pos, Pos, Command); N_COMBOS],
+    pub chords: [Chord; N_CHORDS],
+    /// UTF-8 strings of `Command::SendString`, each terminated by 0.  The rest is 0.
+    pub strings: [u8; STRINGS_SIZE],
+    /// Index of the active profile.
+    pub profile: usize,
+    /// Layers and combos of the inactive profiles.  The slot of the active profile is stale,
+    /// see `profile_keys`.
+    profiles: [Profile; N_PROFILES],
+}
+
+impl Keymap {
+    pub fn new() -> Keymap {
+        let mut combos = [(0, 0, Nop); N_COMBOS];
+        combos[..COMBO_KEYS.len()].copy_from_slice(&COMBO_KEYS);
+        let layers = [SIMPLE_KEY_MAP, MOD1_KEY_MAP, MOD2_KEY_MAP, MOD3_KEY_MAP];
+        let mut profiles = [Profile { layers, combos }; N_PROFILES];
+        for (p, (layers, combos)) in profiles[1..]
+            .iter_mut()
+            .zip(PROFILE_LAYERS.iter().zip(PROFILE_COMBO_KEYS.iter()))
+        {
+            p.layers = *layers;
+            p.combos = [(0, 0, Nop); N_COMBOS];
+            p.combos[..combos.len()].copy_from_slice(combos);
+        }
+        let mut chords = [Chord::EMPTY; N_CHORDS];
+        chords[..CHORDS.len()].copy_from_slice(&CHORDS);
+        let mut strings = [0; STRINGS_SIZE];
+        strings[..STRINGS.len()].copy_from_slice(&STRINGS);
+        Keymap {
+            layers,
+            combos,
+            chords,
+            strings,
+            profile: 0,
+            profiles,
+        }
+    }
+
+    /// Make profile n active, keeping the keys of the active profile.
+    pub fn switch_profile(&mut self, n: usize) {
+        if n == self.profile || n >= N_PROFILES {
+            return;
+        }
+        self.profiles[self.profile] = Profile {
+            layers: self.layers,
+            combos: self.combos,
+        };
+        self.layers = self.profiles[n].layers;
+        self.combos = self.profiles[n].combos;
+        self.profile = n;
+    }
+
+    /// Layers and combos of profile n, active or not.
+    pub fn profile_keys(&self, n: usize) -> Profile {
+        if n == self.profile {
+            Profile {
+                layers: self.layers,
+                combos: self.combos,
+            }
+        } else {
+            self.profiles[n]
+        }
+    }
+
+    pub fn set_profile_keys(&mut self, n: usize, keys: Profile) {
+        if n == self.profile {
+            self.layers = keys.layers;
+            self.combos = keys.combos;
+        } else {
+            self.profiles[n] = keys;
+        }
+    }
+
+    /// String of `Command::SendString`, empty if there is no such string.
+    pub fn string(&self, index: u8) -> &[u8] {
+        self.strings
+            .split(|b| *b == 0)
+            .nth(index as usize)
+            .unwrap_or(&[])
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::new()
+    }
+}
+
+impl Command {
+    /// Size of the byte representation used by the raw HID protocol.
+    /// Bits of `Modifiers` are in bytes 1 and 3, the lower byte first.
+    pub const ENCODED_SIZE: usize = 4;
+
+    pub fn encode(&self) -> [u8; Command::ENCODED_SIZE] {
+        match *self {
+            Nop => [0, 0, 0, 0],
+            KeyPress { kc } => [1, kc.code(), 0, 0],
+            PressModifier { mk } => [2, mk as u8, 0, 0],
+            Command::ModifiedKey { mk, kc } => {
+                [3, mk.bits() as u8, kc.code(), (mk.bits() >> 8) as u8]
+            }
+            Command::RequestReset => [4, 0, 0, 0],
+            Command::Char { mk, c } => [5, mk.bits() as u8, c, (mk.bits() >> 8) as u8],
+            Command::SendString { index } => [6, index, 0, 0],
+            Command::Unicode { c } => {
+                let cp = c as u32;
+                [7, cp as u8, (cp >> 8) as u8, (cp >> 16) as u8]
+            }
+            Command::ToggleNicola => [8, 0, 0, 0],
+            Command::ToggleChords => [9, 0, 0, 0],
+            Command::ModTap { mk, kc } => [10, mk as u8, kc.code(), 0],
+            Command::EnterSteno => [11, 0, 0, 0],
+            Command::SwitchProfile { n } => [12, n, 0, 0],
+            Command::SetHostOs { os } => [13, os as u8, 0, 0],
+        }
+    }
+
+    pub fn decode(b: &[u8]) -> Option<Command> {
+        if b.len() < Command::ENCODED_SIZE {
+            return None;
+        }
+        match b[0] {
+            0 => Some(Nop),
+            1 => Some(KeyPress {
+                kc: Kc::from_code(b[1]),
+            }),
+            2 => ModifierKey::from_u8(b[1]).map(|mk| PressModifier { mk }),
+            3 => Modifiers::decode(b).map(|mk| Command::ModifiedKey {
+                mk,
+                kc: Kc::from_code(b[2]),
+            }),
+            4 => Some(Command::RequestReset),
+            5 if b[2].is_ascii_graphic() => {
+                Modifiers::decode(b).map(|mk| Command::Char { mk, c: b[2] })
+            }
+            6 => Some(Command::SendString { index: b[1] }),
+            7 => core::char::from_u32(b[1] as u32 | (b[2] as u32) << 8 | (b[3] as u32) << 16)
+                .map(|c| Command::Unicode { c }),
+            8 => Some(Command::ToggleNicola),
+            9 => Some(Command::ToggleChords),
+            10 => ModifierKey::from_u8(b[1]).map(|mk| Command::ModTap {
+                mk,
+                kc: Kc::from_code(b[2]),
+            }),
+            11 => Some(Command::EnterSteno),
+            12 if (b[1] as usize) < N_PROFILES => Some(Command::SwitchProfile { n: b[1] }),
+            13 => HostOs::from_u16(b[1] as u16).map(|os| Command::SetHostOs { os }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+impl Command {
+    /// Command of a key in the keymap file.
+    pub fn from_action(a: Action) -> Command {
+        match a {
+            Action::Nop => Nop,
+            Action::Key(kc) => KeyPress { kc },
+            Action::Modifier(i) => PressModifier {
+                mk: ModifierKey::ALL[i],
+            },
+            Action::ModifiedKey(mods, kc) => Command::ModifiedKey {
+                mk: Modifiers::from_bits(mods),
+                kc,
+            },
+            Action::Reset => Command::RequestReset,
+            Action::Char(mods, c) => Command::Char {
+                mk: Modifiers::from_bits(mods),
+                c,
+            },
+            Action::SendString(i) => Command::SendString { index: i as u8 },
+            Action::Unicode(c) => Command::Unicode { c },
+            Action::ToggleNicola => Command::ToggleNicola,
+            Action::ToggleChords => Command::ToggleChords,
+            Action::ModTap(i, kc) => Command::ModTap {
+                mk: ModifierKey::ALL[i],
+                kc,
+            },
+            Action::EnterSteno => Command::EnterSteno,
+            Action::SwitchProfile(n) => Command::SwitchProfile { n: n as u8 },
+            Action::SetHostOs(i) => Command::SetHostOs { os: HostOs::ALL[i] },
+        }
+    }
+
+    pub fn to_action(&self) -> Action {
+        match *self {
+            Nop => Action::Nop,
+            KeyPress { kc } => Action::Key(kc),
+            PressModifier { mk } => Action::Modifier(mk as usize),
+            Command::ModifiedKey { mk, kc } => Action::ModifiedKey(mk.bits(), kc),
+            Command::RequestReset => Action::Reset,
+            Command::Char { mk, c } => Action::Char(mk.bits(), c),
+            Command::SendString { index } => Action::SendString(index as usize),
+            Command::Unicode { c } => Action::Unicode(c),
+            Command::ToggleNicola => Action::ToggleNicola,
+            Command::ToggleChords => Action::ToggleChords,
+            Command::ModTap { mk, kc } => Action::ModTap(mk as usize, kc),
+            Command::EnterSteno => Action::EnterSteno,
+            Command::SwitchProfile { n } => Action::SwitchProfile(n as usize),
+            Command::SetHostOs { os } => Action::SetHostOs(os as usize),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+impl Keymap {
+    /// Replace layers, combos and strings by those in the keymap file.
+    /// Layers, and profiles, which are not in the file are kept.
+    pub fn apply_file(&mut self, file: &KeymapFile) -> Result<(), String> {
+        if let Some(p) = file.profiles.iter().find(|p| p.number >= N_PROFILES) {
+            return Err(format!("profile {} is out of range", p.number));
+        }
+        let profiles = file
+            .profiles
+            .iter()
+            .map(|p| (p.number, &p.layers, &p.combos));
+        let profiles: Vec<_> = Some((0, &file.layers, &file.combos))
+            .into_iter()
+            .chain(profiles)
+            .collect();
+        for (_, layers, combos) in profiles.iter() {
+            if let Some(l) = layers.iter().find(|l| l.number >= N_LAYERS) {
+                return Err(format!("layer {} is out of range", l.number));
+            }
+            if combos.len() > N_COMBOS {
+                return Err(format!("more than {} combos", N_COMBOS));
+            }
+            let actions = layers
+                .iter()
+                .flat_map(|l| l.keys.iter())
+                .chain(combos.iter().map(|c| &c.action));
+            for a in actions {
+                if let Action::SwitchProfile(n) = *a {
+                    if n >= N_PROFILES {
+                        return Err(format!("profile {} is out of range", n));
+                    }
+                }
+            }
+        }
+        if file.chords.len() > N_CHORDS {
+            return Err(format!("more than {} chords", N_CHORDS));
+        }
+        self.strings = pack_strings(&file.strings)?;
+        for (number, layers, combos) in profiles {
+            let mut keys = self.profile_keys(number);
+            for l in layers.iter() {
+                for (c, a) in keys.layers[l.number].iter_mut().zip(l.keys.iter()) {
+                    *c = Command::from_action(*a);
+                }
+            }
+            for (i, combo) in keys.combos.iter_mut().enumerate() {
+                *combo = match combos.get(i) {
+                    Some(c) => (c.k1, c.k2, Command::from_action(c.action)),
+                    None => (0, 0, Nop),
+                };
+            }
+            self.set_profile_keys(number, keys);
+        }
+        for (i, chord) in self.chords.iter_mut().enumerate() {
+            *chord = match file.chords.get(i) {
+                Some(c) => Chord::new(&c.keys, c.text.as_bytes())
+                    .ok_or_else(|| format!("invalid chord at line {}", c.line))?,
+                None => Chord::EMPTY,
+            };
+        }
+        Ok(())
+    }
+
+    /// Layers, used combos and used strings in the keymap file form.
+    /// Strings are given by macros `STRING_<index>`.
+    pub fn to_file(&self) -> KeymapFile {
+        let mut file = KeymapFile::default();
+        let profiles: Vec<Profile> = (0..N_PROFILES).map(|n| self.profile_keys(n)).collect();
+        let used = profiles
+            .iter()
+            .flat_map(|p| p.layers.iter().flat_map(|l| l.iter()))
+            .chain(profiles.iter().flat_map(|p| p.combos.iter().map(|c| &c.2)))
+            .filter_map(|c| match c {
+                Command::SendString { index } => Some(*index as usize + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        for index in 0..used {
+            let text = String::from_utf8_lossy(self.string(index as u8)).into_owned();
+            file.macros.push(crate::keymap_format::Macro {
+                name: format!("STRING_{}", index),
+                action: Action::SendString(index),
+                line: 0,
+            });
+            file.strings.push(text);
+        }
+        for (number, p) in profiles.iter().enumerate() {
+            let (layers, combos) = p.file_keys();
+            if number == 0 {
+                file.layers = layers;
+                file.combos = combos;
+            } else {
+                file.profiles.push(crate::keymap_format::Profile {
+                    number,
+                    layers,
+                    combos,
+                    line: 0,
+                });
+            }
+        }
+        for c in self.chords.iter().filter(|c| !c.is_empty()) {
+            file.chords.push(crate::keymap_format::Chord {
+                keys: c.keys().to_vec(),
+                text: String::from_utf8_lossy(c.text()).into_owned(),
+                line: 0,
+            });
+        }
+        file
+    }
+}
+
+#[cfg(not(target_arch = "arm"))]
+impl Profile {
+    /// Layers and used combos in the keymap file form.
+    fn file_keys(&self) -> (Vec<Layer>, Vec<Combo>) {
+        let mut layers = Vec::new();
+        for (number, layer) in self.layers.iter().enumerate() {
+            let mut keys = [Action::Nop; N_KEYS];
+            for (a, c) in keys.iter_mut().zip(layer.iter()) {
+                *a = c.to_action();
+            }
+            layers.push(Layer {
+                number,
+                keys,
+                line: 0,
+            });
+        }
+        let mut combos = Vec::new();
+        for (k1, k2, c) in self.combos.iter().filter(|c| c.2 != Nop) {
+            combos.push(Combo {
+                k1: *k1,
+                k2: *k2,
+                action: c.to_action(),
+                line: 0,
+            });
+        }
+        (layers, combos)
+    }
+}
+
+/// Strings in the form of `Keymap::strings`.
+#[cfg(not(target_arch = "arm"))]
+pub fn pack_strings(strings: &[String]) -> Result<[u8; STRINGS_SIZE], String> {
+    if strings.len() > u8::MAX as usize + 1 {
+        return Err(format!("more than {} strings", u8::MAX as usize + 1));
+    }
+    let mut packed = [0; STRINGS_SIZE];
+    let mut p = 0;
+    for s in strings.iter() {
+        if s.contains('\0') {
+            return Err(format!("string {:?} has a null character", s));
+        }
+        if p + s.len() + 1 > STRINGS_SIZE {
+            return Err(format!("strings are longer than {} bytes", STRINGS_SIZE));
+        }
+        packed[p..p + s.len()].copy_from_slice(s.as_bytes());
+        p += s.len() + 1;
+    }
+    Ok(packed)
+}
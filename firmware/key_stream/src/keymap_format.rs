@@ -0,0 +1,1138 @@
+//! Text format of keymaps.
+//!
+//! `keymap.txt` is compiled into the built-in keymap by `build.rs`, which includes this file.
+//! Tools read and write keymaps of the device in the same format.
+//!
+//! ```text
+//! # Comment.
+//! [macros]
+//! EXCLAIM = SHIFT1+1
+//! SIGNATURE = "Best regards,\nTaro"
+//!
+//! [layer 0]
+//! TAB   Q W E R T    Y U I O P JP_HYPHEN
+//! ...                (GRID_ROWS rows of GRID_COLS keys, left half first)
+//!
+//! [combos]
+//! R2C8 R2C9 ENTER
+//!
+//! [settings]
+//! combo_threshold = 200
+//!
+//! [profile 1]
+//! [layer 0]
+//! ...
+//! ```
+//!
+//! A key is `_` (nothing), `RESET`, `NICOLA` which toggles the thumb-shift input, `CHORDS` which
+//! toggles the chord input, `STENO` which turns the steno mode on, `PROFILE1` which switches to
+//! keymap profile 1, `OS_MAC`, `OS_WINDOWS` or `OS_LINUX` which sets the OS of the host, a
+//! modifier key such as `SHIFT1`, a name in `hid_keycodes` without `KBD_`, a key code such as
+//! `0x87`, a character such as `'{'`, a macro, or modifier keys joined to a key by `+` such as
+//! `UI1+SHIFT1+JP_OPEN_BRACKET` or `UI1+'{'`.  Characters are typed by the key of the host
+//! layout, see `host_layout`.  A code point such as `U+2192` is entered by the
+//! Unicode input method of the host, see `unicode`.  A modifier key and a key joined by `/` such
+//! as `UI1/JP_EISU` is the modifier while held, and taps the key when released without pressing
+//! another key.  Macros must be defined before they are used.
+//! A macro can be a string in double quotes with escapes `\n`, `\t`, `\"` and `\\`, which types
+//! the string when the key is pressed, entering characters beyond ASCII as code points.
+//! Strings are only given by macros, since keys of layers are separated by spaces.
+//! Keys of combos are `R<row>C<column>` of the grid counting from 1, or positions such as `0xa2`.
+//! A chord is its keys and the text in double quotes, such as `R2C4 R2C8 "ga"`, see `chord`.
+//! `[profile 1]` and on start the layers and combos of another keymap profile, such as a
+//! keymap for another host, up to the next `[profile n]`.  Layers and combos before them are
+//! profile 0, and the other sections are shared by all profiles.
+
+use crate::chord::{self, CHORD_KEYS, TEXT_SIZE};
+use crate::grid::{grid_index, grid_pos, pos_grid, GRID_COLS, GRID_ROWS};
+use crate::hid_keycodes::{Kc, NAMES};
+use std::fmt;
+
+/// Names of `keymap::ModifierKey`, in the order of `ModifierKey::ALL`.
+pub const MODIFIERS: [&str; 13] = [
+    "CTRL1", "SHIFT1", "MOD1", "ALT1", "UI1", "MOD2", "MOD3", "RCTRL", "RSHIFT", "RALT", "RUI",
+    "HYPER", "MEH",
+];
+/// Keys of `keymap::Command::SetHostOs`, in the order of `HostOs::ALL`.
+pub const HOST_OSES: [&str; 3] = ["OS_MAC", "OS_WINDOWS", "OS_LINUX"];
+pub const LAYER_SIZE: usize = GRID_ROWS * GRID_COLS;
+
+/// Key of the keymap file, which is `keymap::Command` without the dependency on the crate.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Action {
+    Nop,
+    Key(Kc),
+    /// Index of `MODIFIERS`.
+    Modifier(usize),
+    /// Bit n of the first value is `MODIFIERS[n]`.
+    ModifiedKey(u16, Kc),
+    Reset,
+    /// Modifiers as `ModifiedKey`, and a printable character typed by the host layout.
+    Char(u16, u8),
+    /// Index of `KeymapFile::strings`.
+    SendString(usize),
+    /// Character entered by the Unicode input method of the host.
+    Unicode(char),
+    ToggleNicola,
+    ToggleChords,
+    /// Index of `MODIFIERS` held, and the key tapped alone.
+    ModTap(usize, Kc),
+    EnterSteno,
+    SwitchProfile(usize),
+    /// Index of `HOST_OSES`.
+    SetHostOs(usize),
+}
+
+pub struct Macro {
+    pub name: String,
+    pub action: Action,
+    pub line: usize,
+}
+
+pub struct Layer {
+    pub number: usize,
+    /// In the order of keymap layers, see `grid::grid_index`.
+    pub keys: [Action; LAYER_SIZE],
+    /// Line of the section header, for diagnostics.
+    pub line: usize,
+}
+
+pub struct Combo {
+    pub k1: u8,
+    pub k2: u8,
+    pub action: Action,
+    pub line: usize,
+}
+
+pub struct Chord {
+    pub keys: Vec<u8>,
+    pub text: String,
+    pub line: usize,
+}
+
+/// Layers and combos of a profile after profile 0.
+pub struct Profile {
+    pub number: usize,
+    pub layers: Vec<Layer>,
+    pub combos: Vec<Combo>,
+    /// Line of the section header, for diagnostics.
+    pub line: usize,
+}
+
+#[derive(Default)]
+pub struct KeymapFile {
+    pub macros: Vec<Macro>,
+    /// Layers of profile 0.
+    pub layers: Vec<Layer>,
+    /// Combos of profile 0.
+    pub combos: Vec<Combo>,
+    pub chords: Vec<Chord>,
+    pub settings: Vec<(String, u16)>,
+    /// Strings of `Action::SendString`, defined by macros.
+    pub strings: Vec<String>,
+    pub profiles: Vec<Profile>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Error {
+    pub line: usize,
+    pub col: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.col, self.message)
+    }
+}
+
+enum Section {
+    None,
+    Macros,
+    Layer { row: usize },
+    Combos,
+    Chords,
+    Settings,
+}
+
+struct Parser {
+    file: KeymapFile,
+    section: Section,
+    line: usize,
+}
+
+/// Word of a line and its column counted in characters from 1.
+type Word<'a> = (usize, &'a str);
+
+pub fn parse(text: &str) -> Result<KeymapFile, Error> {
+    let mut p = Parser {
+        file: KeymapFile::default(),
+        section: Section::None,
+        line: 0,
+    };
+    for (i, line) in text.lines().enumerate() {
+        p.line = i + 1;
+        let line = strip_comment(line);
+        let words = split_words(line);
+        if words.is_empty() {
+            continue;
+        }
+        if line.trim_start().starts_with('[') {
+            p.end_section()?;
+            p.section = p.parse_header(line, &words)?;
+        } else if is_string_macro(&p.section, &words) {
+            p.parse_string_macro(line, &words)?;
+        } else {
+            p.parse_line(line, &words)?;
+        }
+    }
+    p.end_section()?;
+    Ok(p.file)
+}
+
+/// Line before `#`, except `#` of the character `'#'` and in strings.
+fn strip_comment(line: &str) -> &str {
+    let b = line.as_bytes();
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in b.iter().enumerate() {
+        let quoted = i > 0 && b[i - 1] == b'\'' && b.get(i + 1) == Some(&b'\'');
+        match *c {
+            b'#' if !quoted && !in_string => return &line[..i],
+            b'"' if !quoted && !escaped => in_string = !in_string,
+            _ => {}
+        }
+        escaped = in_string && *c == b'\\' && !escaped;
+    }
+    line
+}
+
+fn split_words(line: &str) -> Vec<Word<'_>> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut col = 0;
+    for (i, c) in line.char_indices() {
+        col += 1;
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some((col, i)),
+            (true, Some((wc, wi))) => {
+                words.push((wc, &line[wi..i]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some((wc, wi)) = start {
+        words.push((wc, &line[wi..]));
+    }
+    words
+}
+
+impl Parser {
+    fn error<T>(&self, col: usize, message: String) -> Result<T, Error> {
+        Err(Error {
+            line: self.line,
+            col,
+            message,
+        })
+    }
+
+    fn parse_header(&mut self, line: &str, words: &[Word]) -> Result<Section, Error> {
+        let col = words[0].0;
+        let inner = match line
+            .trim()
+            .strip_prefix('[')
+            .and_then(|l| l.strip_suffix(']'))
+        {
+            Some(inner) => inner,
+            None => return self.error(col, "expected `]` at the end of the section".to_string()),
+        };
+        let name: Vec<&str> = inner.split_whitespace().collect();
+        match name[..] {
+            ["macros"] => Ok(Section::Macros),
+            ["combos"] => Ok(Section::Combos),
+            ["chords"] => Ok(Section::Chords),
+            ["settings"] => Ok(Section::Settings),
+            ["profile", n] => {
+                let number = match n.parse() {
+                    Ok(number) if number > 0 => number,
+                    Ok(_) => {
+                        return self.error(
+                            col,
+                            "profile 0 is the layers and combos before `[profile 1]`".to_string(),
+                        )
+                    }
+                    Err(_) => return self.error(col, format!("invalid profile number `{}`", n)),
+                };
+                if let Some(p) = self.file.profiles.iter().find(|p| p.number == number) {
+                    return self.error(
+                        col,
+                        format!("profile {} is already defined at line {}", number, p.line),
+                    );
+                }
+                self.file.profiles.push(Profile {
+                    number,
+                    layers: Vec::new(),
+                    combos: Vec::new(),
+                    line: self.line,
+                });
+                Ok(Section::None)
+            }
+            ["layer", n] => {
+                let number = match n.parse() {
+                    Ok(number) => number,
+                    Err(_) => return self.error(col, format!("invalid layer number `{}`", n)),
+                };
+                if let Some(l) = self.layers().iter().find(|l| l.number == number) {
+                    return self.error(
+                        col,
+                        format!("layer {} is already defined at line {}", number, l.line),
+                    );
+                }
+                let line = self.line;
+                self.layers_mut().push(Layer {
+                    number,
+                    keys: [Action::Nop; LAYER_SIZE],
+                    line,
+                });
+                Ok(Section::Layer { row: 0 })
+            }
+            _ => self.error(col, format!("unknown section `[{}]`", inner.trim())),
+        }
+    }
+
+    /// Layers of the profile being parsed.
+    fn layers(&self) -> &Vec<Layer> {
+        match self.file.profiles.last() {
+            Some(p) => &p.layers,
+            None => &self.file.layers,
+        }
+    }
+
+    fn layers_mut(&mut self) -> &mut Vec<Layer> {
+        match self.file.profiles.last_mut() {
+            Some(p) => &mut p.layers,
+            None => &mut self.file.layers,
+        }
+    }
+
+    fn combos_mut(&mut self) -> &mut Vec<Combo> {
+        match self.file.profiles.last_mut() {
+            Some(p) => &mut p.combos,
+            None => &mut self.file.combos,
+        }
+    }
+
+    fn end_section(&self) -> Result<(), Error> {
+        if let Section::Layer { row } = self.section {
+            if row != GRID_ROWS {
+                let layer = self.layers().last().unwrap();
+                return Err(Error {
+                    line: layer.line,
+                    col: 1,
+                    message: format!(
+                        "layer {} has {} rows, expected {}",
+                        layer.number, row, GRID_ROWS
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_line(&mut self, line: &str, words: &[Word]) -> Result<(), Error> {
+        match self.section {
+            Section::None => self.error(
+                words[0].0,
+                "expected a section like `[layer 0]`".to_string(),
+            ),
+            Section::Macros => {
+                let (name, action) = self.parse_assignment(words)?;
+                if !is_identifier(name.1) {
+                    return self.error(name.0, format!("invalid macro name `{}`", name.1));
+                }
+                if self.resolve(name.1).is_ok() {
+                    return self.error(name.0, format!("`{}` is already defined", name.1));
+                }
+                let action = self.action(action)?;
+                self.file.macros.push(Macro {
+                    name: name.1.to_string(),
+                    action,
+                    line: self.line,
+                });
+                Ok(())
+            }
+            Section::Layer { row } => {
+                if row == GRID_ROWS {
+                    return self.error(words[0].0, format!("more than {} rows", GRID_ROWS));
+                }
+                if words.len() != GRID_COLS {
+                    let col = words.get(GRID_COLS).unwrap_or(&words[words.len() - 1]).0;
+                    return self.error(
+                        col,
+                        format!("expected {} keys, found {}", GRID_COLS, words.len()),
+                    );
+                }
+                let mut keys = [Action::Nop; GRID_COLS];
+                for (k, w) in keys.iter_mut().zip(words.iter()) {
+                    *k = self.action(*w)?;
+                }
+                let layer = self.layers_mut().last_mut().unwrap();
+                for (col, k) in keys.iter().enumerate() {
+                    layer.keys[grid_index(row, col)] = *k;
+                }
+                self.section = Section::Layer { row: row + 1 };
+                Ok(())
+            }
+            Section::Combos => {
+                if words.len() != 3 {
+                    return self.error(words[0].0, "expected `<key> <key> <action>`".to_string());
+                }
+                let mut keys = [0u8; 2];
+                for (k, w) in keys.iter_mut().zip(words.iter()) {
+                    *k = match parse_key(w.1) {
+                        Ok(k) => k,
+                        Err(e) => return self.error(w.0, e),
+                    };
+                }
+                let action = self.action(words[2])?;
+                let line = self.line;
+                self.combos_mut().push(Combo {
+                    k1: keys[0],
+                    k2: keys[1],
+                    action,
+                    line,
+                });
+                Ok(())
+            }
+            Section::Chords => self.parse_chord(line, words),
+            Section::Settings => {
+                let (name, value) = self.parse_assignment(words)?;
+                let value = match value.1.parse() {
+                    Ok(v) => v,
+                    Err(_) => return self.error(value.0, format!("invalid value `{}`", value.1)),
+                };
+                self.file.settings.push((name.1.to_string(), value));
+                Ok(())
+            }
+        }
+    }
+
+    /// `NAME = "string"`, where the string may have spaces.
+    fn parse_string_macro(&mut self, line: &str, words: &[Word]) -> Result<(), Error> {
+        let col = words[2].0;
+        match words {
+            [name, (_, "="), ..] => {
+                if !is_identifier(name.1) {
+                    return self.error(name.0, format!("invalid macro name `{}`", name.1));
+                }
+                if self.resolve(name.1).is_ok() {
+                    return self.error(name.0, format!("`{}` is already defined", name.1));
+                }
+                let text = self.parse_last_string(line, col)?;
+                self.file.macros.push(Macro {
+                    name: name.1.to_string(),
+                    action: Action::SendString(self.file.strings.len()),
+                    line: self.line,
+                });
+                self.file.strings.push(text);
+                Ok(())
+            }
+            _ => self.error(words[0].0, "expected `<name> = \"<string>\"`".to_string()),
+        }
+    }
+
+    /// `<key>... "<text>"`.
+    fn parse_chord(&mut self, line: &str, words: &[Word]) -> Result<(), Error> {
+        let n = match words.iter().position(|w| w.1.starts_with('"')) {
+            Some(n) if n > 0 => n,
+            _ => return self.error(words[0].0, "expected `<key>... \"<text>\"`".to_string()),
+        };
+        let mut keys = Vec::new();
+        for w in words[..n].iter() {
+            match parse_key(w.1) {
+                Ok(k) => keys.push(k),
+                Err(e) => return self.error(w.0, e),
+            }
+        }
+        let text = self.parse_last_string(line, words[n].0)?;
+        if chord::Chord::new(&keys, text.as_bytes()).is_none() {
+            return self.error(
+                words[0].0,
+                format!(
+                    "expected 1 to {} different keys and 1 to {} printable ASCII characters",
+                    CHORD_KEYS, TEXT_SIZE
+                ),
+            );
+        }
+        self.file.chords.push(Chord {
+            keys,
+            text,
+            line: self.line,
+        });
+        Ok(())
+    }
+
+    /// String in double quotes at the column, which ends the line.
+    fn parse_last_string(&self, line: &str, col: usize) -> Result<String, Error> {
+        let quote = line.char_indices().nth(col - 1).unwrap().0;
+        let (text, rest) = match parse_string(&line[quote..]) {
+            Ok(parsed) => parsed,
+            Err((offset, e)) => return self.error(col + offset, e),
+        };
+        if !rest.trim().is_empty() {
+            let rest_col = line.len() - rest.trim_start().len();
+            return self.error(
+                line[..rest_col].chars().count() + 1,
+                "expected the end of the line after the string".to_string(),
+            );
+        }
+        Ok(text)
+    }
+
+    fn parse_assignment<'a>(&self, words: &[Word<'a>]) -> Result<(Word<'a>, Word<'a>), Error> {
+        match words {
+            [name, (_, "="), value] => Ok((*name, *value)),
+            _ => self.error(words[0].0, "expected `<name> = <value>`".to_string()),
+        }
+    }
+
+    fn action(&self, word: Word) -> Result<Action, Error> {
+        match self.resolve(word.1) {
+            Ok(a) => Ok(a),
+            Err(e) => self.error(word.0, e),
+        }
+    }
+
+    fn resolve(&self, s: &str) -> Result<Action, String> {
+        match self.file.macros.iter().find(|m| m.name == s) {
+            Some(m) => Ok(m.action),
+            None => resolve(s, &|s| self.resolve(s)),
+        }
+    }
+}
+
+/// Resolve a word, using `lookup` for parts joined by `+`.
+fn resolve(s: &str, lookup: &dyn Fn(&str) -> Result<Action, String>) -> Result<Action, String> {
+    // `/` of characters such as `'/'` does not follow a modifier key.
+    if let Some((m, key)) = s.split_once('/') {
+        if let Some(i) = MODIFIERS.iter().position(|n| *n == m) {
+            return match lookup(key)? {
+                Action::Key(kc) => Ok(Action::ModTap(i, kc)),
+                _ => Err(format!("`{}` is not a key to tap", key)),
+            };
+        }
+    }
+    // `+` of the character `'+'` and of code points does not join keys.
+    let key_start = match (s.len().checked_sub(3), code_point_start(s)) {
+        (_, Some(i)) => i,
+        (Some(i), _) if s[i..].starts_with('\'') && s.ends_with('\'') => i,
+        _ => s.len(),
+    };
+    if let Some(plus) = s[..key_start].rfind('+') {
+        let key = &s[plus + 1..];
+        let action = lookup(key)?;
+        let mut mods = 0;
+        for m in s[..plus].split('+') {
+            match MODIFIERS.iter().position(|n| *n == m) {
+                Some(i) => mods |= 1 << i,
+                None => return Err(format!("unknown modifier key `{}`", m)),
+            }
+        }
+        return match action {
+            Action::Key(kc) => Ok(Action::ModifiedKey(mods, kc)),
+            Action::ModifiedKey(m, kc) => Ok(Action::ModifiedKey(m | mods, kc)),
+            Action::Char(m, c) => Ok(Action::Char(m | mods, c)),
+            _ => Err(format!("`{}` is not a key to modify", key)),
+        };
+    }
+    if let Some(hex) = s.strip_prefix("U+").filter(|_| key_start == 0) {
+        return match u32::from_str_radix(hex, 16)
+            .ok()
+            .and_then(std::char::from_u32)
+        {
+            Some(c) => Ok(Action::Unicode(c)),
+            None => Err(format!("`{}` is not a Unicode character", s)),
+        };
+    }
+    if key_start == 0 {
+        let c = s.as_bytes()[1];
+        if !c.is_ascii_graphic() {
+            return Err(format!("`{}` is not a printable character", s));
+        }
+        return Ok(Action::Char(0, c));
+    }
+    match s {
+        "_" => return Ok(Action::Nop),
+        "RESET" => return Ok(Action::Reset),
+        "NICOLA" => return Ok(Action::ToggleNicola),
+        "CHORDS" => return Ok(Action::ToggleChords),
+        "STENO" => return Ok(Action::EnterSteno),
+        _ => {}
+    }
+    if let Some(Ok(n)) = s.strip_prefix("PROFILE").map(str::parse) {
+        return Ok(Action::SwitchProfile(n));
+    }
+    if let Some(i) = MODIFIERS.iter().position(|n| *n == s) {
+        return Ok(Action::Modifier(i));
+    }
+    if let Some(i) = HOST_OSES.iter().position(|n| *n == s) {
+        return Ok(Action::SetHostOs(i));
+    }
+    if let Some(kc) = key_code(s) {
+        return Ok(Action::Key(kc));
+    }
+    match s.strip_prefix("0x").map(|hex| u8::from_str_radix(hex, 16)) {
+        Some(Ok(code)) => Ok(Action::Key(Kc::from_code(code))),
+        _ => Err(format!("unknown key `{}`", s)),
+    }
+}
+
+fn is_string_macro(section: &Section, words: &[Word]) -> bool {
+    match (section, words.get(2)) {
+        (Section::Macros, Some((_, value))) => value.starts_with('"'),
+        _ => false,
+    }
+}
+
+/// String in double quotes at the start of `s`, and the rest of `s`.
+/// Errors are the offset in characters and the message.
+fn parse_string(s: &str) -> Result<(String, &str), (usize, String)> {
+    let mut text = String::new();
+    let mut chars = s.char_indices().enumerate().skip(1);
+    while let Some((n, (i, c))) = chars.next() {
+        match c {
+            '"' => return Ok((text, &s[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, (_, 'n'))) => text.push('\n'),
+                Some((_, (_, 't'))) => text.push('\t'),
+                Some((_, (_, '"'))) => text.push('"'),
+                Some((_, (_, '\\'))) => text.push('\\'),
+                _ => return Err((n, "invalid escape in the string".to_string())),
+            },
+            _ => text.push(c),
+        }
+    }
+    Err((0, "unterminated string".to_string()))
+}
+
+/// String in double quotes with the escapes of `parse_string`.
+pub fn quote_string(text: &str) -> String {
+    let mut s = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '\n' => s += "\\n",
+            '\t' => s += "\\t",
+            '"' => s += "\\\"",
+            '\\' => s += "\\\\",
+            _ => s.push(c),
+        }
+    }
+    s + "\""
+}
+
+/// Start of a code point such as `U+2192` at the end of the word.
+fn code_point_start(s: &str) -> Option<usize> {
+    let i = s.rfind("U+")?;
+    let hex = &s[i + 2..];
+    let joined = i == 0 || s[..i].ends_with('+');
+    if joined && !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(i)
+    } else {
+        None
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_uppercase())
+        && s.chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+}
+
+/// Key code of the name in `hid_keycodes` without `KBD_`.
+pub fn key_code(name: &str) -> Option<Kc> {
+    NAMES
+        .iter()
+        .find(|(n, _)| n.strip_prefix("KBD_") == Some(name))
+        .map(|(_, kc)| *kc)
+}
+
+/// Name of the key code without `KBD_`, see `Kc::name`.
+pub fn key_name(kc: Kc) -> Option<&'static str> {
+    kc.name().and_then(|n| n.strip_prefix("KBD_"))
+}
+
+/// Parse a key without macros, e.g. a command line argument.
+pub fn parse_action(s: &str) -> Result<Action, String> {
+    resolve(s, &parse_action)
+}
+
+pub fn format_action(a: &Action) -> String {
+    match *a {
+        Action::Nop => "_".to_string(),
+        Action::Key(kc) => match key_name(kc) {
+            Some(name) => name.to_string(),
+            None => format!("{:#04x}", kc.code()),
+        },
+        Action::Modifier(i) => MODIFIERS[i].to_string(),
+        Action::ModifiedKey(mods, kc) => format_mods(mods) + &format_action(&Action::Key(kc)),
+        Action::Reset => "RESET".to_string(),
+        Action::Char(mods, c) => format!("{}'{}'", format_mods(mods), c as char),
+        // Strings are named by macros, see `Keymap::to_file`.
+        Action::SendString(i) => format!("STRING_{}", i),
+        Action::Unicode(c) => format!("U+{:04X}", c as u32),
+        Action::ToggleNicola => "NICOLA".to_string(),
+        Action::ToggleChords => "CHORDS".to_string(),
+        Action::ModTap(i, kc) => format!("{}/{}", MODIFIERS[i], format_action(&Action::Key(kc))),
+        Action::EnterSteno => "STENO".to_string(),
+        Action::SwitchProfile(n) => format!("PROFILE{}", n),
+        Action::SetHostOs(i) => HOST_OSES[i].to_string(),
+    }
+}
+
+/// Modifier keys of the bits, each followed by `+`.
+fn format_mods(mods: u16) -> String {
+    let mut s = String::new();
+    for (i, m) in MODIFIERS.iter().enumerate() {
+        if mods & (1 << i) != 0 {
+            s += m;
+            s.push('+');
+        }
+    }
+    s
+}
+
+pub fn parse_key(s: &str) -> Result<u8, String> {
+    let grid = s.strip_prefix('R').and_then(|s| {
+        let mut rc = s.splitn(2, 'C');
+        let row: usize = rc.next()?.parse().ok()?;
+        let col: usize = rc.next()?.parse().ok()?;
+        if (1..=GRID_ROWS).contains(&row) && (1..=GRID_COLS).contains(&col) {
+            Some(grid_pos(row - 1, col - 1))
+        } else {
+            None
+        }
+    });
+    let pos = s
+        .strip_prefix("0x")
+        .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+    grid.or(pos)
+        .ok_or_else(|| format!("invalid key position `{}`", s))
+}
+
+pub fn format_key(pos: u8) -> String {
+    match pos_grid(pos) {
+        Some((row, col)) => format!("R{}C{}", row + 1, col + 1),
+        None => format!("{:#04x}", pos),
+    }
+}
+
+impl KeymapFile {
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        if !self.macros.is_empty() {
+            out += "[macros]\n";
+            for m in self.macros.iter() {
+                let value = match m.action {
+                    // Later macros of the same string are aliases of the first one.
+                    Action::SendString(i) => match self.action_name(&m.action) {
+                        first if first == m.name => quote_string(&self.strings[i]),
+                        first => first,
+                    },
+                    _ => format_action(&m.action),
+                };
+                out += &format!("{} = {}\n", m.name, value);
+            }
+            out.push('\n');
+        }
+        self.write_keys(&mut out, &self.layers, &self.combos);
+        if !self.chords.is_empty() {
+            out += "[chords]\n";
+            for c in self.chords.iter() {
+                let keys: Vec<String> = c.keys.iter().map(|k| format_key(*k)).collect();
+                out += &format!("{} {}\n", keys.join(" "), quote_string(&c.text));
+            }
+            out.push('\n');
+        }
+        if !self.settings.is_empty() {
+            out += "[settings]\n";
+            for (name, value) in self.settings.iter() {
+                out += &format!("{} = {}\n", name, value);
+            }
+            out.push('\n');
+        }
+        for p in self.profiles.iter() {
+            out += &format!("[profile {}]\n", p.number);
+            self.write_keys(&mut out, &p.layers, &p.combos);
+        }
+        out.trim_end().to_string() + "\n"
+    }
+
+    fn write_keys(&self, out: &mut String, layers: &[Layer], combos: &[Combo]) {
+        for layer in layers.iter() {
+            *out += &format!("[layer {}]\n", layer.number);
+            let cells: Vec<String> = layer.keys.iter().map(|a| self.action_name(a)).collect();
+            let widths: Vec<usize> = (0..GRID_COLS)
+                .map(|col| {
+                    (0..GRID_ROWS)
+                        .map(|row| cells[grid_index(row, col)].len())
+                        .max()
+                        .unwrap_or(0)
+                })
+                .collect();
+            for row in 0..GRID_ROWS {
+                let mut line = String::new();
+                for (col, width) in widths.iter().enumerate() {
+                    if col == GRID_COLS / 2 {
+                        line.push_str("  ");
+                    }
+                    let cell = &cells[grid_index(row, col)];
+                    line += &format!("{:<w$} ", cell, w = width);
+                }
+                *out += line.trim_end();
+                out.push('\n');
+            }
+            out.push('\n');
+        }
+        if !combos.is_empty() {
+            *out += "[combos]\n";
+            for c in combos.iter() {
+                *out += &format!(
+                    "{} {} {}\n",
+                    format_key(c.k1),
+                    format_key(c.k2),
+                    self.action_name(&c.action)
+                );
+            }
+            out.push('\n');
+        }
+    }
+
+    /// Macro name of the modified key, the character, the code point or the string, or the key
+    /// itself.
+    pub fn action_name(&self, a: &Action) -> String {
+        match (a, self.macros.iter().find(|m| m.action == *a)) {
+            (Action::ModifiedKey(..), Some(m))
+            | (Action::Char(..), Some(m))
+            | (Action::SendString(..), Some(m))
+            | (Action::Unicode(..), Some(m)) => m.name.clone(),
+            _ => format_action(a),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid_keycodes as KC;
+
+    fn error_of(text: &str) -> (usize, usize, String) {
+        let e = parse(text).err().expect("parse error");
+        (e.line, e.col, e.message)
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(parse_action("_"), Ok(Action::Nop));
+        assert_eq!(parse_action("JP_COLON"), Ok(Action::Key(KC::KBD_JP_COLON)));
+        assert_eq!(parse_action("0x87"), Ok(Action::Key(KC::KBD_JP_UNDERSCORE)));
+        assert_eq!(parse_action("MOD2"), Ok(Action::Modifier(5)));
+        assert_eq!(
+            parse_action("UI1+SHIFT1+9"),
+            Ok(Action::ModifiedKey(0x12, KC::KBD_9))
+        );
+        assert!(parse_action("SHIFT1+MOD2").is_err());
+        assert_eq!(
+            parse_action("RALT+HYPER+A"),
+            Ok(Action::ModifiedKey(0x0a00, KC::KBD_A))
+        );
+        assert_eq!(parse_action("MEH"), Ok(Action::Modifier(12)));
+        assert!(parse_action("KBD_A").is_err());
+        assert_eq!(parse_action("'+'"), Ok(Action::Char(0, b'+')));
+        assert_eq!(parse_action("UI1+'+'"), Ok(Action::Char(0x10, b'+')));
+        assert_eq!(parse_action("'''"), Ok(Action::Char(0, b'\'')));
+        assert!(parse_action("'ab'").is_err());
+        assert_eq!(strip_comment("'#' # comment"), "'#' ");
+        assert_eq!(parse_action("U+2192"), Ok(Action::Unicode('→')));
+        assert_eq!(parse_action("U+1f600"), Ok(Action::Unicode('😀')));
+        assert!(parse_action("U+D800").is_err());
+        assert_eq!(
+            parse_action("SHIFT1+U+2192"),
+            Err("`U+2192` is not a key to modify".to_string())
+        );
+        assert_eq!(parse_action("U"), Ok(Action::Key(KC::KBD_U)));
+        assert_eq!(
+            parse_action("ALT1/LANG1"),
+            Ok(Action::ModTap(3, KC::KBD_LANG1))
+        );
+        assert_eq!(parse_action("'/'"), Ok(Action::Char(0, b'/')));
+        assert_eq!(parse_action("UI1+'/'"), Ok(Action::Char(0x10, b'/')));
+        assert_eq!(
+            parse_action("UI1/'a'"),
+            Err("`'a'` is not a key to tap".to_string())
+        );
+        for a in [
+            Action::Key(KC::KBD_JP_HYPHEN),
+            Action::ModifiedKey(0x12, Kc::from_code(0xa5)),
+            Action::Reset,
+            Action::Char(0x01, b'#'),
+            Action::Char(0x1080, b'{'),
+            Action::Unicode('→'),
+            Action::ToggleNicola,
+            Action::ToggleChords,
+            Action::ModTap(4, KC::KBD_LANG2),
+            Action::ModTap(2, Kc::from_code(0xa5)),
+            Action::EnterSteno,
+            Action::SwitchProfile(1),
+            Action::SetHostOs(2),
+        ]
+        .iter()
+        {
+            assert_eq!(parse_action(&format_action(a)), Ok(*a));
+        }
+        assert_eq!(key_name(KC::KBD_JP_HYPHEN), Some("JP_HYPHEN"));
+    }
+
+    #[test]
+    fn test_key_position() {
+        assert_eq!(parse_key("R2C8"), Ok(0xa2));
+        assert_eq!(parse_key("0x22"), Ok(0x22));
+        assert!(parse_key("R5C1").is_err());
+        assert_eq!(format_key(0x94), "R1C10");
+        assert_eq!(format_key(0x55), "0x55");
+    }
+
+    #[test]
+    fn test_parse() {
+        let file = parse(
+            "[macros]\nBANG = SHIFT1+1\n\n[layer 1]\n\
+             A _ _ _ _ _  B _ _ _ _ _\n\
+             _ _ _ _ _ _  _ _ _ _ _ _\n\
+             _ _ _ _ _ _  _ _ _ _ _ _\n\
+             _ _ _ _ _ _  _ _ _ _ _ CTRL1+BANG # comment\n\
+             [combos]\nR1C1 R1C2 RESET\n[settings]\ncombo_threshold = 150\n",
+        )
+        .unwrap();
+        let layer = &file.layers[0];
+        assert_eq!((layer.number, layer.line), (1, 4));
+        assert_eq!(layer.keys[0], Action::Key(KC::KBD_A));
+        assert_eq!(layer.keys[24], Action::Key(KC::KBD_B));
+        assert_eq!(layer.keys[47], Action::ModifiedKey(0x03, KC::KBD_1));
+        assert_eq!((file.combos[0].k1, file.combos[0].k2), (0x11, 0x12));
+        assert_eq!(file.combos[0].action, Action::Reset);
+        assert_eq!(file.settings, vec![("combo_threshold".to_string(), 150)]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            error_of("[layer 0]\nA B C\n"),
+            (2, 5, "expected 12 keys, found 3".to_string())
+        );
+        assert_eq!(
+            error_of("[layer 0]\nA _ _ _ _ _  _ _ FOO _ _ _\n"),
+            (2, 18, "unknown key `FOO`".to_string())
+        );
+        assert_eq!(
+            error_of("[layer 0]\n_ _ _ _ _ _  _ _ _ _ _ _\n[combos]\n"),
+            (1, 1, "layer 0 has 1 rows, expected 4".to_string())
+        );
+        assert_eq!(
+            error_of("\n  [combos]\nR1C1 R9C1 ENTER\n"),
+            (3, 6, "invalid key position `R9C1`".to_string())
+        );
+        assert_eq!(
+            error_of("[macros]\nA = SHIFT1+1\n"),
+            (2, 1, "`A` is already defined".to_string())
+        );
+        assert_eq!(
+            error_of("[macros]\nMY_KEY = SUPER+1\n"),
+            (2, 10, "unknown modifier key `SUPER`".to_string())
+        );
+        assert_eq!(
+            error_of("A\n"),
+            (1, 1, "expected a section like `[layer 0]`".to_string())
+        );
+        assert_eq!(
+            error_of("[layer]\n"),
+            (1, 1, "unknown section `[layer]`".to_string())
+        );
+        assert_eq!(
+            error_of("[profile 0]\n"),
+            (
+                1,
+                1,
+                "profile 0 is the layers and combos before `[profile 1]`".to_string()
+            )
+        );
+        assert_eq!(
+            error_of("[profile 1]\n[profile 1]\n"),
+            (2, 1, "profile 1 is already defined at line 1".to_string())
+        );
+        assert_eq!(
+            error_of("[profile 1]\nR1C1 R1C2 ENTER\n"),
+            (2, 1, "expected a section like `[layer 0]`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_profiles() {
+        use crate::keymap::{Command, Keymap};
+        let rows = "_ _ _ _ _ _  _ _ _ _ _ _\n".repeat(GRID_ROWS - 1);
+        let text = format!(
+            "[layer 0]\nPROFILE1 A _ _ _ _  _ _ _ _ _ _\n{rows}\
+             [combos]\nR1C1 R1C2 ENTER\n\
+             [profile 1]\n[layer 0]\nPROFILE0 B _ _ _ _  _ _ _ _ _ _\n{rows}\
+             [chords]\nR2C4 R2C8 \"ga\"\n\
+             [layer 1]\n{rows}_ _ _ _ _ _  _ _ _ _ _ _\n",
+            rows = rows
+        );
+        let file = parse(&text).unwrap();
+        assert_eq!(file.layers.len(), 1);
+        assert_eq!(file.combos.len(), 1);
+        assert_eq!(file.chords.len(), 1);
+        let p = &file.profiles[0];
+        assert_eq!((p.number, p.line), (1, 8));
+        assert_eq!(
+            p.layers.iter().map(|l| l.number).collect::<Vec<_>>(),
+            [0, 1]
+        );
+        assert_eq!(p.layers[0].keys[0], Action::SwitchProfile(0));
+        assert!(p.combos.is_empty());
+
+        let again = parse(&file.to_text()).unwrap();
+        assert_eq!(again.profiles[0].layers[0].keys[..], p.layers[0].keys[..]);
+        assert_eq!(again.chords.len(), 1);
+
+        let mut keymap = Keymap::new();
+        keymap.apply_file(&file).unwrap();
+        assert_eq!(keymap.layers[0][1], Command::KeyPress { kc: KC::KBD_A });
+        assert_eq!(
+            keymap.profile_keys(1).layers[0][1],
+            Command::KeyPress { kc: KC::KBD_B }
+        );
+        let file = keymap.to_file();
+        assert_eq!(file.profiles.len(), 1);
+        assert_eq!(file.profiles[0].layers[0].keys[0], Action::SwitchProfile(0));
+        let mut out_of_range = parse("[profile 5]\n").unwrap();
+        assert_eq!(
+            keymap.apply_file(&out_of_range),
+            Err("profile 5 is out of range".to_string())
+        );
+        out_of_range.profiles.clear();
+        out_of_range.combos.push(Combo {
+            k1: 0x11,
+            k2: 0x12,
+            action: Action::SwitchProfile(5),
+            line: 1,
+        });
+        assert_eq!(
+            keymap.apply_file(&out_of_range),
+            Err("profile 5 is out of range".to_string())
+        );
+    }
+
+    #[test]
+    fn test_string_macros() {
+        let text = "[macros]\n\
+                    SIG = \"Best # regards,\\n\\t\\\"Taro\\\"\\\\\"  # comment\n\
+                    DQ = '\"'\n\
+                    SIG2 = SIG\n";
+        let file = parse(text).unwrap();
+        assert_eq!(
+            file.strings,
+            vec!["Best # regards,\n\t\"Taro\"\\".to_string()]
+        );
+        assert_eq!(file.macros[0].action, Action::SendString(0));
+        assert_eq!(file.macros[1].action, Action::Char(0, b'"'));
+        assert_eq!(file.macros[2].action, Action::SendString(0));
+        assert_eq!(file.to_text(), text.replace("  # comment", ""));
+        assert_eq!(
+            error_of("[macros]\nA_B = \"abc\n"),
+            (2, 7, "unterminated string".to_string())
+        );
+        assert_eq!(
+            error_of("[macros]\nA_B = \"a\\x\"\n"),
+            (2, 9, "invalid escape in the string".to_string())
+        );
+        assert_eq!(
+            error_of("[macros]\nA_B = \"a\" b\n"),
+            (
+                2,
+                11,
+                "expected the end of the line after the string".to_string()
+            )
+        );
+        assert_eq!(
+            error_of("[macros]\nA_B = \"a\"\n[combos]\nR1C1 R1C2 CTRL1+A_B\n"),
+            (4, 11, "`A_B` is not a key to modify".to_string())
+        );
+    }
+
+    #[test]
+    fn test_chords() {
+        let text = "[chords]\nR2C4 R2C8 \"ga\"\nR4C5 \" \"\n";
+        let file = parse("[chords]\nR2C4 R2C8 \"ga\" # comment\nR4C5 \" \"\n").unwrap();
+        assert_eq!(file.chords[0].keys, vec![0x24, 0xa2]);
+        assert_eq!(file.chords[0].text, "ga");
+        assert_eq!(
+            (file.chords[1].line, file.chords[1].text.as_str()),
+            (3, " ")
+        );
+        assert_eq!(file.to_text(), text);
+        assert_eq!(
+            error_of("[chords]\nR2C4 R2C4 \"ga\"\n"),
+            (
+                2,
+                1,
+                "expected 1 to 3 different keys and 1 to 5 printable ASCII characters".to_string()
+            )
+        );
+        assert_eq!(
+            error_of("[chords]\n\"ga\"\n"),
+            (2, 1, "expected `<key>... \"<text>\"`".to_string())
+        );
+        assert_eq!(
+            error_of("[chords]\nR2C4 R9C1 \"ga\"\n"),
+            (2, 6, "invalid key position `R9C1`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_builtin_keymap() {
+        use crate::host_os::HostOs;
+        use crate::keymap::{Command, Keymap, ModifierKey};
+        for (name, mk) in MODIFIERS.iter().zip(ModifierKey::ALL.iter()) {
+            assert_eq!(*name, mk.name());
+        }
+        for (name, os) in HOST_OSES.iter().zip(HostOs::ALL.iter()) {
+            assert_eq!(*name, format!("OS_{}", os.name().to_uppercase()));
+        }
+        let file = parse(include_str!("../keymap.txt")).unwrap();
+        let keymap = Keymap::new();
+        for l in file.layers.iter() {
+            for (a, c) in l.keys.iter().zip(keymap.layers[l.number].iter()) {
+                assert_eq!(Command::from_action(*a), *c);
+            }
+        }
+        let mut applied = Keymap::new();
+        applied.layers[2][0] = Command::RequestReset;
+        applied.apply_file(&keymap.to_file()).unwrap();
+        assert_eq!(applied.layers[..], keymap.layers[..]);
+        assert_eq!(applied.combos, keymap.combos);
+    }
+
+    #[test]
+    fn test_keymap_txt_round_trip() {
+        let file = parse(include_str!("../keymap.txt")).unwrap();
+        let again = parse(&file.to_text()).unwrap();
+        assert_eq!(again.layers.len(), file.layers.len());
+        for (a, b) in again.layers.iter().zip(file.layers.iter()) {
+            assert_eq!(a.keys[..], b.keys[..]);
+        }
+        let combos = |f: &KeymapFile| -> Vec<_> {
+            f.combos.iter().map(|c| (c.k1, c.k2, c.action)).collect()
+        };
+        assert_eq!(combos(&again), combos(&file));
+    }
+}
@@ -8,20 +8,64 @@ extern crate std;
 #[allow(unused_imports)]
 use std::println;
 
-mod hid_keycodes;
-mod keymap;
+pub mod chord;
+pub mod console;
+mod crc;
+pub mod grid;
+pub mod hid_keycodes;
+pub mod host_layout;
+pub mod host_os;
+pub mod keymap;
+#[cfg(not(target_arch = "arm"))]
+pub mod keymap_format;
+pub mod nicola;
+pub mod os_detect;
+pub mod protocol;
+#[cfg(not(target_arch = "arm"))]
+pub mod render;
 pub mod ring_buffer;
-
+pub mod settings;
+pub mod shell;
+pub mod steno;
+pub mod storage;
+pub mod unicode;
+#[cfg(not(target_arch = "arm"))]
+pub mod validate;
+
+use crate::chord::{Chord, CHORD_KEYS};
+use crate::console::Console;
 use crate::hid_keycodes as KC;
+use crate::host_layout::HostLayout;
+use crate::host_os::HostOs;
 use crate::keymap::*;
+use crate::nicola::Thumb;
+use crate::os_detect::Trace;
 use crate::ring_buffer::RingBuffer;
+use crate::settings::{FlashPage, SettingsStore};
+use crate::shell::Output;
+use crate::steno::{Packet, Protocol, Stroke};
+use crate::storage::{LoadError, IMAGE_SIZE};
+use crate::unicode::{Reports, UnicodeMode};
 
 const REPORT_SLOTS: usize = 6;
 const N_COL: u8 = 6;
 #[allow(dead_code)]
 const N_ROW: u8 = 4;
-const COMBO_THRESHOLD_CNT: u16 = 219; // * 65536 / 72000 = 200
-const COMBO_SEPARATION_CNT: u16 = 0; // * 65536 / 72000 = 500
+const COMBO_THRESHOLD_CNT: u16 = 219; // * 65536 / 48000 = 299
+const COMBO_SEPARATION_CNT: u16 = 0;
+const NICOLA_THRESHOLD_CNT: u16 = 110; // * 65536 / 48000 = 150
+const CHORD_THRESHOLD_CNT: u16 = 55; // * 65536 / 48000 = 75
+const TAPPING_TERM_CNT: u16 = 146; // * 65536 / 48000 = 199
+/// Longest duration of the timing settings in milliseconds, far within the wrap of cnt.
+const MAX_TIMING_MS: u16 = 10_000;
+/// The host OS is detected when no descriptor is requested for this duration after
+/// SET_CONFIGURATION, since some hosts read strings after it.
+const ENUMERATION_SETTLE_MS: u16 = 1000;
+/// System clock of the firmware.  `cnt` counts 65536 cycles of it.
+pub const CLOCK_KHZ: u32 = 48_000;
+/// Reports of `Command::SendString` emitted by one `read`.  The firmware reads every 15 ms and
+/// sends a report every 1 ms from the buffer of 64 reports, so they are sent before the next read.
+const STRING_REPORTS_PER_READ: usize = 8;
 
 impl ModifierKey {
     pub fn code(&self) -> u8 {
@@ -33,6 +77,19 @@ impl ModifierKey {
             ModifierKey::UI1 => KC::KBD_MODIFIER_LEFT_UI,
             ModifierKey::MOD2 => 0,
             ModifierKey::MOD3 => 0,
+            ModifierKey::RCTRL => KC::KBD_MODIFIER_RIGHT_CTRL,
+            ModifierKey::RSHIFT => KC::KBD_MODIFIER_RIGHT_SHIFT,
+            ModifierKey::RALT => KC::KBD_MODIFIER_RIGHT_ALT,
+            ModifierKey::RUI => KC::KBD_MODIFIER_RIGHT_UI,
+            ModifierKey::HYPER => {
+                KC::KBD_MODIFIER_LEFT_CTRL
+                    | KC::KBD_MODIFIER_LEFT_SHIFT
+                    | KC::KBD_MODIFIER_LEFT_ALT
+                    | KC::KBD_MODIFIER_LEFT_UI
+            }
+            ModifierKey::MEH => {
+                KC::KBD_MODIFIER_LEFT_CTRL | KC::KBD_MODIFIER_LEFT_SHIFT | KC::KBD_MODIFIER_LEFT_ALT
+            }
         }
     }
 }
@@ -44,6 +101,113 @@ pub struct KeyStream {
     on_pos: [bool; 256],
     /// State to implement keyboard features.
     state: FeatureState,
+    /// Keymap in RAM, which can be changed at runtime.
+    keymap: Keymap,
+    /// Timing parameters of combo keys.
+    timing: Timing,
+    /// True if `keymap` is loaded from the persistent storage.
+    keymap_loaded: bool,
+    /// Storage operation which the firmware should perform.
+    storage_request: Option<StorageRequest>,
+    /// Layer used while no layer modifier is pressed.
+    default_layer: usize,
+    /// True if any setting is changed after the last save.
+    settings_changed: bool,
+    /// A thumb key shifts the character key pressed within this duration before it, in cnt.
+    nicola_threshold: u16,
+    /// Thumb key of the NICOLA input being held.
+    nicola_thumb: Option<HeldThumb>,
+    /// Keys pressed within this duration after the first one form a chord, in cnt.
+    chord_threshold: u16,
+    /// Keys held after their chords, which shift the keys pressed while they are held.
+    chord_shift: [ShiftKey; CHORD_KEYS],
+    /// Key of `Command::ModTap` held without pressing another key after it.
+    tap_key: Option<TapKey>,
+    /// The key of `Command::ModTap` taps its key only if released within this duration, in cnt.
+    tapping_term: u16,
+    /// Protocol of the packets of steno strokes.
+    steno_protocol: Protocol,
+    /// Keys of the steno stroke being pressed.
+    stroke: Stroke,
+    /// Bit n is set while the key of keymap index n of the stroke is held.
+    stroke_held: u64,
+    /// Strokes to be sent by `steno_output`.
+    strokes: RingBuffer<Stroke>,
+    /// Bytes of the packet of a stroke not written to the host yet.
+    steno_packet: Packet,
+    /// Detection of the host OS, 0 off, 1 to set `host_os`, 2 to also switch the profile.
+    auto_host_os: u8,
+    /// Profile of each OS of `HostOs::ALL` switched to by `auto_host_os`, or
+    /// `settings::NO_PROFILE`.
+    os_profiles: [u16; 3],
+    /// OS of the host classified from the last enumeration.
+    detected_host_os: Option<HostOs>,
+    /// Number of requests of the enumeration trace when it last changed, and the time of it.
+    trace_change: (usize, u16),
+    /// True if the enumeration trace is classified after its last change.
+    trace_classified: bool,
+    /// Text console on the serial port.  See `console`.
+    console: Console,
+    /// Output of the shell run by the raw HID interface.
+    shell_output: Output,
+    /// Number of presses of each key, by keymap index.
+    presses: [u32; N_KEYS],
+    /// True if the firmware should restart into the system bootloader.
+    requests_bootloader: bool,
+}
+
+/// Operation on the persistent keymap storage.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum StorageRequest {
+    /// Write `KeyStream::keymap_image`.
+    Save,
+    /// Erase the stored keymap to use the compiled-in one.
+    Erase,
+}
+
+/// Timing parameters of combo keys, in cnt.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Timing {
+    /// Combo keys must be pressed within this duration.
+    pub combo_threshold: u16,
+    /// Combo keys are ignored within this duration after the last key press.
+    pub combo_separation: u16,
+}
+
+impl Timing {
+    pub fn new() -> Timing {
+        Timing {
+            combo_threshold: COMBO_THRESHOLD_CNT,
+            combo_separation: COMBO_SEPARATION_CNT,
+        }
+    }
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing::new()
+    }
+}
+
+/// Rounded to nearest, so that ms -> cnt -> ms is lossless.
+pub const fn ms_to_cnt(ms: u16) -> u16 {
+    let cnt = (ms as u64 * CLOCK_KHZ as u64 + 32768) / 65536;
+    if cnt > u16::MAX as u64 {
+        u16::MAX
+    } else {
+        cnt as u16
+    }
+}
+
+pub fn cnt_to_ms(cnt: u16) -> u16 {
+    let ms = (cnt as u64 * 65536 + CLOCK_KHZ as u64 / 2) / CLOCK_KHZ as u64;
+    if ms > u16::MAX as u64 {
+        u16::MAX
+    } else {
+        ms as u16
+    }
 }
 
 /// List of state variables used to implement our own features.
@@ -52,6 +216,58 @@ struct FeatureState {
     commands: [Command; REPORT_SLOTS],
     last_action_cnt: u16,
     requests_reset: bool,
+    /// Layout of the host, which types `Command::Char`.
+    host_layout: HostLayout,
+    /// Input method of characters without keys.
+    unicode_mode: UnicodeMode,
+    /// Text being typed by `Command::SendString`, `Command::Unicode` or the NICOLA input.
+    typing: Option<Typing>,
+    /// True if the NICOLA thumb-shift input is on.  See `nicola`.
+    nicola: bool,
+    /// True if the chord input is on.  See `chord`.
+    chords: bool,
+    /// True if MOD2 and MOD3 switch the IME of the host to 英数 by tapping LANG2.
+    auto_eisu: bool,
+    /// True if keys are steno keys instead of the keymap.  See `steno`.
+    steno: bool,
+    /// OS of the host, which changes the modifiers of reports.
+    host_os: HostOs,
+    /// Profile of `Command::SwitchProfile`, switched by `KeyStream` after the key event.
+    profile_request: Option<u8>,
+}
+
+/// Progress of typing a text.
+#[derive(Copy, Clone)]
+struct Typing {
+    /// Index of the string and the byte of its next character.
+    string: Option<(u8, usize)>,
+    /// Reports of the current character.
+    reports: Reports,
+    /// Number of `reports` emitted.
+    sent: usize,
+}
+
+impl Typing {
+    /// Start with all keys released.
+    fn new(string: Option<(u8, usize)>) -> Typing {
+        let mut reports = Reports::new();
+        reports.push([0; 8]);
+        Typing {
+            string,
+            reports,
+            sent: 0,
+        }
+    }
+}
+
+/// First character of the bytes and its length, or `None` for a byte of broken UTF-8.
+fn first_char(b: &[u8]) -> (Option<char>, usize) {
+    for len in 1..=b.len().min(4) {
+        if let Ok(s) = core::str::from_utf8(&b[..len]) {
+            return (s.chars().next(), len);
+        }
+    }
+    (None, 1)
 }
 
 impl FeatureState {
@@ -61,6 +277,15 @@ impl FeatureState {
             commands: [Command::Nop; REPORT_SLOTS],
             last_action_cnt: 0,
             requests_reset: false,
+            host_layout: HostLayout::Jis,
+            unicode_mode: UnicodeMode::Linux,
+            typing: None,
+            nicola: false,
+            chords: false,
+            auto_eisu: false,
+            steno: false,
+            host_os: HostOs::Mac,
+            profile_request: None,
         }
     }
 
@@ -78,15 +303,52 @@ impl FeatureState {
             Command::PressModifier {
                 mk: ModifierKey::MOD2,
             } => {
+                if !self.mods[1] {
+                    self.switch_to_eisu();
+                }
                 self.mods[1] = true;
                 false
             }
             Command::PressModifier {
                 mk: ModifierKey::MOD3,
             } => {
+                if !self.mods[2] {
+                    self.switch_to_eisu();
+                }
                 self.mods[2] = true;
                 false
             }
+            Command::ModTap { mk, .. } => self.press(&Command::PressModifier { mk: *mk }),
+            Command::SendString { index } => {
+                self.typing = Some(Typing::new(Some((*index, 0))));
+                false
+            }
+            Command::Unicode { c } => {
+                let mut typing = Typing::new(None);
+                self.push_char_reports(*c, &mut typing.reports);
+                self.typing = Some(typing);
+                false
+            }
+            Command::ToggleNicola => {
+                self.nicola = !self.nicola;
+                false
+            }
+            Command::ToggleChords => {
+                self.chords = !self.chords;
+                false
+            }
+            Command::EnterSteno => {
+                self.steno = true;
+                false
+            }
+            Command::SwitchProfile { n } => {
+                self.profile_request = Some(*n);
+                false
+            }
+            Command::SetHostOs { os } => {
+                self.host_os = *os;
+                false
+            }
             other => {
                 self.push_key_command(other);
                 true
@@ -130,6 +392,14 @@ impl FeatureState {
                 self.mods[2] = false;
                 false
             }
+            Command::ModTap { mk, .. } => self.release(&Command::PressModifier { mk: *mk }),
+            Command::SendString { .. }
+            | Command::Unicode { .. }
+            | Command::ToggleNicola
+            | Command::ToggleChords
+            | Command::EnterSteno
+            | Command::SwitchProfile { .. }
+            | Command::SetHostOs { .. } => false,
             other => {
                 self.pop_key_command(other);
                 true
@@ -159,26 +429,132 @@ impl FeatureState {
             match c {
                 Command::Nop => {}
                 Command::KeyPress { kc } => {
-                    key[ptr] = *kc;
+                    key[ptr] = kc.code();
                     ptr += 1;
                 }
                 Command::PressModifier { mk } => {
-                    key[0] |= mk.code();
+                    key[0] |= self.host_os.modifier(*mk).code();
                 }
                 Command::ModifiedKey { mk, kc } => {
-                    key[ptr] = *kc;
+                    key[ptr] = kc.code();
                     ptr += 1;
                     for m in mk.iter() {
-                        key[0] |= m.code();
+                        key[0] |= self.host_os.shortcut_modifier(m).code();
                     }
                 }
                 Command::RequestReset => {
                     self.requests_reset = true;
                 }
+                Command::Char { mk, c } => {
+                    if let Some((shift, kc)) = self.host_layout.char_key(*c) {
+                        key[ptr] = kc.code();
+                        ptr += 1;
+                        if shift {
+                            key[0] |= ModifierKey::SHIFT1.code();
+                        }
+                        for m in mk.iter() {
+                            key[0] |= self.host_os.shortcut_modifier(m).code();
+                        }
+                    }
+                }
+                Command::SendString { .. }
+                | Command::Unicode { .. }
+                | Command::ToggleNicola
+                | Command::ToggleChords
+                | Command::ModTap { .. }
+                | Command::EnterSteno
+                | Command::SwitchProfile { .. }
+                | Command::SetHostOs { .. } => {}
             }
         }
         return key;
     }
+
+    /// Release all keys and layer modifiers.
+    fn release_all(&mut self) {
+        self.mods = [false; 3];
+        self.commands = [Command::Nop; REPORT_SLOTS];
+    }
+
+    /// Tap LANG2 if `auto_eisu` is on, so that the host types symbols and numbers of the layer
+    /// without converting them.
+    fn switch_to_eisu(&mut self) {
+        if self.auto_eisu && self.typing.is_none() {
+            let mut typing = Typing::new(None);
+            typing.reports.tap(0, KC::KBD_LANG2);
+            self.typing = Some(typing);
+        }
+    }
+
+    /// Sequence of a character, by the host layout for ASCII and by the Unicode input method
+    /// for others.
+    fn push_char_reports(&self, c: char, reports: &mut Reports) {
+        if !c.is_ascii() {
+            self.unicode_mode.push_reports(c, reports);
+        } else if let Some((shift, kc)) = self.host_layout.string_key(c as u8) {
+            let modifiers = if shift { ModifierKey::SHIFT1.code() } else { 0 };
+            reports.tap(modifiers, kc);
+        }
+    }
+
+    /// True if a modifier key which changes reports, such as `CTRL1`, is held.
+    fn modifier_held(&self) -> bool {
+        self.commands.iter().any(|c| match c {
+            Command::PressModifier { mk } => mk.code() != 0,
+            Command::ModifiedKey { .. } => true,
+            _ => false,
+        })
+    }
+}
+
+/// Key of `Command::ModTap`, which taps `kc` when released.
+#[derive(Copy, Clone)]
+struct TapKey {
+    pos: Pos,
+    kc: KC::Kc,
+    /// Time of the press.
+    cnt: u16,
+}
+
+/// Thumb key of the NICOLA input.
+#[derive(Copy, Clone)]
+struct HeldThumb {
+    pos: Pos,
+    thumb: Thumb,
+    kc: KC::Kc,
+    /// True if the thumb has shifted a character key, so its own key is not typed.
+    used: bool,
+}
+
+/// Key of the NICOLA input.
+#[derive(Copy, Clone)]
+enum NicolaKey {
+    Char(KC::Kc),
+    Thumb(Thumb, KC::Kc),
+}
+
+/// Thumb key for a character key, decided by the keys pressed after it.
+enum NicolaShift {
+    /// The thumb key pressed next shifts the character key.
+    Thumb(HeldThumb),
+    Alone,
+    Wait,
+}
+
+/// Key of the chord input held after its chord.
+#[derive(Copy, Clone)]
+struct ShiftKey {
+    /// 0 for an unused slot.
+    pos: Pos,
+    /// True if the key is in a typed chord, so its key of the layer is not typed.
+    used: bool,
+}
+
+impl ShiftKey {
+    const NONE: ShiftKey = ShiftKey {
+        pos: 0,
+        used: false,
+    };
 }
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -194,7 +570,7 @@ enum Action {
 struct Event {
     action: Action,
     pos: Pos,
-    cnt: u16, // 1/65536 cnt = 1/72 us
+    cnt: u16, // 1/65536 cnt = 1/48 us
 }
 
 impl KeyStream {
@@ -208,7 +584,192 @@ impl KeyStream {
             }),
             on_pos: [false; 256],
             state: FeatureState::new(),
+            keymap: Keymap::new(),
+            timing: Timing::new(),
+            keymap_loaded: false,
+            storage_request: None,
+            default_layer: 0,
+            settings_changed: false,
+            nicola_threshold: NICOLA_THRESHOLD_CNT,
+            nicola_thumb: None,
+            chord_threshold: CHORD_THRESHOLD_CNT,
+            chord_shift: [ShiftKey::NONE; CHORD_KEYS],
+            tap_key: None,
+            tapping_term: TAPPING_TERM_CNT,
+            steno_protocol: Protocol::GeminiPr,
+            stroke: Stroke::EMPTY,
+            stroke_held: 0,
+            strokes: RingBuffer::new(Stroke::EMPTY),
+            steno_packet: Packet::EMPTY,
+            auto_host_os: 0,
+            os_profiles: [settings::NO_PROFILE; 3],
+            detected_host_os: None,
+            trace_change: (0, 0),
+            trace_classified: false,
+            console: Console::new(),
+            shell_output: Output::new(),
+            presses: [0; N_KEYS],
+            requests_bootloader: false,
+        }
+    }
+
+    /// Current value of a setting.  See `settings` for keys.
+    pub fn setting(&self, key: u8) -> Option<u16> {
+        match key {
+            settings::COMBO_THRESHOLD => Some(cnt_to_ms(self.timing.combo_threshold)),
+            settings::COMBO_SEPARATION => Some(cnt_to_ms(self.timing.combo_separation)),
+            settings::DEFAULT_LAYER => Some(self.default_layer as u16),
+            settings::HOST_LAYOUT => Some(self.state.host_layout as u16),
+            settings::UNICODE_MODE => Some(self.state.unicode_mode as u16),
+            settings::NICOLA => Some(self.state.nicola as u16),
+            settings::NICOLA_THRESHOLD => Some(cnt_to_ms(self.nicola_threshold)),
+            settings::CHORDS => Some(self.state.chords as u16),
+            settings::CHORD_THRESHOLD => Some(cnt_to_ms(self.chord_threshold)),
+            settings::AUTO_EISU => Some(self.state.auto_eisu as u16),
+            settings::STENO => Some(self.state.steno as u16),
+            settings::STENO_PROTOCOL => Some(self.steno_protocol as u16),
+            settings::PROFILE => Some(self.keymap.profile as u16),
+            settings::HOST_OS => Some(self.state.host_os as u16),
+            settings::AUTO_HOST_OS => Some(self.auto_host_os as u16),
+            settings::MAC_PROFILE | settings::WINDOWS_PROFILE | settings::LINUX_PROFILE => {
+                Some(self.os_profiles[(key - settings::MAC_PROFILE) as usize])
+            }
+            settings::TAPPING_TERM => Some(cnt_to_ms(self.tapping_term)),
+            _ => None,
+        }
+    }
+
+    /// Change a setting.  Return false if the key or the value is invalid.
+    pub fn set_setting(&mut self, key: u8, value: u16) -> bool {
+        match key {
+            settings::COMBO_THRESHOLD if value <= MAX_TIMING_MS => {
+                self.timing.combo_threshold = ms_to_cnt(value)
+            }
+            settings::COMBO_SEPARATION if value <= MAX_TIMING_MS => {
+                self.timing.combo_separation = ms_to_cnt(value)
+            }
+            settings::DEFAULT_LAYER if (value as usize) < N_LAYERS => {
+                self.default_layer = value as usize
+            }
+            settings::HOST_LAYOUT => match HostLayout::from_u16(value) {
+                Some(layout) => self.state.host_layout = layout,
+                None => return false,
+            },
+            settings::UNICODE_MODE => match UnicodeMode::from_u16(value) {
+                Some(mode) => self.state.unicode_mode = mode,
+                None => return false,
+            },
+            settings::NICOLA if value <= 1 => {
+                self.state.nicola = value == 1;
+                self.nicola_thumb = None;
+            }
+            settings::NICOLA_THRESHOLD if value <= MAX_TIMING_MS => {
+                self.nicola_threshold = ms_to_cnt(value)
+            }
+            settings::CHORDS if value <= 1 => {
+                self.state.chords = value == 1;
+                self.chord_shift = [ShiftKey::NONE; CHORD_KEYS];
+            }
+            settings::CHORD_THRESHOLD if value <= MAX_TIMING_MS => {
+                self.chord_threshold = ms_to_cnt(value)
+            }
+            settings::AUTO_EISU if value <= 1 => self.state.auto_eisu = value == 1,
+            settings::STENO if value <= 1 => {
+                self.state.steno = value == 1;
+                self.start_steno_stroke();
+            }
+            settings::STENO_PROTOCOL => match Protocol::from_u16(value) {
+                Some(protocol) => self.steno_protocol = protocol,
+                None => return false,
+            },
+            settings::PROFILE if (value as usize) < N_PROFILES => {
+                self.switch_profile(value as usize)
+            }
+            settings::HOST_OS => match HostOs::from_u16(value) {
+                Some(os) => self.state.host_os = os,
+                None => return false,
+            },
+            settings::AUTO_HOST_OS if value <= 2 => self.auto_host_os = value as u8,
+            settings::MAC_PROFILE | settings::WINDOWS_PROFILE | settings::LINUX_PROFILE
+                if (value as usize) < N_PROFILES || value == settings::NO_PROFILE =>
+            {
+                self.os_profiles[(key - settings::MAC_PROFILE) as usize] = value
+            }
+            settings::TAPPING_TERM if value <= MAX_TIMING_MS => {
+                self.tapping_term = ms_to_cnt(value)
+            }
+            _ => return false,
+        }
+        self.settings_changed = true;
+        true
+    }
+
+    /// Apply settings saved in the store.
+    pub fn load_settings<P: FlashPage>(&mut self, store: &SettingsStore<P>) {
+        for key in settings::KEYS.iter() {
+            if let Some(v) = store.get_u16(*key) {
+                self.set_setting(*key, v);
+            }
+        }
+        self.settings_changed = false;
+    }
+
+    /// Save settings if changed.
+    pub fn save_settings<P: FlashPage>(
+        &mut self,
+        store: &mut SettingsStore<P>,
+    ) -> Result<(), settings::Error> {
+        if !self.settings_changed {
+            return Ok(());
+        }
+        for key in settings::KEYS.iter() {
+            if let Some(v) = self.setting(*key) {
+                store.set_u16(*key, v)?;
+            }
         }
+        // Kept on errors, so that the next call writes them again.
+        self.settings_changed = false;
+        Ok(())
+    }
+
+    /// True if any setting is changed and not saved yet.
+    pub fn settings_changed(&self) -> bool {
+        self.settings_changed
+    }
+
+    /// Use the keymap stored in the persistent storage.
+    /// Keep the compiled-in keymap if the image is not valid.
+    /// The active profile is kept.
+    pub fn load_keymap(&mut self, image: &[u8]) -> Result<(), LoadError> {
+        let profile = self.keymap.profile;
+        self.keymap = storage::load(image)?;
+        self.keymap.switch_profile(profile);
+        self.keymap_loaded = true;
+        Ok(())
+    }
+
+    /// Revert to the compiled-in keymap, keeping the active profile.
+    pub fn reset_keymap(&mut self) {
+        let profile = self.keymap.profile;
+        self.keymap = Keymap::new();
+        self.keymap.switch_profile(profile);
+        self.keymap_loaded = false;
+    }
+
+    /// Make keymap profile n active, releasing all keys of the previous one.
+    fn switch_profile(&mut self, n: usize) {
+        self.keymap.switch_profile(n);
+        self.state.release_all();
+        self.tap_key = None;
+    }
+
+    /// Image of the current keymap to write to the persistent storage.
+    pub fn keymap_image(&self) -> [u8; IMAGE_SIZE] {
+        storage::save(&self.keymap)
+    }
+
+    pub fn take_storage_request(&mut self) -> Option<StorageRequest> {
+        self.storage_request.take()
     }
 
     /// Update key events by currently pressed key positions.
@@ -231,6 +792,8 @@ impl KeyStream {
             let on = is_on(mat, peer, *i);
             let was_on = self.on_pos[*i as usize];
             if !was_on && on {
+                let presses = &mut self.presses[pos_to_map_index(*i)];
+                *presses = presses.saturating_add(1);
                 self.push_event(&Event {
                     action: Action::DOWN,
                     pos: *i,
@@ -242,6 +805,14 @@ impl KeyStream {
     }
 
     fn push_event(&mut self, evt: &Event) {
+        if self.console.log && !self.state.steno {
+            console::log_event(
+                &mut self.console,
+                evt.pos,
+                evt.action == Action::DOWN,
+                evt.cnt,
+            );
+        }
         self.events.push(evt)
     }
 
@@ -249,6 +820,90 @@ impl KeyStream {
         self.state.requests_reset
     }
 
+    pub fn requests_bootloader(&self) -> bool {
+        self.requests_bootloader
+    }
+
+    /// Bytes of steno strokes to write to the serial interface, removed by
+    /// `consume_steno_output`.  The next packet follows only after all bytes of a packet are
+    /// consumed, since a cut packet breaks the framing of the protocol.
+    pub fn steno_output(&mut self) -> &[u8] {
+        if self.steno_packet.as_slice().is_empty() {
+            if let Some(stroke) = self.strokes.peek(0) {
+                self.strokes.consume();
+                self.steno_packet = stroke.packet(self.steno_protocol);
+            }
+        }
+        self.steno_packet.as_slice()
+    }
+
+    /// Remove the first n bytes of the steno output, written to the host.
+    pub fn consume_steno_output(&mut self, n: usize) {
+        self.steno_packet.consume(n);
+    }
+
+    /// Watch the descriptor requests of the USB enumeration, and apply the OS of the host by
+    /// `auto_host_os` once the enumeration has settled.  The detected OS is not saved as a
+    /// setting, since it is detected on every enumeration.
+    pub fn watch_usb_trace(&mut self, trace: &Trace, clk: u32) {
+        let cnt = (clk >> 16) as u16;
+        let len = trace.as_slice().len();
+        if !trace.is_configured() || len != self.trace_change.0 {
+            self.trace_change = (len, cnt);
+            self.trace_classified = false;
+            return;
+        }
+        if self.trace_classified
+            || cnt.wrapping_sub(self.trace_change.1) < ms_to_cnt(ENUMERATION_SETTLE_MS)
+        {
+            return;
+        }
+        self.trace_classified = true;
+        self.detected_host_os = os_detect::classify(trace.as_slice());
+        let os = match self.detected_host_os {
+            Some(os) if self.auto_host_os != 0 => os,
+            _ => return,
+        };
+        self.state.host_os = os;
+        let profile = self.os_profiles[os as usize];
+        if self.auto_host_os == 2 && profile != settings::NO_PROFILE {
+            self.switch_profile(profile as usize);
+        }
+    }
+
+    /// Take bytes typed on the console, and run the lines completed by them.  See `console`.
+    pub fn console_input(&mut self, bytes: &[u8]) {
+        if self.state.steno {
+            return;
+        }
+        for b in bytes.iter() {
+            if let Some(line) = self.console.input(*b) {
+                let mut out = Output::new();
+                shell::run(self, line.as_str(), &mut out);
+                self.console.print(out.as_slice());
+                self.console.prompt();
+            }
+        }
+    }
+
+    /// Output of the console to write to the serial interface, and then to pass to
+    /// `consume_console_output`.
+    pub fn console_output(&self) -> &[u8] {
+        if self.state.steno {
+            return &[];
+        }
+        self.console.output()
+    }
+
+    pub fn consume_console_output(&mut self, n: usize) {
+        self.console.consume_output(n);
+    }
+
+    /// OS of the host classified from the last USB enumeration, if any.
+    pub fn detected_host_os(&self) -> Option<HostOs> {
+        self.detected_host_os
+    }
+
     /// Return: `[modifier, key]`
     pub fn read<F>(&mut self, clk: u32, mut emit: F)
     where
@@ -256,18 +911,84 @@ impl KeyStream {
     {
         let cnt = (clk >> 16) as u16;
         let mut executed = false;
-        while let Some(ev) = self.peek_event(0) {
+        let inputs = (self.state.nicola, self.state.chords, self.state.steno);
+        let host_os = self.state.host_os;
+        // Keys wait until the string is typed.
+        while self.state.typing.is_none() {
+            let ev = match self.peek_event(0) {
+                Some(ev) => ev,
+                None => break,
+            };
             let (e, consumed) = self.proc_event(cnt, &ev, &mut emit);
             executed = executed || e || !consumed;
+            if let Some(n) = self.state.profile_request.take() {
+                self.switch_profile(n as usize);
+                self.settings_changed = true;
+                // Report the keys released by the switch.
+                executed = false;
+            }
             if !consumed {
                 break;
             }
         }
-        if !executed {
+        // Toggled by `Command::ToggleNicola`, `Command::ToggleChords`, `Command::EnterSteno` or
+        // the exit stroke, which are saved as settings.
+        if (self.state.nicola, self.state.chords, self.state.steno) != inputs {
+            self.nicola_thumb = None;
+            self.chord_shift = [ShiftKey::NONE; CHORD_KEYS];
+            self.start_steno_stroke();
+            self.settings_changed = true;
+        }
+        // Changed by `Command::SetHostOs`.
+        if self.state.host_os != host_os {
+            self.settings_changed = true;
+        }
+        if self.state.typing.is_some() {
+            self.type_string(&mut emit);
+        } else if !executed {
             emit(self.state.make_key_report());
         }
     }
 
+    /// Emit reports of the text being typed: all keys released, then the sequence of each
+    /// character.  Characters which cannot be typed are skipped.
+    fn type_string<F>(&mut self, emit: &mut F)
+    where
+        F: FnMut([u8; 8]),
+    {
+        let mut n = 0;
+        while let Some(mut t) = self.state.typing {
+            if let Some(report) = t.reports.as_slice().get(t.sent) {
+                if n == STRING_REPORTS_PER_READ {
+                    return;
+                }
+                emit(*report);
+                n += 1;
+                t.sent += 1;
+                self.state.typing = Some(t);
+                continue;
+            }
+            let rest = match t.string {
+                Some((index, pos)) => &self.keymap.string(index)[pos..],
+                None => &[],
+            };
+            if rest.is_empty() {
+                self.state.typing = None;
+                return;
+            }
+            let (c, len) = first_char(rest);
+            if let Some((index, pos)) = t.string {
+                t.string = Some((index, pos + len));
+            }
+            t.reports = Reports::new();
+            t.sent = 0;
+            if let Some(c) = c {
+                self.state.push_char_reports(c, &mut t.reports);
+            }
+            self.state.typing = Some(t);
+        }
+    }
+
     /// return true if emit is called.
     fn proc_event<F>(&mut self, cnt: u16, ev: &Event, mut emit: F) -> (bool, bool)
     where
@@ -279,11 +1000,25 @@ impl KeyStream {
             return (false, true);
         }
 
+        if self.state.steno {
+            self.process_steno(ev);
+            self.consume_event();
+            return (false, true);
+        }
+
         match ev.action {
             Action::DOWN => {
+                // Another key is pressed while the key of `Command::ModTap` is held.
+                self.tap_key = None;
+                if let Some(result) = self
+                    .process_chord(cnt, ev)
+                    .or_else(|| self.process_nicola(cnt, ev))
+                {
+                    return result;
+                }
                 match self.process_combo_keys(cnt, ev) {
                     ComboKeyResult::ProcessCombo { command } => {
-                        if self.state.press(command) {
+                        if self.state.press(&command) {
                             emit(self.state.make_key_report());
                         }
                         self.consume_event(); // consume two keys
@@ -293,26 +1028,45 @@ impl KeyStream {
                     ComboKeyResult::Wait => (false, false),
                     ComboKeyResult::NotCombo => {
                         let idx = pos_to_map_index(ev.pos);
-                        let map = if self.state.mods[0] {
-                            &MOD1_KEY_MAP
-                        } else if self.state.mods[1] {
-                            &MOD2_KEY_MAP
-                        } else if self.state.mods[2] {
-                            &MOD3_KEY_MAP
-                        } else {
-                            &SIMPLE_KEY_MAP
-                        };
-                        let k = &map[idx];
-                        if self.state.press(k) {
+                        let k = self.keymap.layers[self.current_layer()][idx];
+                        if self.state.press(&k) {
                             self.state.last_action_cnt = cnt;
                             emit(self.state.make_key_report());
                         }
+                        if let Command::ModTap { kc, .. } = k {
+                            self.tap_key = Some(TapKey {
+                                pos: ev.pos,
+                                kc,
+                                cnt: ev.cnt,
+                            });
+                        }
                         self.consume_event();
                         (true, true)
                     }
                 }
             }
             Action::UP => {
+                if let Some(t) = self.tap_key.filter(|t| t.pos == ev.pos) {
+                    self.tap_key = None;
+                    // Held longer, such as UI1 for Cmd+click, it is only the modifier.
+                    if ev.cnt.wrapping_sub(t.cnt) <= self.tapping_term {
+                        self.tap_command(Command::KeyPress { kc: t.kc });
+                    }
+                }
+                if let Some(t) = self.nicola_thumb.filter(|t| t.pos == ev.pos) {
+                    self.nicola_thumb = None;
+                    if !t.used {
+                        self.tap_command(Command::KeyPress { kc: t.kc });
+                    }
+                }
+                if let Some(s) = self.chord_shift.iter_mut().find(|s| s.pos == ev.pos) {
+                    let used = s.used;
+                    *s = ShiftKey::NONE;
+                    if !used {
+                        let idx = pos_to_map_index(ev.pos);
+                        self.tap_command(self.keymap.layers[self.default_layer][idx]);
+                    }
+                }
                 self.release_related_keys(ev.pos);
                 self.consume_event();
                 (false, true)
@@ -320,17 +1074,288 @@ impl KeyStream {
         }
     }
 
+    /// Add a key to the stroke, and queue the stroke when all of its keys are released.
+    fn process_steno(&mut self, ev: &Event) {
+        let key = match steno::steno_key(ev.pos) {
+            Some(key) => key,
+            None => return,
+        };
+        let bit = 1 << pos_to_map_index(ev.pos);
+        match ev.action {
+            Action::DOWN => {
+                self.stroke.add(key);
+                self.stroke_held |= bit;
+            }
+            // Keys held since before the steno mode are not of the stroke.
+            Action::UP if self.stroke_held & bit != 0 => {
+                self.stroke_held &= !bit;
+                if self.stroke_held == 0 {
+                    if self.stroke.is_exit() {
+                        self.state.steno = false;
+                    } else {
+                        self.strokes.push(&self.stroke);
+                    }
+                    self.stroke = Stroke::EMPTY;
+                }
+            }
+            Action::UP => {}
+        }
+    }
+
+    /// Forget the stroke being pressed, and release keys of the keymap held when the steno
+    /// mode is turned on.
+    fn start_steno_stroke(&mut self) {
+        self.stroke = Stroke::EMPTY;
+        self.stroke_held = 0;
+        if self.state.steno {
+            self.state.release_all();
+        }
+    }
+
+    /// Layer of the layer modifier held, or the default layer.
+    fn current_layer(&self) -> usize {
+        if self.state.mods[0] {
+            1
+        } else if self.state.mods[1] {
+            2
+        } else if self.state.mods[2] {
+            3
+        } else {
+            self.default_layer
+        }
+    }
+
+    /// Key of the NICOLA input at the position, if the input is on and takes the key: the key
+    /// code of the default layer without any modifier held.
+    fn nicola_key(&self, pos: Pos) -> Option<NicolaKey> {
+        if !self.state.nicola || !self.kana_input_active() {
+            return None;
+        }
+        let kc = match self.keymap.layers[self.default_layer][pos_to_map_index(pos)] {
+            Command::KeyPress { kc } => kc,
+            Command::Char { mk, c } if mk.bits() == 0 => match HostLayout::Jis.char_key(c) {
+                Some((false, kc)) => kc,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        // Rows 9 and above are the right half.
+        if let Some(thumb) = nicola::thumb(kc, pos >> 4 >= 9) {
+            return Some(NicolaKey::Thumb(thumb, kc));
+        }
+        nicola::romaji(kc, None).map(|_| NicolaKey::Char(kc))
+    }
+
+    /// Process a key press of the NICOLA input.  Return `None` if the input does not take the
+    /// key, or the result of `proc_event`.
+    fn process_nicola(&mut self, cnt: u16, ev: &Event) -> Option<(bool, bool)> {
+        match self.nicola_key(ev.pos)? {
+            NicolaKey::Thumb(thumb, kc) => {
+                self.nicola_thumb = Some(HeldThumb {
+                    pos: ev.pos,
+                    thumb,
+                    kc,
+                    used: false,
+                });
+                self.consume_event();
+            }
+            NicolaKey::Char(kc) => {
+                let thumb = match self.nicola_thumb.as_mut() {
+                    Some(held) => {
+                        held.used = true;
+                        Some(held.thumb)
+                    }
+                    None => match self.nicola_shift(cnt, ev) {
+                        NicolaShift::Wait => return Some((false, false)),
+                        NicolaShift::Alone => None,
+                        NicolaShift::Thumb(held) => {
+                            // Consume the thumb key after the character key.
+                            self.consume_event();
+                            self.nicola_thumb = Some(held);
+                            Some(held.thumb)
+                        }
+                    },
+                };
+                self.consume_event();
+                if let Some(romaji) = nicola::romaji(kc, thumb) {
+                    self.type_text(romaji.as_bytes());
+                }
+            }
+        }
+        Some((true, true))
+    }
+
+    /// Process a key press of the chord input.  Return `None` if the input does not take the
+    /// key, or the result of `proc_event`.
+    fn process_chord(&mut self, cnt: u16, ev: &Event) -> Option<(bool, bool)> {
+        if !self.state.chords || !self.kana_input_active() || !self.is_chord_key(ev.pos) {
+            return None;
+        }
+        // Keys pressed together with the key.
+        let mut keys = [ev.pos; CHORD_KEYS];
+        let mut n = 1;
+        while n < CHORD_KEYS {
+            match self.peek_event(n) {
+                None if cnt.wrapping_sub(ev.cnt) <= self.chord_threshold => {
+                    return Some((false, false))
+                }
+                Some(next)
+                    if next.action == Action::DOWN
+                        && next.cnt.wrapping_sub(ev.cnt) <= self.chord_threshold
+                        && self.is_chord_key(next.pos) =>
+                {
+                    keys[n] = next.pos;
+                    n += 1;
+                }
+                _ => break,
+            }
+        }
+        for len in (1..=n).rev() {
+            let (chord, shifts) = match self.find_chord(&keys[..len]) {
+                Some(found) => found,
+                None => continue,
+            };
+            for _ in 0..len {
+                self.consume_event();
+            }
+            for (i, s) in self.chord_shift.iter_mut().enumerate() {
+                if shifts & 1 << i != 0 {
+                    s.used = true;
+                }
+            }
+            if chord.keys().len() > 1 {
+                for k in keys[..len].iter() {
+                    self.add_chord_shift(*k, true);
+                }
+            }
+            self.type_text(chord.text());
+            return Some((true, true));
+        }
+        // The key is not a chord alone, so it shifts the keys pressed while it is held.
+        self.add_chord_shift(ev.pos, false);
+        self.consume_event();
+        Some((true, true))
+    }
+
+    /// Chord of the most of the shift keys with the keys, and the shift keys in it as bits of
+    /// `chord_shift`.
+    fn find_chord(&self, keys: &[Pos]) -> Option<(Chord, u8)> {
+        let mut found: Option<(Chord, u8)> = None;
+        for shifts in 0..1u8 << CHORD_KEYS {
+            if found.is_some_and(|f| f.1.count_ones() >= shifts.count_ones()) {
+                continue;
+            }
+            let mut pressed = [0; CHORD_KEYS * 2];
+            let mut n = 0;
+            for (i, s) in self.chord_shift.iter().enumerate() {
+                if shifts & 1 << i != 0 {
+                    pressed[n] = s.pos;
+                    n += 1;
+                }
+            }
+            if pressed[..n].contains(&0) {
+                continue;
+            }
+            pressed[n..n + keys.len()].copy_from_slice(keys);
+            n += keys.len();
+            if let Some(c) = self
+                .keymap
+                .chords
+                .iter()
+                .find(|c| c.is_pressed_by(&pressed[..n]))
+            {
+                found = Some((*c, shifts));
+            }
+        }
+        found
+    }
+
+    fn is_chord_key(&self, pos: Pos) -> bool {
+        self.keymap.chords.iter().any(|c| c.keys().contains(&pos))
+    }
+
+    fn add_chord_shift(&mut self, pos: Pos, used: bool) {
+        if self.chord_shift.iter().any(|s| s.pos == pos) {
+            return;
+        }
+        if let Some(s) = self.chord_shift.iter_mut().find(|s| s.pos == 0) {
+            *s = ShiftKey { pos, used };
+        }
+    }
+
+    /// True if keys type kana of the NICOLA input or texts of chords instead of their keys: on
+    /// the base layer without any modifier held.
+    fn kana_input_active(&self) -> bool {
+        self.current_layer() == self.default_layer && !self.state.modifier_held()
+    }
+
+    /// Type the ASCII text by the host layout.
+    fn type_text(&mut self, text: &[u8]) {
+        let mut typing = Typing::new(None);
+        for c in text.iter() {
+            self.state
+                .push_char_reports(*c as char, &mut typing.reports);
+        }
+        self.state.typing = Some(typing);
+    }
+
+    /// Type the key or the character of the command, for keys which type it when released
+    /// alone.
+    fn tap_command(&mut self, command: Command) {
+        let mut typing = Typing::new(None);
+        match command {
+            Command::KeyPress { kc } => typing.reports.tap(0, kc),
+            Command::Char { mk, c } if mk.bits() == 0 => {
+                self.state.push_char_reports(c as char, &mut typing.reports)
+            }
+            _ => return,
+        }
+        self.state.typing = Some(typing);
+    }
+
+    /// Thumb key which shifts the character key of the event, pressed after it within the
+    /// threshold.  Of three keys pressed as character, thumb and character, the thumb shifts
+    /// the character closer to it.
+    fn nicola_shift(&self, now_cnt: u16, event: &Event) -> NicolaShift {
+        let next = match self.peek_event(1) {
+            Some(next) => next,
+            None if now_cnt.wrapping_sub(event.cnt) <= self.nicola_threshold => {
+                return NicolaShift::Wait
+            }
+            None => return NicolaShift::Alone,
+        };
+        let gap = next.cnt.wrapping_sub(event.cnt);
+        let held = match (next.action, self.nicola_key(next.pos)) {
+            (Action::DOWN, Some(NicolaKey::Thumb(thumb, kc))) if gap <= self.nicola_threshold => {
+                HeldThumb {
+                    pos: next.pos,
+                    thumb,
+                    kc,
+                    used: true,
+                }
+            }
+            _ => return NicolaShift::Alone,
+        };
+        match self.peek_event(2) {
+            None if now_cnt.wrapping_sub(next.cnt) < gap => NicolaShift::Wait,
+            Some(after)
+                if after.action == Action::DOWN
+                    && after.cnt.wrapping_sub(next.cnt) < gap
+                    && matches!(self.nicola_key(after.pos), Some(NicolaKey::Char(_))) =>
+            {
+                NicolaShift::Alone
+            }
+            _ => NicolaShift::Thumb(held),
+        }
+    }
+
     fn release_related_keys(&mut self, pos: Pos) {
         let idx = pos_to_map_index(pos);
-        let k = &MOD1_KEY_MAP[idx];
-        self.state.release(k);
-        let k = &MOD2_KEY_MAP[idx];
-        self.state.release(k);
-        let k = &MOD3_KEY_MAP[idx];
-        self.state.release(k);
-        let k = &SIMPLE_KEY_MAP[idx];
-        self.state.release(k);
-        for (k1, k2, kc) in COMBO_KEYS.iter() {
+        for layer in 1..N_LAYERS {
+            self.state.release(&self.keymap.layers[layer][idx]);
+        }
+        self.state.release(&self.keymap.layers[0][idx]);
+        for (k1, k2, kc) in self.keymap.combos.iter() {
             if pos == *k1 || pos == *k2 {
                 self.state.release(kc);
             }
@@ -340,13 +1365,13 @@ impl KeyStream {
     fn process_combo_keys(&self, now_cnt: u16, event: &Event) -> ComboKeyResult {
         let pos = event.pos;
         // Ignore key combo in sequence of keys - such as typing words.
-        if self.state.last_action_cnt + COMBO_SEPARATION_CNT > now_cnt {
+        if self.state.last_action_cnt + self.timing.combo_separation > now_cnt {
             ComboKeyResult::NotCombo
-        } else if event.cnt + COMBO_THRESHOLD_CNT < now_cnt {
+        } else if event.cnt + self.timing.combo_threshold < now_cnt {
             ComboKeyResult::NotCombo
-        } else if expect_combo_key(pos) {
+        } else if expect_combo_key(&self.keymap, pos) {
             if let Some(next) = self.peek_event(1) {
-                if let Some(command) = find_combo(pos, next.pos) {
+                if let Some(command) = find_combo(&self.keymap, pos, next.pos) {
                     ComboKeyResult::ProcessCombo { command }
                 } else {
                     ComboKeyResult::NotCombo
@@ -372,7 +1397,7 @@ impl KeyStream {
 }
 
 enum ComboKeyResult {
-    ProcessCombo { command: &'static Command },
+    ProcessCombo { command: Command },
     Wait,
     NotCombo,
 }
@@ -383,8 +1408,8 @@ static VALID_KEY_LIST: [Pos; 48] = [
     0xa3, 0xa4, 0xa5, 0xa6, 0xb1, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6,
 ];
 
-fn expect_combo_key(on_key: Pos) -> bool {
-    for (k1, k2, _) in COMBO_KEYS.iter() {
+fn expect_combo_key(keymap: &Keymap, on_key: Pos) -> bool {
+    for (k1, k2, _) in keymap.combos.iter() {
         if on_key == *k1 {
             return true;
         }
@@ -395,10 +1420,10 @@ fn expect_combo_key(on_key: Pos) -> bool {
     return false;
 }
 
-fn find_combo(on_k1: Pos, on_k2: Pos) -> Option<&'static Command> {
-    for (k1, k2, kc) in COMBO_KEYS.iter() {
+fn find_combo(keymap: &Keymap, on_k1: Pos, on_k2: Pos) -> Option<Command> {
+    for (k1, k2, kc) in keymap.combos.iter() {
         if on_k1 == *k1 && on_k2 == *k2 || on_k1 == *k2 && on_k2 == *k1 {
-            return Some(kc);
+            return Some(*kc);
         }
     }
     return None;
@@ -434,6 +1459,15 @@ impl Command {
             Command::PressModifier { .. } => true,
             Command::ModifiedKey { .. } => true,
             Command::RequestReset { .. } => true,
+            Command::Char { .. } => true,
+            Command::SendString { .. } => true,
+            Command::Unicode { .. } => true,
+            Command::ToggleNicola => true,
+            Command::ToggleChords => true,
+            Command::ModTap { .. } => true,
+            Command::EnterSteno => true,
+            Command::SwitchProfile { .. } => true,
+            Command::SetHostOs { .. } => true,
         }
     }
 }
@@ -441,6 +1475,7 @@ impl Command {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::keymap::pack_strings;
     use std::vec::Vec;
     const COMBO_THRESHOLD_MS: u32 = 200;
     const COMBO_SEPARATION_MS: u32 = 500;
@@ -455,6 +1490,25 @@ mod tests {
         assert_eq!(pos_to_map_index(0xc6), 47);
     }
 
+    #[test]
+    fn test_grid() {
+        use crate::grid::*;
+        for row in 0..GRID_ROWS {
+            for col in 0..GRID_COLS {
+                let pos = grid_pos(row, col);
+                assert_eq!(pos_grid(pos), Some((row, col)));
+                assert_eq!(pos_to_map_index(pos), grid_index(row, col));
+                assert_eq!(index_grid(grid_index(row, col)), (row, col));
+            }
+        }
+        assert_eq!(grid_pos(1, 6), 0xa1);
+        assert_eq!(pos_grid(0x51), None);
+        assert_eq!(pos_grid(0x17), None);
+        for pos in VALID_KEY_LIST.iter() {
+            assert!(pos_grid(*pos).is_some());
+        }
+    }
+
     #[test]
     fn test_feature_state_pressed() {
         let a = Command::KeyPress { kc: KC::KBD_A };
@@ -462,7 +1516,10 @@ mod tests {
         let mut state = FeatureState::new();
         assert_eq!(state.make_key_report(), [0, 0, 0, 0, 0, 0, 0, 0]);
         state.press(&a);
-        assert_eq!(state.make_key_report(), [0, 0, KC::KBD_A, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            state.make_key_report(),
+            [0, 0, KC::KBD_A.code(), 0, 0, 0, 0, 0]
+        );
         state.release(&a);
         assert_eq!(state.make_key_report(), [0, 0, 0, 0, 0, 0, 0, 0]);
     }
@@ -476,22 +1533,34 @@ mod tests {
         assert_eq!(state.make_key_report(), [0, 0, 0, 0, 0, 0, 0, 0]);
 
         state.press(&a);
-        assert_eq!(state.make_key_report(), [0, 0, KC::KBD_A, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            state.make_key_report(),
+            [0, 0, KC::KBD_A.code(), 0, 0, 0, 0, 0]
+        );
 
         state.press(&a); // no change
-        assert_eq!(state.make_key_report(), [0, 0, KC::KBD_A, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            state.make_key_report(),
+            [0, 0, KC::KBD_A.code(), 0, 0, 0, 0, 0]
+        );
 
         state.press(&b);
         assert_eq!(
             state.make_key_report(),
-            [0, 0, KC::KBD_A, KC::KBD_B, 0, 0, 0, 0]
+            [0, 0, KC::KBD_A.code(), KC::KBD_B.code(), 0, 0, 0, 0]
         );
 
         state.release(&a);
-        assert_eq!(state.make_key_report(), [0, 0, KC::KBD_B, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            state.make_key_report(),
+            [0, 0, KC::KBD_B.code(), 0, 0, 0, 0, 0]
+        );
 
         state.release(&a); // no change
-        assert_eq!(state.make_key_report(), [0, 0, KC::KBD_B, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            state.make_key_report(),
+            [0, 0, KC::KBD_B.code(), 0, 0, 0, 0, 0]
+        );
 
         state.release(&b);
         assert_eq!(state.make_key_report(), [0, 0, 0, 0, 0, 0, 0, 0]);
@@ -509,7 +1578,16 @@ mod tests {
         state.press(&a);
         assert_eq!(
             state.make_key_report(),
-            [KC::KBD_MODIFIER_LEFT_SHIFT, 0, KC::KBD_A, 0, 0, 0, 0, 0]
+            [
+                KC::KBD_MODIFIER_LEFT_SHIFT,
+                0,
+                KC::KBD_A.code(),
+                0,
+                0,
+                0,
+                0,
+                0
+            ]
         );
 
         state.press(&ASTERISK);
@@ -518,8 +1596,8 @@ mod tests {
             [
                 KC::KBD_MODIFIER_LEFT_SHIFT,
                 0,
-                KC::KBD_A,
-                KC::KBD_JP_COLON,
+                KC::KBD_A.code(),
+                KC::KBD_JP_COLON.code(),
                 0,
                 0,
                 0,
@@ -530,7 +1608,16 @@ mod tests {
         state.release(&ASTERISK);
         assert_eq!(
             state.make_key_report(),
-            [KC::KBD_MODIFIER_LEFT_SHIFT, 0, KC::KBD_A, 0, 0, 0, 0, 0]
+            [
+                KC::KBD_MODIFIER_LEFT_SHIFT,
+                0,
+                KC::KBD_A.code(),
+                0,
+                0,
+                0,
+                0,
+                0
+            ]
         );
 
         state.release(&a);
@@ -555,7 +1642,16 @@ mod tests {
         state.press(&a);
         assert_eq!(
             state.make_key_report(),
-            [KC::KBD_MODIFIER_LEFT_SHIFT, 0, KC::KBD_A, 0, 0, 0, 0, 0]
+            [
+                KC::KBD_MODIFIER_LEFT_SHIFT,
+                0,
+                KC::KBD_A.code(),
+                0,
+                0,
+                0,
+                0,
+                0
+            ]
         );
 
         state.press(&ASTERISK);
@@ -564,8 +1660,8 @@ mod tests {
             [
                 KC::KBD_MODIFIER_LEFT_SHIFT,
                 0,
-                KC::KBD_A,
-                KC::KBD_JP_COLON,
+                KC::KBD_A.code(),
+                KC::KBD_JP_COLON.code(),
                 0,
                 0,
                 0,
@@ -579,7 +1675,7 @@ mod tests {
             [
                 KC::KBD_MODIFIER_LEFT_SHIFT,
                 0,
-                KC::KBD_JP_COLON,
+                KC::KBD_JP_COLON.code(),
                 0,
                 0,
                 0,
@@ -594,7 +1690,7 @@ mod tests {
             [
                 KC::KBD_MODIFIER_LEFT_SHIFT,
                 0,
-                KC::KBD_JP_COLON,
+                KC::KBD_JP_COLON.code(),
                 0,
                 0,
                 0,
@@ -627,6 +1723,121 @@ mod tests {
         assert_eq!(state.mods, [false, false, false]);
     }
 
+    #[test]
+    fn test_feature_state_right_and_composite_mods() {
+        let ralt_a = Command::ModifiedKey {
+            mk: Modifiers::new(&[ModifierKey::RALT]),
+            kc: KC::KBD_A,
+        };
+        let meh = Command::PressModifier {
+            mk: ModifierKey::MEH,
+        };
+        let hyper = Command::PressModifier {
+            mk: ModifierKey::HYPER,
+        };
+
+        let mut state = FeatureState::new();
+        state.press(&ralt_a);
+        assert_eq!(
+            state.make_key_report(),
+            [
+                KC::KBD_MODIFIER_RIGHT_ALT,
+                0,
+                KC::KBD_A.code(),
+                0,
+                0,
+                0,
+                0,
+                0
+            ]
+        );
+        state.release(&ralt_a);
+        state.press(&meh);
+        assert_eq!(state.make_key_report(), [0x07, 0, 0, 0, 0, 0, 0, 0]);
+        state.release(&meh);
+        state.press(&hyper);
+        assert_eq!(state.make_key_report(), [0x0f, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_feature_state_char() {
+        let lbrace = Command::Char {
+            mk: Modifiers::new(&[ModifierKey::UI1]),
+            c: b'{',
+        };
+        let shift_ui = KC::KBD_MODIFIER_LEFT_SHIFT | KC::KBD_MODIFIER_LEFT_UI;
+
+        let mut state = FeatureState::new();
+        state.press(&lbrace);
+        assert_eq!(
+            state.make_key_report(),
+            [shift_ui, 0, KC::KBD_JP_OPEN_BRACKET.code(), 0, 0, 0, 0, 0]
+        );
+        state.host_layout = HostLayout::Us;
+        assert_eq!(
+            state.make_key_report(),
+            [shift_ui, 0, KC::KBD_OPEN_BRACKET.code(), 0, 0, 0, 0, 0]
+        );
+        state.release(&lbrace);
+        state.press(&Command::Char {
+            mk: Modifiers::new(&[]),
+            c: b'=',
+        });
+        assert_eq!(
+            state.make_key_report(),
+            [0, 0, KC::KBD_PLUS.code(), 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_settings_save_retried() {
+        use crate::settings::RamPage;
+
+        let mut store = SettingsStore::open([RamPage::<512>::new(), RamPage::new()]).unwrap();
+        let mut stream = KeyStream::new();
+        assert!(stream.set_setting(settings::DEFAULT_LAYER, 1));
+        store.pages_mut()[0].fails = true;
+        assert_eq!(
+            stream.save_settings(&mut store),
+            Err(settings::Error::Flash)
+        );
+        assert!(stream.settings_changed());
+        store.pages_mut()[0].fails = false;
+        stream.save_settings(&mut store).unwrap();
+        assert!(!stream.settings_changed());
+
+        let mut stream = KeyStream::new();
+        stream.load_settings(&store);
+        assert_eq!(stream.default_layer, 1);
+    }
+
+    #[test]
+    fn test_settings_save_load() {
+        use crate::settings::RamPage;
+
+        let mut store = SettingsStore::open([RamPage::<512>::new(), RamPage::new()]).unwrap();
+        let mut stream = KeyStream::new();
+        assert!(stream.set_setting(settings::COMBO_THRESHOLD, 150));
+        assert!(stream.set_setting(settings::DEFAULT_LAYER, 1));
+        assert!(!stream.set_setting(settings::DEFAULT_LAYER, 4));
+        assert!(stream.set_setting(settings::HOST_LAYOUT, 1));
+        assert!(!stream.set_setting(settings::HOST_LAYOUT, 2));
+        stream.save_settings(&mut store).unwrap();
+
+        let mut stream = KeyStream::new();
+        stream.load_settings(&store);
+        assert_eq!(stream.timing.combo_threshold, ms_to_cnt(150));
+        assert_eq!(stream.default_layer, 1);
+        assert_eq!(stream.setting(settings::HOST_LAYOUT), Some(1));
+
+        // `Command::ToggleNicola` saves the setting.
+        stream.keymap.layers[1][pos_to_map_index(0x13)] = Command::ToggleNicola;
+        stream.push(&[0x13, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(100));
+        stream.read(ms(101), |_| {});
+        assert_eq!(stream.setting(settings::NICOLA), Some(1));
+        assert!(stream.settings_changed);
+    }
+
     // Convert millisecond to clock with arbitrary offset.
     fn ms(ms: u32) -> u32 {
         (1204 + ms) * 72_000
@@ -655,14 +1866,527 @@ mod tests {
         let mut e = mock_emit();
         stream.push(&[0x22, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(100));
         stream.read(ms(101), |x| e.emit(x));
-        e.verify(vec![[0, 0, KC::KBD_A, 0, 0, 0, 0, 0]]);
+        e.verify(vec![[0, 0, KC::KBD_A.code(), 0, 0, 0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_key_stream_send_string() {
+        let mut stream = KeyStream::new();
+        stream.keymap.strings = pack_strings(&["x".to_string(), "Aa=\n\u{7}".to_string()]).unwrap();
+        stream.keymap.layers[0][pos_to_map_index(0x13)] = Command::SendString { index: 1 };
+        let shift = KC::KBD_MODIFIER_LEFT_SHIFT;
+        let up = [0u8; 8];
+
+        let mut e = mock_emit();
+        stream.push(&[0x13, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(100));
+        stream.read(ms(101), |x| e.emit(x));
+        e.verify(vec![
+            up,
+            [shift, 0, KC::KBD_A.code(), 0, 0, 0, 0, 0],
+            up,
+            [0, 0, KC::KBD_A.code(), 0, 0, 0, 0, 0],
+            up,
+            [shift, 0, KC::KBD_JP_HYPHEN.code(), 0, 0, 0, 0, 0],
+            up,
+            [0, 0, KC::KBD_ENTER.code(), 0, 0, 0, 0, 0],
+        ]);
+
+        // Keys pressed while typing wait for the end of the string.
+        let mut e = mock_emit();
+        stream.push(&[0x13, 0x14, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(115));
+        stream.read(ms(116), |x| e.emit(x));
+        e.verify(vec![up]);
+        let mut e = mock_emit();
+        stream.read(ms(131), |x| e.emit(x));
+        e.verify(vec![[0, 0, KC::KBD_E.code(), 0, 0, 0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_key_stream_unicode() {
+        let mut stream = KeyStream::new();
+        assert!(stream.set_setting(settings::UNICODE_MODE, 1));
+        stream.keymap.layers[0][pos_to_map_index(0x13)] = Command::Unicode { c: '😀' };
+        stream.keymap.strings = pack_strings(&["é".to_string()]).unwrap();
+        stream.keymap.layers[0][pos_to_map_index(0x14)] = Command::SendString { index: 0 };
+        let mut expected = vec![[0u8; 8]];
+        let mut reports = Reports::new();
+        UnicodeMode::Mac.push_reports('😀', &mut reports);
+        expected.extend_from_slice(reports.as_slice());
+        expected.push([0; 8]);
+        let mut reports = Reports::new();
+        UnicodeMode::Mac.push_reports('é', &mut reports);
+        expected.extend_from_slice(reports.as_slice());
+
+        let mut e = mock_emit();
+        stream.push(&[0x13, 0x14, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(100));
+        for i in 0..5 {
+            stream.read(ms(101 + i * 15), |x| e.emit(x));
+        }
+        e.verify(expected);
+    }
+
+    #[test]
+    fn test_key_stream_mod_tap() {
+        let mut stream = KeyStream::new();
+        stream.keymap.layers[0][pos_to_map_index(0x13)] = Command::ModTap {
+            mk: ModifierKey::UI1,
+            kc: KC::KBD_LANG2,
+        };
+        stream.keymap.layers[0][pos_to_map_index(0x14)] = Command::KeyPress { kc: KC::KBD_A };
+        stream.keymap.layers[0][pos_to_map_index(0x15)] = Command::PressModifier {
+            mk: ModifierKey::MOD2,
+        };
+        let ui = KC::KBD_MODIFIER_LEFT_UI;
+        let lang2 = [0, 0, KC::KBD_LANG2.code(), 0, 0, 0, 0, 0];
+        let up = [0u8; 8];
+        let read = |stream: &mut KeyStream, keys: [u8; 8], t: u32| {
+            let mut e = mock_emit();
+            stream.push(&keys, &[0u8; 8], ms(t));
+            stream.read(ms(t + 1), |x| e.emit(x));
+            e.history
+        };
+
+        // Tapped alone.
+        assert_eq!(
+            read(&mut stream, [0x13, 0, 0, 0, 0, 0, 0, 0], 100),
+            vec![[ui, 0, 0, 0, 0, 0, 0, 0]]
+        );
+        assert_eq!(read(&mut stream, [0; 8], 200), vec![up, lang2, up]);
+
+        // Held with another key.
+        assert_eq!(
+            read(&mut stream, [0x13, 0, 0, 0, 0, 0, 0, 0], 400),
+            vec![[ui, 0, 0, 0, 0, 0, 0, 0]]
+        );
+        assert_eq!(
+            read(&mut stream, [0x13, 0x14, 0, 0, 0, 0, 0, 0], 415),
+            vec![[ui, 0, KC::KBD_A.code(), 0, 0, 0, 0, 0]]
+        );
+        assert_eq!(
+            read(&mut stream, [0x13, 0, 0, 0, 0, 0, 0, 0], 430),
+            vec![[ui, 0, 0, 0, 0, 0, 0, 0]]
+        );
+        assert_eq!(read(&mut stream, [0; 8], 445), vec![up]);
+
+        // Held alone longer than `tapping_term`, such as for Cmd+click.
+        assert_eq!(
+            read(&mut stream, [0x13, 0, 0, 0, 0, 0, 0, 0], 460),
+            vec![[ui, 0, 0, 0, 0, 0, 0, 0]]
+        );
+        assert_eq!(read(&mut stream, [0; 8], 700), vec![up]);
+        assert!(stream.set_setting(settings::TAPPING_TERM, 400));
+        assert_eq!(stream.setting(settings::TAPPING_TERM), Some(400));
+        assert_eq!(
+            read(&mut stream, [0x13, 0, 0, 0, 0, 0, 0, 0], 800),
+            vec![[ui, 0, 0, 0, 0, 0, 0, 0]]
+        );
+        assert_eq!(read(&mut stream, [0; 8], 1040), vec![up, lang2, up]);
+        assert!(stream.set_setting(settings::TAPPING_TERM, 200));
+
+        // MOD2 switches to 英数 only with `auto_eisu`.
+        assert_eq!(
+            read(&mut stream, [0x15, 0, 0, 0, 0, 0, 0, 0], 500),
+            Vec::<[u8; 8]>::new()
+        );
+        assert_eq!(read(&mut stream, [0; 8], 515), vec![up]);
+        assert!(stream.set_setting(settings::AUTO_EISU, 1));
+        assert_eq!(
+            read(&mut stream, [0x15, 0, 0, 0, 0, 0, 0, 0], 600),
+            vec![up, lang2, up]
+        );
+        assert_eq!(read(&mut stream, [0; 8], 615), vec![up]);
+    }
+
+    #[test]
+    fn test_key_stream_steno() {
+        use crate::grid::grid_pos;
+        let mut stream = KeyStream::new();
+        stream.keymap.layers[0][pos_to_map_index(0x13)] = Command::EnterSteno;
+        let (k, a, t) = (grid_pos(2, 2), grid_pos(3, 4), grid_pos(1, 10));
+        let exit = [
+            grid_pos(3, 0),
+            grid_pos(3, 1),
+            grid_pos(3, 2),
+            grid_pos(3, 3),
+        ];
+        let read = |stream: &mut KeyStream, keys: &[u8], t: u32| {
+            let mut mat = [0u8; 8];
+            mat[..keys.len()].copy_from_slice(keys);
+            let mut e = mock_emit();
+            stream.push(&mat, &[0u8; 8], ms(t));
+            stream.read(ms(t + 1), |x| e.emit(x));
+            e.history
+        };
+        let take_packet = |stream: &mut KeyStream| {
+            let packet = stream.steno_output().to_vec();
+            stream.consume_steno_output(packet.len());
+            packet
+        };
+
+        read(&mut stream, &[0x13], 100);
+        read(&mut stream, &[0x13, k], 115);
+        assert!(stream.state.steno);
+        assert_eq!(stream.setting(settings::STENO), Some(1));
+        // The stroke is sent when all of its keys are released, the STENO key is not of it.
+        assert_eq!(read(&mut stream, &[k, a], 130), vec![[0u8; 8]]);
+        read(&mut stream, &[a, t], 145);
+        assert!(take_packet(&mut stream).is_empty());
+        read(&mut stream, &[], 160);
+        assert_eq!(
+            take_packet(&mut stream),
+            &[0x80, 0x08, 0x20, 0x00, 0x04, 0x00]
+        );
+        assert!(take_packet(&mut stream).is_empty());
+
+        // The rest of a packet written in part comes before the next stroke.
+        read(&mut stream, &[k], 170);
+        read(&mut stream, &[], 185);
+        read(&mut stream, &[a], 190);
+        read(&mut stream, &[], 195);
+        assert_eq!(stream.steno_output(), &[0x80, 0x08, 0x00, 0x00, 0x00, 0x00]);
+        stream.consume_steno_output(4);
+        assert_eq!(take_packet(&mut stream), &[0x00, 0x00]);
+        assert_eq!(
+            take_packet(&mut stream),
+            &[0x80, 0x00, 0x20, 0x00, 0x00, 0x00]
+        );
+        assert!(take_packet(&mut stream).is_empty());
+
+        assert!(stream.set_setting(settings::STENO_PROTOCOL, 1));
+        read(&mut stream, &[k], 200);
+        read(&mut stream, &[], 215);
+        assert_eq!(take_packet(&mut stream), &[0x04, 0]);
+
+        // The exit stroke is not sent.
+        read(&mut stream, &exit, 300);
+        read(&mut stream, &[], 315);
+        assert!(take_packet(&mut stream).is_empty());
+        assert!(!stream.state.steno);
+        assert_eq!(
+            read(&mut stream, &[k], 400),
+            vec![[0, 0, KC::KBD_X.code(), 0, 0, 0, 0, 0]]
+        );
+        assert!(take_packet(&mut stream).is_empty());
+        assert_eq!(stream.setting(settings::STENO), Some(0));
+    }
+
+    #[test]
+    fn test_key_stream_host_os() {
+        let mut stream = KeyStream::new();
+        stream.keymap.layers[0][pos_to_map_index(0x13)] = Command::SetHostOs { os: HostOs::Linux };
+        stream.keymap.layers[0][pos_to_map_index(0x14)] = Command::PressModifier {
+            mk: ModifierKey::UI1,
+        };
+        stream.keymap.layers[0][pos_to_map_index(0x15)] = Command::ModifiedKey {
+            mk: Modifiers::new(&[ModifierKey::UI1, ModifierKey::SHIFT1]),
+            kc: KC::KBD_A,
+        };
+        let read = |stream: &mut KeyStream, keys: [u8; 8], t: u32| {
+            let mut e = mock_emit();
+            stream.push(&keys, &[0u8; 8], ms(t));
+            stream.read(ms(t + 1), |x| e.emit(x));
+            e.history
+        };
+        let shift = KC::KBD_MODIFIER_LEFT_SHIFT;
+
+        read(&mut stream, [0x13, 0, 0, 0, 0, 0, 0, 0], 100);
+        read(&mut stream, [0; 8], 115);
+        assert_eq!(stream.setting(settings::HOST_OS), Some(2));
+        assert!(stream.settings_changed);
+        // UI1 alone is ALT1, and UI1 of shortcuts is CTRL1.
+        assert_eq!(
+            read(&mut stream, [0x14, 0, 0, 0, 0, 0, 0, 0], 200),
+            vec![[KC::KBD_MODIFIER_LEFT_ALT, 0, 0, 0, 0, 0, 0, 0]]
+        );
+        read(&mut stream, [0; 8], 215);
+        assert_eq!(
+            read(&mut stream, [0x15, 0, 0, 0, 0, 0, 0, 0], 300),
+            vec![[
+                KC::KBD_MODIFIER_LEFT_CTRL | shift,
+                0,
+                KC::KBD_A.code(),
+                0,
+                0,
+                0,
+                0,
+                0
+            ]]
+        );
+        read(&mut stream, [0; 8], 315);
+
+        assert!(!stream.set_setting(settings::HOST_OS, 3));
+        assert!(stream.set_setting(settings::HOST_OS, 0));
+        assert_eq!(
+            read(&mut stream, [0x15, 0, 0, 0, 0, 0, 0, 0], 400),
+            vec![[
+                KC::KBD_MODIFIER_LEFT_UI | shift,
+                0,
+                KC::KBD_A.code(),
+                0,
+                0,
+                0,
+                0,
+                0
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_key_stream_detect_host_os() {
+        let mut stream = KeyStream::new();
+        let mut trace = Trace::new();
+        trace.push(os_detect::DESCRIPTOR_CONFIGURATION, 0, 255);
+        // Off by default, only the detected OS is kept.
+        trace.set_configured();
+        stream.watch_usb_trace(&trace, ms(0));
+        stream.watch_usb_trace(&trace, ms(1100));
+        assert_eq!(stream.detected_host_os(), Some(HostOs::Windows));
+        assert_eq!(stream.setting(settings::HOST_OS), Some(0));
+
+        assert!(stream.set_setting(settings::AUTO_HOST_OS, 2));
+        assert!(!stream.set_setting(settings::AUTO_HOST_OS, 3));
+        stream.settings_changed = false;
+        trace.clear();
+        stream.watch_usb_trace(&trace, ms(2000));
+        trace.push(os_detect::DESCRIPTOR_STRING, 0, 255);
+        trace.set_configured();
+        stream.watch_usb_trace(&trace, ms(2100));
+        // Strings may still be read within the settle time.
+        stream.watch_usb_trace(&trace, ms(2500));
+        assert_eq!(stream.setting(settings::HOST_OS), Some(0));
+        trace.push(os_detect::DESCRIPTOR_STRING, 2, 255);
+        stream.watch_usb_trace(&trace, ms(2600));
+        stream.watch_usb_trace(&trace, ms(3200));
+        assert_eq!(stream.setting(settings::HOST_OS), Some(0));
+        stream.watch_usb_trace(&trace, ms(3400));
+        assert_eq!(stream.detected_host_os(), Some(HostOs::Linux));
+        assert_eq!(stream.setting(settings::HOST_OS), Some(2));
+        // No profile is switched to unless it is set for the OS.
+        assert_eq!(stream.setting(settings::PROFILE), Some(0));
+        assert!(!stream.settings_changed);
+
+        assert!(stream.set_setting(settings::LINUX_PROFILE, 1));
+        assert!(!stream.set_setting(settings::MAC_PROFILE, N_PROFILES as u16));
+        assert_eq!(
+            stream.setting(settings::MAC_PROFILE),
+            Some(settings::NO_PROFILE)
+        );
+        trace.clear();
+        stream.watch_usb_trace(&trace, ms(4000));
+        trace.push(os_detect::DESCRIPTOR_STRING, 0, 255);
+        trace.set_configured();
+        stream.watch_usb_trace(&trace, ms(4100));
+        stream.watch_usb_trace(&trace, ms(4900));
+        assert_eq!(stream.setting(settings::PROFILE), Some(1));
+    }
+
+    #[test]
+    fn test_key_stream_profile() {
+        use crate::settings::RamPage;
+        let mut stream = KeyStream::new();
+        let (switch, key) = (pos_to_map_index(0x13), pos_to_map_index(0x14));
+        stream.keymap.layers[0][switch] = Command::SwitchProfile { n: 1 };
+        stream.keymap.layers[0][key] = Command::KeyPress { kc: KC::KBD_A };
+        stream.keymap.switch_profile(1);
+        stream.keymap.layers[0][switch] = Command::SwitchProfile { n: 0 };
+        stream.keymap.layers[0][key] = Command::KeyPress { kc: KC::KBD_B };
+        stream.keymap.switch_profile(0);
+        let up = [0u8; 8];
+        let read = |stream: &mut KeyStream, keys: [u8; 8], t: u32| {
+            let mut e = mock_emit();
+            stream.push(&keys, &[0u8; 8], ms(t));
+            stream.read(ms(t + 1), |x| e.emit(x));
+            e.history
+        };
+
+        // Keys held while switching are released.
+        assert_eq!(
+            read(&mut stream, [0x14, 0, 0, 0, 0, 0, 0, 0], 100),
+            vec![[0, 0, KC::KBD_A.code(), 0, 0, 0, 0, 0]]
+        );
+        assert_eq!(
+            read(&mut stream, [0x14, 0x13, 0, 0, 0, 0, 0, 0], 115),
+            vec![up]
+        );
+        assert_eq!(stream.setting(settings::PROFILE), Some(1));
+        assert!(stream.settings_changed);
+        read(&mut stream, [0; 8], 130);
+        assert_eq!(
+            read(&mut stream, [0x14, 0, 0, 0, 0, 0, 0, 0], 200),
+            vec![[0, 0, KC::KBD_B.code(), 0, 0, 0, 0, 0]]
+        );
+        read(&mut stream, [0; 8], 215);
+
+        // The active profile is a setting, and kept when the keymap is reloaded.
+        let mut store = SettingsStore::open([RamPage::<512>::new(), RamPage::new()]).unwrap();
+        stream.save_settings(&mut store).unwrap();
+        let image = stream.keymap_image();
+        let mut stream = KeyStream::new();
+        stream.load_settings(&store);
+        stream.load_keymap(&image).unwrap();
+        assert_eq!(stream.keymap.profile, 1);
+        assert_eq!(
+            stream.keymap.layers[0][key],
+            Command::KeyPress { kc: KC::KBD_B }
+        );
+        assert!(!stream.set_setting(settings::PROFILE, N_PROFILES as u16));
+        assert!(stream.set_setting(settings::PROFILE, 0));
+        assert_eq!(
+            stream.keymap.layers[0][key],
+            Command::KeyPress { kc: KC::KBD_A }
+        );
+    }
+
+    /// Reports of typing the romaji on a JIS host.
+    fn typed(romaji: &str) -> Vec<[u8; 8]> {
+        let mut reports = vec![[0u8; 8]];
+        for c in romaji.bytes() {
+            let (_, kc) = HostLayout::Jis.string_key(c).unwrap();
+            reports.push([0, 0, kc.code(), 0, 0, 0, 0, 0]);
+            reports.push([0; 8]);
+        }
+        reports
+    }
+
+    fn nicola_stream() -> KeyStream {
+        let mut stream = KeyStream::new();
+        assert!(stream.set_setting(settings::NICOLA, 1));
+        stream.keymap.layers[0][pos_to_map_index(0xc1)] = Command::KeyPress {
+            kc: KC::KBD_JP_HENKAN,
+        };
+        stream
+    }
+
+    #[test]
+    fn test_key_stream_nicola() {
+        let w = 0x13;
+        let e = 0x14;
+        let left = 0x45;
+        let right = 0xc1;
+
+        // A character key alone after the threshold.
+        let mut stream = nicola_stream();
+        let mut m = mock_emit();
+        stream.push(&[w, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(100));
+        stream.read(ms(101), |x| m.emit(x));
+        stream.read(ms(250), |x| m.emit(x));
+        m.verify(typed("ka"));
+
+        // A thumb key pressed just after the character key shifts it, and is not typed.
+        let mut stream = nicola_stream();
+        let mut m = mock_emit();
+        stream.push(&[w, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(100));
+        stream.push(&[w, left, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(130));
+        stream.read(ms(131), |x| m.emit(x));
+        stream.read(ms(170), |x| m.emit(x));
+        stream.push(&[0; 8], &[0u8; 8], ms(180));
+        stream.read(ms(181), |x| m.emit(x));
+        let mut expected = typed("e");
+        expected.push([0; 8]);
+        m.verify(expected);
+
+        // Continuous shift while the thumb key is held.
+        let mut stream = nicola_stream();
+        let mut m = mock_emit();
+        stream.push(&[right, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(100));
+        stream.read(ms(101), |x| m.emit(x));
+        stream.push(&[right, w, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(300));
+        stream.read(ms(301), |x| m.emit(x));
+        m.verify(typed("ga"));
+
+        // A thumb key alone types its own key.
+        let mut stream = nicola_stream();
+        let mut m = mock_emit();
+        stream.push(&[left, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(100));
+        stream.push(&[0; 8], &[0u8; 8], ms(150));
+        stream.read(ms(151), |x| m.emit(x));
+        m.verify(vec![
+            [0; 8],
+            [0, 0, KC::KBD_SPACEBAR.code(), 0, 0, 0, 0, 0],
+            [0; 8],
+        ]);
+
+        // The thumb key shifts the character key closer to it.
+        let mut stream = nicola_stream();
+        let mut m = mock_emit();
+        stream.push(&[w, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(100));
+        stream.push(&[w, left, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(150));
+        stream.push(&[w, left, e, 0, 0, 0, 0, 0], &[0u8; 8], ms(160));
+        stream.read(ms(161), |x| m.emit(x));
+        stream.read(ms(176), |x| m.emit(x));
+        let mut expected = typed("ka");
+        expected.extend(typed("ri"));
+        m.verify(expected);
+
+        // Keys of the base layer when the input is off.
+        let mut stream = nicola_stream();
+        assert!(stream.set_setting(settings::NICOLA, 0));
+        let mut m = mock_emit();
+        stream.push(&[w, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(100));
+        stream.read(ms(101), |x| m.emit(x));
+        m.verify(vec![[0, 0, KC::KBD_W.code(), 0, 0, 0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_key_stream_chords() {
+        let j = 0xa2;
+        let k = 0xa3;
+        let space = 0x45;
+        let mut stream = KeyStream::new();
+        assert!(stream.set_setting(settings::CHORDS, 1));
+        stream.keymap.chords[0] = Chord::new(&[j], b"a").unwrap();
+        stream.keymap.chords[1] = Chord::new(&[k], b"i").unwrap();
+        stream.keymap.chords[2] = Chord::new(&[j, k], b"u").unwrap();
+        stream.keymap.chords[3] = Chord::new(&[space, j], b"e").unwrap();
+
+        // A key alone after the threshold, then keys pressed together.
+        let mut m = mock_emit();
+        stream.push(&[j, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(100));
+        stream.read(ms(101), |x| m.emit(x));
+        stream.read(ms(200), |x| m.emit(x));
+        stream.push(&[0; 8], &[0u8; 8], ms(210));
+        stream.push(&[j, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(300));
+        stream.push(&[j, k, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(320));
+        stream.read(ms(321), |x| m.emit(x));
+        stream.read(ms(400), |x| m.emit(x));
+        let mut expected = typed("a");
+        expected.extend(typed("u"));
+        m.verify(expected);
+
+        // Continuous shift by a key which is not a chord alone.
+        let mut m = mock_emit();
+        stream.push(&[0; 8], &[0u8; 8], ms(500));
+        stream.push(&[space, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(600));
+        stream.read(ms(700), |x| m.emit(x));
+        stream.push(&[space, j, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(800));
+        stream.read(ms(900), |x| m.emit(x));
+        stream.push(&[space, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(910));
+        stream.push(&[space, j, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(1000));
+        stream.read(ms(1100), |x| m.emit(x));
+        stream.push(&[0; 8], &[0u8; 8], ms(1200));
+        stream.read(ms(1201), |x| m.emit(x));
+        let mut expected = typed("e");
+        expected.extend(typed("e"));
+        expected.push([0; 8]);
+        m.verify(expected);
+
+        // The key types its key of the layer when released alone.
+        let mut m = mock_emit();
+        stream.push(&[space, 0, 0, 0, 0, 0, 0, 0], &[0u8; 8], ms(1300));
+        stream.push(&[0; 8], &[0u8; 8], ms(1350));
+        stream.read(ms(1351), |x| m.emit(x));
+        m.verify(vec![
+            [0; 8],
+            [0, 0, KC::KBD_SPACEBAR.code(), 0, 0, 0, 0, 0],
+            [0; 8],
+        ]);
     }
 
     #[test]
     fn test_key_stream_combo_key_flash_by_time() {
         let mut stream = KeyStream::new();
         let mut e = mock_emit();
-        let semicolon = [0, 0, KC::KBD_JP_SEMICOLON, 0, 0, 0, 0, 0];
+        let semicolon = [0, 0, KC::KBD_JP_SEMICOLON.code(), 0, 0, 0, 0, 0];
 
         stream.push(&[0u8; 8], &[0xa5, 0, 0, 0, 0, 0, 0, 0], ms(0));
         stream.read(ms(1), |x| e.emit(x));
@@ -700,9 +2424,18 @@ mod tests {
     fn test_key_stream_combo_no_pause() {
         let mut stream = KeyStream::new();
         let mut e = mock_emit();
-        let a = [0, 0, KC::KBD_A, 0, 0, 0, 0, 0];
-        let semi = [0, 0, KC::KBD_JP_SEMICOLON, 0, 0, 0, 0, 0];
-        let semi_bksp = [0, 0, KC::KBD_JP_SEMICOLON, KC::KBD_BACKSPACE, 0, 0, 0, 0];
+        let a = [0, 0, KC::KBD_A.code(), 0, 0, 0, 0, 0];
+        let semi = [0, 0, KC::KBD_JP_SEMICOLON.code(), 0, 0, 0, 0, 0];
+        let semi_bksp = [
+            0,
+            0,
+            KC::KBD_JP_SEMICOLON.code(),
+            KC::KBD_BACKSPACE.code(),
+            0,
+            0,
+            0,
+            0,
+        ];
 
         stream.push(&[0u8; 8], &[0x22, 0, 0, 0, 0, 0, 0, 0], ms(0));
         stream.read(ms(1), |x| e.emit(x));
@@ -718,8 +2451,8 @@ mod tests {
     fn test_key_stream_combo_after_pause() {
         let mut stream = KeyStream::new();
         let mut e = mock_emit();
-        let a = [0, 0, KC::KBD_A, 0, 0, 0, 0, 0];
-        let bracket = [0, 0, KC::KBD_JP_CLOSE_BRACKET, 0, 0, 0, 0, 0];
+        let a = [0, 0, KC::KBD_A.code(), 0, 0, 0, 0, 0];
+        let bracket = [0, 0, KC::KBD_JP_CLOSE_BRACKET.code(), 0, 0, 0, 0, 0];
 
         stream.push(&[0u8; 8], &[0x22, 0, 0, 0, 0, 0, 0, 0], ms(0));
         stream.read(ms(1), |x| e.emit(x));
@@ -741,7 +2474,7 @@ mod tests {
     fn test_key_stream_combo_key_flash_by_release() {
         let mut stream = KeyStream::new();
         let mut e = mock_emit();
-        let semicolon = [0, 0, KC::KBD_JP_SEMICOLON, 0, 0, 0, 0, 0];
+        let semicolon = [0, 0, KC::KBD_JP_SEMICOLON.code(), 0, 0, 0, 0, 0];
 
         stream.push(&[0u8; 8], &[0xa5, 0, 0, 0, 0, 0, 0, 0], ms(0));
         stream.read(ms(1), |x| e.emit(x));
@@ -757,8 +2490,8 @@ mod tests {
     fn test_key_stream_combo_key_flash_by_other_key() {
         let mut stream = KeyStream::new();
         let mut e = mock_emit();
-        let semicolon = [0, 0, KC::KBD_JP_SEMICOLON, 0, 0, 0, 0, 0];
-        let a = [0, 0, KC::KBD_A, 0, 0, 0, 0, 0];
+        let semicolon = [0, 0, KC::KBD_JP_SEMICOLON.code(), 0, 0, 0, 0, 0];
+        let a = [0, 0, KC::KBD_A.code(), 0, 0, 0, 0, 0];
 
         stream.push(&[0u8; 8], &[0xa5, 0, 0, 0, 0, 0, 0, 0], ms(0));
         stream.read(ms(1), |x| e.emit(x));
@@ -774,7 +2507,7 @@ mod tests {
     fn test_key_stream_combo_in_one_scan() {
         let mut stream = KeyStream::new();
         let mut e = mock_emit();
-        let bracket = [0, 0, KC::KBD_JP_CLOSE_BRACKET, 0, 0, 0, 0, 0];
+        let bracket = [0, 0, KC::KBD_JP_CLOSE_BRACKET.code(), 0, 0, 0, 0, 0];
         let zero = [0, 0, 0, 0, 0, 0, 0, 0];
 
         stream.push(&[0u8; 8], &[0xa5, 0, 0, 0, 0, 0, 0, 0], ms(0));
@@ -793,7 +2526,7 @@ mod tests {
     fn test_key_stream_combo_in_two_scans() {
         let mut stream = KeyStream::new();
         let mut e = mock_emit();
-        let bracket = [0, 0, KC::KBD_JP_CLOSE_BRACKET, 0, 0, 0, 0, 0];
+        let bracket = [0, 0, KC::KBD_JP_CLOSE_BRACKET.code(), 0, 0, 0, 0, 0];
         let zero = [0, 0, 0, 0, 0, 0, 0, 0];
 
         stream.push(&[0u8; 8], &[0xa5, 0, 0, 0, 0, 0, 0, 0], ms(0));
@@ -0,0 +1,108 @@
+//! NICOLA (親指シフト) thumb-shift input.
+//!
+//! While the mode is on, a character key types kana of the NICOLA layout: the kana of the
+//! key alone, or with the left or the right thumb key pressed at the same time.  Kana are
+//! typed as romaji, so the host IME must be in the romaji input of kana.
+//!
+//! Keys are found by the key codes of the base layer on a JIS host: `Q` to `JP_AT`, `A` to
+//! `JP_SEMICOLON` and `Z` to `SLASH` are character keys, and `SPACEBAR`, `JP_MUHENKAN` and
+//! `JP_HENKAN` are thumb keys, left or right by the half of the keyboard.
+//!
+//! Simultaneity, as the NICOLA rules:
+//! - A character key pressed while a thumb key is held is shifted by the thumb, however long
+//!   the thumb has been held (continuous shift).
+//! - A thumb key pressed within the threshold after a character key shifts the character,
+//!   unless the next character key follows the thumb sooner, when the thumb shifts that one.
+//! - A thumb key released without shifting any character types its own key.
+
+use crate::hid_keycodes::{self as KC, Kc};
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Thumb {
+    Left,
+    Right,
+}
+
+/// Kana of character keys in romaji: (key, alone, left thumb, right thumb).
+/// Punctuation is typed as the IME turns `,`, `.`, `/` and `-` into 「、」, 「。」, 「・」 and
+/// 「ー」.  Empty strings are kana which the layout does not have, for which the key alone is
+/// typed.
+static KANA: [(Kc, &str, &str, &str); 31] = [
+    (KC::KBD_Q, ".", "xa", ""),
+    (KC::KBD_W, "ka", "e", "ga"),
+    (KC::KBD_E, "ta", "ri", "da"),
+    (KC::KBD_R, "ko", "xya", "go"),
+    (KC::KBD_T, "sa", "re", "za"),
+    (KC::KBD_Y, "ra", "pa", "yo"),
+    (KC::KBD_U, "ti", "di", "ni"),
+    (KC::KBD_I, "ku", "gu", "ru"),
+    (KC::KBD_O, "tu", "du", "ma"),
+    (KC::KBD_P, ",", "pi", "xe"),
+    (KC::KBD_JP_AT, ",", "", ""),
+    (KC::KBD_A, "u", "wo", "vu"),
+    (KC::KBD_S, "si", "a", "zi"),
+    (KC::KBD_D, "te", "na", "de"),
+    (KC::KBD_F, "ke", "xyu", "ge"),
+    (KC::KBD_G, "se", "mo", "ze"),
+    (KC::KBD_H, "ha", "ba", "mi"),
+    (KC::KBD_J, "to", "do", "o"),
+    (KC::KBD_K, "ki", "gi", "no"),
+    (KC::KBD_L, "i", "po", "xyo"),
+    (KC::KBD_JP_SEMICOLON, "nn", "", "xtu"),
+    (KC::KBD_Z, ".", "xu", ""),
+    (KC::KBD_X, "hi", "-", "bi"),
+    (KC::KBD_C, "su", "ro", "zu"),
+    (KC::KBD_V, "hu", "ya", "bu"),
+    (KC::KBD_B, "he", "xi", "be"),
+    (KC::KBD_N, "me", "pu", "nu"),
+    (KC::KBD_M, "so", "zo", "yu"),
+    (KC::KBD_COMMA, "ne", "pe", "mu"),
+    (KC::KBD_DOT, "ho", "bo", "wa"),
+    (KC::KBD_SLASH, "/", "", "xo"),
+];
+
+/// Romaji of the kana of the character key, with the thumb key if shifted.
+pub fn romaji(kc: Kc, thumb: Option<Thumb>) -> Option<&'static str> {
+    let (_, alone, left, right) = KANA.iter().find(|k| k.0 == kc)?;
+    let shifted = match thumb {
+        None => alone,
+        Some(Thumb::Left) => left,
+        Some(Thumb::Right) => right,
+    };
+    Some(if shifted.is_empty() { alone } else { shifted })
+}
+
+/// Thumb of the key, if it is a thumb key.  `right_half` is the half of the key.
+pub fn thumb(kc: Kc, right_half: bool) -> Option<Thumb> {
+    match kc {
+        KC::KBD_SPACEBAR | KC::KBD_JP_MUHENKAN | KC::KBD_JP_HENKAN if right_half => {
+            Some(Thumb::Right)
+        }
+        KC::KBD_SPACEBAR | KC::KBD_JP_MUHENKAN | KC::KBD_JP_HENKAN => Some(Thumb::Left),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_romaji() {
+        assert_eq!(romaji(KC::KBD_W, None), Some("ka"));
+        assert_eq!(romaji(KC::KBD_W, Some(Thumb::Left)), Some("e"));
+        assert_eq!(romaji(KC::KBD_W, Some(Thumb::Right)), Some("ga"));
+        assert_eq!(
+            romaji(KC::KBD_JP_SEMICOLON, Some(Thumb::Right)),
+            Some("xtu")
+        );
+        // No kana with the thumb, then the key alone.
+        assert_eq!(romaji(KC::KBD_Q, Some(Thumb::Right)), Some("."));
+        assert_eq!(romaji(KC::KBD_JP_COLON, None), None);
+        assert_eq!(romaji(KC::KBD_TAB, None), None);
+        assert_eq!(thumb(KC::KBD_SPACEBAR, false), Some(Thumb::Left));
+        assert_eq!(thumb(KC::KBD_JP_HENKAN, true), Some(Thumb::Right));
+        assert_eq!(thumb(KC::KBD_A, false), None);
+    }
+}
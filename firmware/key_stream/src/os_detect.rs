@@ -0,0 +1,214 @@
+//! Detection of the OS of the host from the descriptors it requests during the USB
+//! enumeration.
+//!
+//! Every OS reads the same descriptors, but the lengths it asks for and the strings it reads
+//! tell them apart:
+//!
+//! - Windows reads the configuration descriptor with the length 255 instead of its total
+//!   length, and the Microsoft OS string descriptor 0xEE on the first enumeration.
+//! - macOS reads the first 2 bytes of string descriptors to learn their lengths before reading
+//!   them.
+//! - Linux reads every string descriptor with the length 255.
+//!
+//! The firmware records the requests into a `Trace`, and `classify` guesses the OS once the
+//! enumeration has settled.  The guess is a heuristic: hosts not matching any of the above are
+//! not classified.
+
+use crate::host_os::HostOs;
+
+pub const DESCRIPTOR_CONFIGURATION: u8 = 0x02;
+pub const DESCRIPTOR_STRING: u8 = 0x03;
+/// Index of the Microsoft OS string descriptor.
+const MS_OS_STRING: u8 = 0xee;
+/// Requests recorded after this many are dropped.
+pub const MAX_REQUESTS: usize = 32;
+
+/// GET_DESCRIPTOR request of the host.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct DescriptorRequest {
+    pub descriptor_type: u8,
+    pub index: u8,
+    /// wLength, the number of bytes the host reads.
+    pub length: u16,
+}
+
+/// Descriptor requests since the last bus reset.
+#[derive(Copy, Clone)]
+pub struct Trace {
+    requests: [DescriptorRequest; MAX_REQUESTS],
+    len: usize,
+    /// True after SET_CONFIGURATION.
+    configured: bool,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace {
+            requests: [DescriptorRequest {
+                descriptor_type: 0,
+                index: 0,
+                length: 0,
+            }; MAX_REQUESTS],
+            len: 0,
+            configured: false,
+        }
+    }
+
+    /// Start a new enumeration on a bus reset.
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.configured = false;
+    }
+
+    pub fn push(&mut self, descriptor_type: u8, index: u8, length: u16) {
+        if self.len < MAX_REQUESTS {
+            self.requests[self.len] = DescriptorRequest {
+                descriptor_type,
+                index,
+                length,
+            };
+            self.len += 1;
+        }
+    }
+
+    pub fn set_configured(&mut self) {
+        self.configured = true;
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.configured
+    }
+
+    pub fn as_slice(&self) -> &[DescriptorRequest] {
+        &self.requests[..self.len]
+    }
+}
+
+impl Default for Trace {
+    fn default() -> Trace {
+        Trace::new()
+    }
+}
+
+/// Guess the OS of the host from the descriptor requests of an enumeration.
+pub fn classify(requests: &[DescriptorRequest]) -> Option<HostOs> {
+    let is_string = |r: &&DescriptorRequest| r.descriptor_type == DESCRIPTOR_STRING;
+    let strings = || requests.iter().filter(is_string);
+    if strings().any(|r| r.index == MS_OS_STRING)
+        || requests
+            .iter()
+            .any(|r| r.descriptor_type == DESCRIPTOR_CONFIGURATION && r.length == 255)
+    {
+        return Some(HostOs::Windows);
+    }
+    if strings().any(|r| r.length == 2) {
+        return Some(HostOs::Mac);
+    }
+    if strings().count() > 0 && strings().all(|r| r.length == 255) {
+        return Some(HostOs::Linux);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: u8 = 0x01;
+
+    /// Trace of the requests `(descriptor type, index, length)`.
+    fn trace(requests: &[(u8, u8, u16)]) -> Trace {
+        let mut trace = Trace::new();
+        for (t, i, l) in requests.iter() {
+            trace.push(*t, *i, *l);
+        }
+        trace.set_configured();
+        trace
+    }
+
+    const CONFIG: u8 = DESCRIPTOR_CONFIGURATION;
+    const STRING: u8 = DESCRIPTOR_STRING;
+
+    #[test]
+    fn test_classify_linux() {
+        let t = trace(&[
+            (DEVICE, 0, 64),
+            (DEVICE, 0, 18),
+            (CONFIG, 0, 9),
+            (CONFIG, 0, 91),
+            (STRING, 0, 255),
+            (STRING, 2, 255),
+            (STRING, 1, 255),
+            (STRING, 3, 255),
+        ]);
+        assert_eq!(classify(t.as_slice()), Some(HostOs::Linux));
+    }
+
+    #[test]
+    fn test_classify_windows() {
+        let t = trace(&[
+            (DEVICE, 0, 64),
+            (DEVICE, 0, 18),
+            (CONFIG, 0, 255),
+            (STRING, 0xee, 18),
+            (STRING, 0, 255),
+            (STRING, 2, 255),
+        ]);
+        assert_eq!(classify(t.as_slice()), Some(HostOs::Windows));
+        // The OS string descriptor is only read on the first enumeration.
+        let t = trace(&[
+            (DEVICE, 0, 64),
+            (DEVICE, 0, 18),
+            (CONFIG, 0, 9),
+            (CONFIG, 0, 255),
+            (STRING, 3, 255),
+            (STRING, 0, 255),
+            (STRING, 2, 255),
+        ]);
+        assert_eq!(classify(t.as_slice()), Some(HostOs::Windows));
+    }
+
+    #[test]
+    fn test_classify_mac() {
+        let t = trace(&[
+            (DEVICE, 0, 8),
+            (DEVICE, 0, 18),
+            (CONFIG, 0, 9),
+            (CONFIG, 0, 91),
+            (STRING, 0, 2),
+            (STRING, 0, 4),
+            (STRING, 2, 2),
+            (STRING, 2, 26),
+            (STRING, 1, 2),
+            (STRING, 1, 10),
+            (STRING, 3, 2),
+            (STRING, 3, 34),
+        ]);
+        assert_eq!(classify(t.as_slice()), Some(HostOs::Mac));
+    }
+
+    #[test]
+    fn test_classify_unknown() {
+        // No strings are read yet.
+        let t = trace(&[(DEVICE, 0, 64), (DEVICE, 0, 18), (CONFIG, 0, 9)]);
+        assert_eq!(classify(t.as_slice()), None);
+        let t = trace(&[(DEVICE, 0, 18), (CONFIG, 0, 91), (STRING, 2, 64)]);
+        assert_eq!(classify(t.as_slice()), None);
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut t = Trace::new();
+        for i in 0..MAX_REQUESTS + 3 {
+            t.push(STRING, i as u8, 255);
+        }
+        assert_eq!(t.as_slice().len(), MAX_REQUESTS);
+        assert_eq!(t.as_slice()[1].index, 1);
+        assert!(!t.is_configured());
+        t.set_configured();
+        t.clear();
+        assert!(t.as_slice().is_empty());
+        assert!(!t.is_configured());
+    }
+}
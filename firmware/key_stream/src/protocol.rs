@@ -0,0 +1,714 @@
+//! Request/response protocol of the raw HID configuration interface.
+//!
+//! Every packet is `PACKET_SIZE` bytes.
+//! Request: `[request id, arguments...]`.
+//! Response: `[request id, status, payload...]`.
+//! Multi-byte values are little endian.
+//! Keys and combos are those of the active keymap profile, which is the setting
+//! `settings::PROFILE`.
+
+use crate::chord::Chord;
+use crate::keymap::{Command, Pos, N_CHORDS, N_COMBOS, N_KEYS, N_LAYERS, N_PROFILES, STRINGS_SIZE};
+use crate::shell::{self, Output};
+use crate::{cnt_to_ms, settings, KeyStream, StorageRequest};
+
+pub const PACKET_SIZE: usize = 32;
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Bytes of `Keymap::strings` in a request or a response.
+pub const STRINGS_CHUNK: usize = 28;
+/// Bytes of a line of the shell in a request.
+pub const SHELL_LINE_SIZE: usize = 30;
+/// Bytes of the output of the shell in a response.
+pub const SHELL_CHUNK: usize = 29;
+
+const REQ_GET_INFO: u8 = 0x01;
+const REQ_GET_KEY: u8 = 0x02;
+const REQ_SET_KEY: u8 = 0x03;
+const REQ_GET_COMBO: u8 = 0x04;
+const REQ_SET_COMBO: u8 = 0x05;
+const REQ_GET_TIMING: u8 = 0x06;
+const REQ_SET_TIMING: u8 = 0x07;
+const REQ_SAVE_KEYMAP: u8 = 0x08;
+const REQ_RESET_KEYMAP: u8 = 0x09;
+const REQ_GET_SETTING: u8 = 0x0a;
+const REQ_SET_SETTING: u8 = 0x0b;
+const REQ_GET_STRINGS: u8 = 0x0c;
+const REQ_SET_STRINGS: u8 = 0x0d;
+const REQ_GET_CHORD: u8 = 0x0e;
+const REQ_SET_CHORD: u8 = 0x0f;
+const REQ_SHELL: u8 = 0x10;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Request {
+    GetInfo,
+    GetKey {
+        layer: u8,
+        index: u8,
+    },
+    SetKey {
+        layer: u8,
+        index: u8,
+        command: Command,
+    },
+    GetCombo {
+        slot: u8,
+    },
+    SetCombo {
+        slot: u8,
+        k1: Pos,
+        k2: Pos,
+        command: Command,
+    },
+    GetTiming,
+    /// Durations in milliseconds.
+    SetTiming {
+        combo_threshold: u16,
+        combo_separation: u16,
+    },
+    /// Write the current keymap to flash.
+    SaveKeymap,
+    /// Revert to the compiled-in keymap and erase the stored one.
+    ResetKeymap,
+    /// See `settings` for keys.
+    GetSetting {
+        key: u8,
+    },
+    SetSetting {
+        key: u8,
+        value: u16,
+    },
+    /// `STRINGS_CHUNK` bytes of `Keymap::strings` from the offset, less at the end.
+    GetStrings {
+        offset: u16,
+    },
+    /// Write `len` bytes of `data` to `Keymap::strings` at the offset.
+    SetStrings {
+        offset: u16,
+        len: u8,
+        data: [u8; STRINGS_CHUNK],
+    },
+    GetChord {
+        slot: u8,
+    },
+    SetChord {
+        slot: u8,
+        chord: Chord,
+    },
+    /// Run the first `len` bytes of `line` by the `shell`, and respond with `[n, output...]`,
+    /// the first n bytes of its output, at most `SHELL_CHUNK`.  `len` 0 responds with the next
+    /// bytes of the output, and n is 0 at its end.
+    Shell {
+        len: u8,
+        line: [u8; SHELL_LINE_SIZE],
+    },
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Error {
+    UnknownRequest = 1,
+    OutOfRange = 2,
+    InvalidCommand = 3,
+}
+
+impl Request {
+    pub fn parse(packet: &[u8; PACKET_SIZE]) -> Result<Request, Error> {
+        let req = match packet[0] {
+            REQ_GET_INFO => Request::GetInfo,
+            REQ_GET_KEY => Request::GetKey {
+                layer: packet[1],
+                index: packet[2],
+            },
+            REQ_SET_KEY => Request::SetKey {
+                layer: packet[1],
+                index: packet[2],
+                command: Command::decode(&packet[3..]).ok_or(Error::InvalidCommand)?,
+            },
+            REQ_GET_COMBO => Request::GetCombo { slot: packet[1] },
+            REQ_SET_COMBO => Request::SetCombo {
+                slot: packet[1],
+                k1: packet[2],
+                k2: packet[3],
+                command: Command::decode(&packet[4..]).ok_or(Error::InvalidCommand)?,
+            },
+            REQ_GET_TIMING => Request::GetTiming,
+            REQ_SET_TIMING => Request::SetTiming {
+                combo_threshold: read_u16(&packet[1..]),
+                combo_separation: read_u16(&packet[3..]),
+            },
+            REQ_SAVE_KEYMAP => Request::SaveKeymap,
+            REQ_RESET_KEYMAP => Request::ResetKeymap,
+            REQ_GET_SETTING => Request::GetSetting { key: packet[1] },
+            REQ_SET_SETTING => Request::SetSetting {
+                key: packet[1],
+                value: read_u16(&packet[2..]),
+            },
+            REQ_GET_STRINGS => Request::GetStrings {
+                offset: read_u16(&packet[1..]),
+            },
+            REQ_SET_STRINGS => {
+                let mut data = [0u8; STRINGS_CHUNK];
+                data.copy_from_slice(&packet[4..]);
+                Request::SetStrings {
+                    offset: read_u16(&packet[1..]),
+                    len: packet[3],
+                    data,
+                }
+            }
+            REQ_GET_CHORD => Request::GetChord { slot: packet[1] },
+            REQ_SET_CHORD => Request::SetChord {
+                slot: packet[1],
+                chord: Chord::decode(&packet[2..]).ok_or(Error::InvalidCommand)?,
+            },
+            REQ_SHELL => {
+                let mut line = [0u8; SHELL_LINE_SIZE];
+                line.copy_from_slice(&packet[2..]);
+                Request::Shell {
+                    len: packet[1],
+                    line,
+                }
+            }
+            _ => return Err(Error::UnknownRequest),
+        };
+        Ok(req)
+    }
+
+    /// Build request packet.  Used by host tools.
+    pub fn encode(&self) -> [u8; PACKET_SIZE] {
+        let mut p = [0u8; PACKET_SIZE];
+        match *self {
+            Request::GetInfo => p[0] = REQ_GET_INFO,
+            Request::GetKey { layer, index } => {
+                p[..3].copy_from_slice(&[REQ_GET_KEY, layer, index]);
+            }
+            Request::SetKey {
+                layer,
+                index,
+                command,
+            } => {
+                p[..3].copy_from_slice(&[REQ_SET_KEY, layer, index]);
+                p[3..3 + Command::ENCODED_SIZE].copy_from_slice(&command.encode());
+            }
+            Request::GetCombo { slot } => p[..2].copy_from_slice(&[REQ_GET_COMBO, slot]),
+            Request::SetCombo {
+                slot,
+                k1,
+                k2,
+                command,
+            } => {
+                p[..4].copy_from_slice(&[REQ_SET_COMBO, slot, k1, k2]);
+                p[4..4 + Command::ENCODED_SIZE].copy_from_slice(&command.encode());
+            }
+            Request::GetTiming => p[0] = REQ_GET_TIMING,
+            Request::SetTiming {
+                combo_threshold,
+                combo_separation,
+            } => {
+                p[0] = REQ_SET_TIMING;
+                write_u16(&mut p[1..], combo_threshold);
+                write_u16(&mut p[3..], combo_separation);
+            }
+            Request::SaveKeymap => p[0] = REQ_SAVE_KEYMAP,
+            Request::ResetKeymap => p[0] = REQ_RESET_KEYMAP,
+            Request::GetSetting { key } => p[..2].copy_from_slice(&[REQ_GET_SETTING, key]),
+            Request::SetSetting { key, value } => {
+                p[..2].copy_from_slice(&[REQ_SET_SETTING, key]);
+                write_u16(&mut p[2..], value);
+            }
+            Request::GetStrings { offset } => {
+                p[0] = REQ_GET_STRINGS;
+                write_u16(&mut p[1..], offset);
+            }
+            Request::SetStrings { offset, len, data } => {
+                p[0] = REQ_SET_STRINGS;
+                write_u16(&mut p[1..], offset);
+                p[3] = len;
+                p[4..].copy_from_slice(&data);
+            }
+            Request::GetChord { slot } => p[..2].copy_from_slice(&[REQ_GET_CHORD, slot]),
+            Request::SetChord { slot, chord } => {
+                p[..2].copy_from_slice(&[REQ_SET_CHORD, slot]);
+                p[2..2 + Chord::ENCODED_SIZE].copy_from_slice(&chord.encode());
+            }
+            Request::Shell { len, line } => {
+                p[..2].copy_from_slice(&[REQ_SHELL, len]);
+                p[2..].copy_from_slice(&line);
+            }
+        }
+        p
+    }
+}
+
+/// Response payload builder.
+struct Response {
+    packet: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    fn new(id: u8) -> Response {
+        let mut packet = [0u8; PACKET_SIZE];
+        packet[0] = id;
+        Response { packet, len: 2 }
+    }
+
+    fn error(id: u8, e: Error) -> [u8; PACKET_SIZE] {
+        let mut r = Response::new(id);
+        r.packet[1] = e as u8;
+        r.packet
+    }
+
+    fn push(&mut self, data: &[u8]) -> &mut Response {
+        self.packet[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+        self
+    }
+}
+
+impl KeyStream {
+    /// Process one request packet of the raw HID interface and return the response packet.
+    pub fn handle_request(&mut self, packet: &[u8; PACKET_SIZE]) -> [u8; PACKET_SIZE] {
+        let id = packet[0];
+        let req = match Request::parse(packet) {
+            Ok(req) => req,
+            Err(e) => return Response::error(id, e),
+        };
+        let mut r = Response::new(id);
+        match req {
+            Request::GetInfo => {
+                r.push(&[
+                    PROTOCOL_VERSION,
+                    version_part(env!("CARGO_PKG_VERSION_MAJOR")),
+                    version_part(env!("CARGO_PKG_VERSION_MINOR")),
+                    version_part(env!("CARGO_PKG_VERSION_PATCH")),
+                    N_LAYERS as u8,
+                    N_KEYS as u8,
+                    N_COMBOS as u8,
+                    self.keymap_loaded as u8,
+                ]);
+                r.push(&(STRINGS_SIZE as u16).to_le_bytes());
+                r.push(&[N_CHORDS as u8, N_PROFILES as u8]);
+            }
+            Request::GetKey { layer, index } => match self.key_entry(layer, index) {
+                Some(c) => {
+                    r.push(&c.encode());
+                }
+                None => return Response::error(id, Error::OutOfRange),
+            },
+            Request::SetKey {
+                layer,
+                index,
+                command,
+            } => match self.key_entry(layer, index) {
+                Some(c) => *c = command,
+                None => return Response::error(id, Error::OutOfRange),
+            },
+            Request::GetCombo { slot } => match self.keymap.combos.get(slot as usize) {
+                Some((k1, k2, c)) => {
+                    r.push(&[*k1, *k2]).push(&c.encode());
+                }
+                None => return Response::error(id, Error::OutOfRange),
+            },
+            Request::SetCombo {
+                slot,
+                k1,
+                k2,
+                command,
+            } => match self.keymap.combos.get_mut(slot as usize) {
+                Some(combo) => *combo = (k1, k2, command),
+                None => return Response::error(id, Error::OutOfRange),
+            },
+            Request::GetTiming => {
+                let mut buf = [0u8; 4];
+                write_u16(&mut buf[0..], cnt_to_ms(self.timing.combo_threshold));
+                write_u16(&mut buf[2..], cnt_to_ms(self.timing.combo_separation));
+                r.push(&buf);
+            }
+            Request::SetTiming {
+                combo_threshold,
+                combo_separation,
+            } => {
+                let timing = self.timing;
+                if !(self.set_setting(settings::COMBO_THRESHOLD, combo_threshold)
+                    && self.set_setting(settings::COMBO_SEPARATION, combo_separation))
+                {
+                    self.timing = timing;
+                    return Response::error(id, Error::OutOfRange);
+                }
+            }
+            Request::SaveKeymap => {
+                self.storage_request = Some(StorageRequest::Save);
+            }
+            Request::ResetKeymap => {
+                self.reset_keymap();
+                self.storage_request = Some(StorageRequest::Erase);
+            }
+            Request::GetSetting { key } => match self.setting(key) {
+                Some(v) => {
+                    r.push(&v.to_le_bytes());
+                }
+                None => return Response::error(id, Error::OutOfRange),
+            },
+            Request::SetSetting { key, value } => {
+                if !self.set_setting(key, value) {
+                    return Response::error(id, Error::OutOfRange);
+                }
+            }
+            Request::GetStrings { offset } => match self.keymap.strings.get(offset as usize..) {
+                Some(rest) => {
+                    r.push(&rest[..rest.len().min(STRINGS_CHUNK)]);
+                }
+                None => return Response::error(id, Error::OutOfRange),
+            },
+            Request::SetStrings { offset, len, data } => {
+                let start = offset as usize;
+                let len = len as usize;
+                if len > STRINGS_CHUNK || start + len > STRINGS_SIZE {
+                    return Response::error(id, Error::OutOfRange);
+                }
+                self.keymap.strings[start..start + len].copy_from_slice(&data[..len]);
+            }
+            Request::GetChord { slot } => match self.keymap.chords.get(slot as usize) {
+                Some(c) => {
+                    r.push(&c.encode());
+                }
+                None => return Response::error(id, Error::OutOfRange),
+            },
+            Request::SetChord { slot, chord } => match self.keymap.chords.get_mut(slot as usize) {
+                Some(c) => *c = chord,
+                None => return Response::error(id, Error::OutOfRange),
+            },
+            Request::Shell { len, line } => {
+                let line = match line.get(..len as usize).map(core::str::from_utf8) {
+                    Some(Ok(line)) => line,
+                    Some(Err(_)) => return Response::error(id, Error::InvalidCommand),
+                    None => return Response::error(id, Error::OutOfRange),
+                };
+                if len > 0 {
+                    let mut out = Output::new();
+                    shell::run(self, line, &mut out);
+                    self.shell_output = out;
+                }
+                let output = self.shell_output.as_slice();
+                let n = output.len().min(SHELL_CHUNK);
+                r.push(&[n as u8]).push(&output[..n]);
+                self.shell_output.consume(n);
+            }
+        }
+        r.packet
+    }
+
+    fn key_entry(&mut self, layer: u8, index: u8) -> Option<&mut Command> {
+        self.keymap
+            .layers
+            .get_mut(layer as usize)?
+            .get_mut(index as usize)
+    }
+}
+
+fn version_part(s: &str) -> u8 {
+    s.parse().unwrap_or(0)
+}
+
+pub fn read_u16(b: &[u8]) -> u16 {
+    b[0] as u16 | (b[1] as u16) << 8
+}
+
+pub fn write_u16(b: &mut [u8], v: u16) {
+    b[0] = v as u8;
+    b[1] = (v >> 8) as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid_keycodes as KC;
+    use crate::keymap::{ModifierKey, Modifiers};
+    use crate::ms_to_cnt;
+
+    #[test]
+    fn test_request_round_trip() {
+        let reqs = [
+            Request::GetInfo,
+            Request::GetKey {
+                layer: 2,
+                index: 47,
+            },
+            Request::SetKey {
+                layer: 1,
+                index: 3,
+                command: Command::ModifiedKey {
+                    mk: Modifiers::new(&[ModifierKey::SHIFT1, ModifierKey::UI1]),
+                    kc: KC::KBD_9,
+                },
+            },
+            Request::SetKey {
+                layer: 0,
+                index: 5,
+                command: Command::Char {
+                    mk: Modifiers::new(&[ModifierKey::RUI, ModifierKey::HYPER]),
+                    c: b'{',
+                },
+            },
+            Request::GetCombo { slot: 4 },
+            Request::SetCombo {
+                slot: 15,
+                k1: 0x22,
+                k2: 0xa3,
+                command: Command::KeyPress { kc: KC::KBD_ENTER },
+            },
+            Request::GetTiming,
+            Request::SetTiming {
+                combo_threshold: 180,
+                combo_separation: 20,
+            },
+            Request::SaveKeymap,
+            Request::ResetKeymap,
+            Request::GetSetting { key: 3 },
+            Request::SetSetting { key: 1, value: 300 },
+            Request::GetStrings { offset: 0x1c0 },
+            Request::SetStrings {
+                offset: 28,
+                len: 3,
+                data: [7; STRINGS_CHUNK],
+            },
+            Request::GetChord { slot: 191 },
+            Request::SetChord {
+                slot: 3,
+                chord: Chord::new(&[0x24, 0xa2, 0x45], b"xtu").unwrap(),
+            },
+            Request::Shell {
+                len: 5,
+                line: [b'x'; SHELL_LINE_SIZE],
+            },
+        ];
+        for req in reqs.iter() {
+            assert_eq!(Request::parse(&req.encode()), Ok(*req));
+        }
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let mut p = [0u8; PACKET_SIZE];
+        p[0] = 0xee;
+        assert_eq!(Request::parse(&p), Err(Error::UnknownRequest));
+        p[0] = REQ_SET_KEY;
+        p[3] = 0xee;
+        assert_eq!(Request::parse(&p), Err(Error::InvalidCommand));
+        let mut p = [0u8; PACKET_SIZE];
+        p[..4].copy_from_slice(&[REQ_SET_CHORD, 0, 0x24, 0x24]);
+        p[5] = b'a';
+        assert_eq!(Request::parse(&p), Err(Error::InvalidCommand));
+    }
+
+    #[test]
+    fn test_handle_chords() {
+        let mut stream = KeyStream::new();
+        let chord = Chord::new(&[0x24, 0xa2], b"ga").unwrap();
+        let set = Request::SetChord { slot: 7, chord };
+        assert_eq!(stream.handle_request(&set.encode())[1], 0);
+        assert_eq!(stream.keymap.chords[7], chord);
+        let res = stream.handle_request(&Request::GetChord { slot: 7 }.encode());
+        assert_eq!(Chord::decode(&res[2..]), Some(chord));
+        let res = stream.handle_request(
+            &Request::GetChord {
+                slot: N_CHORDS as u8,
+            }
+            .encode(),
+        );
+        assert_eq!(res[1], Error::OutOfRange as u8);
+        assert_eq!(
+            stream.handle_request(&Request::GetInfo.encode())[12],
+            N_CHORDS as u8
+        );
+        assert_eq!(
+            stream.handle_request(&Request::GetInfo.encode())[13],
+            N_PROFILES as u8
+        );
+    }
+
+    #[test]
+    fn test_handle_set_get_key() {
+        let mut stream = KeyStream::new();
+        let a = Command::KeyPress { kc: KC::KBD_A };
+        let set = Request::SetKey {
+            layer: 3,
+            index: 10,
+            command: a,
+        };
+        assert_eq!(stream.handle_request(&set.encode())[..2], [REQ_SET_KEY, 0]);
+        assert_eq!(stream.keymap.layers[3][10], a);
+
+        let res = stream.handle_request(
+            &Request::GetKey {
+                layer: 3,
+                index: 10,
+            }
+            .encode(),
+        );
+        assert_eq!(res[..6], [REQ_GET_KEY, 0, 1, KC::KBD_A.code(), 0, 0]);
+
+        let res = stream.handle_request(&Request::GetKey { layer: 4, index: 0 }.encode());
+        assert_eq!(res[..2], [REQ_GET_KEY, Error::OutOfRange as u8]);
+    }
+
+    #[test]
+    fn test_handle_combo_and_timing() {
+        let mut stream = KeyStream::new();
+        let esc = Command::KeyPress { kc: KC::KBD_ESCAPE };
+        let set = Request::SetCombo {
+            slot: 12,
+            k1: 0x11,
+            k2: 0x12,
+            command: esc,
+        };
+        stream.handle_request(&set.encode());
+        let res = stream.handle_request(&Request::GetCombo { slot: 12 }.encode());
+        assert_eq!(
+            res[..8],
+            [REQ_GET_COMBO, 0, 0x11, 0x12, 1, KC::KBD_ESCAPE.code(), 0, 0]
+        );
+        assert_eq!(crate::find_combo(&stream.keymap, 0x12, 0x11), Some(esc));
+
+        let set = Request::SetTiming {
+            combo_threshold: 100,
+            combo_separation: 0,
+        };
+        stream.handle_request(&set.encode());
+        assert_eq!(stream.timing.combo_threshold, ms_to_cnt(100));
+        let res = stream.handle_request(&Request::GetTiming.encode());
+        assert_eq!(read_u16(&res[2..]), cnt_to_ms(ms_to_cnt(100)));
+
+        // Neither is changed if one of them is out of range.
+        let set = Request::SetTiming {
+            combo_threshold: 200,
+            combo_separation: 60000,
+        };
+        assert_eq!(
+            stream.handle_request(&set.encode())[1],
+            Error::OutOfRange as u8
+        );
+        assert_eq!(stream.timing.combo_threshold, ms_to_cnt(100));
+        assert_eq!(stream.timing.combo_separation, 0);
+    }
+
+    #[test]
+    fn test_handle_storage_requests() {
+        let mut stream = KeyStream::new();
+        let image = {
+            let mut other = KeyStream::new();
+            other.keymap.layers[0][0] = Command::Nop;
+            other.keymap_image()
+        };
+        assert_eq!(stream.load_keymap(&image), Ok(()));
+        assert_eq!(stream.handle_request(&Request::GetInfo.encode())[9], 1);
+
+        stream.handle_request(&Request::SaveKeymap.encode());
+        assert_eq!(stream.take_storage_request(), Some(StorageRequest::Save));
+        assert_eq!(stream.take_storage_request(), None);
+
+        stream.handle_request(&Request::ResetKeymap.encode());
+        assert_eq!(stream.take_storage_request(), Some(StorageRequest::Erase));
+        assert_ne!(stream.keymap.layers[0][0], Command::Nop);
+        assert_eq!(stream.handle_request(&Request::GetInfo.encode())[9], 0);
+    }
+
+    #[test]
+    fn test_handle_strings() {
+        let mut stream = KeyStream::new();
+        let mut data = [0u8; STRINGS_CHUNK];
+        data[..4].copy_from_slice(b"hi\0!");
+        let set = Request::SetStrings {
+            offset: 0,
+            len: 3,
+            data,
+        };
+        assert_eq!(stream.handle_request(&set.encode())[1], 0);
+        assert_eq!(stream.keymap.string(0), b"hi");
+
+        let res = stream.handle_request(&Request::GetStrings { offset: 1 }.encode());
+        assert_eq!(res[..5], [REQ_GET_STRINGS, 0, b'i', 0, 0]);
+        let last = (STRINGS_SIZE - 1) as u16;
+        let set = Request::SetStrings {
+            offset: last,
+            len: 2,
+            data,
+        };
+        assert_eq!(
+            stream.handle_request(&set.encode())[1],
+            Error::OutOfRange as u8
+        );
+        let get = Request::GetStrings {
+            offset: STRINGS_SIZE as u16 + 1,
+        };
+        assert_eq!(
+            stream.handle_request(&get.encode())[1],
+            Error::OutOfRange as u8
+        );
+    }
+
+    #[test]
+    fn test_handle_settings() {
+        let mut stream = KeyStream::new();
+        let set = Request::SetSetting {
+            key: settings::DEFAULT_LAYER,
+            value: 2,
+        };
+        assert_eq!(stream.handle_request(&set.encode())[1], 0);
+        let get = Request::GetSetting {
+            key: settings::DEFAULT_LAYER,
+        };
+        assert_eq!(
+            stream.handle_request(&get.encode())[..4],
+            [REQ_GET_SETTING, 0, 2, 0]
+        );
+
+        let set = Request::SetSetting {
+            key: settings::DEFAULT_LAYER,
+            value: N_LAYERS as u16,
+        };
+        assert_eq!(
+            stream.handle_request(&set.encode())[1],
+            Error::OutOfRange as u8
+        );
+    }
+
+    #[test]
+    fn test_handle_shell() {
+        let mut stream = KeyStream::new();
+        let shell = |stream: &mut KeyStream, text: &[u8]| {
+            let mut line = [0u8; SHELL_LINE_SIZE];
+            line[..text.len()].copy_from_slice(text);
+            let req = Request::Shell {
+                len: text.len() as u8,
+                line,
+            };
+            stream.handle_request(&req.encode())
+        };
+        let res = shell(&mut stream, b"get");
+        assert_eq!(res[..3], [REQ_SHELL, 0, SHELL_CHUNK as u8]);
+        assert_eq!(&res[3..], b"combo_threshold = 299\ncombo_s");
+        // The rest of the output, then its end.
+        let mut output = std::vec::Vec::new();
+        loop {
+            let res = shell(&mut stream, b"");
+            let n = res[2] as usize;
+            if n == 0 {
+                break;
+            }
+            output.extend_from_slice(&res[3..3 + n]);
+        }
+        assert!(output.starts_with(b"eparation = "));
+        assert!(output.ends_with(b"linux_profile = 255\n"));
+
+        let res = shell(&mut stream, b"set chords 1");
+        assert_eq!(res[..3], [REQ_SHELL, 0, 0]);
+        assert_eq!(stream.setting(settings::CHORDS), Some(1));
+        let res = shell(&mut stream, &[0xff]);
+        assert_eq!(res[1], Error::InvalidCommand as u8);
+        let mut p = [0u8; PACKET_SIZE];
+        p[..2].copy_from_slice(&[REQ_SHELL, SHELL_LINE_SIZE as u8 + 1]);
+        assert_eq!(stream.handle_request(&p)[1], Error::OutOfRange as u8);
+    }
+}
@@ -0,0 +1,340 @@
+//! Diagrams of keymaps in the split layout of the keyboard, for cheat sheets.
+//!
+//! Keys are placed by the grid of `grid.rs`, the same order as `pos_to_map_index`.
+//! Modified keys are shown by their macro names when the keymap file has them.
+//! Layouts for keyboard-layout-editor.com carry the legends of all layers on each key.
+
+use crate::grid::{grid_index, index_grid, pos_grid, GRID_COLS, GRID_ROWS};
+use crate::keymap_format::{format_key, Action, KeymapFile};
+
+const HALF_COLS: usize = GRID_COLS / 2;
+
+/// Plain text tables of the layers, followed by the list of combos.
+pub fn ascii(file: &KeymapFile) -> String {
+    let mut out = String::new();
+    for layer in file.layers.iter() {
+        let cells: Vec<String> = layer.keys.iter().map(|a| legend(file, a)).collect();
+        let widths: Vec<usize> = (0..GRID_COLS)
+            .map(|col| {
+                (0..GRID_ROWS)
+                    .map(|row| cells[grid_index(row, col)].chars().count())
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+        let border = row_line(&widths, |_, w| "-".repeat(w + 2), '+');
+        out += &format!("Layer {}\n", layer.number);
+        out += &border;
+        for row in 0..GRID_ROWS {
+            out += &row_line(
+                &widths,
+                |col, w| format!(" {:<w$} ", cells[grid_index(row, col)], w = w),
+                '|',
+            );
+            out += &border;
+        }
+        out.push('\n');
+    }
+    if !file.combos.is_empty() {
+        out += "Combos\n";
+        let base = file.layers.iter().find(|l| l.number == 0);
+        for c in file.combos.iter() {
+            let mut line = format!("  {} {}", format_key(c.k1), format_key(c.k2));
+            if let Some(base) = base {
+                line += &format!(
+                    " ({} {})",
+                    key_legend(file, base.keys.as_ref(), c.k1),
+                    key_legend(file, base.keys.as_ref(), c.k2)
+                );
+            }
+            out += &format!("{:<32} {}\n", line, legend(file, &c.action));
+        }
+    }
+    out
+}
+
+/// One line of a table, with a gap between the halves.
+fn row_line(widths: &[usize], cell: impl Fn(usize, usize) -> String, sep: char) -> String {
+    let mut line = String::new();
+    for (col, w) in widths.iter().enumerate() {
+        if col == HALF_COLS {
+            line += "   ";
+        }
+        if col % HALF_COLS == 0 {
+            line.push(sep);
+        }
+        line += &cell(col, *w);
+        line.push(sep);
+    }
+    line + "\n"
+}
+
+const KEY: f32 = 64.0;
+const MARGIN: f32 = 16.0;
+const TITLE: f32 = 24.0;
+const FONT: f32 = 12.0;
+
+/// SVG image of the layers, one below another, and the combos drawn on the base layer.
+pub fn svg(file: &KeymapFile) -> String {
+    let width = MARGIN * 2.0 + KEY * (GRID_COLS + 1) as f32;
+    let block = TITLE + KEY * GRID_ROWS as f32 + MARGIN;
+    let n_blocks = file.layers.len() + if file.combos.is_empty() { 0 } else { 1 };
+    let height = MARGIN + block * n_blocks as f32;
+    let mut out = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" \
+         font-family=\"sans-serif\" font-size=\"{}\">\n",
+        width, height, FONT
+    );
+    out += &format!(
+        "<rect width=\"{}\" height=\"{}\" fill=\"white\"/>\n",
+        width, height
+    );
+    let mut top = MARGIN;
+    for layer in file.layers.iter() {
+        out += &title(top, &format!("Layer {}", layer.number));
+        for (i, a) in layer.keys.iter().enumerate() {
+            out += &key_svg(top, i, &legend(file, a));
+        }
+        top += block;
+    }
+    if !file.combos.is_empty() {
+        out += &title(top, "Combos");
+        let base = file.layers.iter().find(|l| l.number == 0);
+        for i in 0..GRID_ROWS * GRID_COLS {
+            let text = match base {
+                Some(base) => legend(file, &base.keys[i]),
+                None => String::new(),
+            };
+            out += &key_svg(top, i, &text);
+        }
+        for c in file.combos.iter() {
+            let (p1, p2) = match (pos_grid(c.k1), pos_grid(c.k2)) {
+                (Some(p1), Some(p2)) => (p1, p2),
+                _ => continue,
+            };
+            let (x1, y1) = key_center(top, p1);
+            let (x2, y2) = key_center(top, p2);
+            let (x, y) = ((x1 + x2) / 2.0, (y1 + y2) / 2.0);
+            let text = legend(file, &c.action);
+            out += &format!(
+                "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"#c03030\" stroke-width=\"2\"/>\n",
+                x1, y1, x2, y2
+            );
+            out += &format!(
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"4\" fill=\"#c03030\"/>\n",
+                x - text_width(&text) / 2.0 - 3.0,
+                y - FONT / 2.0 - 2.0,
+                text_width(&text) + 6.0,
+                FONT + 4.0
+            );
+            out += &text_svg(x, y, FONT, "white", &text);
+        }
+    }
+    out += "</svg>\n";
+    out
+}
+
+fn title(top: f32, text: &str) -> String {
+    format!(
+        "<text x=\"{}\" y=\"{}\" font-size=\"{}\" font-weight=\"bold\">{}</text>\n",
+        MARGIN,
+        top + TITLE * 0.7,
+        FONT * 1.5,
+        escape(text)
+    )
+}
+
+/// Center of the key at (row, col) of the grid, with one key of gap between the halves.
+fn key_center(top: f32, (row, col): (usize, usize)) -> (f32, f32) {
+    let x = if col < HALF_COLS { col } else { col + 1 };
+    (
+        MARGIN + KEY * (x as f32 + 0.5),
+        top + TITLE + KEY * (row as f32 + 0.5),
+    )
+}
+
+fn key_svg(top: f32, index: usize, text: &str) -> String {
+    let (row, col) = index_grid(index);
+    let (x, y) = key_center(top, (row, col));
+    let size = KEY - 4.0;
+    let mut out = format!(
+        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" rx=\"6\" fill=\"#f4f4f4\" stroke=\"#404040\"/>\n",
+        x - size / 2.0,
+        y - size / 2.0,
+        size,
+        size
+    );
+    if !text.is_empty() {
+        // Shrink long legends to fit the key.
+        let font = FONT.min(FONT * (size - 6.0) / text_width(text));
+        out += &text_svg(x, y, font, "black", text);
+    }
+    out
+}
+
+fn text_svg(x: f32, y: f32, font: f32, fill: &str, text: &str) -> String {
+    format!(
+        "<text x=\"{}\" y=\"{}\" font-size=\"{:.1}\" fill=\"{}\" text-anchor=\"middle\" \
+         dominant-baseline=\"central\">{}</text>\n",
+        x,
+        y,
+        font,
+        fill,
+        escape(text)
+    )
+}
+
+/// Rough width of the text in the default font size.
+fn text_width(text: &str) -> f32 {
+    text.len() as f32 * FONT * 0.6
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Legend slots of keyboard-layout-editor for layers 0 to 3: top left, top right, bottom left
+/// and bottom right.
+const KLE_SLOTS: [usize; 4] = [0, 2, 1, 3];
+
+/// Layout for keyboard-layout-editor.com in its raw data format, with the legends of all
+/// layers on each key and the combos in the notes.
+pub fn kle(file: &KeymapFile) -> String {
+    let mut rows = Vec::new();
+    let mut notes = Vec::new();
+    for c in file.combos.iter() {
+        notes.push(format!(
+            "{} {}: {}",
+            format_key(c.k1),
+            format_key(c.k2),
+            legend(file, &c.action)
+        ));
+    }
+    if !notes.is_empty() {
+        rows.push(format!("{{\"notes\": {}}}", quote(&notes.join("\n"))));
+    }
+    for row in 0..GRID_ROWS {
+        let mut keys = Vec::new();
+        for col in 0..GRID_COLS {
+            if col == HALF_COLS {
+                // One key of gap between the halves.
+                keys.push("{\"x\": 1}".to_string());
+            }
+            let mut slots = vec![String::new(); 4];
+            for layer in file.layers.iter().filter(|l| l.number < KLE_SLOTS.len()) {
+                slots[KLE_SLOTS[layer.number]] = legend(file, &layer.keys[grid_index(row, col)]);
+            }
+            while slots.last().map(String::as_str) == Some("") {
+                slots.pop();
+            }
+            keys.push(quote(&slots.join("\n")));
+        }
+        rows.push(format!("[{}]", keys.join(", ")));
+    }
+    format!("[\n{}\n]\n", rows.join(",\n"))
+}
+
+fn quote(text: &str) -> String {
+    format!(
+        "\"{}\"",
+        text.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+    )
+}
+
+/// Legend of the key, blank for nothing.
+fn legend(file: &KeymapFile, a: &Action) -> String {
+    match a {
+        Action::Nop => String::new(),
+        // The character itself, unless the keymap file names it.
+        Action::Char(0, c) if file.macros.iter().all(|m| m.action != *a) => {
+            (*c as char).to_string()
+        }
+        Action::Unicode(c) if file.macros.iter().all(|m| m.action != *a) => c.to_string(),
+        _ => file.action_name(a),
+    }
+}
+
+fn key_legend(file: &KeymapFile, keys: &[Action], pos: u8) -> String {
+    match pos_grid(pos) {
+        Some((row, col)) => file.action_name(&keys[grid_index(row, col)]),
+        None => "?".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap_format::parse;
+
+    const TEXT: &str = "\
+[macros]
+BANG = SHIFT1+1
+
+[layer 0]
+TAB  Q W E R T  Y U I O P BANG
+_    A S D F G  H J K L U+2192 _
+_    Z X C V B  N M _ _ _ _
+CTRL1 _ _ _ _ _  _ _ _ _ _ RESET
+
+[combos]
+R2C8 R2C9 ENTER
+";
+
+    #[test]
+    fn test_ascii() {
+        let text = ascii(&parse(TEXT).unwrap());
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "Layer 0");
+        assert_eq!(
+            lines[1],
+            "+-------+---+---+---+---+---+   +---+---+---+---+---+-------+"
+        );
+        assert_eq!(
+            lines[2],
+            "| TAB   | Q | W | E | R | T |   | Y | U | I | O | P | BANG  |"
+        );
+        assert_eq!(
+            lines[8],
+            "| CTRL1 |   |   |   |   |   |   |   |   |   |   |   | RESET |"
+        );
+        assert_eq!(
+            lines[4],
+            "|       | A | S | D | F | G |   | H | J | K | L | → |       |"
+        );
+        assert_eq!(lines[9], lines[1]);
+        assert_eq!(lines[11], "Combos");
+        assert_eq!(lines[12], "  R2C8 R2C9 (J K)                ENTER");
+    }
+
+    #[test]
+    fn test_svg() {
+        let text = svg(&parse(TEXT).unwrap());
+        assert!(text.starts_with("<svg "));
+        assert!(text.ends_with("</svg>\n"));
+        // Background, two blocks of keys and the label of the combo.
+        assert_eq!(text.matches("<rect ").count(), 1 + 2 * 48 + 1);
+        assert_eq!(text.matches("<line ").count(), 1);
+        assert!(text.contains(">BANG</text>"));
+        assert!(text.contains(">ENTER</text>"));
+        assert_eq!(escape("A<&>"), "A&lt;&amp;&gt;");
+    }
+
+    #[test]
+    fn test_kle() {
+        let mut file = parse(TEXT).unwrap();
+        let mut layer_1 = parse(&TEXT.replace("[layer 0]", "[layer 1]")).unwrap();
+        file.layers.append(&mut layer_1.layers);
+        let text = kle(&file);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], r#"{"notes": "R2C8 R2C9: ENTER"},"#);
+        assert!(lines[2].starts_with(r#"["TAB\n\nTAB", "Q\n\nQ", "#));
+        assert!(lines[2].contains(r#""T\n\nT", {"x": 1}, "Y\n\nY""#));
+        assert!(lines[5].ends_with(r#""RESET\n\nRESET"]"#));
+        assert_eq!(lines.len(), 7);
+        assert_eq!(quote("a\"b\\"), r#""a\"b\\""#);
+    }
+}
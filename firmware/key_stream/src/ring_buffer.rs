@@ -39,7 +39,7 @@ impl<T: Copy> RingBuffer<T> {
         if read_pos >= write_pos {
             None
         } else {
-            Some(self.buf[(read_pos % BUFFER_SIZE)])
+            Some(self.buf[read_pos % BUFFER_SIZE])
         }
     }
 
@@ -0,0 +1,585 @@
+//! Key/value settings store on two flash pages.
+//!
+//! Records are appended to the active page, so a setting can be changed many times before
+//! the page is erased.  When the active page is full, the latest record of each key is
+//! copied to the other page, which becomes the active page.
+//!
+//! Page: `[magic "SETS", sequence: u32, records...]`.  The page with the larger sequence
+//! is active.  The header is written last on compaction, so an interrupted compaction
+//! leaves the old page active.
+//! Record: `[key, value length, 0xff, 0xff, CRC-32 of key, length and value: u32, value]`,
+//! padded to 8 bytes.  Key 0xff marks the end of records.  Empty value removes the key.
+
+use crate::crc::crc32;
+
+/// Write unit.  Offsets and lengths of `FlashPage::write` are multiples of this.
+pub const ALIGN: usize = 8;
+pub const MAX_VALUE_SIZE: usize = 32;
+const MAGIC: [u8; 4] = *b"SETS";
+const HEADER_SIZE: usize = 8;
+const RECORD_HEADER_SIZE: usize = 8;
+const ERASED: u8 = 0xff;
+
+// Keys of settings stored by the firmware.  Values are u16.
+/// Combo threshold in milliseconds.
+pub const COMBO_THRESHOLD: u8 = 0x01;
+/// Combo separation in milliseconds.
+pub const COMBO_SEPARATION: u8 = 0x02;
+/// Layer used while no layer modifier is pressed.
+pub const DEFAULT_LAYER: u8 = 0x03;
+/// Keyboard layout of the host, index of `HostLayout::ALL`.
+pub const HOST_LAYOUT: u8 = 0x04;
+/// Input method of Unicode characters, index of `UnicodeMode::ALL`.
+pub const UNICODE_MODE: u8 = 0x05;
+/// NICOLA thumb-shift input, 1 if on.
+pub const NICOLA: u8 = 0x06;
+/// Duration in milliseconds within which a thumb key shifts the character key before it.
+pub const NICOLA_THRESHOLD: u8 = 0x07;
+/// Chord input, 1 if on.
+pub const CHORDS: u8 = 0x08;
+/// Duration in milliseconds within which keys are pressed together as a chord.
+pub const CHORD_THRESHOLD: u8 = 0x09;
+/// Tap LANG2 (英数) when MOD2 or MOD3 is pressed, 1 if on.
+pub const AUTO_EISU: u8 = 0x0a;
+/// Steno mode, 1 if on.
+pub const STENO: u8 = 0x0b;
+/// Protocol of steno strokes, index of `steno::Protocol::ALL`.
+pub const STENO_PROTOCOL: u8 = 0x0c;
+/// Active keymap profile, index of `Keymap::profile`.
+pub const PROFILE: u8 = 0x0d;
+/// OS of the host, which swaps modifiers, index of `HostOs::ALL`.
+pub const HOST_OS: u8 = 0x0e;
+/// Detect the OS of the host on the USB enumeration: 0 off, 1 to set `HOST_OS`, 2 to also switch
+/// `PROFILE` by `MAC_PROFILE`, `WINDOWS_PROFILE` and `LINUX_PROFILE`.
+pub const AUTO_HOST_OS: u8 = 0x0f;
+/// Duration in milliseconds within which a key of `Command::ModTap` taps its key when released.
+pub const TAPPING_TERM: u8 = 0x10;
+/// Profile switched to when `AUTO_HOST_OS` detects macOS, or `NO_PROFILE` to keep the profile.
+pub const MAC_PROFILE: u8 = 0x11;
+/// Profile switched to when `AUTO_HOST_OS` detects Windows, or `NO_PROFILE`.
+pub const WINDOWS_PROFILE: u8 = 0x12;
+/// Profile switched to when `AUTO_HOST_OS` detects Linux, or `NO_PROFILE`.
+pub const LINUX_PROFILE: u8 = 0x13;
+/// Value of `MAC_PROFILE`, `WINDOWS_PROFILE` and `LINUX_PROFILE` which keeps the profile.
+pub const NO_PROFILE: u16 = 0xff;
+
+/// Keys of all settings, to load and save them at once.
+pub const KEYS: [u8; 19] = [
+    COMBO_THRESHOLD,
+    COMBO_SEPARATION,
+    DEFAULT_LAYER,
+    HOST_LAYOUT,
+    UNICODE_MODE,
+    NICOLA,
+    NICOLA_THRESHOLD,
+    CHORDS,
+    CHORD_THRESHOLD,
+    AUTO_EISU,
+    STENO,
+    STENO_PROTOCOL,
+    PROFILE,
+    HOST_OS,
+    AUTO_HOST_OS,
+    TAPPING_TERM,
+    MAC_PROFILE,
+    WINDOWS_PROFILE,
+    LINUX_PROFILE,
+];
+
+/// Names of settings for tools and consoles.
+pub fn name(key: u8) -> Option<&'static str> {
+    match key {
+        COMBO_THRESHOLD => Some("combo_threshold"),
+        COMBO_SEPARATION => Some("combo_separation"),
+        DEFAULT_LAYER => Some("default_layer"),
+        HOST_LAYOUT => Some("host_layout"),
+        UNICODE_MODE => Some("unicode_mode"),
+        NICOLA => Some("nicola"),
+        NICOLA_THRESHOLD => Some("nicola_threshold"),
+        CHORDS => Some("chords"),
+        CHORD_THRESHOLD => Some("chord_threshold"),
+        AUTO_EISU => Some("auto_eisu"),
+        STENO => Some("steno"),
+        STENO_PROTOCOL => Some("steno_protocol"),
+        PROFILE => Some("profile"),
+        HOST_OS => Some("host_os"),
+        AUTO_HOST_OS => Some("auto_host_os"),
+        TAPPING_TERM => Some("tapping_term"),
+        MAC_PROFILE => Some("mac_profile"),
+        WINDOWS_PROFILE => Some("windows_profile"),
+        LINUX_PROFILE => Some("linux_profile"),
+        _ => None,
+    }
+}
+
+pub fn key_by_name(name: &str) -> Option<u8> {
+    KEYS.iter().copied().find(|k| self::name(*k) == Some(name))
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Error {
+    /// Flash driver failed.
+    Flash,
+    /// Value is longer than `MAX_VALUE_SIZE`.
+    TooLarge,
+    /// Latest records do not fit in a page.
+    Full,
+}
+
+/// One erasable page of NOR flash.
+/// Erased bytes read 0xff, and write can only clear bits.
+pub trait FlashPage {
+    fn size(&self) -> usize;
+    fn read(&self, offset: usize, buf: &mut [u8]);
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error>;
+    fn erase(&mut self) -> Result<(), Error>;
+}
+
+/// Flash page on RAM, to test the store and to simulate the device on the host.
+pub struct RamPage<const N: usize> {
+    pub data: [u8; N],
+    pub erase_count: usize,
+    /// True to fail writes and erases, as broken flash does.
+    pub fails: bool,
+}
+
+impl<const N: usize> RamPage<N> {
+    pub fn new() -> RamPage<N> {
+        RamPage {
+            data: [ERASED; N],
+            erase_count: 0,
+            fails: false,
+        }
+    }
+}
+
+impl<const N: usize> Default for RamPage<N> {
+    fn default() -> RamPage<N> {
+        RamPage::new()
+    }
+}
+
+impl<const N: usize> FlashPage for RamPage<N> {
+    fn size(&self) -> usize {
+        N
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        if !offset.is_multiple_of(ALIGN)
+            || !data.len().is_multiple_of(ALIGN)
+            || offset + data.len() > N
+            || self.fails
+        {
+            return Err(Error::Flash);
+        }
+        for (d, s) in self.data[offset..].iter_mut().zip(data.iter()) {
+            *d &= *s;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), Error> {
+        if self.fails {
+            return Err(Error::Flash);
+        }
+        self.data = [ERASED; N];
+        self.erase_count += 1;
+        Ok(())
+    }
+}
+
+/// Record position in a page.
+#[derive(Copy, Clone)]
+struct Record {
+    key: u8,
+    len: usize,
+    offset: usize,
+}
+
+impl Record {
+    fn value_offset(&self) -> usize {
+        self.offset + RECORD_HEADER_SIZE
+    }
+
+    fn next_offset(&self) -> usize {
+        self.offset + record_size(self.len)
+    }
+}
+
+fn record_size(len: usize) -> usize {
+    (RECORD_HEADER_SIZE + len).div_ceil(ALIGN) * ALIGN
+}
+
+pub struct SettingsStore<P: FlashPage> {
+    pages: [P; 2],
+    active: usize,
+    sequence: u32,
+    /// Offset to append the next record in the active page.
+    end: usize,
+}
+
+impl<P: FlashPage> SettingsStore<P> {
+    /// Open the store, formatting the pages if neither is valid.
+    pub fn open(pages: [P; 2]) -> Result<SettingsStore<P>, Error> {
+        let mut store = SettingsStore {
+            pages,
+            active: 0,
+            sequence: 0,
+            end: HEADER_SIZE,
+        };
+        let seqs = [store.page_sequence(0), store.page_sequence(1)];
+        match seqs {
+            [Some(a), Some(b)] => {
+                store.active = if b > a { 1 } else { 0 };
+                store.sequence = if b > a { b } else { a };
+            }
+            [Some(a), None] => store.sequence = a,
+            [None, Some(b)] => {
+                store.active = 1;
+                store.sequence = b;
+            }
+            [None, None] => {
+                store.pages[0].erase()?;
+                store.write_header(0, 1)?;
+                store.sequence = 1;
+            }
+        }
+        store.end = store.scan_end();
+        Ok(store)
+    }
+
+    /// Erase both pages and start an empty store, such as when `open` fails.
+    pub fn format(pages: [P; 2]) -> Result<SettingsStore<P>, Error> {
+        let mut store = SettingsStore {
+            pages,
+            active: 0,
+            sequence: 1,
+            end: HEADER_SIZE,
+        };
+        store.pages[1].erase()?;
+        store.pages[0].erase()?;
+        store.write_header(0, 1)?;
+        Ok(store)
+    }
+
+    /// Pages of the store, to inspect or break them in tests.
+    pub fn pages_mut(&mut self) -> &mut [P; 2] {
+        &mut self.pages
+    }
+
+    /// Copy the latest value of `key` into `buf`, and return its length.
+    pub fn get(&self, key: u8, buf: &mut [u8]) -> Option<usize> {
+        let record = self.find_latest(self.active, key)?;
+        if record.len == 0 || record.len > buf.len() {
+            return None;
+        }
+        self.pages[self.active].read(record.value_offset(), &mut buf[..record.len]);
+        Some(record.len)
+    }
+
+    /// Store value of `key`.  Nothing is written if the value is not changed.
+    pub fn set(&mut self, key: u8, value: &[u8]) -> Result<(), Error> {
+        if value.len() > MAX_VALUE_SIZE || key == ERASED {
+            return Err(Error::TooLarge);
+        }
+        let mut current = [0u8; MAX_VALUE_SIZE];
+        match self.get(key, &mut current) {
+            Some(len) if current[..len] == *value => return Ok(()),
+            None if value.is_empty() => return Ok(()),
+            _ => {}
+        }
+        if self.end + record_size(value.len()) > self.pages[self.active].size() {
+            self.compact()?;
+            if self.end + record_size(value.len()) > self.pages[self.active].size() {
+                return Err(Error::Full);
+            }
+        }
+        let end = self.end;
+        self.end += record_size(value.len());
+        let result = append(&mut self.pages[self.active], end, key, value);
+        if result.is_err() {
+            // Records after a broken one are not read, so the next write compacts the page.
+            self.end = self.pages[self.active].size();
+        }
+        result
+    }
+
+    pub fn remove(&mut self, key: u8) -> Result<(), Error> {
+        self.set(key, &[])
+    }
+
+    pub fn get_u16(&self, key: u8) -> Option<u16> {
+        let mut buf = [0u8; 2];
+        match self.get(key, &mut buf) {
+            Some(2) => Some(u16::from_le_bytes(buf)),
+            _ => None,
+        }
+    }
+
+    pub fn set_u16(&mut self, key: u8, value: u16) -> Result<(), Error> {
+        self.set(key, &value.to_le_bytes())
+    }
+
+    /// Move the latest records to the other page.
+    fn compact(&mut self) -> Result<(), Error> {
+        let from = self.active;
+        let to = 1 - from;
+        self.pages[to].erase()?;
+        let mut end = HEADER_SIZE;
+        let mut offset = HEADER_SIZE;
+        while let Some(record) = self.read_record(from, offset) {
+            offset = record.next_offset();
+            if record.len == 0 || !self.is_latest(from, &record) {
+                continue;
+            }
+            let mut value = [0u8; MAX_VALUE_SIZE];
+            self.pages[from].read(record.value_offset(), &mut value[..record.len]);
+            append(&mut self.pages[to], end, record.key, &value[..record.len])?;
+            end += record_size(record.len);
+        }
+        self.write_header(to, self.sequence + 1)?;
+        self.sequence += 1;
+        self.active = to;
+        self.end = end;
+        Ok(())
+    }
+
+    fn is_latest(&self, page: usize, record: &Record) -> bool {
+        let mut offset = record.next_offset();
+        while let Some(r) = self.read_record(page, offset) {
+            if r.key == record.key {
+                return false;
+            }
+            offset = r.next_offset();
+        }
+        true
+    }
+
+    fn find_latest(&self, page: usize, key: u8) -> Option<Record> {
+        let mut found = None;
+        let mut offset = HEADER_SIZE;
+        while let Some(r) = self.read_record(page, offset) {
+            if r.key == key {
+                found = Some(r);
+            }
+            offset = r.next_offset();
+        }
+        found
+    }
+
+    /// Offset after the last record.
+    /// If a broken record is found, such as one interrupted by reset, return the end of the
+    /// page so that the next write compacts the page.
+    fn scan_end(&self) -> usize {
+        let mut offset = HEADER_SIZE;
+        while let Some(r) = self.read_record(self.active, offset) {
+            offset = r.next_offset();
+        }
+        let mut key = [0u8];
+        if offset < self.pages[self.active].size() {
+            self.pages[self.active].read(offset, &mut key);
+            if key[0] != ERASED {
+                return self.pages[self.active].size();
+            }
+        }
+        offset
+    }
+
+    /// Read valid record at `offset`, or None at the end of records.
+    fn read_record(&self, page: usize, offset: usize) -> Option<Record> {
+        let p = &self.pages[page];
+        if offset + RECORD_HEADER_SIZE > p.size() {
+            return None;
+        }
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        p.read(offset, &mut header);
+        let key = header[0];
+        let len = header[1] as usize;
+        if key == ERASED || len > MAX_VALUE_SIZE || offset + record_size(len) > p.size() {
+            return None;
+        }
+        let mut value = [0u8; MAX_VALUE_SIZE];
+        p.read(offset + RECORD_HEADER_SIZE, &mut value[..len]);
+        let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if record_crc(key, &value[..len]) != crc {
+            return None;
+        }
+        Some(Record { key, len, offset })
+    }
+
+    fn page_sequence(&self, page: usize) -> Option<u32> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.pages[page].read(0, &mut header);
+        let seq = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if header[0..4] == MAGIC && seq != u32::MAX {
+            Some(seq)
+        } else {
+            None
+        }
+    }
+
+    fn write_header(&mut self, page: usize, sequence: u32) -> Result<(), Error> {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4..8].copy_from_slice(&sequence.to_le_bytes());
+        self.pages[page].write(0, &header)
+    }
+}
+
+fn record_crc(key: u8, value: &[u8]) -> u32 {
+    let mut buf = [0u8; 2 + MAX_VALUE_SIZE];
+    buf[0] = key;
+    buf[1] = value.len() as u8;
+    buf[2..2 + value.len()].copy_from_slice(value);
+    crc32(&buf[..2 + value.len()])
+}
+
+fn append<P: FlashPage>(page: &mut P, offset: usize, key: u8, value: &[u8]) -> Result<(), Error> {
+    let mut buf = [ERASED; RECORD_HEADER_SIZE + MAX_VALUE_SIZE + ALIGN];
+    buf[0] = key;
+    buf[1] = value.len() as u8;
+    buf[4..8].copy_from_slice(&record_crc(key, value).to_le_bytes());
+    buf[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + value.len()].copy_from_slice(value);
+    page.write(offset, &buf[..record_size(value.len())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Page = RamPage<256>;
+
+    fn open() -> SettingsStore<Page> {
+        SettingsStore::open([Page::new(), Page::new()]).unwrap()
+    }
+
+    fn reopen(store: SettingsStore<Page>) -> SettingsStore<Page> {
+        SettingsStore::open(store.pages).unwrap()
+    }
+
+    #[test]
+    fn test_set_get() {
+        let mut store = open();
+        assert_eq!(store.get_u16(COMBO_THRESHOLD), None);
+        store.set_u16(COMBO_THRESHOLD, 200).unwrap();
+        store.set_u16(DEFAULT_LAYER, 2).unwrap();
+        store.set_u16(COMBO_THRESHOLD, 180).unwrap();
+        assert_eq!(store.get_u16(COMBO_THRESHOLD), Some(180));
+        assert_eq!(store.get_u16(DEFAULT_LAYER), Some(2));
+
+        let store = reopen(store);
+        assert_eq!(store.get_u16(COMBO_THRESHOLD), Some(180));
+        assert_eq!(store.get_u16(DEFAULT_LAYER), Some(2));
+    }
+
+    #[test]
+    fn test_variable_length_and_remove() {
+        let mut store = open();
+        store.set(0x40, b"hello, world").unwrap();
+        let mut buf = [0u8; MAX_VALUE_SIZE];
+        assert_eq!(store.get(0x40, &mut buf), Some(12));
+        assert_eq!(&buf[..12], b"hello, world");
+        store.remove(0x40).unwrap();
+        assert_eq!(store.get(0x40, &mut buf), None);
+        assert_eq!(store.set(0x41, &[0u8; MAX_VALUE_SIZE + 1]), Err(Error::TooLarge));
+    }
+
+    #[test]
+    fn test_unchanged_value_is_not_written() {
+        let mut store = open();
+        store.set_u16(COMBO_THRESHOLD, 200).unwrap();
+        let end = store.end;
+        store.set_u16(COMBO_THRESHOLD, 200).unwrap();
+        assert_eq!(store.end, end);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let mut store = open();
+        store.set_u16(DEFAULT_LAYER, 1).unwrap();
+        for i in 0..1000 {
+            store.set_u16(COMBO_THRESHOLD, i).unwrap();
+        }
+        assert_eq!(store.get_u16(COMBO_THRESHOLD), Some(999));
+        assert_eq!(store.get_u16(DEFAULT_LAYER), Some(1));
+        // 16 bytes per record, so 15 records in a page and 2 of them survive compaction.
+        let erases = store.pages[0].erase_count + store.pages[1].erase_count;
+        assert!(erases <= 1000 / 13 + 1, "erased {} times", erases);
+
+        let store = reopen(store);
+        assert_eq!(store.get_u16(COMBO_THRESHOLD), Some(999));
+        assert_eq!(store.get_u16(DEFAULT_LAYER), Some(1));
+    }
+
+    #[test]
+    fn test_full() {
+        let mut store = open();
+        let value = [1u8; MAX_VALUE_SIZE];
+        // 40 bytes per record, 6 records in a page.
+        for key in 0..6 {
+            store.set(key, &value).unwrap();
+        }
+        assert_eq!(store.set(6, &value), Err(Error::Full));
+        assert_eq!(store.get(5, &mut [0u8; MAX_VALUE_SIZE]), Some(MAX_VALUE_SIZE));
+    }
+
+    #[test]
+    fn test_interrupted_compaction_keeps_old_page() {
+        let mut store = open();
+        store.set_u16(COMBO_THRESHOLD, 150).unwrap();
+        let mut pages = store.pages;
+        // Records are copied to page 1, but power is lost before the header is written.
+        pages[1].erase().unwrap();
+        append(&mut pages[1], HEADER_SIZE, COMBO_THRESHOLD, &99u16.to_le_bytes()).unwrap();
+
+        let store = SettingsStore::open(pages).unwrap();
+        assert_eq!(store.active, 0);
+        assert_eq!(store.get_u16(COMBO_THRESHOLD), Some(150));
+    }
+
+    #[test]
+    fn test_broken_record_is_skipped() {
+        let mut store = open();
+        store.set_u16(COMBO_THRESHOLD, 150).unwrap();
+        let end = store.end;
+        // Reset while writing the next record: header is written, but CRC is wrong.
+        store.pages[0]
+            .write(end, &[COMBO_THRESHOLD, 2, 0xff, 0xff, 0, 0, 0, 0])
+            .unwrap();
+
+        let mut store = reopen(store);
+        assert_eq!(store.get_u16(COMBO_THRESHOLD), Some(150));
+        store.set_u16(COMBO_THRESHOLD, 160).unwrap();
+        assert_eq!(store.active, 1);
+        assert_eq!(reopen(store).get_u16(COMBO_THRESHOLD), Some(160));
+    }
+
+    #[test]
+    fn test_failed_write_is_retried() {
+        let mut store = open();
+        store.set_u16(COMBO_THRESHOLD, 150).unwrap();
+        store.pages[0].fails = true;
+        assert_eq!(store.set_u16(COMBO_THRESHOLD, 160), Err(Error::Flash));
+        store.pages[0].fails = false;
+        store.set_u16(COMBO_THRESHOLD, 160).unwrap();
+        assert_eq!(reopen(store).get_u16(COMBO_THRESHOLD), Some(160));
+    }
+
+    #[test]
+    fn test_format() {
+        let mut pages = [Page::new(), Page::new()];
+        pages[0].fails = true;
+        let pages = match SettingsStore::open(pages) {
+            Err(Error::Flash) => [Page::new(), Page::new()],
+            _ => panic!("open of broken flash succeeded"),
+        };
+        let mut store = SettingsStore::format(pages).unwrap();
+        store.set_u16(COMBO_THRESHOLD, 150).unwrap();
+        assert_eq!(reopen(store).get_u16(COMBO_THRESHOLD), Some(150));
+    }
+}
@@ -0,0 +1,324 @@
+//! Command shell of the keyboard, which runs a line of text and writes its output.
+//!
+//! The shell does not know the transport: the serial console (see `console`) and the raw HID
+//! request `Shell` (see `protocol`) pass lines to `run` and send the output back.
+//!
+//! - `help` lists the commands.
+//! - `matrix` draws the keys held, `#` for each of them.
+//! - `layer` shows the current and the default layer, and `layer <n>` changes the default one.
+//! - `get` shows all settings, `get <setting>` one of them, and `set <setting> <value>`
+//!   changes it as `keytool set` does.
+//! - `keymap get <pos>` shows the encoded command of the key on each layer, where `<pos>` is
+//!   a position such as `0x22` or a key of the grid such as `R2C2`.
+//! - `stats` counts key presses since the keyboard started.
+//! - `log on` prints every key event on the serial console, and `log off` stops it.
+//! - `reset` restarts the keyboard, and `bootloader` restarts it into the system bootloader
+//!   to flash a new firmware over USB DFU.
+
+use core::fmt::{self, Write};
+
+use crate::grid::{grid_pos, index_grid, pos_grid, GRID_COLS, GRID_ROWS};
+use crate::keymap::{Pos, N_KEYS, N_LAYERS};
+use crate::{pos_to_map_index, settings, KeyStream};
+
+/// Bytes of the output of a command.  Output which does not fit is dropped.
+pub const OUTPUT_SIZE: usize = 512;
+/// Words of the longest command.
+const MAX_WORDS: usize = 4;
+
+/// Output of commands, waiting for the transport.
+#[derive(Copy, Clone)]
+pub struct Output {
+    buf: [u8; OUTPUT_SIZE],
+    len: usize,
+}
+
+impl Output {
+    pub fn new() -> Output {
+        Output {
+            buf: [0; OUTPUT_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Remove the first n bytes, sent by the transport.
+    pub fn consume(&mut self, n: usize) {
+        let n = n.min(self.len);
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+    }
+
+    pub fn push(&mut self, b: &[u8]) {
+        let n = b.len().min(OUTPUT_SIZE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&b[..n]);
+        self.len += n;
+    }
+}
+
+impl Default for Output {
+    fn default() -> Output {
+        Output::new()
+    }
+}
+
+impl Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Run a command line.
+pub fn run<W: Write>(stream: &mut KeyStream, line: &str, out: &mut W) {
+    let mut words = [""; MAX_WORDS];
+    let mut n = 0;
+    for word in line.split_whitespace().take(MAX_WORDS) {
+        words[n] = word;
+        n += 1;
+    }
+    let result = match words[..n] {
+        [] => Ok(()),
+        ["help"] => writeln!(
+            out,
+            "matrix, layer [<n>], get [<setting>], set <setting> <value>, keymap get <pos>, \
+             stats, log on|off, reset, bootloader"
+        ),
+        ["matrix"] => matrix(stream, out),
+        ["layer"] => writeln!(
+            out,
+            "layer {} (default {})",
+            stream.current_layer(),
+            stream.default_layer
+        ),
+        ["layer", n] => match n.parse::<u16>() {
+            Ok(n) if stream.set_setting(settings::DEFAULT_LAYER, n) => Ok(()),
+            _ => writeln!(out, "invalid layer: {}", n),
+        },
+        ["get"] => settings::KEYS
+            .iter()
+            .try_for_each(|key| show_setting(stream, *key, out)),
+        ["get", name] => match settings::key_by_name(name) {
+            Some(key) => show_setting(stream, key, out),
+            None => writeln!(out, "unknown setting: {}", name),
+        },
+        ["set", name, value] => match (settings::key_by_name(name), value.parse::<u16>()) {
+            (Some(key), Ok(v)) if stream.set_setting(key, v) => Ok(()),
+            (None, _) => writeln!(out, "unknown setting: {}", name),
+            _ => writeln!(out, "invalid value: {}", value),
+        },
+        ["keymap", "get", pos] => match parse_pos(pos) {
+            Some(pos) => keymap_get(stream, pos, out),
+            None => writeln!(out, "invalid position: {}", pos),
+        },
+        ["stats"] => stats(stream, out),
+        ["log", "on"] => {
+            stream.console.log = true;
+            Ok(())
+        }
+        ["log", "off"] => {
+            stream.console.log = false;
+            Ok(())
+        }
+        ["reset"] => {
+            stream.state.requests_reset = true;
+            Ok(())
+        }
+        ["bootloader"] => {
+            stream.requests_bootloader = true;
+            Ok(())
+        }
+        ["help", ..]
+        | ["matrix", ..]
+        | ["layer", ..]
+        | ["get", ..]
+        | ["set", ..]
+        | ["keymap", ..]
+        | ["stats", ..]
+        | ["log", ..]
+        | ["reset", ..]
+        | ["bootloader", ..] => writeln!(out, "invalid arguments, see help"),
+        [command, ..] => writeln!(out, "unknown command: {}", command),
+    };
+    result.ok();
+}
+
+fn show_setting<W: Write>(stream: &KeyStream, key: u8, out: &mut W) -> fmt::Result {
+    match (settings::name(key), stream.setting(key)) {
+        (Some(name), Some(v)) => writeln!(out, "{} = {}", name, v),
+        _ => Ok(()),
+    }
+}
+
+/// Position of `0x22` or `R2C2`, if it is a key of the keyboard.
+fn parse_pos(s: &str) -> Option<Pos> {
+    let pos = if let Some(hex) = s.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16).ok()?
+    } else {
+        let (row, col) = s.strip_prefix('R')?.split_once('C')?;
+        let (row, col) = (row.parse::<usize>().ok()?, col.parse::<usize>().ok()?);
+        if !(1..=GRID_ROWS).contains(&row) || !(1..=GRID_COLS).contains(&col) {
+            return None;
+        }
+        grid_pos(row - 1, col - 1)
+    };
+    pos_grid(pos).map(|_| pos)
+}
+
+/// Draw the keys held, the halves apart.
+fn matrix<W: Write>(stream: &KeyStream, out: &mut W) -> fmt::Result {
+    for row in 0..GRID_ROWS {
+        for col in 0..GRID_COLS {
+            if col == GRID_COLS / 2 {
+                out.write_char(' ')?;
+            }
+            let on = stream.on_pos[grid_pos(row, col) as usize];
+            out.write_char(if on { '#' } else { '.' })?;
+        }
+        out.write_char('\n')?;
+    }
+    Ok(())
+}
+
+/// Commands of the key on each layer, as `Command::encode` bytes.
+fn keymap_get<W: Write>(stream: &KeyStream, pos: Pos, out: &mut W) -> fmt::Result {
+    let index = pos_to_map_index(pos);
+    for layer in 0..N_LAYERS {
+        write!(out, "layer {}:", layer)?;
+        for b in stream.keymap.layers[layer][index].encode().iter() {
+            write!(out, " {:02x}", b)?;
+        }
+        out.write_char('\n')?;
+    }
+    Ok(())
+}
+
+fn stats<W: Write>(stream: &KeyStream, out: &mut W) -> fmt::Result {
+    let total: u32 = stream.presses.iter().sum();
+    writeln!(out, "presses: {}", total)?;
+    let (index, count) = (0..N_KEYS)
+        .map(|i| (i, stream.presses[i]))
+        .fold((0, 0), |max, p| if p.1 > max.1 { p } else { max });
+    if count > 0 {
+        let (row, col) = index_grid(index);
+        writeln!(out, "most pressed: R{}C{} ({})", row + 1, col + 1, count)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid_keycodes as KC;
+    use crate::keymap::Command;
+    use std::string::String;
+
+    /// Run the commands of a transcript, lines of `> ` and a command followed by its output,
+    /// and compare the output.
+    fn check_transcript(stream: &mut KeyStream, transcript: &str) {
+        let mut expected = String::new();
+        let mut actual = String::new();
+        for line in transcript.lines() {
+            expected.push_str(line);
+            expected.push('\n');
+            if let Some(command) = line.strip_prefix("> ") {
+                actual.push_str(line);
+                actual.push('\n');
+                let mut out = Output::new();
+                run(stream, command, &mut out);
+                actual.push_str(core::str::from_utf8(out.as_slice()).unwrap());
+            }
+        }
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_transcript() {
+        let mut stream = KeyStream::new();
+        stream.keymap.layers[0][0] = Command::KeyPress { kc: KC::KBD_A };
+        stream.keymap.layers[1][0] = Command::Nop;
+        stream.keymap.layers[2][0] = Command::ToggleNicola;
+        stream.keymap.layers[3][0] = Command::Nop;
+        let mut mat = [0u8; 8];
+        mat[0] = grid_pos(0, 0);
+        mat[1] = grid_pos(3, 11);
+        stream.push(&mat, &[0; 8], 0);
+        stream.push(&[0; 8], &[0; 8], 0);
+        mat[1] = 0;
+        stream.push(&mat, &[0; 8], 0);
+        check_transcript(
+            &mut stream,
+            "\
+> get chords
+chords = 0
+> set  chords 1
+> get chords
+chords = 1
+> set nicola 2
+invalid value: 2
+> get foo
+unknown setting: foo
+> layer
+layer 0 (default 0)
+> layer 2
+> layer
+layer 2 (default 2)
+> layer 9
+invalid layer: 9
+> matrix
+#..... ......
+...... ......
+...... ......
+...... ......
+> keymap get R1C1
+layer 0: 01 04 00 00
+layer 1: 00 00 00 00
+layer 2: 08 00 00 00
+layer 3: 00 00 00 00
+> keymap get 0x11
+layer 0: 01 04 00 00
+layer 1: 00 00 00 00
+layer 2: 08 00 00 00
+layer 3: 00 00 00 00
+> keymap get 0x17
+invalid position: 0x17
+> keymap get R5C1
+invalid position: R5C1
+> stats
+presses: 3
+most pressed: R1C1 (2)
+> log
+invalid arguments, see help
+> reboot
+unknown command: reboot
+",
+        );
+    }
+
+    #[test]
+    fn test_restart() {
+        let mut stream = KeyStream::new();
+        let mut out = Output::new();
+        run(&mut stream, "reset", &mut out);
+        assert!(stream.requests_reset());
+        assert!(!stream.requests_bootloader());
+        run(&mut stream, "bootloader", &mut out);
+        assert!(stream.requests_bootloader());
+        assert!(out.as_slice().is_empty());
+    }
+
+    #[test]
+    fn test_output() {
+        let mut out = Output::new();
+        write!(out, "abc").unwrap();
+        out.consume(1);
+        assert_eq!(out.as_slice(), b"bc");
+        for _ in 0..OUTPUT_SIZE {
+            out.push(b"x");
+        }
+        assert_eq!(out.as_slice().len(), OUTPUT_SIZE);
+    }
+}
@@ -0,0 +1,278 @@
+//! Stenography for Plover: every key of the grid is a key of a steno machine, and the keys
+//! pressed until all of them are released are a stroke, sent to the host as a packet of the
+//! GeminiPR or the TX Bolt protocol over the USB serial interface.
+//!
+//! The layout is the steno board of ortholinear keyboards, with the number bar on the top
+//! row and the vowels on the inner thumb keys:
+//!
+//! ```text
+//! #1  #2  #3  #4  #5  #6     #7  #8  #9  #A  #B  #C
+//! S1- S1- T-  P-  H-  *1     *3  -F  -P  -L  -T  -D
+//! S2- S2- K-  W-  R-  *2     *4  -R  -B  -G  -S  -Z
+//! Fn  pwr re1 re2 A-  O-     -E  -U  re1 re2 pwr Fn
+//! ```
+//!
+//! The stroke of exactly Fn, pwr, re1 and re2 leaves the steno mode instead of being sent.
+
+use crate::grid::{pos_grid, GRID_COLS, GRID_ROWS};
+
+/// Keys of a steno machine, in the order of their bits in a GeminiPR packet.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum StenoKey {
+    Fn,
+    N1,
+    N2,
+    N3,
+    N4,
+    N5,
+    N6,
+    S1,
+    S2,
+    LT,
+    LK,
+    LP,
+    LW,
+    LH,
+    LR,
+    A,
+    O,
+    Star1,
+    Star2,
+    Res1,
+    Res2,
+    Pwr,
+    Star3,
+    Star4,
+    E,
+    U,
+    RF,
+    RR,
+    RP,
+    RB,
+    RL,
+    RG,
+    RT,
+    RS,
+    RD,
+    N7,
+    N8,
+    N9,
+    NA,
+    NB,
+    NC,
+    RZ,
+}
+
+use StenoKey::*;
+
+const LAYOUT: [[StenoKey; GRID_COLS]; GRID_ROWS] = [
+    [N1, N2, N3, N4, N5, N6, N7, N8, N9, NA, NB, NC],
+    [S1, S1, LT, LP, LH, Star1, Star3, RF, RP, RL, RT, RD],
+    [S2, S2, LK, LW, LR, Star2, Star4, RR, RB, RG, RS, RZ],
+    [Fn, Pwr, Res1, Res2, A, O, E, U, Res1, Res2, Pwr, Fn],
+];
+
+/// Steno key of the position, if it is a key of the grid.
+pub fn steno_key(pos: u8) -> Option<StenoKey> {
+    pos_grid(pos).map(|(row, col)| LAYOUT[row][col])
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum Protocol {
+    GeminiPr,
+    TxBolt,
+}
+
+impl Protocol {
+    /// All protocols, in the order of their values of `settings::STENO_PROTOCOL`.
+    pub const ALL: [Protocol; 2] = [Protocol::GeminiPr, Protocol::TxBolt];
+
+    pub fn from_u16(v: u16) -> Option<Protocol> {
+        Protocol::ALL.get(v as usize).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Protocol::GeminiPr => "geminipr",
+            Protocol::TxBolt => "txbolt",
+        }
+    }
+}
+
+/// Keys of a stroke, bit n is the key of `StenoKey` n.
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub struct Stroke(u64);
+
+/// Bytes of the longest packet, a GeminiPR packet.
+pub const PACKET_SIZE: usize = 6;
+
+/// Bytes of a stroke to be written to the serial interface.
+#[derive(Copy, Clone)]
+pub struct Packet {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Packet {
+    pub const EMPTY: Packet = Packet {
+        buf: [0; PACKET_SIZE],
+        len: 0,
+    };
+
+    fn push(&mut self, b: u8) {
+        self.buf[self.len] = b;
+        self.len += 1;
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    /// Remove the first n bytes, written to the host.
+    pub fn consume(&mut self, n: usize) {
+        let n = n.min(self.len);
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+    }
+}
+
+impl Stroke {
+    pub const EMPTY: Stroke = Stroke(0);
+
+    pub fn add(&mut self, key: StenoKey) {
+        self.0 |= 1 << key as u64;
+    }
+
+    pub fn contains(&self, key: StenoKey) -> bool {
+        self.0 & (1 << key as u64) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// True for the stroke which leaves the steno mode.
+    pub fn is_exit(&self) -> bool {
+        let mut exit = Stroke::EMPTY;
+        for key in [Fn, Pwr, Res1, Res2].iter() {
+            exit.add(*key);
+        }
+        *self == exit
+    }
+
+    pub fn packet(&self, protocol: Protocol) -> Packet {
+        let mut packet = Packet::EMPTY;
+        // Every key is in the layout, some of them twice.
+        let keys = LAYOUT.iter().flatten().filter(|k| self.contains(**k));
+        match protocol {
+            // 6 bytes of 7 key bits each, the first byte marked by the top bit.
+            Protocol::GeminiPr => {
+                packet.len = PACKET_SIZE;
+                packet.buf[0] = 0x80;
+                for key in keys {
+                    let i = *key as usize;
+                    packet.buf[i / 7] |= 0x40 >> (i % 7);
+                }
+            }
+            // A byte of each group of 6 keys with any key pressed, the group in the top 2 bits,
+            // then 0 which ends the stroke.
+            Protocol::TxBolt => {
+                let mut groups = [0u8; 4];
+                for key in keys {
+                    if let Some((group, bit)) = tx_bolt_bit(*key) {
+                        groups[group] |= 1 << bit;
+                    }
+                }
+                for (group, bits) in groups.iter().enumerate() {
+                    if *bits != 0 {
+                        packet.push((group as u8) << 6 | bits);
+                    }
+                }
+                packet.push(0);
+            }
+        }
+        packet
+    }
+}
+
+/// Group and bit of the key in TX Bolt, which has a single S-, * and number key, and no Fn,
+/// pwr and re keys.
+fn tx_bolt_bit(key: StenoKey) -> Option<(usize, u8)> {
+    let bit = match key {
+        S1 | S2 => (0, 0),
+        LT => (0, 1),
+        LK => (0, 2),
+        LP => (0, 3),
+        LW => (0, 4),
+        LH => (0, 5),
+        LR => (1, 0),
+        A => (1, 1),
+        O => (1, 2),
+        Star1 | Star2 | Star3 | Star4 => (1, 3),
+        E => (1, 4),
+        U => (1, 5),
+        RF => (2, 0),
+        RR => (2, 1),
+        RP => (2, 2),
+        RB => (2, 3),
+        RL => (2, 4),
+        RG => (2, 5),
+        RT => (3, 0),
+        RS => (3, 1),
+        RD => (3, 2),
+        RZ => (3, 3),
+        N1 | N2 | N3 | N4 | N5 | N6 | N7 | N8 | N9 | NA | NB | NC => (3, 4),
+        Fn | Pwr | Res1 | Res2 => return None,
+    };
+    Some(bit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::grid_pos;
+
+    fn stroke(keys: &[StenoKey]) -> Stroke {
+        let mut stroke = Stroke::EMPTY;
+        for key in keys.iter() {
+            stroke.add(*key);
+        }
+        stroke
+    }
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(steno_key(grid_pos(1, 2)), Some(LT));
+        assert_eq!(steno_key(grid_pos(2, 11)), Some(RZ));
+        assert_eq!(steno_key(grid_pos(3, 4)), Some(A));
+        assert_eq!(steno_key(grid_pos(3, 7)), Some(U));
+        assert_eq!(steno_key(0x55), None);
+        assert!(stroke(&[Fn, Pwr, Res1, Res2]).is_exit());
+        assert!(!stroke(&[Fn, Pwr, Res1, Res2, A]).is_exit());
+        assert!(stroke(&[RZ]).contains(RZ));
+    }
+
+    #[test]
+    fn test_gemini_pr() {
+        // KAT
+        let packet = stroke(&[LK, A, RT]).packet(Protocol::GeminiPr);
+        assert_eq!(packet.as_slice(), &[0x80, 0x08, 0x20, 0x00, 0x04, 0x00]);
+        let packet = stroke(&[Fn, RZ]).packet(Protocol::GeminiPr);
+        assert_eq!(packet.as_slice(), &[0xc0, 0, 0, 0, 0, 0x01]);
+    }
+
+    #[test]
+    fn test_tx_bolt() {
+        let packet = stroke(&[LK, A, RT]).packet(Protocol::TxBolt);
+        assert_eq!(packet.as_slice(), &[0x04, 0x42, 0xc1, 0]);
+        // Both S and all stars are single keys, and numbers are the number key.
+        let packet = stroke(&[S1, S2, Star2, Star3, N9]).packet(Protocol::TxBolt);
+        assert_eq!(packet.as_slice(), &[0x01, 0x48, 0xd0, 0]);
+        assert_eq!(stroke(&[Pwr]).packet(Protocol::TxBolt).as_slice(), &[0]);
+        assert_eq!(Protocol::from_u16(1), Some(Protocol::TxBolt));
+        assert_eq!(Protocol::from_u16(2), None);
+    }
+}
@@ -0,0 +1,257 @@
+//! Keymap image stored in on-chip flash.
+//!
+//! Header (12 bytes):
+//! `[magic "KMAP", version: u16, payload length: u16, payload CRC-32: u32]`
+//! Payload:
+//! `layers: N_LAYERS * N_KEYS commands, combos: N_COMBOS * [k1, k2, command],
+//! strings: STRINGS_SIZE bytes, chords: N_CHORDS * chord,
+//! (N_PROFILES - 1) * [layers, combos]`
+//! The first layers and combos are of profile 0, and the last ones of profiles 1 and on.
+//! Images of version 1, which have no strings, are loaded with the built-in strings, images of
+//! versions 1 and 2, which have no chords, with the built-in chords, and images of versions 1
+//! to 3, which have no profiles, with the built-in profiles 1 and on.
+//! Multi-byte values are little endian.  The image is padded to a multiple of 8 bytes,
+//! which is the programming unit of STM32L4 flash.
+
+use crate::chord::Chord;
+use crate::crc::crc32;
+use crate::keymap::{
+    Command, Keymap, Profile, N_CHORDS, N_COMBOS, N_KEYS, N_LAYERS, N_PROFILES, STRINGS_SIZE,
+};
+
+const MAGIC: [u8; 4] = *b"KMAP";
+pub const VERSION: u16 = 4;
+const HEADER_SIZE: usize = 12;
+const COMBO_SIZE: usize = 2 + Command::ENCODED_SIZE;
+/// Payload of version 1.
+const KEYS_SIZE: usize = N_LAYERS * N_KEYS * Command::ENCODED_SIZE + N_COMBOS * COMBO_SIZE;
+/// Payload of version 2.
+const STRINGS_END: usize = KEYS_SIZE + STRINGS_SIZE;
+/// Payload of version 3.
+const CHORDS_END: usize = STRINGS_END + N_CHORDS * Chord::ENCODED_SIZE;
+const PAYLOAD_SIZE: usize = CHORDS_END + (N_PROFILES - 1) * KEYS_SIZE;
+pub const IMAGE_SIZE: usize = (HEADER_SIZE + PAYLOAD_SIZE).div_ceil(8) * 8;
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum LoadError {
+    /// No image is written, or the region is erased.
+    NoImage,
+    /// Image is written by an incompatible firmware.
+    Version,
+    /// Image is broken.
+    Crc,
+    /// Image contains unknown command.
+    InvalidCommand,
+}
+
+/// Serialize keymap into the image format.
+pub fn save(keymap: &Keymap) -> [u8; IMAGE_SIZE] {
+    let mut image = [0u8; IMAGE_SIZE];
+    let payload = &mut image[HEADER_SIZE..HEADER_SIZE + PAYLOAD_SIZE];
+    save_keys(&keymap.profile_keys(0), &mut payload[..KEYS_SIZE]);
+    let mut p = KEYS_SIZE;
+    payload[p..p + STRINGS_SIZE].copy_from_slice(&keymap.strings);
+    p += STRINGS_SIZE;
+    for c in keymap.chords.iter() {
+        payload[p..p + Chord::ENCODED_SIZE].copy_from_slice(&c.encode());
+        p += Chord::ENCODED_SIZE;
+    }
+    for n in 1..N_PROFILES {
+        save_keys(&keymap.profile_keys(n), &mut payload[p..p + KEYS_SIZE]);
+        p += KEYS_SIZE;
+    }
+    let crc = crc32(&image[HEADER_SIZE..HEADER_SIZE + PAYLOAD_SIZE]);
+    image[0..4].copy_from_slice(&MAGIC);
+    image[4..6].copy_from_slice(&VERSION.to_le_bytes());
+    image[6..8].copy_from_slice(&(PAYLOAD_SIZE as u16).to_le_bytes());
+    image[8..12].copy_from_slice(&crc.to_le_bytes());
+    image
+}
+
+/// Deserialize keymap from the image, or tell why it cannot be used.
+pub fn load(image: &[u8]) -> Result<Keymap, LoadError> {
+    if image.len() < HEADER_SIZE || image[0..4] != MAGIC {
+        return Err(LoadError::NoImage);
+    }
+    let version = u16::from_le_bytes([image[4], image[5]]);
+    let length = u16::from_le_bytes([image[6], image[7]]) as usize;
+    match (version, length) {
+        (VERSION, PAYLOAD_SIZE) | (3, CHORDS_END) | (2, STRINGS_END) | (1, KEYS_SIZE) => {}
+        _ => return Err(LoadError::Version),
+    }
+    if image.len() < HEADER_SIZE + length {
+        return Err(LoadError::Crc);
+    }
+    let payload = &image[HEADER_SIZE..HEADER_SIZE + length];
+    let crc = u32::from_le_bytes([image[8], image[9], image[10], image[11]]);
+    if crc32(payload) != crc {
+        return Err(LoadError::Crc);
+    }
+
+    let mut keymap = Keymap::new();
+    keymap.set_profile_keys(0, load_keys(&payload[..KEYS_SIZE])?);
+    let mut p = KEYS_SIZE;
+    if length >= STRINGS_END {
+        keymap
+            .strings
+            .copy_from_slice(&payload[p..p + STRINGS_SIZE]);
+        p += STRINGS_SIZE;
+    }
+    if length >= CHORDS_END {
+        for chord in keymap.chords.iter_mut() {
+            *chord = Chord::decode(&payload[p..]).ok_or(LoadError::InvalidCommand)?;
+            p += Chord::ENCODED_SIZE;
+        }
+    }
+    if length == PAYLOAD_SIZE {
+        for n in 1..N_PROFILES {
+            keymap.set_profile_keys(n, load_keys(&payload[p..p + KEYS_SIZE])?);
+            p += KEYS_SIZE;
+        }
+    }
+    Ok(keymap)
+}
+
+/// Layers and combos of a profile into `KEYS_SIZE` bytes.
+fn save_keys(keys: &Profile, payload: &mut [u8]) {
+    let mut p = 0;
+    for layer in keys.layers.iter() {
+        for c in layer.iter() {
+            payload[p..p + Command::ENCODED_SIZE].copy_from_slice(&c.encode());
+            p += Command::ENCODED_SIZE;
+        }
+    }
+    for (k1, k2, c) in keys.combos.iter() {
+        payload[p] = *k1;
+        payload[p + 1] = *k2;
+        payload[p + 2..p + COMBO_SIZE].copy_from_slice(&c.encode());
+        p += COMBO_SIZE;
+    }
+}
+
+fn load_keys(payload: &[u8]) -> Result<Profile, LoadError> {
+    let mut keys = Profile {
+        layers: [[Command::Nop; N_KEYS]; N_LAYERS],
+        combos: [(0, 0, Command::Nop); N_COMBOS],
+    };
+    let mut p = 0;
+    for layer in keys.layers.iter_mut() {
+        for c in layer.iter_mut() {
+            *c = Command::decode(&payload[p..]).ok_or(LoadError::InvalidCommand)?;
+            p += Command::ENCODED_SIZE;
+        }
+    }
+    for combo in keys.combos.iter_mut() {
+        let c = Command::decode(&payload[p + 2..]).ok_or(LoadError::InvalidCommand)?;
+        *combo = (payload[p], payload[p + 1], c);
+        p += COMBO_SIZE;
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hid_keycodes as KC;
+    use crate::keymap::{ModifierKey, Modifiers};
+
+    #[test]
+    fn test_save_load() {
+        let mut keymap = Keymap::new();
+        keymap.layers[2][7] = Command::KeyPress { kc: KC::KBD_Z };
+        keymap.combos[10] = (0x11, 0x21, Command::RequestReset);
+        keymap.combos[11] = (
+            0x12,
+            0x22,
+            Command::Char {
+                mk: Modifiers::new(&[ModifierKey::UI1]),
+                c: b'{',
+            },
+        );
+        keymap.strings[..3].copy_from_slice(b"ab\0");
+        keymap.chords[100] = Chord::new(&[0x24, 0xa2], b"ga").unwrap();
+        keymap.switch_profile(1);
+        keymap.layers[0][3] = Command::SwitchProfile { n: 0 };
+        let image = save(&keymap);
+        assert_eq!(IMAGE_SIZE % 8, 0);
+
+        let mut loaded = load(&image).unwrap();
+        assert_eq!(loaded.profile, 0);
+        loaded.switch_profile(1);
+        assert_eq!(loaded.layers[..], keymap.layers[..]);
+        keymap.switch_profile(0);
+        loaded.switch_profile(0);
+        assert_eq!(loaded.layers[..], keymap.layers[..]);
+        assert_eq!(loaded.combos, keymap.combos);
+        assert_eq!(loaded.strings[..], keymap.strings[..]);
+        assert_eq!(loaded.chords[..], keymap.chords[..]);
+    }
+
+    #[test]
+    fn test_load_version_3() {
+        let mut keymap = Keymap::new();
+        keymap.chords[0] = Chord::new(&[0x11], b"a").unwrap();
+        keymap.switch_profile(1);
+        keymap.layers[0][0] = Command::RequestReset;
+        let mut image = save(&keymap);
+        let crc = crc32(&image[HEADER_SIZE..HEADER_SIZE + CHORDS_END]);
+        image[4..6].copy_from_slice(&3u16.to_le_bytes());
+        image[6..8].copy_from_slice(&(CHORDS_END as u16).to_le_bytes());
+        image[8..12].copy_from_slice(&crc.to_le_bytes());
+
+        let mut loaded = load(&image).unwrap();
+        assert_eq!(loaded.chords[..], keymap.chords[..]);
+        loaded.switch_profile(1);
+        assert_eq!(loaded.layers[..], Keymap::new().profile_keys(1).layers[..]);
+    }
+
+    #[test]
+    fn test_load_version_2() {
+        let mut keymap = Keymap::new();
+        keymap.strings[0] = b'x';
+        keymap.chords[0] = Chord::new(&[0x11], b"a").unwrap();
+        let mut image = save(&keymap);
+        let crc = crc32(&image[HEADER_SIZE..HEADER_SIZE + STRINGS_END]);
+        image[4..6].copy_from_slice(&2u16.to_le_bytes());
+        image[6..8].copy_from_slice(&(STRINGS_END as u16).to_le_bytes());
+        image[8..12].copy_from_slice(&crc.to_le_bytes());
+
+        let loaded = load(&image).unwrap();
+        assert_eq!(loaded.strings[..], keymap.strings[..]);
+        assert_eq!(loaded.chords[..], Keymap::new().chords[..]);
+    }
+
+    #[test]
+    fn test_load_version_1() {
+        let mut keymap = Keymap::new();
+        keymap.layers[1][0] = Command::RequestReset;
+        keymap.strings[0] = b'x';
+        let mut image = save(&keymap);
+        let crc = crc32(&image[HEADER_SIZE..HEADER_SIZE + KEYS_SIZE]);
+        image[4..6].copy_from_slice(&1u16.to_le_bytes());
+        image[6..8].copy_from_slice(&(KEYS_SIZE as u16).to_le_bytes());
+        image[8..12].copy_from_slice(&crc.to_le_bytes());
+
+        let loaded = load(&image).unwrap();
+        assert_eq!(loaded.layers[..], keymap.layers[..]);
+        assert_eq!(loaded.strings[..], Keymap::new().strings[..]);
+    }
+
+    #[test]
+    fn test_load_erased() {
+        // Erased flash reads 0xff.
+        assert_eq!(load(&[0xffu8; IMAGE_SIZE]).err(), Some(LoadError::NoImage));
+    }
+
+    #[test]
+    fn test_load_broken() {
+        let mut image = save(&Keymap::new());
+        image[HEADER_SIZE + 5] ^= 1;
+        assert_eq!(load(&image).err(), Some(LoadError::Crc));
+
+        let mut image = save(&Keymap::new());
+        image[4] = 0xff;
+        assert_eq!(load(&image).err(), Some(LoadError::Version));
+    }
+}
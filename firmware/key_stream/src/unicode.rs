@@ -0,0 +1,203 @@
+//! Input of Unicode characters by the input methods of host OSes.
+//!
+//! Keyboards send key codes only, so a character without a key is entered by a sequence of
+//! keys which the host turns into the character.  `Command::Unicode` and characters of
+//! `Command::SendString` beyond ASCII are typed by the sequence of the mode in settings.
+//!
+//! - Linux (IBus, GTK): Ctrl+Shift+U, the hex code, then space.
+//! - macOS: the input source "Unicode Hex Input", where the hex code of each UTF-16 unit is
+//!   typed while Option is held.
+//! - Windows: WinCompose with the default compose key, right Alt.  Compose, `u`, the hex
+//!   code, then Enter.
+//!
+//! Hex digits and letters are the same keys on all host layouts.
+
+use crate::hid_keycodes::{self as KC, Kc};
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum UnicodeMode {
+    Linux,
+    Mac,
+    WinCompose,
+}
+
+/// Reports of the longest sequence, WinCompose with 6 hex digits.
+pub const MAX_REPORTS: usize = 18;
+
+/// Reports to be sent in order.
+#[derive(Copy, Clone)]
+pub struct Reports {
+    buf: [[u8; 8]; MAX_REPORTS],
+    len: usize,
+}
+
+impl Reports {
+    pub fn new() -> Reports {
+        Reports {
+            buf: [[0; 8]; MAX_REPORTS],
+            len: 0,
+        }
+    }
+
+    /// Reports beyond `MAX_REPORTS` are dropped.
+    pub fn push(&mut self, report: [u8; 8]) {
+        if self.len < MAX_REPORTS {
+            self.buf[self.len] = report;
+            self.len += 1;
+        }
+    }
+
+    /// Press the key with the modifier bits of the report, and release all keys.
+    pub fn tap(&mut self, modifiers: u8, kc: Kc) {
+        self.push([modifiers, 0, kc.code(), 0, 0, 0, 0, 0]);
+        self.push([0; 8]);
+    }
+
+    pub fn as_slice(&self) -> &[[u8; 8]] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Default for Reports {
+    fn default() -> Reports {
+        Reports::new()
+    }
+}
+
+impl UnicodeMode {
+    /// All modes, in the order of their values of `settings::UNICODE_MODE`.
+    pub const ALL: [UnicodeMode; 3] = [
+        UnicodeMode::Linux,
+        UnicodeMode::Mac,
+        UnicodeMode::WinCompose,
+    ];
+
+    pub fn from_u16(v: u16) -> Option<UnicodeMode> {
+        UnicodeMode::ALL.get(v as usize).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            UnicodeMode::Linux => "linux",
+            UnicodeMode::Mac => "mac",
+            UnicodeMode::WinCompose => "wincompose",
+        }
+    }
+
+    /// Append the input sequence of the character, which ends with all keys released.
+    pub fn push_reports(&self, c: char, reports: &mut Reports) {
+        let cp = c as u32;
+        match *self {
+            UnicodeMode::Linux => {
+                reports.tap(
+                    KC::KBD_MODIFIER_LEFT_CTRL | KC::KBD_MODIFIER_LEFT_SHIFT,
+                    KC::KBD_U,
+                );
+                push_hex(reports, 0, cp);
+                reports.tap(0, KC::KBD_SPACEBAR);
+            }
+            UnicodeMode::Mac => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units).iter() {
+                    push_hex(reports, KC::KBD_MODIFIER_LEFT_ALT, *unit as u32);
+                }
+                reports.push([0; 8]);
+            }
+            UnicodeMode::WinCompose => {
+                reports.tap(KC::KBD_MODIFIER_RIGHT_ALT, KC::KBD_NONE);
+                reports.tap(0, KC::KBD_U);
+                push_hex(reports, 0, cp);
+                reports.tap(0, KC::KBD_ENTER);
+            }
+        }
+    }
+}
+
+/// Hex digits of the value, at least 4, typed with the modifiers held.
+fn push_hex(reports: &mut Reports, modifiers: u8, value: u32) {
+    let mut digits = 4;
+    while value >> (digits * 4) != 0 {
+        digits += 1;
+    }
+    for i in (0..digits).rev() {
+        let kc = match (value >> (i * 4)) & 0xf {
+            0 => KC::KBD_0,
+            d @ 1..=9 => Kc::from_code(KC::KBD_1.code() + d as u8 - 1),
+            d => Kc::from_code(KC::KBD_A.code() + d as u8 - 10),
+        };
+        reports.push([modifiers, 0, kc.code(), 0, 0, 0, 0, 0]);
+        reports.push([modifiers, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(mode: UnicodeMode, c: char) -> Vec<[u8; 2]> {
+        let mut reports = Reports::new();
+        mode.push_reports(c, &mut reports);
+        reports.as_slice().iter().map(|r| [r[0], r[2]]).collect()
+    }
+
+    #[test]
+    fn test_push_reports() {
+        let ctrl_shift = KC::KBD_MODIFIER_LEFT_CTRL | KC::KBD_MODIFIER_LEFT_SHIFT;
+        assert_eq!(
+            keys(UnicodeMode::Linux, '→'),
+            vec![
+                [ctrl_shift, KC::KBD_U.code()],
+                [0, 0],
+                [0, KC::KBD_2.code()],
+                [0, 0],
+                [0, KC::KBD_1.code()],
+                [0, 0],
+                [0, KC::KBD_9.code()],
+                [0, 0],
+                [0, KC::KBD_2.code()],
+                [0, 0],
+                [0, KC::KBD_SPACEBAR.code()],
+                [0, 0],
+            ]
+        );
+
+        // U+1F600 is D83D DE00 in UTF-16.
+        let alt = KC::KBD_MODIFIER_LEFT_ALT;
+        let mac = keys(UnicodeMode::Mac, '😀');
+        assert_eq!(mac.len(), 17);
+        assert_eq!(
+            &mac[..4],
+            &[
+                [alt, KC::KBD_D.code()],
+                [alt, 0],
+                [alt, KC::KBD_8.code()],
+                [alt, 0]
+            ]
+        );
+        assert_eq!(&mac[8..10], &[[alt, KC::KBD_D.code()], [alt, 0]]);
+        assert_eq!(mac[16], [0, 0]);
+
+        let win = keys(UnicodeMode::WinCompose, '😀');
+        assert_eq!(win.len(), 16);
+        assert_eq!(
+            &win[..4],
+            &[
+                [KC::KBD_MODIFIER_RIGHT_ALT, 0],
+                [0, 0],
+                [0, KC::KBD_U.code()],
+                [0, 0]
+            ]
+        );
+        assert_eq!(&win[4..6], &[[0, KC::KBD_1.code()], [0, 0]]);
+        assert_eq!(&win[12..14], &[[0, KC::KBD_0.code()], [0, 0]]);
+        assert_eq!(win[14], [0, KC::KBD_ENTER.code()]);
+        assert_eq!(
+            keys(UnicodeMode::WinCompose, '\u{10ffff}').len(),
+            MAX_REPORTS
+        );
+
+        assert_eq!(UnicodeMode::from_u16(2), Some(UnicodeMode::WinCompose));
+        assert_eq!(UnicodeMode::from_u16(3), None);
+    }
+}
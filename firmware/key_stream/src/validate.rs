@@ -0,0 +1,406 @@
+//! Static checks of keymap files for mistakes which do not break the syntax.
+//! `build.rs`, which includes this file, fails on errors of `keymap.txt` and reports warnings.
+//!
+//! - A layer key should be the same key in the layer it activates.  Otherwise the layer is
+//!   entered by one key and the position means another while the layer is active.
+//! - Every layer should be reachable from the base layer by layer keys.
+//! - A modified key or a character at a combo position can be taken by the combo when typed
+//!   fast.
+//! - Combos must use keys of the keyboard, and each pair of keys can have only one combo.
+//! - Chords must use keys of the keyboard, and each set of keys can have only one chord.
+//! - Strings are typed by keys of the host layout or the Unicode input method, which have no
+//!   control characters but line breaks and tabs.
+//!
+//! Layers and combos of each keymap profile are checked on their own.
+
+use crate::grid::{grid_index, grid_pos, index_grid, pos_grid};
+use crate::host_layout::HostLayout;
+use crate::keymap_format::{
+    format_action, format_key, Action, Chord, Combo, KeymapFile, Layer, MODIFIERS,
+};
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Line in the keymap file.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", self.line, severity, self.message)
+    }
+}
+
+/// Layer activated by the modifier key, `MODn` activates layer n.
+fn target_layer(a: &Action) -> Option<usize> {
+    match *a {
+        Action::Modifier(i) | Action::ModTap(i, _) => {
+            MODIFIERS[i].strip_prefix("MOD")?.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+pub fn check(file: &KeymapFile) -> Vec<Diagnostic> {
+    let mut diags = Vec::new();
+    let profiles = file.profiles.iter().map(|p| (&p.layers, &p.combos));
+    for (layers, combos) in Some((&file.layers, &file.combos))
+        .into_iter()
+        .chain(profiles)
+    {
+        check_layer_keys(layers, &mut diags);
+        check_reachable(layers, &mut diags);
+        check_combos(layers, combos, &mut diags);
+    }
+    check_chords(file, &mut diags);
+    check_strings(file, &mut diags);
+    diags
+}
+
+pub fn has_error(diags: &[Diagnostic]) -> bool {
+    diags.iter().any(|d| d.severity == Severity::Error)
+}
+
+fn check_layer_keys(layers: &[Layer], diags: &mut Vec<Diagnostic>) {
+    for layer in layers.iter() {
+        for (i, a) in layer.keys.iter().enumerate() {
+            let n = match target_layer(a) {
+                Some(n) if n != layer.number => n,
+                _ => continue,
+            };
+            let target = match layers.iter().find(|l| l.number == n) {
+                Some(target) => target,
+                // Partial keymaps, e.g. uploaded by tools, may not have all layers.
+                None => continue,
+            };
+            if target.keys[i] != *a {
+                let (row, col) = index_grid(i);
+                diags.push(Diagnostic {
+                    severity: Severity::Warning,
+                    line: target.line,
+                    message: format!(
+                        "{} at {} of layer {} activates layer {}, where the key is {}",
+                        format_action(a),
+                        format_key(grid_pos(row, col)),
+                        layer.number,
+                        n,
+                        format_action(&target.keys[i])
+                    ),
+                });
+            }
+        }
+    }
+}
+
+fn check_reachable(layers: &[Layer], diags: &mut Vec<Diagnostic>) {
+    let mut reached = vec![0usize];
+    let mut i = 0;
+    while i < reached.len() {
+        if let Some(layer) = layers.iter().find(|l| l.number == reached[i]) {
+            for n in layer.keys.iter().filter_map(target_layer) {
+                if !reached.contains(&n) {
+                    reached.push(n);
+                }
+            }
+        }
+        i += 1;
+    }
+    if !layers.iter().any(|l| l.number == 0) {
+        return;
+    }
+    for layer in layers.iter().filter(|l| !reached.contains(&l.number)) {
+        diags.push(Diagnostic {
+            severity: Severity::Warning,
+            line: layer.line,
+            message: format!(
+                "layer {} is not reachable from layer 0 by layer keys",
+                layer.number
+            ),
+        });
+    }
+}
+
+fn check_combos(layers: &[Layer], combos: &[Combo], diags: &mut Vec<Diagnostic>) {
+    for (n, c) in combos.iter().enumerate() {
+        let mut error = |message| {
+            diags.push(Diagnostic {
+                severity: Severity::Error,
+                line: c.line,
+                message,
+            })
+        };
+        if c.k1 == c.k2 {
+            error(format!("combo of the same key {}", format_key(c.k1)));
+        }
+        for k in [c.k1, c.k2].iter().filter(|k| pos_grid(**k).is_none()) {
+            error(format!(
+                "combo key {} is not a key of the keyboard",
+                format_key(*k)
+            ));
+        }
+        let same = |o: &&Combo| (o.k1, o.k2) == (c.k1, c.k2) || (o.k1, o.k2) == (c.k2, c.k1);
+        if let Some(first) = combos[..n].iter().find(same) {
+            error(format!(
+                "combo {} {} is already defined at line {}, and never activated",
+                format_key(c.k1),
+                format_key(c.k2),
+                first.line
+            ));
+            continue;
+        }
+
+        for layer in layers.iter() {
+            for k in [c.k1, c.k2].iter() {
+                let a = match pos_grid(*k) {
+                    Some((row, col)) => layer.keys[grid_index(row, col)],
+                    None => continue,
+                };
+                if is_modified(&a) {
+                    diags.push(Diagnostic {
+                        severity: Severity::Warning,
+                        line: layer.line,
+                        message: format!(
+                            "{} at {} of layer {} is taken by the combo at line {} when typed with {}",
+                            format_action(&a),
+                            format_key(*k),
+                            layer.number,
+                            c.line,
+                            format_key(if *k == c.k1 { c.k2 } else { c.k1 })
+                        ),
+                    });
+                }
+            }
+        }
+    }
+}
+
+fn check_chords(file: &KeymapFile, diags: &mut Vec<Diagnostic>) {
+    for (n, c) in file.chords.iter().enumerate() {
+        let mut error = |message| {
+            diags.push(Diagnostic {
+                severity: Severity::Error,
+                line: c.line,
+                message,
+            })
+        };
+        for k in c.keys.iter().filter(|k| pos_grid(**k).is_none()) {
+            error(format!(
+                "chord key {} is not a key of the keyboard",
+                format_key(*k)
+            ));
+        }
+        let same =
+            |o: &&Chord| o.keys.len() == c.keys.len() && o.keys.iter().all(|k| c.keys.contains(k));
+        if let Some(first) = file.chords[..n].iter().find(same) {
+            let keys: Vec<String> = c.keys.iter().map(|k| format_key(*k)).collect();
+            error(format!(
+                "chord {} is already defined at line {}, and never typed",
+                keys.join(" "),
+                first.line
+            ));
+        }
+    }
+}
+
+fn check_strings(file: &KeymapFile, diags: &mut Vec<Diagnostic>) {
+    for (i, m) in file.macros.iter().enumerate() {
+        let index = match m.action {
+            // Later macros of the same string are aliases.
+            Action::SendString(index) if !file.macros[..i].iter().any(|n| n.action == m.action) => {
+                index
+            }
+            _ => continue,
+        };
+        let mut skipped = String::new();
+        for c in file.strings[index].chars() {
+            let typed = !c.is_ascii()
+                || HostLayout::ALL
+                    .iter()
+                    .all(|l| l.string_key(c as u8).is_some());
+            if !typed && !skipped.contains(c) {
+                skipped.push(c);
+            }
+        }
+        if !skipped.is_empty() {
+            diags.push(Diagnostic {
+                severity: Severity::Warning,
+                line: m.line,
+                message: format!(
+                    "{} has characters which are not typed: {}",
+                    m.name,
+                    skipped.escape_debug()
+                ),
+            });
+        }
+    }
+}
+
+/// True if the key is typed with modifiers, on any host layout for characters.
+fn is_modified(a: &Action) -> bool {
+    match *a {
+        Action::ModifiedKey(..) => true,
+        Action::Char(mods, c) => {
+            mods != 0
+                || HostLayout::ALL
+                    .iter()
+                    .any(|l| matches!(l.char_key(c), Some((true, _))))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap_format::parse;
+
+    const EMPTY_ROWS: &str = "_ _ _ _ _ _  _ _ _ _ _ _\n_ _ _ _ _ _  _ _ _ _ _ _\n";
+
+    fn layer(n: usize, row1: &str, row4: &str) -> String {
+        format!("[layer {}]\n{}\n{}{}\n", n, row1, EMPTY_ROWS, row4)
+    }
+
+    fn messages(text: &str) -> Vec<(Severity, usize, String)> {
+        check(&parse(text).unwrap())
+            .into_iter()
+            .map(|d| (d.severity, d.line, d.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_builtin_keymap() {
+        let diags = check(&parse(include_str!("../keymap.txt")).unwrap());
+        assert!(!has_error(&diags), "{:?}", diags);
+    }
+
+    #[test]
+    fn test_layer_keys() {
+        let row = "_ _ _ _ _ _  _ _ _ _ _ _";
+        let text = layer(0, "MOD2 _ _ _ _ _  _ _ _ _ _ _", row)
+            + &layer(2, "SHIFT1 _ _ _ _ _  _ _ _ _ _ _", row)
+            + &layer(3, row, row);
+        assert_eq!(
+            messages(&text),
+            vec![
+                (
+                    Severity::Warning,
+                    6,
+                    "MOD2 at R1C1 of layer 0 activates layer 2, where the key is SHIFT1"
+                        .to_string()
+                ),
+                (
+                    Severity::Warning,
+                    11,
+                    "layer 3 is not reachable from layer 0 by layer keys".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_combos() {
+        let row = "_ _ _ _ _ _  _ _ _ _ _ _";
+        let text = layer(0, "SHIFT1+1 _ _ _ _ _  _ _ _ _ _ _", row)
+            + "[combos]\nR1C1 R1C2 ENTER\nR1C2 R1C1 TAB\nR1C3 0x55 ESCAPE\nR1C4 R1C4 A\n";
+        let m = messages(&text);
+        assert_eq!(
+            m[0],
+            (
+                Severity::Warning,
+                1,
+                "SHIFT1+1 at R1C1 of layer 0 is taken by the combo at line 7 when typed with R1C2"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            m[1],
+            (
+                Severity::Error,
+                8,
+                "combo R1C2 R1C1 is already defined at line 7, and never activated".to_string()
+            )
+        );
+        assert_eq!(
+            m[2],
+            (
+                Severity::Error,
+                9,
+                "combo key 0x55 is not a key of the keyboard".to_string()
+            )
+        );
+        assert_eq!(
+            m[3],
+            (
+                Severity::Error,
+                10,
+                "combo of the same key R1C4".to_string()
+            )
+        );
+        assert_eq!(m.len(), 4);
+    }
+
+    #[test]
+    fn test_profiles() {
+        let row = "_ _ _ _ _ _  _ _ _ _ _ _";
+        // Layer 2 is reachable in profile 0, but not in profile 1.
+        let text = layer(0, "MOD2 _ _ _ _ _  _ _ _ _ _ _", row)
+            + &layer(2, "MOD2 _ _ _ _ _  _ _ _ _ _ _", row)
+            + "[profile 1]\n"
+            + &layer(0, row, row)
+            + &layer(2, row, row);
+        assert_eq!(
+            messages(&text),
+            vec![(
+                Severity::Warning,
+                17,
+                "layer 2 is not reachable from layer 0 by layer keys".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_chords() {
+        let text = "[chords]\nR2C4 R2C8 \"ga\"\nR2C8 R2C4 \"gi\"\n0x55 \"a\"\n";
+        assert_eq!(
+            messages(text),
+            vec![
+                (
+                    Severity::Error,
+                    3,
+                    "chord R2C8 R2C4 is already defined at line 2, and never typed".to_string()
+                ),
+                (
+                    Severity::Error,
+                    4,
+                    "chord key 0x55 is not a key of the keyboard".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        let text = "[macros]\nMAIL = \"a@b.c\\n\"\nNAME = \"Tarō\u{7}\"\nALIAS = NAME\n";
+        assert_eq!(
+            messages(text),
+            vec![(
+                Severity::Warning,
+                3,
+                "NAME has characters which are not typed: \\u{7}".to_string()
+            )]
+        );
+    }
+}
@@ -1,6 +1,12 @@
 MEMORY
 {
-  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 64K
+  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 56K
+  /* Rest of the keymap override after its first page in KEYMAP.  See `flash.rs`. */
+  KEYMAP_EXT (r) : ORIGIN = 0x0800E000, LENGTH = 2K
+  /* Settings store, two pages.  See `flash.rs`. */
+  SETTINGS (r) : ORIGIN = 0x0800E800, LENGTH = 4K
+  /* Keymap override written at runtime, first page.  See `flash.rs`. */
+  KEYMAP (r) : ORIGIN = 0x0800F800, LENGTH = 2K
   RAM  (rwx) : ORIGIN = 0x20000000, LENGTH =  32K
   SRAM2 (rw) : ORIGIN = 0x10000000, LENGTH =  8K
 }
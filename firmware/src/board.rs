@@ -0,0 +1,20 @@
+//! USB descriptors of the board, generated by `build.rs` from `boards/<BOARD>.txt`.
+
+include!(concat!(env!("OUT_DIR"), "/board.rs"));
+
+/// 96-bit unique ID of STM32L4.
+const UID_ADDRESS: usize = 0x1FFF_7590;
+const UID_SIZE: usize = 12;
+pub const SERIAL_SIZE: usize = UID_SIZE * 2;
+
+/// Serial number of the USB device: the unique ID of the chip in hex, so that hosts tell two
+/// keyboards of the same board apart.
+pub fn serial_number(buf: &'static mut [u8; SERIAL_SIZE]) -> &'static str {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    for i in 0..UID_SIZE {
+        let b = unsafe { core::ptr::read_volatile((UID_ADDRESS + i) as *const u8) };
+        buf[i * 2] = HEX[(b >> 4) as usize];
+        buf[i * 2 + 1] = HEX[(b & 0x0f) as usize];
+    }
+    core::str::from_utf8(buf).unwrap()
+}
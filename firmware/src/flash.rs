@@ -0,0 +1,181 @@
+// Program on-chip flash to store data across power cycles.
+// RM0394 3.3.6 Flash main memory erase sequences, 3.3.7 Flash main memory programming sequences.
+use core::ptr;
+use key_stream::settings::{self, FlashPage};
+use key_stream::storage::IMAGE_SIZE;
+use stm32l4xx_hal::stm32;
+
+const KEY1: u32 = 0x45670123;
+const KEY2: u32 = 0xCDEF89AB;
+
+const FLASH_BASE: usize = 0x0800_0000;
+const PAGE_SIZE: usize = 2048;
+/// Must match KEYMAP in memory.x.  Images of up to one page, written by older firmware, are
+/// found here, so the address is fixed and larger images continue in KEYMAP_EXT.
+const KEYMAP_ADDRESS: usize = 0x0800_F800;
+/// Must match KEYMAP_EXT in memory.x.
+const KEYMAP_EXT_ADDRESS: usize = 0x0800_E000;
+/// Must match SETTINGS in memory.x.
+const SETTINGS_ADDRESS: usize = 0x0800_E800;
+/// Bytes of the image in the KEYMAP page, and in the KEYMAP_EXT page.
+const KEYMAP_SPLIT: usize = if IMAGE_SIZE < PAGE_SIZE {
+    IMAGE_SIZE
+} else {
+    PAGE_SIZE
+};
+
+const _: () = assert!(
+    IMAGE_SIZE <= 2 * PAGE_SIZE,
+    "keymap image overflows KEYMAP and KEYMAP_EXT"
+);
+
+// EOP, OPERR, PROGERR, WRPERR, PGAERR, SIZERR, PGSERR, MISERR, FASTERR, RDERR, OPTVERR
+const SR_CLEAR_MASK: u32 = 0xC3FB;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Flash controller reported error.  Raw value of FLASH_SR.
+    Status(u32),
+}
+
+/// Stored keymap image, the KEYMAP page followed by the KEYMAP_EXT page.  Erased flash reads
+/// as 0xff.
+pub fn read_keymap(image: &mut [u8; IMAGE_SIZE]) {
+    let (first, rest) = image.split_at_mut(KEYMAP_SPLIT);
+    first.copy_from_slice(unsafe {
+        core::slice::from_raw_parts(KEYMAP_ADDRESS as *const u8, first.len())
+    });
+    rest.copy_from_slice(unsafe {
+        core::slice::from_raw_parts(KEYMAP_EXT_ADDRESS as *const u8, rest.len())
+    });
+}
+
+pub fn write_keymap(image: &[u8; IMAGE_SIZE]) -> Result<(), Error> {
+    let (first, rest) = image.split_at(KEYMAP_SPLIT);
+    unlock();
+    // The header is in the first page, so it is written last.
+    let result = erase_keymap_pages()
+        .and_then(|_| program(KEYMAP_EXT_ADDRESS, rest))
+        .and_then(|_| program(KEYMAP_ADDRESS, first));
+    lock();
+    result
+}
+
+pub fn erase_keymap() -> Result<(), Error> {
+    unlock();
+    let result = erase_keymap_pages();
+    lock();
+    result
+}
+
+fn erase_keymap_pages() -> Result<(), Error> {
+    erase_page(KEYMAP_ADDRESS)?;
+    erase_page(KEYMAP_EXT_ADDRESS)
+}
+
+/// Flash page used by the settings store.
+pub struct Page {
+    address: usize,
+}
+
+/// The two pages of SETTINGS region.
+pub fn settings_pages() -> [Page; 2] {
+    [
+        Page {
+            address: SETTINGS_ADDRESS,
+        },
+        Page {
+            address: SETTINGS_ADDRESS + PAGE_SIZE,
+        },
+    ]
+}
+
+impl FlashPage for Page {
+    fn size(&self) -> usize {
+        PAGE_SIZE
+    }
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        let src = unsafe {
+            core::slice::from_raw_parts((self.address + offset) as *const u8, buf.len())
+        };
+        buf.copy_from_slice(src);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), settings::Error> {
+        unlock();
+        let result = program(self.address + offset, data);
+        lock();
+        result.map_err(|_| settings::Error::Flash)
+    }
+
+    fn erase(&mut self) -> Result<(), settings::Error> {
+        unlock();
+        let result = erase_page(self.address);
+        lock();
+        result.map_err(|_| settings::Error::Flash)
+    }
+}
+
+fn flash() -> &'static stm32::flash::RegisterBlock {
+    unsafe { &(*stm32::FLASH::ptr()) }
+}
+
+fn unlock() {
+    let flash = flash();
+    if flash.cr.read().lock().bit_is_set() {
+        flash.keyr.write(|w| unsafe { w.keyr().bits(KEY1) });
+        flash.keyr.write(|w| unsafe { w.keyr().bits(KEY2) });
+    }
+}
+
+fn lock() {
+    flash().cr.modify(|_, w| w.lock().set_bit());
+}
+
+fn wait() -> Result<(), Error> {
+    let flash = flash();
+    while flash.sr.read().bsy().bit_is_set() {}
+    let sr = flash.sr.read().bits();
+    // Clear all flags, including EOP.
+    flash.sr.write(|w| unsafe { w.bits(SR_CLEAR_MASK) });
+    if sr & SR_CLEAR_MASK & !1 != 0 {
+        Err(Error::Status(sr))
+    } else {
+        Ok(())
+    }
+}
+
+fn erase_page(address: usize) -> Result<(), Error> {
+    let flash = flash();
+    wait()?;
+    let page = ((address - FLASH_BASE) / PAGE_SIZE) as u8;
+    flash
+        .cr
+        .modify(|_, w| unsafe { w.per().set_bit().pnb().bits(page) });
+    flash.cr.modify(|_, w| w.strt().set_bit());
+    let result = wait();
+    flash.cr.modify(|_, w| w.per().clear_bit());
+    result
+}
+
+/// Program data by double words.  `data.len()` must be a multiple of 8.
+fn program(address: usize, data: &[u8]) -> Result<(), Error> {
+    let flash = flash();
+    wait()?;
+    flash.cr.modify(|_, w| w.pg().set_bit());
+    let mut result = Ok(());
+    for (i, dw) in data.chunks(8).enumerate() {
+        let dst = (address + i * 8) as *mut u32;
+        unsafe {
+            ptr::write_volatile(dst, u32::from_le_bytes([dw[0], dw[1], dw[2], dw[3]]));
+            ptr::write_volatile(dst.add(1), u32::from_le_bytes([dw[4], dw[5], dw[6], dw[7]]));
+        }
+        result = wait();
+        if result.is_err() {
+            break;
+        }
+    }
+    flash.cr.modify(|_, w| w.pg().clear_bit());
+    result
+}
@@ -1,3 +1,5 @@
+use key_stream::os_detect::Trace;
+use key_stream::protocol::PACKET_SIZE;
 use usb_device::class_prelude::*;
 use usb_device::Result;
 
@@ -31,12 +33,24 @@ const REPORT_DESCR: &[u8] = &[
     0x05, 0x01, 0x09, 0x06, 0xA1, 0x01, 0x05, 0x07, 0x19, 0xE0, 0x29, 0xE7, 0x15, 0x00, 0x25, 0x01,
     0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x05, 0x75, 0x01,
     0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x03, 0x95, 0x01, 0x75, 0x03, 0x91, 0x01, 0x95, 0x06,
-    0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xC0,
+    // Key codes up to 0xE7, the whole Keyboard/Keypad page.  The logical maximum is a 2-byte
+    // item since a 1-byte 0xE7 is negative.
+    0x75, 0x08, 0x15, 0x00, 0x26, 0xE7, 0x00, 0x05, 0x07, 0x19, 0x00, 0x29, 0xE7, 0x81, 0x00, 0xC0,
+];
+
+// Vendor defined usage page 0xFF60, usage 0x61, as the interface of raw HID.
+// Input and output reports are PACKET_SIZE bytes without report ID.
+const RAW_REPORT_DESCR: &[u8] = &[
+    0x06, 0x60, 0xFF, 0x09, 0x61, 0xA1, 0x01, 0x09, 0x62, 0x15, 0x00, 0x26, 0xFF, 0x00, 0x95,
+    PACKET_SIZE as u8, 0x75, 0x08, 0x81, 0x02, 0x09, 0x63, 0x15, 0x00, 0x26, 0xFF, 0x00, 0x95,
+    PACKET_SIZE as u8, 0x75, 0x08, 0x91, 0x02, 0xC0,
 ];
 
 pub struct HIDClass<'a, B: UsbBus> {
     report_if: InterfaceNumber,
     report_ep: EndpointIn<'a, B>,
+    /// Descriptors requested by the host since the last bus reset, to detect its OS.
+    trace: Trace,
 }
 
 impl<B: UsbBus> HIDClass<'_, B> {
@@ -46,12 +60,17 @@ impl<B: UsbBus> HIDClass<'_, B> {
         HIDClass {
             report_if: alloc.interface(),
             report_ep: alloc.interrupt(8, 10),
+            trace: Trace::new(),
         }
     }
 
     pub fn write(&mut self, data: &[u8]) -> Result<usize> {
         self.report_ep.write(data)
     }
+
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
 }
 
 impl<B: UsbBus> UsbClass<B> for HIDClass<'_, B> {
@@ -82,12 +101,24 @@ impl<B: UsbBus> UsbClass<B> for HIDClass<'_, B> {
         Ok(())
     }
 
+    fn reset(&mut self) {
+        self.trace.clear();
+    }
+
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let req = xfer.request();
 
         if req.request_type == control::RequestType::Standard {
             match (req.recipient, req.request) {
-                (control::Recipient::Interface, control::Request::GET_DESCRIPTOR) => {
+                // Classes see the requests before the device answers them.
+                (control::Recipient::Device, control::Request::GET_DESCRIPTOR) => {
+                    let (dtype, index) = req.descriptor_type_index();
+                    self.trace.push(dtype, index, req.length);
+                    return;
+                }
+                (control::Recipient::Interface, control::Request::GET_DESCRIPTOR)
+                    if req.index == u8::from(self.report_if) as u16 =>
+                {
                     let (dtype, _index) = req.descriptor_type_index();
                     if dtype == 0x21 {
                         // HID descriptor
@@ -143,6 +174,14 @@ impl<B: UsbBus> UsbClass<B> for HIDClass<'_, B> {
     fn control_out(&mut self, xfer: ControlOut<B>) {
         let req = xfer.request();
 
+        if req.request_type == control::RequestType::Standard
+            && req.recipient == control::Recipient::Device
+            && req.request == control::Request::SET_CONFIGURATION
+        {
+            self.trace.set_configured();
+            return;
+        }
+
         if !(req.request_type == control::RequestType::Class
             && req.recipient == control::Recipient::Interface
             && req.index == u8::from(self.report_if) as u16)
@@ -153,3 +192,126 @@ impl<B: UsbBus> UsbClass<B> for HIDClass<'_, B> {
         xfer.reject().ok();
     }
 }
+
+/// Vendor defined HID interface to configure the keyboard from the host.
+/// See `key_stream::protocol` for the packet format.
+pub struct RawHIDClass<'a, B: UsbBus> {
+    report_if: InterfaceNumber,
+    in_ep: EndpointIn<'a, B>,
+    out_ep: EndpointOut<'a, B>,
+    request: [u8; PACKET_SIZE],
+    has_request: bool,
+}
+
+impl<B: UsbBus> RawHIDClass<'_, B> {
+    pub fn new(alloc: &UsbBusAllocator<B>) -> RawHIDClass<'_, B> {
+        RawHIDClass {
+            report_if: alloc.interface(),
+            in_ep: alloc.interrupt(PACKET_SIZE as u16, 1),
+            out_ep: alloc.interrupt(PACKET_SIZE as u16, 1),
+            request: [0; PACKET_SIZE],
+            has_request: false,
+        }
+    }
+
+    /// Take the request received from the host, if any.
+    pub fn take_request(&mut self) -> Option<[u8; PACKET_SIZE]> {
+        if self.has_request {
+            self.has_request = false;
+            Some(self.request)
+        } else {
+            None
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8; PACKET_SIZE]) -> Result<usize> {
+        self.in_ep.write(data)
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for RawHIDClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.report_if,
+            USB_CLASS_HID,
+            USB_SUBCLASS_NONE,
+            USB_INTERFACE_NONE,
+        )?;
+
+        let descr_len: u16 = RAW_REPORT_DESCR.len() as u16;
+        writer.write(
+            0x21,
+            &[
+                0x01,                   // bcdHID
+                0x01,                   // bcdHID
+                0x00,                   // bContryCode
+                0x01,                   // bNumDescriptors
+                0x22,                   // bDescriptorType
+                descr_len as u8,        // wDescriptorLength
+                (descr_len >> 8) as u8, // wDescriptorLength
+            ],
+        )?;
+
+        writer.endpoint(&self.in_ep)?;
+        writer.endpoint(&self.out_ep)?;
+
+        Ok(())
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.out_ep.address() {
+            return;
+        }
+        // A request which is not taken yet is overwritten.
+        if let Ok(PACKET_SIZE) = self.out_ep.read(&mut self.request) {
+            self.has_request = true;
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+
+        if !(req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.report_if) as u16)
+        {
+            return;
+        }
+
+        if req.request_type == control::RequestType::Standard
+            && req.request == control::Request::GET_DESCRIPTOR
+        {
+            let (dtype, _index) = req.descriptor_type_index();
+            if dtype == 0x22 {
+                xfer.accept_with(RAW_REPORT_DESCR).ok();
+            }
+            return;
+        }
+
+        if req.request_type == control::RequestType::Class && req.request == REQ_GET_REPORT {
+            xfer.accept_with(&[0u8; PACKET_SIZE]).ok();
+        } else if req.request_type == control::RequestType::Class {
+            xfer.reject().ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = xfer.request();
+
+        if !(req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.report_if) as u16)
+        {
+            return;
+        }
+
+        match req.request {
+            // Some hosts send SET_IDLE to every HID interface.
+            REQ_SET_IDLE => {
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+}
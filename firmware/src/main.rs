@@ -17,17 +17,22 @@ use stm32l4xx_hal::usb::{Peripheral, UsbBus, UsbBusType};
 use stm32l4xx_hal::{prelude::*, stm32};
 use usb_device::bus;
 use usb_device::prelude::*;
+use usbd_serial::SerialPort;
 
 use crate::i2c_slave::I2CSlave;
 use direct_drive::Switches;
-use hid::HIDClass;
+use hid::{HIDClass, RawHIDClass};
 use key_stream::ring_buffer::RingBuffer;
-use key_stream::KeyStream;
+use key_stream::settings::SettingsStore;
+use key_stream::storage::IMAGE_SIZE;
+use key_stream::{KeyStream, StorageRequest};
 use peer::Peer;
 use stm32l4xx_hal::gpio::{Alternate, OpenDrain, Output, PA10, PA9};
 use stm32l4xx_hal::rcc::{PllConfig, PllDivider, APB1R1};
 
+mod board;
 mod direct_drive;
+mod flash;
 mod hid;
 // mod matrix;
 mod i2c_slave;
@@ -35,7 +40,7 @@ mod peer;
 mod reset;
 
 // Do not change CLOCK while using STM32L412.
-const CLOCK: u32 = 48; // MHz
+const CLOCK: u32 = key_stream::CLOCK_KHZ / 1000; // MHz
 const READ_PERIOD: u32 = CLOCK * 1000; // about 1ms
 const TRANSFORM_PERIOD: u32 = READ_PERIOD * 15; // about 15ms
 const SEND_PERIOD: u32 = READ_PERIOD; // 1ms
@@ -46,10 +51,13 @@ const APP: () = {
     struct Resources {
         usb_dev: Option<UsbDevice<'static, UsbBusType>>,
         hid: Option<HIDClass<'static, UsbBusType>>,
+        raw_hid: Option<RawHIDClass<'static, UsbBusType>>,
+        serial: Option<SerialPort<'static, UsbBusType>>,
         stream: KeyStream,
         switches: Switches,
         peer: Option<Peer>,
         report_buffer: RingBuffer<[u8; 8]>,
+        settings: Option<SettingsStore<flash::Page>>,
         slave: Option<
             I2CSlave<
                 PA9<Alternate<stm32l4xx_hal::gpio::AF4, Output<OpenDrain>>>,
@@ -62,6 +70,7 @@ const APP: () = {
     #[init(schedule = [read_loop, transform_loop, send_loop, slave_loop])]
     fn init(mut cx: init::Context) -> init::LateResources {
         static mut USB_BUS: Option<bus::UsbBusAllocator<UsbBusType>> = None;
+        static mut SERIAL_NUMBER: [u8; board::SERIAL_SIZE] = [0; board::SERIAL_SIZE];
 
         cx.core.DCB.enable_trace();
         DWT::unlock();
@@ -85,7 +94,18 @@ const APP: () = {
 
         enable_crs();
 
-        let stream = KeyStream::new();
+        let mut stream = KeyStream::new();
+        // Fall back to the compiled-in keymap if no valid keymap is stored.
+        let mut image = [0u8; IMAGE_SIZE];
+        flash::read_keymap(&mut image);
+        stream.load_keymap(&image).ok();
+        // Saved settings are lost if the pages cannot be opened, but later changes are kept.
+        let settings = SettingsStore::open(flash::settings_pages())
+            .or_else(|_| SettingsStore::format(flash::settings_pages()))
+            .ok();
+        if let Some(ref store) = settings {
+            stream.load_settings(store);
+        }
         let switches = Switches::new(
             gpiob
                 .pb8
@@ -191,13 +211,19 @@ const APP: () = {
             *USB_BUS = Some(UsbBus::new(usb));
 
             let hid = HIDClass::new(USB_BUS.as_ref().unwrap());
+            let raw_hid = RawHIDClass::new(USB_BUS.as_ref().unwrap());
+            let serial = SerialPort::new(USB_BUS.as_ref().unwrap());
 
+            // Miscellaneous class with interface association descriptors, which group the
+            // two interfaces of the serial port.
             let usb_dev =
-                UsbDeviceBuilder::new(USB_BUS.as_ref().unwrap(), UsbVidPid(0xc410, 0x0000))
-                    .manufacturer("tomykaira")
-                    .product("FLAT7")
-                    .serial_number("TEST")
-                    .device_class(0)
+                UsbDeviceBuilder::new(USB_BUS.as_ref().unwrap(), UsbVidPid(board::VID, board::PID))
+                    .manufacturer(board::MANUFACTURER)
+                    .product(board::PRODUCT)
+                    .serial_number(board::serial_number(SERIAL_NUMBER))
+                    .device_class(0xef)
+                    .device_sub_class(0x02)
+                    .device_protocol(0x01)
                     .build();
             let i2c = I2c::i2c1(
                 cx.device.I2C1,
@@ -216,10 +242,13 @@ const APP: () = {
             init::LateResources {
                 usb_dev: Some(usb_dev),
                 hid: Some(hid),
+                raw_hid: Some(raw_hid),
+                serial: Some(serial),
                 stream,
                 switches,
                 peer: Some(Peer::new(i2c)),
                 report_buffer: RingBuffer::new([0; 8]),
+                settings,
                 slave: None,
                 apb1: rcc.apb1r1,
             }
@@ -238,10 +267,13 @@ const APP: () = {
             init::LateResources {
                 usb_dev: None,
                 hid: None,
+                raw_hid: None,
+                serial: None,
                 stream,
                 switches,
                 peer: None,
                 report_buffer: RingBuffer::new([0; 8]),
+                settings,
                 slave: Some(slave),
                 apb1: rcc.apb1r1,
             }
@@ -295,7 +327,7 @@ const APP: () = {
         }
     }
 
-    #[task(schedule = [transform_loop], resources = [stream, report_buffer], priority = 1)]
+    #[task(schedule = [transform_loop], resources = [stream, report_buffer, hid, raw_hid, serial, settings], priority = 1)]
     fn transform_loop(mut cx: transform_loop::Context) {
         cx.schedule
             .transform_loop(Instant::now() + TRANSFORM_PERIOD.cycles())
@@ -303,16 +335,85 @@ const APP: () = {
 
         let stream = &mut cx.resources.stream;
         let report_buffer = &mut cx.resources.report_buffer;
+        let hid = &mut cx.resources.hid;
+        let raw_hid = &mut cx.resources.raw_hid;
+        let serial = &mut cx.resources.serial;
+
+        if let Some(trace) = hid.lock(|h| h.as_ref().map(|h| *h.trace())) {
+            stream.watch_usb_trace(&trace, DWT::get_cycle_count());
+        }
+
+        let mut buf = [0u8; 64];
+        if let Some(Ok(n)) = serial.lock(|s| s.as_mut().map(|s| s.read(&mut buf))) {
+            stream.console_input(&buf[..n]);
+        }
+
+        if let Some(req) = raw_hid.lock(|r| r.as_mut().and_then(|r| r.take_request())) {
+            let res = stream.handle_request(&req);
+            raw_hid.lock(|r| r.as_mut().unwrap().write(&res)).ok();
+        }
+
+        match stream.take_storage_request() {
+            Some(StorageRequest::Save) => {
+                flash::write_keymap(&stream.keymap_image()).ok();
+            }
+            Some(StorageRequest::Erase) => {
+                flash::erase_keymap().ok();
+            }
+            None => {}
+        }
+        let settings = &mut *cx.resources.settings;
+        if settings.is_none() && stream.settings_changed() {
+            *settings = SettingsStore::format(flash::settings_pages()).ok();
+        }
+        // Settings which failed to be written are written again in the next loop.
+        if let Some(store) = settings.as_mut() {
+            stream.save_settings(store).ok();
+        }
 
         stream.read(DWT::get_cycle_count(), |k| {
             report_buffer.push(&k);
         });
 
+        // Packets are written whole, the rest of one after a short write is kept for the next
+        // loop.  Strokes are dropped while no host opens the port.
+        loop {
+            let output = stream.steno_output();
+            if output.is_empty() {
+                break;
+            }
+            let written = serial.lock(|s| {
+                s.as_mut().map(|s| {
+                    if s.dtr() {
+                        s.write(output)
+                    } else {
+                        Ok(output.len())
+                    }
+                })
+            });
+            match written {
+                Some(Ok(n)) if n > 0 => stream.consume_steno_output(n),
+                _ => break,
+            }
+        }
+
+        let output = stream.console_output();
+        if !output.is_empty() {
+            if let Some(Ok(n)) = serial.lock(|s| s.as_mut().map(|s| s.write(output))) {
+                stream.consume_console_output(n);
+            }
+        }
+
         if stream.requests_reset() {
             unsafe {
                 reset::reset();
             }
         }
+        if stream.requests_bootloader() {
+            unsafe {
+                reset::bootloader();
+            }
+        }
     }
 
     #[task(schedule = [send_loop], resources = [hid, report_buffer], priority = 1)]
@@ -334,11 +435,13 @@ const APP: () = {
         }
     }
 
-    #[task(binds=USB, resources = [usb_dev, hid], priority = 2)]
+    #[task(binds=USB, resources = [usb_dev, hid, raw_hid, serial], priority = 2)]
     fn usb_tx(cx: usb_tx::Context) {
         usb_poll(
             &mut cx.resources.usb_dev.as_mut().unwrap(),
             &mut cx.resources.hid.as_mut().unwrap(),
+            &mut cx.resources.raw_hid.as_mut().unwrap(),
+            &mut cx.resources.serial.as_mut().unwrap(),
         );
     }
 
@@ -347,10 +450,14 @@ const APP: () = {
     }
 };
 
-fn usb_poll<B: bus::UsbBus>(usb_dev: &mut UsbDevice<'static, B>, hid: &mut HIDClass<'static, B>) {
-    if !usb_dev.poll(&mut [hid]) {
-        return;
-    }
+fn usb_poll<B: bus::UsbBus>(
+    usb_dev: &mut UsbDevice<'static, B>,
+    hid: &mut HIDClass<'static, B>,
+    raw_hid: &mut RawHIDClass<'static, B>,
+    serial: &mut SerialPort<'static, B>,
+) {
+    // Bytes from the host are read by the console in `transform_loop`.
+    usb_dev.poll(&mut [hid, raw_hid, serial]);
 }
 
 fn enable_crs() {
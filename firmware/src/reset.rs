@@ -1,18 +1,57 @@
+use core::mem::MaybeUninit;
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::pre_init;
 use stm32l4::stm32l4x2::Peripherals;
 use stm32l4xx_hal::hal::watchdog::WatchdogEnable;
 use stm32l4xx_hal::time::MilliSeconds;
 use stm32l4xx_hal::watchdog::IndependentWatchdog;
 
-const KEY1: u32 = 0x45670123;
-const KEY2: u32 = 0xCDEF89AB;
-const OPTKEY1: u32 = 0x08192A3B;
-const OPTKEY2: u32 = 0x4C5D6E7F;
+/// System memory of STM32L4, which holds the bootloader.
+const SYSTEM_MEMORY: u32 = 0x1FFF_0000;
 
 pub unsafe fn reset() {
     // Restart by watchdog.
+    let p = Peripherals::steal();
     let mut wd = IndependentWatchdog::new(p.IWDG);
     wd.start(MilliSeconds(1));
 
     // Wait asynchronous reset.
     loop {}
 }
+
+/// Set before a reset to enter the bootloader. Not initialized at startup, so
+/// the value survives the reset.
+#[link_section = ".uninit.BOOTLOADER_REQUEST"]
+static mut BOOTLOADER_REQUEST: MaybeUninit<u32> = MaybeUninit::uninit();
+
+const BOOTLOADER_MAGIC: u32 = 0xB007_10AD;
+
+/// Restart into the system bootloader, which flashes a new firmware over USB DFU.
+///
+/// The jump is made by `enter_bootloader` right after the reset, so that the
+/// bootloader starts with SysTick, NVIC, USB and clocks in their reset state.
+pub unsafe fn bootloader() -> ! {
+    BOOTLOADER_REQUEST
+        .as_mut_ptr()
+        .write_volatile(BOOTLOADER_MAGIC);
+    SCB::sys_reset()
+}
+
+/// Jump to the system bootloader if `bootloader` requested it before the reset.
+#[pre_init]
+unsafe fn enter_bootloader() {
+    if BOOTLOADER_REQUEST.as_ptr().read_volatile() != BOOTLOADER_MAGIC {
+        return;
+    }
+    BOOTLOADER_REQUEST.as_mut_ptr().write_volatile(0);
+
+    let p = Peripherals::steal();
+    // Map the system memory at 0 as the BOOT0 pin does.
+    p.RCC.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+    p.SYSCFG.memrmp.modify(|_, w| w.mem_mode().bits(0b001));
+
+    let vectors = SYSTEM_MEMORY as *const u32;
+    cortex_m::register::msp::write(*vectors);
+    let entry: extern "C" fn() -> ! = core::mem::transmute(*vectors.offset(1) as usize);
+    entry()
+}
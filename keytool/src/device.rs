@@ -0,0 +1,300 @@
+//! Connection to the keyboard, or to a simulated one, over the raw HID protocol.
+
+use key_stream::chord::Chord;
+use key_stream::keymap::{Command, Pos};
+use key_stream::protocol::{
+    read_u16, Request, PACKET_SIZE, SHELL_CHUNK, SHELL_LINE_SIZE, STRINGS_CHUNK,
+};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+/// Usage page of the raw HID interface, as the first item of its report descriptor.
+const RAW_USAGE_PAGE_ITEM: [u8; 3] = [0x06, 0x60, 0xff];
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// No keyboard is found.
+    NotFound,
+    /// Response to another request.
+    Mismatch,
+    /// Error status returned by the device.
+    Status(u8),
+    /// No hidraw on this platform.
+    Unsupported,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::NotFound => write!(f, "keyboard not found; specify --device or --sim"),
+            Error::Mismatch => write!(f, "unexpected response from the device"),
+            Error::Status(1) => write!(f, "device does not know the request"),
+            Error::Status(2) => write!(f, "value out of range"),
+            Error::Status(3) => write!(f, "invalid command"),
+            Error::Status(s) => write!(f, "device returned error {}", s),
+            Error::Unsupported => write!(
+                f,
+                "keytool reaches the keyboard only through Linux hidraw; use --sim on other platforms"
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// Exchange of one request packet and one response packet.
+pub trait Transport {
+    fn transact(&mut self, req: &[u8; PACKET_SIZE]) -> io::Result<[u8; PACKET_SIZE]>;
+}
+
+/// Whether `Hidraw` works on this platform; the keyboard is reached only on Linux.
+pub const HIDRAW_SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// Linux hidraw device node.
+pub struct Hidraw {
+    file: File,
+}
+
+impl Hidraw {
+    pub fn open(path: &Path) -> io::Result<Hidraw> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Hidraw { file })
+    }
+
+    /// Find the raw HID interface of the keyboard by its report descriptor.
+    pub fn find() -> Option<PathBuf> {
+        let mut nodes: Vec<_> = fs::read_dir("/sys/class/hidraw")
+            .ok()?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name())
+            .collect();
+        nodes.sort();
+        nodes.into_iter().find_map(|name| {
+            let descr = Path::new("/sys/class/hidraw")
+                .join(&name)
+                .join("device/report_descriptor");
+            match fs::read(descr) {
+                Ok(d) if d.starts_with(&RAW_USAGE_PAGE_ITEM) => Some(Path::new("/dev").join(&name)),
+                _ => None,
+            }
+        })
+    }
+}
+
+impl Transport for Hidraw {
+    fn transact(&mut self, req: &[u8; PACKET_SIZE]) -> io::Result<[u8; PACKET_SIZE]> {
+        // The first byte is the report number, which is 0 for unnumbered reports.
+        let mut out = [0u8; PACKET_SIZE + 1];
+        out[1..].copy_from_slice(req);
+        self.file.write_all(&out)?;
+        let mut res = [0u8; PACKET_SIZE];
+        let n = self.file.read(&mut res)?;
+        if n != PACKET_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short report"));
+        }
+        Ok(res)
+    }
+}
+
+/// Simulated device served by `keytool simulate`.
+pub struct Socket {
+    stream: TcpStream,
+}
+
+impl Socket {
+    pub fn connect(addr: &str) -> io::Result<Socket> {
+        Ok(Socket {
+            stream: TcpStream::connect(addr)?,
+        })
+    }
+}
+
+impl Transport for Socket {
+    fn transact(&mut self, req: &[u8; PACKET_SIZE]) -> io::Result<[u8; PACKET_SIZE]> {
+        self.stream.write_all(req)?;
+        let mut res = [0u8; PACKET_SIZE];
+        self.stream.read_exact(&mut res)?;
+        Ok(res)
+    }
+}
+
+/// Response of `GetInfo`.
+pub struct Info {
+    pub protocol: u8,
+    pub version: (u8, u8, u8),
+    pub n_layers: usize,
+    pub n_keys: usize,
+    pub n_combos: usize,
+    pub keymap_loaded: bool,
+    /// Bytes of `Keymap::strings`, 0 for firmware without strings.
+    pub strings_size: usize,
+    /// Chord slots, 0 for firmware without chords.
+    pub n_chords: usize,
+    /// Keymap profiles, 1 for firmware without profiles.
+    pub n_profiles: usize,
+}
+
+/// Typed requests to the device.
+pub struct Client {
+    transport: Box<dyn Transport>,
+}
+
+impl Client {
+    pub fn new(transport: Box<dyn Transport>) -> Client {
+        Client { transport }
+    }
+
+    /// Send the request and return the payload of the response.
+    fn request(&mut self, req: Request) -> Result<[u8; PACKET_SIZE - 2], Error> {
+        let packet = req.encode();
+        let res = self.transport.transact(&packet)?;
+        if res[0] != packet[0] {
+            return Err(Error::Mismatch);
+        }
+        if res[1] != 0 {
+            return Err(Error::Status(res[1]));
+        }
+        let mut payload = [0u8; PACKET_SIZE - 2];
+        payload.copy_from_slice(&res[2..]);
+        Ok(payload)
+    }
+
+    pub fn info(&mut self) -> Result<Info, Error> {
+        let p = self.request(Request::GetInfo)?;
+        Ok(Info {
+            protocol: p[0],
+            version: (p[1], p[2], p[3]),
+            n_layers: p[4] as usize,
+            n_keys: p[5] as usize,
+            n_combos: p[6] as usize,
+            keymap_loaded: p[7] != 0,
+            strings_size: read_u16(&p[8..]) as usize,
+            n_chords: p[10] as usize,
+            n_profiles: (p[11] as usize).max(1),
+        })
+    }
+
+    pub fn get_key(&mut self, layer: usize, index: usize) -> Result<Command, Error> {
+        let p = self.request(Request::GetKey {
+            layer: layer as u8,
+            index: index as u8,
+        })?;
+        Command::decode(&p).ok_or(Error::Mismatch)
+    }
+
+    pub fn set_key(&mut self, layer: usize, index: usize, command: Command) -> Result<(), Error> {
+        self.request(Request::SetKey {
+            layer: layer as u8,
+            index: index as u8,
+            command,
+        })?;
+        Ok(())
+    }
+
+    pub fn get_combo(&mut self, slot: usize) -> Result<(Pos, Pos, Command), Error> {
+        let p = self.request(Request::GetCombo { slot: slot as u8 })?;
+        let command = Command::decode(&p[2..]).ok_or(Error::Mismatch)?;
+        Ok((p[0], p[1], command))
+    }
+
+    pub fn set_combo(
+        &mut self,
+        slot: usize,
+        k1: Pos,
+        k2: Pos,
+        command: Command,
+    ) -> Result<(), Error> {
+        self.request(Request::SetCombo {
+            slot: slot as u8,
+            k1,
+            k2,
+            command,
+        })?;
+        Ok(())
+    }
+
+    pub fn get_chord(&mut self, slot: usize) -> Result<Chord, Error> {
+        let p = self.request(Request::GetChord { slot: slot as u8 })?;
+        Chord::decode(&p).ok_or(Error::Mismatch)
+    }
+
+    pub fn set_chord(&mut self, slot: usize, chord: Chord) -> Result<(), Error> {
+        self.request(Request::SetChord {
+            slot: slot as u8,
+            chord,
+        })?;
+        Ok(())
+    }
+
+    /// Run a command of the shell of the keyboard and return its output.
+    pub fn shell(&mut self, command: &str) -> Result<Vec<u8>, Error> {
+        let mut line = [0u8; SHELL_LINE_SIZE];
+        line[..command.len()].copy_from_slice(command.as_bytes());
+        let mut len = command.len() as u8;
+        let mut output = Vec::new();
+        loop {
+            let p = self.request(Request::Shell { len, line })?;
+            let n = (p[0] as usize).min(SHELL_CHUNK);
+            if n == 0 {
+                return Ok(output);
+            }
+            output.extend_from_slice(&p[1..1 + n]);
+            len = 0;
+        }
+    }
+
+    pub fn get_setting(&mut self, key: u8) -> Result<u16, Error> {
+        let p = self.request(Request::GetSetting { key })?;
+        Ok(read_u16(&p))
+    }
+
+    pub fn set_setting(&mut self, key: u8, value: u16) -> Result<(), Error> {
+        self.request(Request::SetSetting { key, value })?;
+        Ok(())
+    }
+
+    /// Read `size` bytes of `Keymap::strings`.
+    pub fn get_strings(&mut self, size: usize) -> Result<Vec<u8>, Error> {
+        let mut strings = Vec::new();
+        while strings.len() < size {
+            let p = self.request(Request::GetStrings {
+                offset: strings.len() as u16,
+            })?;
+            let n = (size - strings.len()).min(STRINGS_CHUNK);
+            strings.extend_from_slice(&p[..n]);
+        }
+        Ok(strings)
+    }
+
+    pub fn set_strings(&mut self, strings: &[u8]) -> Result<(), Error> {
+        for (i, chunk) in strings.chunks(STRINGS_CHUNK).enumerate() {
+            let mut data = [0u8; STRINGS_CHUNK];
+            data[..chunk.len()].copy_from_slice(chunk);
+            self.request(Request::SetStrings {
+                offset: (i * STRINGS_CHUNK) as u16,
+                len: chunk.len() as u8,
+                data,
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn save_keymap(&mut self) -> Result<(), Error> {
+        self.request(Request::SaveKeymap)?;
+        Ok(())
+    }
+
+    pub fn reset_keymap(&mut self) -> Result<(), Error> {
+        self.request(Request::ResetKeymap)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,477 @@
+//! Host tool to read and change the keymap and settings of the keyboard over raw HID.
+
+mod device;
+mod json;
+mod qmk;
+mod sim;
+
+use crate::device::{Client, Error, Hidraw, Socket, Transport, HIDRAW_SUPPORTED};
+use key_stream::chord::Chord;
+use key_stream::grid::{grid_index, pos_grid};
+use key_stream::keymap::{
+    pack_strings, Command, Keymap, N_CHORDS, N_COMBOS, N_LAYERS, N_PROFILES, STRINGS_SIZE,
+};
+use key_stream::keymap_format::{self, format_action, parse_key, Combo, KeymapFile, Layer};
+use key_stream::protocol::SHELL_LINE_SIZE;
+use key_stream::validate;
+use key_stream::{render, settings};
+use std::net::TcpListener;
+use std::path::Path;
+use std::{env, fs, process};
+
+const DEFAULT_SIM_ADDR: &str = "127.0.0.1:7890";
+
+const USAGE: &str = "\
+usage: keytool [--device <hidraw> | --sim [<addr>]] <command> [<args>]
+
+The keyboard is reached through Linux hidraw, so Linux is the only supported
+platform; elsewhere only --sim and the commands without a keyboard work.
+
+commands:
+    info                              show firmware and keymap information
+    dump [<file>]                     write keymap and settings in the keymap file format
+    upload <file> [--no-save]         write keymap and settings from a file, then save the
+                                      keymap; settings, and the profile switches made to
+                                      write each profile, are saved by the keyboard on every
+                                      change, also with --no-save
+    check <file>                      check a keymap file without a keyboard
+    import-qmk <keymap.json> [<file>] convert a QMK keymap.json to the keymap file format
+    render [--svg <out> | --kle <out>] [<file>]
+                                      draw the layers and combos of a keymap file, or of the
+                                      built-in keymap, as text, an SVG image or a layout of
+                                      keyboard-layout-editor.com
+    get-key <layer> <key>             show a key, e.g. `get-key 0 R2C3`
+    set-key <layer> <key> <action>    change a key, e.g. `set-key 2 R1C2 SHIFT1+1`
+    set-combo <slot> <key> <key> <action>
+                                      change a combo, `_` as the action clears the slot
+    get <setting>                     show a setting
+    set <setting> <value>             change a setting
+    save                              save the keymap to flash
+    reset                             revert to the built-in keymap
+    shell <command>...                run a command of the keyboard shell, e.g. `shell stats`
+    simulate [<addr>]                 serve a simulated keyboard for --sim
+
+settings: combo_threshold, combo_separation (ms), default_layer,
+          host_layout (0: JIS, 1: US),
+          unicode_mode (0: Linux, 1: macOS, 2: WinCompose),
+          nicola (0: off, 1: on), nicola_threshold (ms),
+          chords (0: off, 1: on), chord_threshold (ms),
+          auto_eisu (0: off, 1: on), steno (0: off, 1: on),
+          steno_protocol (0: GeminiPR, 1: TX Bolt),
+          profile (keymap profile used by get-key, set-key and set-combo),
+          host_os (0: macOS, 1: Windows, 2: Linux),
+          auto_host_os (0: off, 1: detect host_os, 2: also profile),
+          tapping_term (ms),
+          mac_profile, windows_profile, linux_profile (profile switched to by
+          auto_host_os 2 on each OS, 255: keep the profile)
+";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("keytool: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let mut device = None;
+    let mut sim = None;
+    let mut rest = args;
+    while let Some(opt) = rest.first() {
+        match opt.as_str() {
+            "--device" => {
+                device = Some(rest.get(1).ok_or("--device needs a path")?.clone());
+                rest = &rest[2..];
+            }
+            "--sim" => match rest.get(1) {
+                Some(addr) if addr.contains(':') => {
+                    sim = Some(addr.clone());
+                    rest = &rest[2..];
+                }
+                _ => {
+                    sim = Some(DEFAULT_SIM_ADDR.to_string());
+                    rest = &rest[1..];
+                }
+            },
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                return Ok(());
+            }
+            _ => break,
+        }
+    }
+    let (cmd, args) = match rest.split_first() {
+        Some((cmd, args)) => (cmd.as_str(), args),
+        None => return Err(USAGE.to_string()),
+    };
+    if cmd == "simulate" {
+        let addr = args.first().map(String::as_str).unwrap_or(DEFAULT_SIM_ADDR);
+        let listener = TcpListener::bind(addr).map_err(|e| e.to_string())?;
+        eprintln!("simulated keyboard listening on {}", addr);
+        return sim::Simulator::new()
+            .serve(listener)
+            .map_err(|e| e.to_string());
+    }
+    if let ("check", [file]) = (cmd, args) {
+        return read_keymap_file(file).map(|_| ());
+    }
+    if cmd == "render" {
+        return render(args);
+    }
+    if cmd == "import-qmk" {
+        return import_qmk(args);
+    }
+
+    let transport: Box<dyn Transport> = match (device, sim) {
+        (_, Some(addr)) => {
+            Box::new(Socket::connect(&addr).map_err(|e| format!("{}: {}", addr, e))?)
+        }
+        (_, None) if !HIDRAW_SUPPORTED => return Err(Error::Unsupported.to_string()),
+        (Some(path), None) => {
+            Box::new(Hidraw::open(Path::new(&path)).map_err(|e| format!("{}: {}", path, e))?)
+        }
+        (None, None) => {
+            let path = Hidraw::find().ok_or_else(|| Error::NotFound.to_string())?;
+            Box::new(Hidraw::open(&path).map_err(|e| format!("{}: {}", path.display(), e))?)
+        }
+    };
+    let mut client = Client::new(transport);
+    match (cmd, args) {
+        ("info", []) => info(&mut client),
+        ("dump", []) => {
+            print!("{}", read_dump(&mut client)?.to_text());
+            Ok(())
+        }
+        ("dump", [file]) => {
+            let text = read_dump(&mut client)?.to_text();
+            fs::write(file, text).map_err(|e| format!("{}: {}", file, e))
+        }
+        ("upload", [file]) => upload(&mut client, file, true),
+        ("upload", [file, opt]) if opt == "--no-save" => upload(&mut client, file, false),
+        ("get-key", [layer, key]) => {
+            let index = key_index(key)?;
+            let c = client
+                .get_key(parse_num(layer)?, index)
+                .map_err(|e| e.to_string())?;
+            println!("{}", format_action(&c.to_action()));
+            Ok(())
+        }
+        ("set-key", [layer, key, command]) => {
+            let index = key_index(key)?;
+            let command = parse_command(command)?;
+            client
+                .set_key(parse_num(layer)?, index, command)
+                .map_err(|e| e.to_string())
+        }
+        ("set-combo", [slot, k1, k2, command]) => {
+            let command = parse_command(command)?;
+            let (k1, k2) = match command {
+                Command::Nop => (0, 0),
+                _ => (parse_key(k1)?, parse_key(k2)?),
+            };
+            client
+                .set_combo(parse_num(slot)?, k1, k2, command)
+                .map_err(|e| e.to_string())
+        }
+        ("get", [name]) => {
+            let v = client
+                .get_setting(setting_key(name)?)
+                .map_err(|e| e.to_string())?;
+            println!("{}", v);
+            Ok(())
+        }
+        ("set", [name, value]) => {
+            let value = value
+                .parse()
+                .map_err(|_| format!("invalid value `{}`", value))?;
+            client
+                .set_setting(setting_key(name)?, value)
+                .map_err(|e| e.to_string())
+        }
+        ("save", []) => client.save_keymap().map_err(|e| e.to_string()),
+        ("reset", []) => client.reset_keymap().map_err(|e| e.to_string()),
+        ("shell", words) if !words.is_empty() => {
+            let command = words.join(" ");
+            if command.len() > SHELL_LINE_SIZE {
+                return Err(format!(
+                    "shell command longer than {} bytes",
+                    SHELL_LINE_SIZE
+                ));
+            }
+            let output = client.shell(&command).map_err(|e| e.to_string())?;
+            print!("{}", String::from_utf8_lossy(&output));
+            Ok(())
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn info(client: &mut Client) -> Result<(), String> {
+    let info = client.info().map_err(|e| e.to_string())?;
+    let (major, minor, patch) = info.version;
+    println!("firmware: {}.{}.{}", major, minor, patch);
+    println!("protocol: {}", info.protocol);
+    println!(
+        "keymap:   {} layers x {} keys, {} combos, {}",
+        info.n_layers,
+        info.n_keys,
+        info.n_combos,
+        if info.keymap_loaded {
+            "loaded from flash"
+        } else {
+            "built-in"
+        }
+    );
+    if info.strings_size > 0 {
+        println!("strings:  {} bytes", info.strings_size);
+    }
+    if info.n_chords > 0 {
+        println!("chords:   {} slots", info.n_chords);
+    }
+    if info.n_profiles > 1 {
+        println!("profiles: {}", info.n_profiles);
+    }
+    Ok(())
+}
+
+fn read_dump(client: &mut Client) -> Result<KeymapFile, String> {
+    let e = |e: Error| e.to_string();
+    let info = client.info().map_err(e)?;
+    let mut keymap = Keymap::new();
+    let n_profiles = info.n_profiles.min(N_PROFILES);
+    let active = active_profile(client, n_profiles)?;
+    let result = (0..n_profiles).try_for_each(|profile| -> Result<(), String> {
+        select_profile(client, n_profiles, profile)?;
+        let mut keys = keymap.profile_keys(profile);
+        for (n, layer) in keys.layers.iter_mut().enumerate().take(info.n_layers) {
+            for (i, c) in layer.iter_mut().enumerate().take(info.n_keys) {
+                *c = client.get_key(n, i).map_err(e)?;
+            }
+        }
+        for (slot, combo) in keys.combos.iter_mut().enumerate().take(info.n_combos) {
+            *combo = client.get_combo(slot).map_err(e)?;
+        }
+        keymap.set_profile_keys(profile, keys);
+        Ok(())
+    });
+    // Also on errors, since the keyboard keeps the selected profile.
+    select_profile(client, n_profiles, active)?;
+    result?;
+    let size = info.strings_size.min(STRINGS_SIZE);
+    keymap.strings = [0; STRINGS_SIZE];
+    keymap.strings[..size].copy_from_slice(&client.get_strings(size).map_err(e)?);
+    keymap.chords = [Chord::EMPTY; N_CHORDS];
+    for (slot, chord) in keymap.chords.iter_mut().enumerate().take(info.n_chords) {
+        *chord = client.get_chord(slot).map_err(e)?;
+    }
+    let mut file = keymap.to_file();
+    file.layers.truncate(info.n_layers);
+    file.profiles.truncate(n_profiles - 1);
+    for p in file.profiles.iter_mut() {
+        p.layers.truncate(info.n_layers);
+    }
+    for key in settings::KEYS.iter() {
+        // Older firmware may not have all settings.
+        if let (Some(name), Ok(v)) = (settings::name(*key), client.get_setting(*key)) {
+            file.settings.push((name.to_string(), v));
+        }
+    }
+    Ok(file)
+}
+
+fn upload(client: &mut Client, file: &str, save: bool) -> Result<(), String> {
+    let parsed = read_keymap_file(file)?;
+    let info = client.info().map_err(|e| e.to_string())?;
+    let n_profiles = info.n_profiles.min(N_PROFILES);
+    if let Some(p) = parsed.profiles.iter().find(|p| p.number >= n_profiles) {
+        return Err(format!(
+            "{}:{}: the keyboard has no profile {}",
+            file, p.line, p.number
+        ));
+    }
+    let profiles = parsed
+        .profiles
+        .iter()
+        .map(|p| (p.number, &p.layers, &p.combos));
+    let profiles: Vec<(usize, &Vec<Layer>, &Vec<Combo>)> =
+        Some((0, &parsed.layers, &parsed.combos))
+            .into_iter()
+            .chain(profiles)
+            .collect();
+    let n_layers = info.n_layers.min(N_LAYERS);
+    let n_combos = info.n_combos.min(N_COMBOS);
+    for (_, layers, combos) in profiles.iter() {
+        if let Some(l) = layers.iter().find(|l| l.number >= n_layers) {
+            return Err(format!(
+                "{}:{}: the keyboard has no layer {}",
+                file, l.line, l.number
+            ));
+        }
+        if combos.len() > n_combos {
+            return Err(format!("the keyboard has only {} combo slots", n_combos));
+        }
+    }
+    let n_chords = info.n_chords.min(N_CHORDS);
+    if parsed.chords.len() > n_chords {
+        return Err(format!("the keyboard has only {} chord slots", n_chords));
+    }
+    let strings = pack_strings(&parsed.strings)?;
+    let strings_used: usize = parsed.strings.iter().map(|s| s.len() + 1).sum();
+    let strings_size = info.strings_size.min(STRINGS_SIZE);
+    if strings_used > strings_size {
+        return Err(format!(
+            "the keyboard has only {} bytes for strings, {} are used",
+            strings_size, strings_used
+        ));
+    }
+    let mut settings = Vec::new();
+    for (name, value) in parsed.settings.iter() {
+        settings.push((setting_key(name)?, *value));
+    }
+
+    let e = |e: Error| e.to_string();
+    let active = active_profile(client, n_profiles)?;
+    let result = profiles.iter().try_for_each(|(profile, layers, combos)| {
+        select_profile(client, n_profiles, *profile)?;
+        write_keys(client, info.n_keys, n_combos, layers, combos)
+    });
+    // Also on errors, since the keyboard keeps the selected profile.
+    select_profile(client, n_profiles, active)?;
+    result?;
+    client.set_strings(&strings[..strings_size]).map_err(e)?;
+    for slot in 0..n_chords {
+        let chord = match parsed.chords.get(slot) {
+            Some(c) => Chord::new(&c.keys, c.text.as_bytes())
+                .ok_or_else(|| format!("{}:{}: invalid chord", file, c.line))?,
+            None => Chord::EMPTY,
+        };
+        client.set_chord(slot, chord).map_err(e)?;
+    }
+    for (key, value) in settings {
+        client.set_setting(key, value).map_err(e)?;
+    }
+    if save {
+        client.save_keymap().map_err(e)?;
+    }
+    Ok(())
+}
+
+/// Write layers and combos of the selected profile.  Combo slots past `combos` are cleared.
+fn write_keys(
+    client: &mut Client,
+    n_keys: usize,
+    n_combos: usize,
+    layers: &[Layer],
+    combos: &[Combo],
+) -> Result<(), String> {
+    let e = |e: Error| e.to_string();
+    for l in layers.iter() {
+        for (i, a) in l.keys.iter().enumerate().take(n_keys) {
+            client
+                .set_key(l.number, i, Command::from_action(*a))
+                .map_err(e)?;
+        }
+    }
+    for slot in 0..n_combos {
+        let (k1, k2, c) = match combos.get(slot) {
+            Some(c) => (c.k1, c.k2, Command::from_action(c.action)),
+            None => (0, 0, Command::Nop),
+        };
+        client.set_combo(slot, k1, k2, c).map_err(e)?;
+    }
+    Ok(())
+}
+
+/// Profile whose keys are read and written by requests, 0 if the keyboard has no profiles.
+fn active_profile(client: &mut Client, n_profiles: usize) -> Result<usize, String> {
+    if n_profiles == 1 {
+        return Ok(0);
+    }
+    let v = client
+        .get_setting(settings::PROFILE)
+        .map_err(|e| e.to_string())?;
+    Ok(v as usize)
+}
+
+fn select_profile(client: &mut Client, n_profiles: usize, profile: usize) -> Result<(), String> {
+    if n_profiles == 1 {
+        return Ok(());
+    }
+    client
+        .set_setting(settings::PROFILE, profile as u16)
+        .map_err(|e| e.to_string())
+}
+
+fn render(args: &[String]) -> Result<(), String> {
+    let (format, args) = match args {
+        [opt, out, rest @ ..] if opt == "--svg" || opt == "--kle" => (Some((opt, out)), rest),
+        _ => (None, args),
+    };
+    let file = match args {
+        [] => Keymap::new().to_file(),
+        [file] => read_keymap_file(file)?,
+        _ => return Err(USAGE.to_string()),
+    };
+    let (text, out) = match format {
+        Some((opt, out)) if opt == "--svg" => (render::svg(&file), out),
+        Some((_, out)) => (render::kle(&file), out),
+        None => {
+            print!("{}", render::ascii(&file));
+            return Ok(());
+        }
+    };
+    fs::write(out, text).map_err(|e| format!("{}: {}", out, e))
+}
+
+fn import_qmk(args: &[String]) -> Result<(), String> {
+    let (json_file, out) = match args {
+        [json_file] => (json_file, None),
+        [json_file, out] => (json_file, Some(out)),
+        _ => return Err(USAGE.to_string()),
+    };
+    let text = fs::read_to_string(json_file).map_err(|e| format!("{}: {}", json_file, e))?;
+    let import = qmk::import(&text).map_err(|e| format!("{}: {}", json_file, e))?;
+    for d in import.diagnostics.iter() {
+        eprintln!("{}: {}", json_file, d);
+    }
+    let text = import.file.to_text();
+    match out {
+        Some(out) => fs::write(out, text).map_err(|e| format!("{}: {}", out, e)),
+        None => {
+            print!("{}", text);
+            Ok(())
+        }
+    }
+}
+
+/// Parse and validate a keymap file, printing the warnings.
+fn read_keymap_file(file: &str) -> Result<KeymapFile, String> {
+    let text = fs::read_to_string(file).map_err(|e| format!("{}: {}", file, e))?;
+    let parsed = keymap_format::parse(&text).map_err(|e| format!("{}:{}", file, e))?;
+    let diags = validate::check(&parsed);
+    for d in diags.iter() {
+        eprintln!("{}:{}", file, d);
+    }
+    if validate::has_error(&diags) {
+        return Err(format!("{}: the keymap has errors", file));
+    }
+    Ok(parsed)
+}
+
+fn parse_command(s: &str) -> Result<Command, String> {
+    keymap_format::parse_action(s).map(Command::from_action)
+}
+
+fn key_index(s: &str) -> Result<usize, String> {
+    let (row, col) = pos_grid(parse_key(s)?).ok_or_else(|| format!("`{}` is not a key", s))?;
+    Ok(grid_index(row, col))
+}
+
+fn setting_key(name: &str) -> Result<u8, String> {
+    settings::key_by_name(name).ok_or_else(|| format!("unknown setting `{}`", name))
+}
+
+fn parse_num(s: &str) -> Result<usize, String> {
+    s.parse().map_err(|_| format!("invalid number `{}`", s))
+}
@@ -0,0 +1,518 @@
+//! Import of QMK `keymap.json` files.
+//!
+//! QMK key codes are USB HID usages, so keys are imported by their codes and send the same
+//! reports; the names in the keymap file are then those of a JIS host, e.g. `KC_LBRC` becomes
+//! `JP_AT`.  Layer n of QMK becomes layer n, and `MO(n)` becomes `MODn`.
+//!
+//! Tap-hold keys of the keyboard are one modifier key and a key, so `LT(n, kc)` becomes
+//! `MODn/kc` and mod-taps of one modifier such as `LGUI_T(kc)` become `UI1/kc`; others keep
+//! the layer key or the tapped key.  Transparent keys take the key of layer 0, which is the layer below while a
+//! layer key of the base layer is held.  Every such change is reported.
+
+use crate::json::{self, Value};
+use key_stream::grid::{grid_index, grid_pos, GRID_COLS, GRID_ROWS};
+use key_stream::hid_keycodes::Kc;
+use key_stream::keymap::N_LAYERS;
+use key_stream::keymap_format::{format_key, Action, KeymapFile, Layer, LAYER_SIZE, MODIFIERS};
+
+/// QMK names of the basic keys and their codes.
+const KEYS: &[(&str, u8)] = &[
+    ("NO", 0x00),
+    ("ENTER", 0x28),
+    ("ENT", 0x28),
+    ("ESCAPE", 0x29),
+    ("ESC", 0x29),
+    ("BACKSPACE", 0x2a),
+    ("BSPC", 0x2a),
+    ("TAB", 0x2b),
+    ("SPACE", 0x2c),
+    ("SPC", 0x2c),
+    ("MINUS", 0x2d),
+    ("MINS", 0x2d),
+    ("EQUAL", 0x2e),
+    ("EQL", 0x2e),
+    ("LEFT_BRACKET", 0x2f),
+    ("LBRACKET", 0x2f),
+    ("LBRC", 0x2f),
+    ("RIGHT_BRACKET", 0x30),
+    ("RBRACKET", 0x30),
+    ("RBRC", 0x30),
+    ("BACKSLASH", 0x31),
+    ("BSLS", 0x31),
+    ("NONUS_HASH", 0x32),
+    ("NUHS", 0x32),
+    ("SEMICOLON", 0x33),
+    ("SCLN", 0x33),
+    ("QUOTE", 0x34),
+    ("QUOT", 0x34),
+    ("GRAVE", 0x35),
+    ("GRV", 0x35),
+    ("COMMA", 0x36),
+    ("COMM", 0x36),
+    ("DOT", 0x37),
+    ("SLASH", 0x38),
+    ("SLSH", 0x38),
+    ("CAPS_LOCK", 0x39),
+    ("CAPS", 0x39),
+    ("PRINT_SCREEN", 0x46),
+    ("PSCR", 0x46),
+    ("SCROLL_LOCK", 0x47),
+    ("SCRL", 0x47),
+    ("PAUSE", 0x48),
+    ("PAUS", 0x48),
+    ("INSERT", 0x49),
+    ("INS", 0x49),
+    ("HOME", 0x4a),
+    ("PAGE_UP", 0x4b),
+    ("PGUP", 0x4b),
+    ("DELETE", 0x4c),
+    ("DEL", 0x4c),
+    ("END", 0x4d),
+    ("PAGE_DOWN", 0x4e),
+    ("PGDN", 0x4e),
+    ("RIGHT", 0x4f),
+    ("RGHT", 0x4f),
+    ("LEFT", 0x50),
+    ("DOWN", 0x51),
+    ("UP", 0x52),
+    ("NUM_LOCK", 0x53),
+    ("NUM", 0x53),
+    ("KP_SLASH", 0x54),
+    ("PSLS", 0x54),
+    ("KP_ASTERISK", 0x55),
+    ("PAST", 0x55),
+    ("KP_MINUS", 0x56),
+    ("PMNS", 0x56),
+    ("KP_PLUS", 0x57),
+    ("PPLS", 0x57),
+    ("KP_ENTER", 0x58),
+    ("PENT", 0x58),
+    ("KP_DOT", 0x63),
+    ("PDOT", 0x63),
+    ("NONUS_BACKSLASH", 0x64),
+    ("NUBS", 0x64),
+    ("APPLICATION", 0x65),
+    ("APP", 0x65),
+    ("INTERNATIONAL_1", 0x87),
+    ("INT1", 0x87),
+    ("INTERNATIONAL_2", 0x88),
+    ("INT2", 0x88),
+    ("INTERNATIONAL_3", 0x89),
+    ("INT3", 0x89),
+    ("INTERNATIONAL_4", 0x8a),
+    ("INT4", 0x8a),
+    ("INTERNATIONAL_5", 0x8b),
+    ("INT5", 0x8b),
+    ("LANGUAGE_1", 0x90),
+    ("LNG1", 0x90),
+    ("LANGUAGE_2", 0x91),
+    ("LNG2", 0x91),
+];
+
+/// QMK names of shifted keys and the codes of the unshifted keys.
+const SHIFTED_KEYS: &[(&str, u8)] = &[
+    ("EXLM", 0x1e),
+    ("EXCLAIM", 0x1e),
+    ("AT", 0x1f),
+    ("HASH", 0x20),
+    ("DLR", 0x21),
+    ("DOLLAR", 0x21),
+    ("PERC", 0x22),
+    ("PERCENT", 0x22),
+    ("CIRC", 0x23),
+    ("CIRCUMFLEX", 0x23),
+    ("AMPR", 0x24),
+    ("AMPERSAND", 0x24),
+    ("ASTR", 0x25),
+    ("ASTERISK", 0x25),
+    ("LPRN", 0x26),
+    ("LEFT_PAREN", 0x26),
+    ("RPRN", 0x27),
+    ("RIGHT_PAREN", 0x27),
+    ("UNDS", 0x2d),
+    ("UNDERSCORE", 0x2d),
+    ("PLUS", 0x2e),
+    ("LCBR", 0x2f),
+    ("LEFT_CURLY_BRACE", 0x2f),
+    ("RCBR", 0x30),
+    ("RIGHT_CURLY_BRACE", 0x30),
+    ("PIPE", 0x31),
+    ("COLN", 0x33),
+    ("COLON", 0x33),
+    ("DQUO", 0x34),
+    ("DQT", 0x34),
+    ("DOUBLE_QUOTE", 0x34),
+    ("TILD", 0x35),
+    ("TILDE", 0x35),
+    ("LABK", 0x36),
+    ("LT", 0x36),
+    ("RABK", 0x37),
+    ("GT", 0x37),
+    ("QUES", 0x38),
+    ("QUESTION", 0x38),
+];
+
+/// QMK names of modifiers and the modifier key.
+const MODS: &[(&str, &str)] = &[
+    ("LCTL", "CTRL1"),
+    ("LCTRL", "CTRL1"),
+    ("LEFT_CTRL", "CTRL1"),
+    ("LSFT", "SHIFT1"),
+    ("LSHIFT", "SHIFT1"),
+    ("LEFT_SHIFT", "SHIFT1"),
+    ("LALT", "ALT1"),
+    ("LOPT", "ALT1"),
+    ("LEFT_ALT", "ALT1"),
+    ("LGUI", "UI1"),
+    ("LCMD", "UI1"),
+    ("LWIN", "UI1"),
+    ("LEFT_GUI", "UI1"),
+    ("RCTL", "RCTRL"),
+    ("RCTRL", "RCTRL"),
+    ("RIGHT_CTRL", "RCTRL"),
+    ("RSFT", "RSHIFT"),
+    ("RSHIFT", "RSHIFT"),
+    ("RIGHT_SHIFT", "RSHIFT"),
+    ("RALT", "RALT"),
+    ("ROPT", "RALT"),
+    ("ALGR", "RALT"),
+    ("RIGHT_ALT", "RALT"),
+    ("RGUI", "RUI"),
+    ("RCMD", "RUI"),
+    ("RWIN", "RUI"),
+    ("RIGHT_GUI", "RUI"),
+    ("HYPR", "HYPER"),
+    ("HYPER", "HYPER"),
+    ("MEH", "MEH"),
+];
+
+/// Short names of modifier functions such as `S(kc)`, and the names of mod-taps without `_T`.
+const MOD_ALIASES: &[(&str, &str)] = &[
+    ("C", "LCTL"),
+    ("S", "LSFT"),
+    ("A", "LALT"),
+    ("G", "LGUI"),
+    ("CTL", "LCTL"),
+    ("SFT", "LSFT"),
+    ("ALT", "LALT"),
+    ("GUI", "LGUI"),
+    ("OPT", "LALT"),
+    ("CMD", "LGUI"),
+    ("WIN", "LGUI"),
+];
+
+pub struct Import {
+    pub file: KeymapFile,
+    /// Changes and losses of the import, one line each.
+    pub diagnostics: Vec<String>,
+}
+
+pub fn import(text: &str) -> Result<Import, String> {
+    let root = json::parse(text).map_err(|e| e.to_string())?;
+    let layers = root
+        .get("layers")
+        .and_then(Value::as_array)
+        .ok_or("`layers` is not an array of layers")?;
+    let mut diagnostics = Vec::new();
+    let mut file = KeymapFile::default();
+    let mut sources: Vec<Vec<String>> = Vec::new();
+    for (n, layer) in layers.iter().enumerate() {
+        let keys = layer
+            .as_array()
+            .ok_or_else(|| format!("layer {} is not an array", n))?;
+        if keys.len() != LAYER_SIZE {
+            return Err(format!(
+                "layer {} has {} keys, expected {} in {} rows of {}",
+                n,
+                keys.len(),
+                LAYER_SIZE,
+                GRID_ROWS,
+                GRID_COLS
+            ));
+        }
+        if n >= N_LAYERS {
+            diagnostics.push(format!(
+                "layer {}: dropped, the keyboard has {} layers",
+                n, N_LAYERS
+            ));
+            continue;
+        }
+        let names = keys
+            .iter()
+            .map(|k| k.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("layer {} has a key which is not a string", n))?;
+        sources.push(names);
+    }
+
+    for (n, names) in sources.iter().enumerate() {
+        let mut layer = Layer {
+            number: n,
+            keys: [Action::Nop; LAYER_SIZE],
+            line: 0,
+        };
+        // QMK layouts list the keys row by row across both halves.
+        for (i, name) in names.iter().enumerate() {
+            let (row, col) = (i / GRID_COLS, i % GRID_COLS);
+            let index = grid_index(row, col);
+            let mut report = |message: String| {
+                diagnostics.push(format!(
+                    "layer {} {}: {}: {}",
+                    n,
+                    format_key(grid_pos(row, col)),
+                    name,
+                    message
+                ))
+            };
+            layer.keys[index] = match convert(name.trim()) {
+                Ok(Converted::Exact(a)) => a,
+                Ok(Converted::Changed(a, message)) => {
+                    report(message);
+                    a
+                }
+                Ok(Converted::Transparent) if n == 0 => Action::Nop,
+                Ok(Converted::Transparent) => file.layers[0].keys[index],
+                Err(message) => {
+                    report(message + ", imported as nothing");
+                    Action::Nop
+                }
+            };
+        }
+        file.layers.push(layer);
+    }
+    Ok(Import { file, diagnostics })
+}
+
+enum Converted {
+    Exact(Action),
+    /// The action and what was changed.
+    Changed(Action, String),
+    Transparent,
+}
+
+fn convert(name: &str) -> Result<Converted, String> {
+    if let "KC_TRNS" | "KC_TRANSPARENT" | "_______" = name {
+        return Ok(Converted::Transparent);
+    }
+    if name == "XXXXXXX" {
+        return Ok(Converted::Exact(Action::Nop));
+    }
+    if let "QK_BOOT" | "QK_BOOTLOADER" | "RESET" = name {
+        return Ok(Converted::Exact(Action::Reset));
+    }
+    let (function, args) = match name.find('(') {
+        Some(i) if name.ends_with(')') => {
+            let args: Vec<&str> = name[i + 1..name.len() - 1]
+                .split(',')
+                .map(str::trim)
+                .collect();
+            (&name[..i], args)
+        }
+        _ => return key(name).map(Converted::Exact),
+    };
+    match (function, args.as_slice()) {
+        ("MO", [n]) => layer_key(n).map(Converted::Exact),
+        ("LT", [n, tap]) => match (layer_key(n)?, key(tap)?) {
+            (Action::Modifier(i), Action::Key(kc)) => Ok(Converted::Exact(Action::ModTap(i, kc))),
+            (layer, _) => Ok(Converted::Changed(
+                layer,
+                format!("only keys are tapped, tapping for {} is lost", tap),
+            )),
+        },
+        ("MT", [mods, tap]) => mod_tap(mods, tap),
+        ("UC", [cp]) => unicode(cp).map(Converted::Exact),
+        (f, [tap]) if f.ends_with("_T") => mod_tap(&f[..f.len() - 2], tap),
+        (f, [kc]) => {
+            let bits = modifier_bits(f)?;
+            match convert(kc)? {
+                Converted::Exact(Action::Key(kc)) => {
+                    Ok(Converted::Exact(Action::ModifiedKey(bits, kc)))
+                }
+                Converted::Exact(Action::ModifiedKey(b, kc)) => {
+                    Ok(Converted::Exact(Action::ModifiedKey(b | bits, kc)))
+                }
+                _ => Err(format!("`{}` is not a key to modify", kc)),
+            }
+        }
+        _ => Err("unsupported key".to_string()),
+    }
+}
+
+fn key(name: &str) -> Result<Action, String> {
+    let unsupported = || Err("unsupported key".to_string());
+    let name = match name.strip_prefix("KC_") {
+        Some(name) => name,
+        None => return unsupported(),
+    };
+    if let Some((_, m)) = MODS.iter().find(|(n, _)| *n == name) {
+        return Ok(Action::Modifier(modifier_index(m)));
+    }
+    if let Some((_, kc)) = KEYS.iter().find(|(n, _)| *n == name) {
+        return Ok(match kc {
+            0 => Action::Nop,
+            code => Action::Key(Kc::from_code(*code)),
+        });
+    }
+    if let Some((_, kc)) = SHIFTED_KEYS.iter().find(|(n, _)| *n == name) {
+        let shift = 1 << modifier_index("SHIFT1");
+        return Ok(Action::ModifiedKey(shift, Kc::from_code(*kc)));
+    }
+    let b = name.as_bytes();
+    let code = match (b, name.get(1..).and_then(|s| s.parse::<u8>().ok())) {
+        ([c], _) if c.is_ascii_uppercase() => c - b'A' + 0x04,
+        ([b'0'], _) => 0x27,
+        ([c], _) if c.is_ascii_digit() => c - b'1' + 0x1e,
+        ([b'F', ..], Some(n @ 1..=12)) => 0x3a + n - 1,
+        ([b'F', ..], Some(n @ 13..=24)) => 0x68 + n - 13,
+        ([b'P', ..], Some(0)) if name.len() == 2 => 0x62,
+        ([b'P', ..], Some(n @ 1..=9)) if name.len() == 2 => 0x59 + n - 1,
+        _ => return unsupported(),
+    };
+    Ok(Action::Key(Kc::from_code(code)))
+}
+
+fn layer_key(n: &str) -> Result<Action, String> {
+    match n.parse() {
+        Ok(n @ 1..=3) => Ok(Action::Modifier(modifier_index(&format!("MOD{}", n)))),
+        _ => Err(format!("no layer key for layer {}, only 1 to 3", n)),
+    }
+}
+
+fn mod_tap(mods: &str, tap: &str) -> Result<Converted, String> {
+    let mut bits = 0;
+    for m in mods.split('|') {
+        bits |= modifier_bits(m.trim().trim_start_matches("MOD_"))?;
+    }
+    match key(tap)? {
+        Action::Key(kc) if bits.count_ones() == 1 => Ok(Converted::Exact(Action::ModTap(
+            bits.trailing_zeros() as usize,
+            kc,
+        ))),
+        tapped => Ok(Converted::Changed(
+            tapped,
+            "only one modifier taps a key, holding for the modifiers is lost".to_string(),
+        )),
+    }
+}
+
+fn unicode(cp: &str) -> Result<Action, String> {
+    u32::from_str_radix(cp.trim_start_matches("0x").trim_start_matches("0X"), 16)
+        .ok()
+        .and_then(char::from_u32)
+        .map(Action::Unicode)
+        .ok_or_else(|| format!("`{}` is not a Unicode character", cp))
+}
+
+/// Bits of the modifiers of the QMK modifier function, such as `LSFT` or `S`.
+fn modifier_bits(function: &str) -> Result<u16, String> {
+    let name = MOD_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == function)
+        .map_or(function, |(_, name)| name);
+    match MODS.iter().find(|(n, _)| *n == name) {
+        Some((_, m)) => Ok(1 << modifier_index(m)),
+        None => Err(format!("unsupported function `{}`", function)),
+    }
+}
+
+fn modifier_index(name: &str) -> usize {
+    MODIFIERS.iter().position(|m| *m == name).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use key_stream::hid_keycodes as KC;
+    use key_stream::keymap_format::format_action;
+
+    fn layer_json(keys: &[&str]) -> String {
+        let mut all: Vec<String> = keys.iter().map(|k| format!("\"{}\"", k)).collect();
+        all.resize(LAYER_SIZE, "\"KC_NO\"".to_string());
+        format!("[{}]", all.join(", "))
+    }
+
+    #[test]
+    fn test_convert() {
+        let action = |name| match convert(name) {
+            Ok(Converted::Exact(a)) | Ok(Converted::Changed(a, _)) => format_action(&a),
+            Ok(Converted::Transparent) => "transparent".to_string(),
+            Err(e) => e,
+        };
+        assert_eq!(action("KC_A"), "A");
+        assert_eq!(action("KC_0"), "0");
+        assert_eq!(action("KC_1"), "1");
+        assert_eq!(action("KC_F12"), "F12");
+        assert_eq!(action("KC_ENT"), "ENTER");
+        assert_eq!(action("KC_LBRC"), "JP_AT");
+        assert_eq!(action("KC_EXLM"), "SHIFT1+1");
+        assert_eq!(action("KC_LSFT"), "SHIFT1");
+        assert_eq!(action("KC_NO"), "_");
+        assert_eq!(action("KC_TRNS"), "transparent");
+        assert_eq!(action("QK_BOOT"), "RESET");
+        assert_eq!(action("MO(2)"), "MOD2");
+        assert_eq!(action("LT(1, KC_SPC)"), "MOD1/SPACEBAR");
+        assert_eq!(action("LT(1, KC_EXLM)"), "MOD1");
+        assert_eq!(action("LSFT_T(KC_A)"), "SHIFT1/A");
+        assert_eq!(action("LGUI_T(KC_LNG2)"), "UI1/JP_EISU");
+        assert_eq!(action("MT(MOD_LCTL | MOD_LSFT, KC_Z)"), "Z");
+        assert_eq!(action("LCTL(KC_C)"), "CTRL1+C");
+        assert_eq!(action("C(S(KC_TAB))"), "CTRL1+SHIFT1+TAB");
+        assert_eq!(action("UC(0x2192)"), "U+2192");
+        assert_eq!(action("UC(0xD800)"), "`0xD800` is not a Unicode character");
+        assert_eq!(action("MO(4)"), "no layer key for layer 4, only 1 to 3");
+        assert_eq!(action("KC_RSFT"), "RSHIFT");
+        assert_eq!(action("KC_HYPR"), "HYPER");
+        assert_eq!(action("MEH(KC_K)"), "MEH+K");
+        assert_eq!(action("RALT(KC_E)"), "RALT+E");
+        assert_eq!(action("TG(1)"), "unsupported function `TG`");
+        assert_eq!(action("KC_MPLY"), "unsupported key");
+    }
+
+    #[test]
+    fn test_import() {
+        let text = format!(
+            r#"{{"keyboard": "test", "layout": "LAYOUT_ortho_4x12", "layers": [{}, {}]}}"#,
+            layer_json(&["KC_TAB", "KC_Q", "KC_W", "KC_E", "KC_R", "KC_T", "KC_Y", "MO(1)"]),
+            layer_json(&[
+                "KC_TRNS",
+                "KC_EXLM",
+                "KC_W",
+                "KC_E",
+                "KC_R",
+                "KC_T",
+                "LT(1, KC_EXLM)"
+            ])
+        );
+        let import = import(&text).unwrap();
+        let layers = &import.file.layers;
+        assert_eq!(layers.len(), 2);
+        // The seventh key of the first row is R1C7, the first key of the right half.
+        assert_eq!(layers[0].keys[grid_index(0, 6)], Action::Key(KC::KBD_Y));
+        assert_eq!(
+            format_action(&layers[0].keys[grid_index(0, 7)]),
+            "MOD1".to_string()
+        );
+        assert_eq!(layers[1].keys[grid_index(0, 0)], Action::Key(KC::KBD_TAB));
+        assert_eq!(
+            import.diagnostics,
+            vec!["layer 1 R1C7: LT(1, KC_EXLM): only keys are tapped, tapping for KC_EXLM is lost"]
+        );
+
+        let short = r#"{"layers": [["KC_A"]]}"#;
+        assert_eq!(
+            import_err(short),
+            "layer 0 has 1 keys, expected 48 in 4 rows of 12"
+        );
+        assert_eq!(
+            import_err(r#"{"layout": "x"}"#),
+            "`layers` is not an array of layers"
+        );
+    }
+
+    fn import_err(text: &str) -> String {
+        match import(text) {
+            Ok(_) => panic!("imported {}", text),
+            Err(e) => e,
+        }
+    }
+}
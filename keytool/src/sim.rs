@@ -0,0 +1,110 @@
+//! Simulated keyboard serving the raw HID protocol over TCP.
+//!
+//! Settings are kept in a store on RAM while the simulator runs.  Keymap storage requests
+//! are only logged.
+
+use key_stream::protocol::PACKET_SIZE;
+use key_stream::settings::{RamPage, SettingsStore};
+use key_stream::{KeyStream, StorageRequest};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+const SETTINGS_PAGE_SIZE: usize = 2048;
+
+pub struct Simulator {
+    stream: KeyStream,
+    settings: SettingsStore<RamPage<SETTINGS_PAGE_SIZE>>,
+}
+
+impl Simulator {
+    pub fn new() -> Simulator {
+        let settings = match SettingsStore::open([RamPage::new(), RamPage::new()]) {
+            Ok(store) => store,
+            Err(_) => unreachable!("erased pages are always valid"),
+        };
+        Simulator {
+            stream: KeyStream::new(),
+            settings,
+        }
+    }
+
+    /// Same as the transform loop of the firmware does for one request.
+    pub fn handle(&mut self, packet: &[u8; PACKET_SIZE]) -> [u8; PACKET_SIZE] {
+        let res = self.stream.handle_request(packet);
+        match self.stream.take_storage_request() {
+            Some(StorageRequest::Save) => eprintln!("keymap saved"),
+            Some(StorageRequest::Erase) => eprintln!("keymap erased"),
+            None => {}
+        }
+        if self.stream.save_settings(&mut self.settings).is_err() {
+            eprintln!("failed to save settings");
+        }
+        res
+    }
+
+    /// Serve connections one by one until the listener fails.
+    pub fn serve(&mut self, listener: TcpListener) -> io::Result<()> {
+        for conn in listener.incoming() {
+            let conn = conn?;
+            if let Err(e) = self.serve_connection(conn) {
+                eprintln!("connection closed: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn serve_connection(&mut self, mut conn: TcpStream) -> io::Result<()> {
+        let mut packet = [0u8; PACKET_SIZE];
+        loop {
+            match conn.read_exact(&mut packet) {
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                r => r?,
+            }
+            conn.write_all(&self.handle(&packet))?;
+        }
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Simulator {
+        Simulator::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{Client, Socket};
+    use key_stream::keymap::{Command, STRINGS_SIZE};
+    use key_stream::settings;
+    use std::thread;
+
+    #[test]
+    fn test_client_with_simulator() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || Simulator::new().serve(listener));
+
+        let mut client = Client::new(Box::new(Socket::connect(&addr).unwrap()));
+        let info = client.info().unwrap();
+        assert_eq!((info.n_layers, info.n_keys), (4, 48));
+        assert!(!info.keymap_loaded);
+
+        client.set_key(1, 5, Command::RequestReset).unwrap();
+        assert!(client.get_key(1, 5).unwrap() == Command::RequestReset);
+        assert!(client.get_key(4, 0).is_err());
+
+        assert_eq!(info.strings_size, STRINGS_SIZE);
+        let mut strings = vec![b'a'; 40];
+        strings.push(0);
+        client.set_strings(&strings).unwrap();
+        assert_eq!(client.get_strings(41).unwrap(), strings);
+
+        client.set_setting(settings::DEFAULT_LAYER, 2).unwrap();
+        assert_eq!(client.get_setting(settings::DEFAULT_LAYER).unwrap(), 2);
+
+        client.save_keymap().unwrap();
+        client.reset_keymap().unwrap();
+        assert!(client.get_key(1, 5).unwrap() != Command::RequestReset);
+    }
+}